@@ -0,0 +1,87 @@
+use std::{fmt, iter::Sum};
+
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+
+/// A number of coins, with arithmetic that fails instead of wrapping.
+///
+/// `TXOutput::value` is still an `i32` on the wire, widening it changes how
+/// every transaction is encoded and hashed and needs a new network version.
+/// Totals over many outputs are computed as `Amount`s meanwhile.
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+pub struct Amount(u64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+    pub const MAX: Amount = Amount(u64::MAX);
+
+    pub fn new(coins: u64) -> Self {
+        Self(coins)
+    }
+
+    /// Converts the value of an output, which is never negative in a valid
+    /// transaction.
+    pub fn from_value(value: i32) -> Result<Self> {
+        u64::try_from(value)
+            .map(Self)
+            .map_err(|_| anyhow!("Negative amount {}", value))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).map(Self)
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Self)
+    }
+
+    /// Adds up `values`, failing on a negative value or an overflow.
+    pub fn sum_values<I>(values: I) -> Result<Amount>
+    where
+        I: IntoIterator<Item = i32>,
+    {
+        values
+            .into_iter()
+            .map(Amount::from_value)
+            .sum::<Result<Option<Amount>>>()?
+            .ok_or_else(|| anyhow!("Amount overflow"))
+    }
+}
+
+/// Sums to `None` on overflow.
+impl Sum<Amount> for Option<Amount> {
+    fn sum<I: Iterator<Item = Amount>>(mut iter: I) -> Self {
+        iter.try_fold(Amount::ZERO, Amount::checked_add)
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_overflow_is_an_error() {
+        assert_eq!(
+            Amount::sum_values([i32::MAX, i32::MAX, 2]).unwrap(),
+            Amount::new(2 * i32::MAX as u64 + 2)
+        );
+        assert!(Amount::sum_values([1, -1]).is_err());
+
+        assert_eq!(Amount::MAX.checked_add(Amount::new(1)), None);
+        assert_eq!(Amount::ZERO.checked_sub(Amount::new(1)), None);
+        let total: Option<Amount> = [Amount::MAX, Amount::new(1)].into_iter().sum();
+        assert_eq!(total, None);
+    }
+}
@@ -1,93 +1,258 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::{
+    collections::HashSet,
+    fmt,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use anyhow::Result;
-use bincode::{config::standard, serde::encode_to_vec};
+use anyhow::{Result, anyhow};
+use bincode::{
+    config::standard,
+    serde::{decode_from_slice, encode_to_vec},
+};
 use log::info;
 use rs_merkle::MerkleTree;
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
 
-use crate::Transaction;
+use crate::{ChainParams, DECODE_CONFIG, Transaction, format_timestamp};
 
-const TARGET_BITS: usize = 2;
+/// A 32 byte block or transaction hash, displayed and parsed as hex.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub struct Hash(pub [u8; 32]);
 
-pub type HashType = [u8; 32];
+impl Hash {
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl From<[u8; 32]> for Hash {
+    fn from(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl From<Hash> for [u8; 32] {
+    fn from(hash: Hash) -> Self {
+        hash.0
+    }
+}
+
+impl TryFrom<&[u8]> for Hash {
+    type Error = anyhow::Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self> {
+        let bytes: [u8; 32] = bytes
+            .try_into()
+            .map_err(|_| anyhow!("Expected 32 hash bytes, got {}", bytes.len()))?;
+        Ok(Self(bytes))
+    }
+}
+
+impl AsRef<[u8]> for Hash {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Display for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+impl fmt::Debug for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Hash({})", self)
+    }
+}
+
+impl FromStr for Hash {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let bytes = hex::decode(s).map_err(|e| anyhow!("Invalid hash {}: {}", s, e))?;
+        Self::try_from(bytes.as_slice())
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Block {
     timestamp: u128,
     pub transactions: Vec<Transaction>,
-    pub prev_block_hash: HashType,
-    pub hash: HashType,
+    pub prev_block_hash: Hash,
+    pub hash: Hash,
     pub nonce: i32,
     pub height: i32,
+    /// Difficulty as the number of leading zero bits the hash must have
+    pub bits: u32,
 }
 
 impl Block {
-    pub fn new_genesis_block(coinbase: Transaction) -> Self {
-        Self::new(vec![coinbase], HashType::default(), 0).unwrap()
+    pub fn new_genesis_block(coinbase: Transaction, params: &ChainParams) -> Self {
+        Self::new(
+            vec![coinbase],
+            Hash::default(),
+            0,
+            params.genesis_bits(),
+            params,
+        )
+        .unwrap()
     }
 
     pub fn new(
         transactions: Vec<Transaction>,
-        prev_block_hash: HashType,
+        prev_block_hash: Hash,
+        height: i32,
+        bits: u32,
+        params: &ChainParams,
+    ) -> Result<Self> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+        Self::new_at(
+            transactions,
+            prev_block_hash,
+            height,
+            bits,
+            timestamp,
+            params,
+        )
+    }
+
+    /// Mines a block with a fixed `timestamp` instead of the current time.
+    ///
+    /// The nonce search always starts from zero, so the same inputs always
+    /// produce the same block, which lets tests assert on block hashes.
+    #[cfg(test)]
+    pub(crate) fn new_deterministic(
+        transactions: Vec<Transaction>,
+        prev_block_hash: Hash,
         height: i32,
+        bits: u32,
+        timestamp: u128,
+        params: &ChainParams,
+    ) -> Result<Self> {
+        Self::new_at(
+            transactions,
+            prev_block_hash,
+            height,
+            bits,
+            timestamp,
+            params,
+        )
+    }
+
+    fn new_at(
+        transactions: Vec<Transaction>,
+        prev_block_hash: Hash,
+        height: i32,
+        bits: u32,
+        timestamp: u128,
+        params: &ChainParams,
     ) -> Result<Self> {
         let mut data = Self {
-            timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis(),
+            timestamp,
             prev_block_hash,
             transactions,
-            hash: HashType::default(),
+            hash: Hash::default(),
             nonce: 0,
             height,
+            bits,
         };
-        data.run_proof_of_work()?;
+        data.run_proof_of_work(params)?;
         Ok(data)
     }
 
-    fn prepare_hash_data(&self) -> Result<Vec<u8>> {
-        let data_to_hash = (
+    pub fn timestamp(&self) -> u128 {
+        self.timestamp
+    }
+
+    /// Size of the block as stored and sent, in bytes.
+    pub fn size(&self) -> Result<usize> {
+        Ok(encode_to_vec(self, standard())?.len())
+    }
+
+    /// Expected number of hashes needed to find this block.
+    pub fn work(&self) -> f64 {
+        2f64.powi(self.bits as i32)
+    }
+
+    /// Decodes a block from the hex of its stored bytes, as printed by
+    /// getrawblock.
+    pub fn from_hex(data: &str) -> Result<Self> {
+        let bytes = hex::decode(data.trim()).map_err(|e| anyhow!("Invalid hex: {}", e))?;
+        let (block, len): (Block, usize) = decode_from_slice(&bytes, DECODE_CONFIG)?;
+        if len != bytes.len() {
+            return Err(anyhow!("{} trailing bytes after block", bytes.len() - len));
+        }
+        Ok(block)
+    }
+
+    /// The header of the block on a chain using `params`.
+    pub fn header(&self, params: &ChainParams) -> Result<BlockHeader> {
+        Ok(BlockHeader {
+            prev_block_hash: self.prev_block_hash,
+            merkle_root: self.merkle_root(params)?,
+            timestamp: self.timestamp,
+            nonce: self.nonce,
+            height: self.height,
+            bits: self.bits,
+            hash: self.hash,
+        })
+    }
+
+    fn prepare_hash_data(&self, params: &ChainParams) -> Result<Vec<u8>> {
+        pow_hash_data(
             &self.prev_block_hash,
-            self.hash_transactions()?,
+            &self.merkle_root(params)?,
             self.timestamp,
-            TARGET_BITS,
+            self.bits,
             self.nonce,
-        );
-        let data = encode_to_vec(data_to_hash, standard())?;
-        Ok(data)
+        )
     }
 
-    fn hash_transactions(&self) -> Result<HashType> {
+    /// Root of the Merkle tree over the wtxids of the transactions, or their
+    /// txids on chains of an older format, see `ChainParams::chain_format`.
+    /// A single transaction is its own root, and no transactions give the
+    /// zero hash.
+    pub fn merkle_root(&self, params: &ChainParams) -> Result<Hash> {
         let mut leaves = Vec::new();
         for tx in &self.transactions {
-            leaves.push(tx.hash()?);
+            let leaf = if params.commits_to_wtxids() {
+                tx.wtxid()?
+            } else {
+                tx.hash()?
+            };
+            leaves.push(leaf.0);
         }
         let merkle_tree = MerkleTree::<rs_merkle::algorithms::Sha256>::from_leaves(&leaves);
-        Ok(merkle_tree.root().unwrap())
+        Ok(merkle_tree.root().map(Hash).unwrap_or_default())
+    }
+
+    /// The id of the first transaction appearing more than once, if any.
+    pub fn duplicate_transaction(&self) -> Option<&str> {
+        let mut seen = HashSet::new();
+        self.transactions
+            .iter()
+            .find(|tx| !seen.insert(&tx.id))
+            .map(|tx| tx.id.as_str())
     }
 
-    fn validate(&self) -> Result<bool> {
-        let hash = self.hash()?;
-        let target = [0u8; TARGET_BITS];
-        Ok(hash[0..TARGET_BITS] == target[..])
+    pub fn validate(&self, params: &ChainParams) -> Result<bool> {
+        Ok(params.pow_disabled || meets_target(&self.hash(params)?, self.bits))
     }
 
-    fn hash(&self) -> Result<HashType> {
-        let data = self.prepare_hash_data()?;
-        // Bitcoin uses double SHA-256: SHA256(SHA256(data))
-        let mut hasher = Sha256::new();
-        hasher.update(&data);
-        let first_hash = hasher.finalize();
-        let mut hasher = Sha256::new();
-        hasher.update(first_hash);
-        Ok(hasher.finalize().into())
+    /// The proof-of-work hash of the block's contents, which the stored
+    /// `hash` has to equal.
+    pub fn hash(&self, params: &ChainParams) -> Result<Hash> {
+        let data = self.prepare_hash_data(params)?;
+        params.pow_algorithm.hash(&data)
     }
 
-    fn run_proof_of_work(&mut self) -> Result<()> {
+    fn run_proof_of_work(&mut self, params: &ChainParams) -> Result<()> {
         info!("Mining the block");
         loop {
-            if self.validate()? {
-                self.hash = self.hash()?;
+            if self.validate(params)? {
+                self.hash = self.hash(params)?;
                 break;
             }
             self.nonce += 1;
@@ -95,3 +260,305 @@ impl Block {
         Ok(())
     }
 }
+
+/// The proof-of-work relevant part of a block, without its transactions.
+///
+/// The serialized layout is part of the header export format, bump
+/// `HEADERS_FORMAT_VERSION` when changing it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BlockHeader {
+    pub prev_block_hash: Hash,
+    pub merkle_root: Hash,
+    pub timestamp: u128,
+    pub nonce: i32,
+    pub height: i32,
+    pub bits: u32,
+    pub hash: Hash,
+}
+
+pub const HEADERS_FORMAT_VERSION: u32 = 2;
+
+impl BlockHeader {
+    /// Checks that the stored hash matches the header contents and satisfies
+    /// the proof-of-work target, which any hash does when
+    /// `ChainParams::pow_disabled`.
+    pub fn validate(&self, params: &ChainParams) -> Result<bool> {
+        let data = pow_hash_data(
+            &self.prev_block_hash,
+            &self.merkle_root,
+            self.timestamp,
+            self.bits,
+            self.nonce,
+        )?;
+        let hash = params.pow_algorithm.hash(&data)?;
+        Ok(hash == self.hash && (params.pow_disabled || meets_target(&hash, self.bits)))
+    }
+}
+
+fn pow_hash_data(
+    prev_block_hash: &Hash,
+    merkle_root: &Hash,
+    timestamp: u128,
+    bits: u32,
+    nonce: i32,
+) -> Result<Vec<u8>> {
+    let data_to_hash = (prev_block_hash, merkle_root, timestamp, bits, nonce);
+    let data = encode_to_vec(data_to_hash, standard())?;
+    Ok(data)
+}
+
+/// Whether `hash` starts with at least `bits` zero bits.
+fn meets_target(hash: &Hash, bits: u32) -> bool {
+    let mut zeros = 0;
+    for byte in &hash.0 {
+        zeros += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    zeros >= bits
+}
+
+/// Estimates the network hashrate in hashes per second from `blocks`, given
+/// newest first. The oldest block only marks the start of the window, so at
+/// least two blocks spanning a non-zero time are needed; otherwise 0.
+pub fn estimate_hashps(blocks: &[Block]) -> f64 {
+    let (Some(newest), Some(oldest)) = (blocks.first(), blocks.last()) else {
+        return 0.0;
+    };
+    let timespan_ms = newest.timestamp.saturating_sub(oldest.timestamp);
+    if blocks.len() < 2 || timespan_ms == 0 {
+        return 0.0;
+    }
+    let work: f64 = blocks[..blocks.len() - 1].iter().map(Block::work).sum();
+    work / (timespan_ms as f64 / 1000.0)
+}
+
+/// One block of `difficulty_history`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DifficultyPoint {
+    pub height: i32,
+    pub timestamp: u128,
+    pub bits: u32,
+    /// Time since the previous block, unknown for the genesis block
+    pub interval_ms: Option<u128>,
+}
+
+/// Difficulty and block intervals of `blocks`, given newest first. The
+/// result is oldest first and leaves out the oldest block, which only
+/// provides the first interval, unless it is the genesis block.
+pub fn difficulty_history(blocks: &[Block]) -> Vec<DifficultyPoint> {
+    let mut history: Vec<DifficultyPoint> = blocks
+        .iter()
+        .enumerate()
+        .map(|(i, block)| DifficultyPoint {
+            height: block.height,
+            timestamp: block.timestamp,
+            bits: block.bits,
+            interval_ms: blocks
+                .get(i + 1)
+                .map(|prev| block.timestamp.saturating_sub(prev.timestamp)),
+        })
+        .collect();
+    if history
+        .last()
+        .is_some_and(|p| p.interval_ms.is_none() && p.height != 0)
+    {
+        history.pop();
+    }
+    history.reverse();
+    history
+}
+
+/// Mean of the known intervals of `history`, if there are any.
+pub fn average_interval_ms(history: &[DifficultyPoint]) -> Option<f64> {
+    let intervals: Vec<u128> = history.iter().filter_map(|p| p.interval_ms).collect();
+    if intervals.is_empty() {
+        return None;
+    }
+    Some(intervals.iter().sum::<u128>() as f64 / intervals.len() as f64)
+}
+
+impl fmt::Display for Block {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Block #{} {}", self.height, self.hash)?;
+        writeln!(f, "  prev:      {}", self.prev_block_hash)?;
+        writeln!(f, "  timestamp: {}", format_timestamp(self.timestamp))?;
+        writeln!(f, "  nonce:     {}", self.nonce)?;
+        writeln!(f, "  bits:      {}", self.bits)?;
+        write!(f, "  transactions: {}", self.transactions.len())?;
+        for tx in &self.transactions {
+            write!(f, "\n    {}", tx)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{PowAlgorithm, Wallet};
+
+    #[test]
+    fn test_hash_conversions() {
+        let hash = Hash([0xab; 32]);
+        let hex = hash.to_string();
+        assert_eq!(hex, "ab".repeat(32));
+        assert_eq!(hex.parse::<Hash>().unwrap(), hash);
+        assert!("abcd".parse::<Hash>().is_err());
+        assert!("zz".repeat(32).parse::<Hash>().is_err());
+        assert_eq!(<[u8; 32]>::from(hash), [0xab; 32]);
+        assert!(Hash::try_from(&[0u8; 31][..]).is_err());
+        // Encoded like the bare array, so stored blocks still decode
+        assert_eq!(
+            encode_to_vec(hash, standard()).unwrap(),
+            encode_to_vec([0xabu8; 32], standard()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_pow_algorithm_mismatch() {
+        let params = ChainParams::default();
+        let sha256 = ChainParams {
+            pow_algorithm: PowAlgorithm::Sha256,
+            ..Default::default()
+        };
+        let double_sha256 = ChainParams {
+            pow_algorithm: PowAlgorithm::DoubleSha256,
+            ..Default::default()
+        };
+        let addr = Wallet::new().get_address(&params);
+        let cbtx = Transaction::new_coinbase(&addr, String::new(), &params).unwrap();
+        let mut block = Block::new(vec![cbtx], Hash::default(), 0, 16, &sha256).unwrap();
+        assert!(block.validate(&sha256).unwrap());
+        // Pick a nonce that satisfies SHA256 but not double-SHA256
+        while block.validate(&double_sha256).unwrap() {
+            block.nonce += 1;
+            block.run_proof_of_work(&sha256).unwrap();
+        }
+        assert!(!block.validate(&double_sha256).unwrap());
+    }
+
+    #[test]
+    fn test_estimate_hashps() {
+        // Ten blocks, newest first, spaced two seconds apart
+        let blocks: Vec<Block> = (0..10)
+            .rev()
+            .map(|height| Block {
+                timestamp: 1_000_000 + height as u128 * 2000,
+                transactions: vec![],
+                prev_block_hash: Hash::default(),
+                hash: Hash::default(),
+                nonce: 0,
+                height,
+                bits: 16,
+            })
+            .collect();
+        let work = blocks[0].work();
+        assert_eq!(estimate_hashps(&blocks), work / 2.0);
+        assert_eq!(estimate_hashps(&blocks[..1]), 0.0);
+        assert_eq!(estimate_hashps(&[]), 0.0);
+    }
+
+    #[test]
+    fn test_merkle_root_edge_cases() {
+        let params = ChainParams::default();
+        let addr = Wallet::new().get_address(&params);
+        let cbtx = Transaction::new_coinbase(&addr, String::new(), &params).unwrap();
+        let other = Transaction::new_coinbase(&addr, "other".to_owned(), &params).unwrap();
+        let mut block = Block::new(
+            vec![cbtx.clone()],
+            Hash::default(),
+            0,
+            params.min_difficulty_bits,
+            &params,
+        )
+        .unwrap();
+        assert_eq!(block.merkle_root(&params).unwrap(), cbtx.wtxid().unwrap());
+        assert!(block.header(&params).unwrap().validate(&params).unwrap());
+
+        // Tampering with the transactions breaks the committed root
+        block.transactions.push(other);
+        assert_ne!(block.merkle_root(&params).unwrap(), cbtx.wtxid().unwrap());
+        assert!(!block.header(&params).unwrap().validate(&params).unwrap());
+
+        // Chains of the legacy format commit to txids, without signatures
+        let legacy = ChainParams {
+            chain_format: 1,
+            ..params.clone()
+        };
+        let mut signed = cbtx.clone();
+        signed.v_in[0].signature = vec![1];
+        block.transactions = vec![signed.clone()];
+        assert_eq!(block.merkle_root(&legacy).unwrap(), signed.hash().unwrap());
+        assert_eq!(block.merkle_root(&params).unwrap(), signed.wtxid().unwrap());
+        assert_ne!(signed.hash().unwrap(), signed.wtxid().unwrap());
+
+        block.transactions.clear();
+        assert_eq!(block.merkle_root(&params).unwrap(), Hash::default());
+    }
+
+    #[test]
+    fn test_deterministic_mining() {
+        let params = ChainParams::default();
+        let to = crate::pub_key_hash_to_address(&[0; 20], &params);
+        let mine = |timestamp| {
+            let cbtx = Transaction::new_coinbase(&to, String::new(), &params).unwrap();
+            Block::new_deterministic(
+                vec![cbtx],
+                Hash::default(),
+                0,
+                params.min_difficulty_bits,
+                timestamp,
+                &params,
+            )
+            .unwrap()
+        };
+
+        let block = mine(1_700_000_000_000);
+        assert_eq!(block.hash, mine(1_700_000_000_000).hash);
+        assert_eq!(block.timestamp(), 1_700_000_000_000);
+        assert_eq!(
+            block.hash.to_string(),
+            "00d9d09a8443b69a89155e961820a200d166253255bed07f25ffc3e0ffd6990d"
+        );
+        assert_ne!(block.hash, mine(1_700_000_000_001).hash);
+    }
+
+    #[test]
+    fn test_difficulty_history() {
+        let params = ChainParams::default();
+        let to = crate::pub_key_hash_to_address(&[0; 20], &params);
+        let mut blocks: Vec<Block> = [0, 10_000, 30_000, 36_000]
+            .into_iter()
+            .enumerate()
+            .map(|(height, timestamp)| {
+                let cbtx = Transaction::new_coinbase(&to, height.to_string(), &params).unwrap();
+                let bits = params.min_difficulty_bits + height as u32;
+                Block::new_deterministic(
+                    vec![cbtx],
+                    Hash::default(),
+                    height as i32,
+                    bits,
+                    timestamp,
+                    &params,
+                )
+                .unwrap()
+            })
+            .collect();
+        blocks.reverse();
+
+        let history = difficulty_history(&blocks);
+        assert_eq!(history.len(), 4);
+        assert_eq!(history[0].interval_ms, None);
+        assert_eq!(history[3].bits, params.min_difficulty_bits + 3);
+        assert_eq!(average_interval_ms(&history), Some(12_000.0));
+
+        // Without the genesis block the oldest block only provides an interval
+        let history = difficulty_history(&blocks[..2]);
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].height, 3);
+        assert_eq!(history[0].interval_ms, Some(6_000));
+        assert_eq!(average_interval_ms(&[]), None);
+    }
+}
@@ -1,84 +1,198 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
 
 use anyhow::{Result, anyhow};
 use bincode::{
     config::standard,
-    serde::{decode_from_slice, encode_to_vec},
+    error::DecodeError,
+    serde::{decode_from_slice, decode_from_std_read, encode_into_std_write, encode_to_vec},
+};
+use log::{debug, info};
+use serde::Serialize;
+
+use crate::{
+    Amount, Block, BlockHeader, CHAIN_FORMAT_VERSION, ChainParams, DECODE_CONFIG, DifficultyPoint,
+    HEADERS_FORMAT_VERSION, Hash, InputReport, OutPoint, TXOutput, TXOutputs, Transaction, Wallets,
+    block_subsidy, difficulty_history, estimate_hashps,
 };
-use log::info;
 
-use crate::{Block, HashType, TXOutputs, Transaction};
+const HEADERS_MAGIC: &[u8; 4] = b"RSBH";
+const CHAIN_MAGIC: &[u8; 4] = b"RSBC";
 
-const GENESIS_COINBASE_DATA: &str =
-    "The Times 03/Jan/2009 Chancellor on brink of second bailout for banks";
+/// Key of the tip hash
+const TIP_KEY: &str = "l";
+/// Key of the genesis block hash, whose coinbase commits to the chain's
+/// parameters
+const GENESIS_KEY: &str = "g";
+/// Tree of the best chain's block hashes, keyed by big-endian height
+const HEIGHTS_TREE: &str = "heights";
+/// Tree locating transactions: a txid followed by the hash of a block
+/// holding it, for every stored block whether on the best chain or not
+const TX_INDEX_TREE: &str = "transactions";
+/// Present in `TX_INDEX_TREE` once the blocks stored before it existed are
+/// indexed
+const TX_INDEX_MARKER: &[u8] = b"";
+/// Most hashes a block locator may hold, as in Bitcoin
+pub const MAX_LOCATOR_LEN: usize = 101;
+/// What to do about a database whose tip pointer is unusable
+const RECOVERY_HINT: &str = "Restore the blockchain directory from a backup, or recreate it and run importchain, then reindex";
 
 pub struct Blockchain {
-    pub tip: HashType,
+    pub tip: Hash,
     pub db: sled::Db,
+    pub params: ChainParams,
+    pub durability: Durability,
+}
+
+/// When `add_block` makes a new block durable.
+///
+/// sled flushes in the background about every 500ms regardless, so `Batch`
+/// only risks the blocks added since the last flush. After a crash those are
+/// missing from the chain and get downloaded again from peers, which makes
+/// `Batch` fine for bulk downloads but not for blocks only we have, like the
+/// ones we mine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// fsync after every block
+    #[default]
+    Full,
+    /// Defer to the next `flush`
+    Batch,
 }
 
 impl Blockchain {
-    pub fn new() -> Result<Self> {
-        let db = sled::open("db/blockchain")?;
-        match db.get("l")? {
-            Some(hash) => {
+    /// Opens the chain stored at `path`, which may be empty.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with_params(path, ChainParams::default())
+    }
+
+    /// Like `open`, for a chain following `params`. The parameters its
+    /// genesis block commits to, see `ChainParams::genesis_message`, take
+    /// precedence.
+    pub fn open_with_params(path: impl AsRef<Path>, params: ChainParams) -> Result<Self> {
+        let db = sled::open(path)?;
+        let params = match read_genesis(&db)? {
+            Some(genesis) => genesis_params(params, &genesis)?,
+            None => params,
+        };
+        backfill_tx_index(&db)?;
+        match read_tip(&db)? {
+            Some(tip) => {
                 info!("Found blockchain");
-                let mut last_hash = HashType::default();
-                last_hash.copy_from_slice(&hash);
-                Ok(Blockchain { tip: last_hash, db })
+                index_heights(&db, tip)?;
+                Ok(Blockchain {
+                    tip,
+                    db,
+                    params,
+                    durability: Durability::default(),
+                })
             }
             None => {
                 info!("No existing blockchain found.");
                 Ok(Blockchain {
-                    tip: HashType::default(),
+                    tip: Hash::default(),
                     db,
+                    params,
+                    durability: Durability::default(),
                 })
             }
         }
     }
 
-    pub fn create(addr: &str) -> Result<Self> {
+    /// Replaces whatever is stored at `path` with a new chain whose genesis
+    /// block pays `addr`.
+    pub fn create_at(path: impl AsRef<Path>, addr: &str) -> Result<Self> {
+        Self::create_with_params(path, addr, ChainParams::default())
+    }
+
+    /// Like `create_at`, with a genesis block mined under `params`.
+    ///
+    /// The genesis block commits to the parameters every later block
+    /// depends on, see `ChainParams::genesis_message`, so reopening the
+    /// chain or copying it to another node keeps them.
+    pub fn create_with_params(
+        path: impl AsRef<Path>,
+        addr: &str,
+        params: ChainParams,
+    ) -> Result<Self> {
         info!("Create new blockchain");
 
-        let cbtx = Transaction::new_coinbase(addr, GENESIS_COINBASE_DATA.to_owned())?;
-        let genesis = Block::new_genesis_block(cbtx);
+        let cbtx = Transaction::new_coinbase(addr, params.genesis_message(), &params)?;
+        let genesis = Block::new_genesis_block(cbtx, &params);
 
-        let _ = std::fs::remove_dir_all("db/blockchain");
+        let _ = std::fs::remove_dir_all(&path);
 
         let hash = genesis.hash;
-        let db = sled::open("db/blockchain")?;
-        db.insert(hash, encode_to_vec(genesis, standard())?)?;
-        db.insert("l", &hash)?;
+        let db = sled::open(&path)?;
+        db.insert(hash, encode_to_vec(&genesis, standard())?)?;
+        db.insert(TIP_KEY, hash.as_ref())?;
+        db.insert(GENESIS_KEY, hash.as_ref())?;
+        index_heights(&db, hash)?;
+        index_transactions(&db, &genesis)?;
+        db.open_tree(TX_INDEX_TREE)?
+            .insert(TX_INDEX_MARKER, vec![])?;
         db.flush()?;
 
-        let bc = Blockchain { tip: hash, db };
+        let bc = Blockchain {
+            tip: hash,
+            db,
+            params,
+            durability: Durability::default(),
+        };
         Ok(bc)
     }
 
+    /// Writes blocks added under `Durability::Batch` to disk.
+    pub fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
     pub fn find_utxo(&self) -> HashMap<String, TXOutputs> {
-        let mut utxos: HashMap<String, TXOutputs> = HashMap::new();
-        let mut spend_txos: HashMap<String, Vec<i32>> = HashMap::new();
+        self.iter_utxo().collect()
+    }
 
-        for block in self.iter() {
-            for tx in block.transactions {
-                for (out_idx, out) in tx.v_out.iter().enumerate() {
-                    if let Some(ids) = spend_txos.get(&tx.id) {
-                        if ids.contains(&(out_idx as i32)) {
-                            continue;
-                        }
+    /// The unspent outputs of each transaction, newest first, as the chain
+    /// is walked back from the tip. Every spend of an output comes after
+    /// it, so a transaction's outputs are final once it is reached and
+    /// only the spends of transactions not reached yet are kept.
+    pub fn iter_utxo(&self) -> impl Iterator<Item = (String, TXOutputs)> + '_ {
+        let mut spend_txos: HashMap<Hash, Vec<u32>> = HashMap::new();
+        self.iter().flat_map(move |block| {
+            let mut utxos = vec![];
+            // Spends within a block come after the transaction they spend
+            for tx in block.transactions.into_iter().rev() {
+                let spent = spend_txos.remove(&tx.hash_val).unwrap_or_default();
+                if !tx.is_coinbase() {
+                    for in_ in tx.v_in {
+                        let prev_out = in_.prev_out;
+                        spend_txos
+                            .entry(prev_out.txid)
+                            .or_default()
+                            .push(prev_out.vout);
                     }
-                    utxos.entry(tx.id.clone()).or_default().push(out.clone());
                 }
 
-                if !tx.is_coinbase() {
-                    for in_ in tx.v_in {
-                        spend_txos.entry(in_.tx_id).or_default().push(in_.v_out);
+                let mut outs = TXOutputs {
+                    height: block.height,
+                    block_hash: block.hash,
+                    ..TXOutputs::default()
+                };
+                for (out_idx, out) in tx.v_out.into_iter().enumerate() {
+                    if !spent.contains(&(out_idx as u32)) {
+                        outs.push(out);
                     }
                 }
+                if !outs.outputs.is_empty() {
+                    utxos.push((tx.id, outs));
+                }
             }
-        }
-
-        utxos
+            utxos
+        })
     }
 
     pub fn add_block(&mut self, block: &Block) -> Result<()> {
@@ -86,25 +200,196 @@ impl Blockchain {
 
         let hash = block.hash;
         if self.db.get(hash)?.is_some() {
-            return Ok(());
+            // Only the block we already have hashes to its key
+            return self.check_stored_hash(block);
+        }
+
+        let genesis = block.prev_block_hash == Hash::default() && self.tip == Hash::default();
+        if genesis {
+            // The chain follows the parameters its genesis block commits to,
+            // so a node downloading it checks it as its creator did
+            let params = genesis_params(self.params.clone(), block)?;
+            let previous = std::mem::replace(&mut self.params, params);
+            if let Err(e) = self.check_block(block) {
+                self.params = previous;
+                return Err(e);
+            }
+        } else {
+            self.check_block(block)?;
         }
 
+        // Indexed first, so a crash in between leaves an entry for a missing
+        // block rather than a block missing from the index
+        index_transactions(&self.db, block)?;
         self.db.insert(hash, encode_to_vec(block, standard())?)?;
+        if genesis {
+            self.db.insert(GENESIS_KEY, hash.as_ref())?;
+        }
 
         let last_height = self.get_best_height()?;
         if block.height > last_height {
-            self.db.insert("l", &hash)?;
-            self.db.flush()?;
+            self.db.insert(TIP_KEY, hash.as_ref())?;
+            index_heights(&self.db, hash)?;
+            if self.durability == Durability::Full {
+                self.db.flush()?;
+            }
             self.tip = hash;
         }
 
         Ok(())
     }
 
-    pub fn iter(&self) -> BlockchainIterator {
+    /// Errors unless the hash a block is stored under and linked to by its
+    /// children is the hash of its contents.
+    fn check_stored_hash(&self, block: &Block) -> Result<()> {
+        if block.hash(&self.params)? != block.hash {
+            return Err(anyhow!(
+                "Block {} doesn't hash to its stored hash",
+                block.hash
+            ));
+        }
+        Ok(())
+    }
+
+    /// The checks `add_block` runs before storing a block, which don't need
+    /// its parent to be our tip.
+    fn check_block(&self, block: &Block) -> Result<()> {
+        let hash = block.hash;
+        self.check_stored_hash(block)?;
+        if !self.params.pow_disabled && block.bits < self.params.min_difficulty_bits {
+            return Err(anyhow!("Block {} is below the minimum difficulty", hash));
+        }
+        let expected_bits = self
+            .bits_after(&block.prev_block_hash)
+            .map_err(|e| anyhow!("Block {}: {}", hash, e))?;
+        if block.bits != expected_bits {
+            return Err(anyhow!(
+                "Block {} has a difficulty of {} bits, expected {}",
+                hash,
+                block.bits,
+                expected_bits
+            ));
+        }
+        if !block.validate(&self.params)? {
+            return Err(anyhow!("Invalid proof of work for block {}", hash));
+        }
+        if let Some(tx) = block.transactions.iter().find(|tx| !tx.is_well_formed()) {
+            return Err(anyhow!(
+                "Block {} contains transaction {} without inputs or outputs",
+                hash,
+                tx.id
+            ));
+        }
+        if let Some(tx) = block
+            .transactions
+            .iter()
+            .find(|tx| !tx.within_size_limits(&self.params))
+        {
+            return Err(anyhow!(
+                "Block {} contains transaction {} with too many inputs or outputs",
+                hash,
+                tx.id
+            ));
+        }
+        if let Some(id) = block.duplicate_transaction() {
+            return Err(anyhow!(
+                "Block {} contains transaction {} more than once",
+                hash,
+                id
+            ));
+        }
+        self.check_coinbase_value(&block.transactions, block.prev_block_hash, block.height)
+            .map_err(|e| anyhow!("Block {}: {}", hash, e))
+    }
+
+    /// Checks `block` as a new tip without adding it: everything `add_block`
+    /// checks, plus that its hash commits to its contents, that it extends
+    /// the current tip, and that every transaction spends outputs unspent at
+    /// the tip or earlier in the block, with valid signatures. The error
+    /// names the first check that failed.
+    pub fn test_block(&self, block: &Block) -> Result<()> {
+        let hash = block.hash;
+        if !block.header(&self.params)?.validate(&self.params)? {
+            return Err(anyhow!(
+                "Block {} doesn't hash to its stored hash, its header or Merkle root was changed",
+                hash
+            ));
+        }
+        self.check_block(block)?;
+        if block.prev_block_hash != self.tip {
+            return Err(anyhow!(
+                "Block {} builds on {}, not on the tip {}",
+                hash,
+                block.prev_block_hash,
+                self.tip
+            ));
+        }
+        let expected_height = self.get_best_height()? + 1;
+        if block.height != expected_height {
+            return Err(anyhow!(
+                "Block {} has height {}, expected {}",
+                hash,
+                block.height,
+                expected_height
+            ));
+        }
+        match block.transactions.first() {
+            Some(tx) if tx.is_coinbase() => {}
+            _ => return Err(anyhow!("Block {} doesn't start with a coinbase", hash)),
+        }
+        // Signature checks don't look at whether an output is still unspent
+        let mut spent: HashSet<OutPoint> = self
+            .iter()
+            .flat_map(|block| block.transactions)
+            .filter(|tx| !tx.is_coinbase())
+            .flat_map(|tx| tx.v_in)
+            .map(|vin| vin.prev_out)
+            .collect();
+        for (i, tx) in block.transactions.iter().enumerate().skip(1) {
+            if tx.is_coinbase() {
+                return Err(anyhow!("Block {} has a second coinbase {}", hash, tx.id));
+            }
+            for vin in &tx.v_in {
+                if !spent.insert(vin.prev_out) {
+                    return Err(anyhow!(
+                        "Block {}: transaction {} spends {}, which is already spent",
+                        hash,
+                        tx.id,
+                        vin.prev_out
+                    ));
+                }
+            }
+            let valid = self
+                .verify_transaction_in_block(tx, &block.transactions[..i])
+                .map_err(|e| anyhow!("Block {}: transaction {}: {}", hash, tx.id, e))?;
+            if !valid {
+                return Err(anyhow!(
+                    "Block {}: transaction {} spends unknown outputs or has a bad signature",
+                    hash,
+                    tx.id
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn iter(&self) -> BlockchainIterator<'_> {
+        self.iter_from(self.tip)
+    }
+
+    /// Iterates from `hash` back to the genesis block.
+    pub fn iter_from(&self, hash: Hash) -> BlockchainIterator<'_> {
         BlockchainIterator {
-            current_hash: self.tip,
-            bc: self,
+            current_hash: hash,
+            db: &self.db,
+        }
+    }
+
+    /// Returns a read-only view of the chain as of the current tip.
+    pub fn reader(&self) -> BlockchainReader {
+        BlockchainReader {
+            tip: self.tip,
+            db: self.db.clone(),
         }
     }
 
@@ -115,25 +400,167 @@ impl Blockchain {
                     return Some(tx);
                 }
             }
-            if block.prev_block_hash.is_empty() {
+            if block.prev_block_hash == Hash::default() {
                 break;
             }
         }
         None
     }
 
+    /// Finds transaction `id` and the transactions spending its outputs, by
+    /// output index. Every block from the tip down to the one holding `id`
+    /// is scanned, `UTXOSet::spenders` uses its index instead.
+    pub fn find_spenders(&self, id: &str) -> Option<(Transaction, HashMap<i32, String>)> {
+        let txid: Hash = id.parse().ok()?;
+        let mut spenders = HashMap::new();
+        for block in self.iter() {
+            for tx in block.transactions.into_iter().rev() {
+                if tx.hash_val == txid {
+                    return Some((tx, spenders));
+                }
+                if tx.is_coinbase() {
+                    continue;
+                }
+                for in_ in &tx.v_in {
+                    if in_.prev_out.txid == txid {
+                        spenders.insert(in_.prev_out.vout as i32, tx.id.clone());
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// Returns the transaction together with the height of the block holding it.
+    pub fn find_transaction_with_height(&self, id: &str) -> Option<(Transaction, i32)> {
+        for block in self.iter() {
+            for tx in block.transactions {
+                if tx.id == id {
+                    return Some((tx, block.height));
+                }
+            }
+        }
+        None
+    }
+
+    /// Status of `tx` on the current best chain. It is worked out from the
+    /// chain on every call rather than tracked, so once a reorg disconnects
+    /// the block holding `tx` it is reported as `Pending` again, or as
+    /// `Conflicted` if the new chain spends one of its inputs elsewhere.
+    pub fn transaction_status(&self, tx: &Transaction) -> Result<TxStatus> {
+        let best_height = self.get_best_height()?;
+        let inputs: HashSet<OutPoint> = tx.v_in.iter().map(|vin| vin.prev_out).collect();
+        let mut conflicted = false;
+        for block in self.iter() {
+            for other in &block.transactions {
+                if other.id == tx.id {
+                    return Ok(TxStatus::Confirmed(best_height - block.height + 1));
+                }
+                if !tx.is_coinbase()
+                    && !other.is_coinbase()
+                    && other.v_in.iter().any(|vin| inputs.contains(&vin.prev_out))
+                {
+                    conflicted = true;
+                }
+            }
+        }
+        Ok(if conflicted {
+            TxStatus::Conflicted
+        } else {
+            TxStatus::Pending
+        })
+    }
+
+    /// Maps every transaction id on the chain to the height of its block.
+    pub fn transaction_heights(&self) -> HashMap<String, i32> {
+        let mut heights = HashMap::new();
+        for block in self.iter() {
+            for tx in block.transactions {
+                heights.insert(tx.id, block.height);
+            }
+        }
+        heights
+    }
+
+    /// Number of blocks that would be disconnected from the current chain if
+    /// `block` became the new tip.
+    ///
+    /// Returns 0 when the block extends the tip, would not become the tip, or
+    /// its ancestry isn't fully known yet.
+    pub fn reorg_depth(&self, block: &Block) -> Result<i32> {
+        let best_height = self.get_best_height()?;
+        if block.height <= best_height || block.prev_block_hash == self.tip {
+            return Ok(0);
+        }
+
+        let main_chain: HashSet<Hash> = self.get_block_hashs().into_iter().collect();
+        let mut current = block.prev_block_hash;
+        while !main_chain.contains(&current) {
+            match self.db.get(current)? {
+                Some(data) => {
+                    let (parent, _): (Block, usize) = decode_from_slice(&data, DECODE_CONFIG)?;
+                    current = parent.prev_block_hash;
+                }
+                None => return Ok(0),
+            }
+        }
+
+        let fork_point = self.get_block(&current)?;
+        Ok(best_height - fork_point.height)
+    }
+
     pub fn sign_transaction(&self, tx: &mut Transaction, private_key: &[u8]) -> Result<()> {
-        let mut prev_txs = HashMap::new();
+        let prev_txs = self.spent_transactions(tx)?;
+        tx.sign(private_key, prev_txs)
+    }
+
+    pub fn sign_transaction_with_wallets(
+        &self,
+        tx: &mut Transaction,
+        wallets: &Wallets,
+    ) -> Result<()> {
+        let prev_txs = self.spent_transactions(tx)?;
+        tx.sign_with_wallets(wallets, prev_txs, &self.params)
+    }
 
+    /// The transactions whose outputs `tx` spends, by id, failing on the
+    /// first one that isn't on the chain.
+    fn spent_transactions(&self, tx: &Transaction) -> Result<HashMap<String, Transaction>> {
+        let mut prev_txs = HashMap::new();
+        if tx.is_coinbase() {
+            return Ok(prev_txs);
+        }
         for vin in &tx.v_in {
-            let prev_tx = self.find_transaction(&vin.tx_id).unwrap();
+            let tx_id = vin.prev_out.txid.to_string();
+            if prev_txs.contains_key(&tx_id) {
+                continue;
+            }
+            let prev_tx = self.find_transaction(&tx_id).ok_or_else(|| {
+                anyhow!(
+                    "Can't sign input {}, transaction {} isn't on the chain",
+                    vin.prev_out,
+                    tx_id
+                )
+            })?;
             prev_txs.insert(prev_tx.id.to_owned(), prev_tx);
         }
-
-        tx.sign(private_key, prev_txs)
+        Ok(prev_txs)
     }
 
     pub fn verify_transaction(&self, tx: &Transaction) -> Result<bool> {
+        self.verify_transaction_in_block(tx, &[])
+    }
+
+    /// Like `verify_transaction`, with inputs also resolved from `earlier`,
+    /// the transactions preceding `tx` in its block.
+    pub fn verify_transaction_in_block(
+        &self,
+        tx: &Transaction,
+        earlier: &[Transaction],
+    ) -> Result<bool> {
+        if !tx.is_well_formed() || !tx.within_size_limits(&self.params) {
+            return Ok(false);
+        }
         if tx.is_coinbase() {
             return Ok(true);
         }
@@ -141,40 +568,110 @@ impl Blockchain {
         let mut prev_txs = HashMap::new();
 
         for vin in &tx.v_in {
-            let prev_tx = self.find_transaction(&vin.tx_id).unwrap();
-            prev_txs.insert(prev_tx.id.to_owned(), prev_tx);
+            let txid = vin.prev_out.txid;
+            let in_block = earlier.iter().find(|t| t.hash_val == txid).cloned();
+            match in_block.or_else(|| self.find_transaction(&txid.to_string())) {
+                Some(prev_tx) => {
+                    prev_txs.insert(prev_tx.id.to_owned(), prev_tx);
+                }
+                None => {
+                    debug!("Previous transaction {} not on chain", txid);
+                    return Ok(false);
+                }
+            }
+        }
+
+        tx.verify(prev_txs, &self.params)
+    }
+
+    /// Verifies each input of `tx` separately. Inputs whose previous
+    /// transaction can't be found are reported as invalid.
+    pub fn verify_transaction_report(&self, tx: &Transaction) -> Vec<InputReport> {
+        let mut prev_txs = HashMap::new();
+
+        for vin in &tx.v_in {
+            if let Some(prev_tx) = self.find_transaction(&vin.prev_out.txid.to_string()) {
+                prev_txs.insert(prev_tx.id.to_owned(), prev_tx);
+            }
         }
 
-        tx.verify(prev_txs)
+        tx.verify_report(&prev_txs, &self.params)
     }
 
     pub fn mine_block(&mut self, transactions: Vec<Transaction>) -> Result<Block> {
         info!("mine_block");
 
-        for tx in &transactions {
-            if !self.verify_transaction(tx)? {
+        for (i, tx) in transactions.iter().enumerate() {
+            if !self.verify_transaction_in_block(tx, &transactions[..i])? {
                 return Err(anyhow!("ERROR: Invalid transaction"));
             }
         }
+        let mut ids = HashSet::new();
+        if let Some(tx) = transactions.iter().find(|tx| !ids.insert(&tx.id)) {
+            return Err(anyhow!("ERROR: Duplicate transaction {}", tx.id));
+        }
 
         let last_hash = self.get_last_hash()?;
-        let new_block = Block::new(transactions, last_hash, self.get_best_height()? + 1)?;
+        let height = self.get_best_height()? + 1;
+        self.check_coinbase_value(&transactions, last_hash, height)?;
+        let new_block = Block::new(
+            transactions,
+            last_hash,
+            height,
+            self.next_bits()?,
+            &self.params,
+        )?;
 
         self.add_block(&new_block)?;
         Ok(new_block)
     }
 
+    /// Difficulty required of the next block mined on top of the tip.
+    pub fn next_bits(&self) -> Result<u32> {
+        self.bits_after(&read_tip(&self.db)?.unwrap_or_default())
+    }
+
+    /// Difficulty required of a block whose parent is `prev_block_hash`,
+    /// the genesis difficulty for the default hash. The retarget window is
+    /// walked back from the parent, so a side branch is held to its own
+    /// history.
+    fn bits_after(&self, prev_block_hash: &Hash) -> Result<u32> {
+        if *prev_block_hash == Hash::default() {
+            return Ok(self.params.genesis_bits());
+        }
+        let parent = self.get_block(prev_block_hash)?;
+        let interval = self.params.retarget_interval;
+        if interval <= 0 || (parent.height + 1) % interval != 0 {
+            return Ok(parent.bits);
+        }
+
+        let first_height = (parent.height + 1 - interval).max(0);
+        let mut first = parent.clone();
+        while first.height > first_height {
+            first = self.get_block(&first.prev_block_hash)?;
+        }
+        let actual_ms = parent.timestamp().saturating_sub(first.timestamp());
+        let expected_ms = self.params.target_spacing_ms * (parent.height - first.height) as u128;
+        Ok(self.params.retarget(parent.bits, actual_ms, expected_ms))
+    }
+
     pub fn get_best_height(&self) -> Result<i32> {
-        let hash = match self.db.get("l")? {
+        let hash = match read_tip(&self.db)? {
             Some(h) => h,
             None => return Ok(-1),
         };
-        let encoded_block = self.db.get(hash)?.unwrap();
-        let block: Block = decode_from_slice(&encoded_block, standard()).map(|(b, _)| b)?;
+        let encoded_block = self.db.get(hash)?.ok_or_else(|| {
+            anyhow!(
+                "Corrupt tip pointer in database: block {} is missing. {}",
+                hash,
+                RECOVERY_HINT
+            )
+        })?;
+        let block: Block = decode_from_slice(&encoded_block, DECODE_CONFIG).map(|(b, _)| b)?;
         Ok(block.height)
     }
 
-    pub fn get_block_hashs(&self) -> Vec<HashType> {
+    pub fn get_block_hashs(&self) -> Vec<Hash> {
         let mut res = vec![];
         for ele in self.iter() {
             res.push(ele.hash);
@@ -182,35 +679,505 @@ impl Blockchain {
         res
     }
 
-    fn get_last_hash(&self) -> Result<HashType> {
-        let hash = self.db.get("l")?.unwrap();
-        let mut last_hash = [0u8; 32];
-        last_hash.copy_from_slice(&hash);
-        Ok(last_hash)
+    fn get_last_hash(&self) -> Result<Hash> {
+        read_tip(&self.db)?.ok_or_else(|| anyhow!("No blockchain found, create one first"))
     }
 
-    pub fn get_block(&self, block_hash: &HashType) -> Result<Block> {
-        let data = self.db.get(block_hash)?.unwrap();
-        let block: Block = decode_from_slice(&data, standard())
-            .ok()
-            .map(|(b, _)| b)
-            .unwrap();
+    /// Estimated network hashrate over the last `blocks` blocks. Uses the
+    /// whole chain when it is shorter than that.
+    pub fn network_hashps(&self, blocks: usize) -> f64 {
+        let recent: Vec<Block> = self.iter().take(blocks).collect();
+        estimate_hashps(&recent)
+    }
+
+    /// Difficulty of the last `last` blocks, oldest first.
+    pub fn difficulty_history(&self, last: usize) -> Vec<DifficultyPoint> {
+        // One more block for the interval of the oldest one
+        let recent: Vec<Block> = self.iter().take(last + 1).collect();
+        let mut history = difficulty_history(&recent);
+        if history.len() > last {
+            history.remove(0);
+        }
+        history
+    }
+
+    /// Writes the header of every block, genesis first, to `path`.
+    ///
+    /// The file starts with a magic and `HEADERS_FORMAT_VERSION`, followed by
+    /// the bincode encoded headers.
+    pub fn export_headers(&self, path: impl AsRef<Path>) -> Result<usize> {
+        let mut headers = self
+            .iter()
+            .map(|b| b.header(&self.params))
+            .collect::<Result<Vec<_>>>()?;
+        headers.reverse();
+
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(HEADERS_MAGIC)?;
+        writer.write_all(&HEADERS_FORMAT_VERSION.to_be_bytes())?;
+        for header in &headers {
+            encode_into_std_write(header, &mut writer, standard())?;
+        }
+        writer.flush()?;
+        Ok(headers.len())
+    }
+
+    /// Reads headers written by `export_headers` and checks that they form a
+    /// valid proof-of-work chain from genesis.
+    pub fn import_headers(
+        path: impl AsRef<Path>,
+        params: &ChainParams,
+    ) -> Result<Vec<BlockHeader>> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 4];
+        let mut version = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        reader.read_exact(&mut version)?;
+        if &magic != HEADERS_MAGIC {
+            return Err(anyhow!("Not a header export file"));
+        }
+        let version = u32::from_be_bytes(version);
+        if version != HEADERS_FORMAT_VERSION {
+            return Err(anyhow!("Unsupported header format version {}", version));
+        }
+
+        let mut headers: Vec<BlockHeader> = vec![];
+        loop {
+            let header: BlockHeader = match decode_from_std_read(&mut reader, DECODE_CONFIG) {
+                Ok(h) => h,
+                Err(DecodeError::UnexpectedEnd { .. }) => break,
+                Err(DecodeError::Io { inner, .. })
+                    if inner.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    break;
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            let (expected_prev, expected_height) = match headers.last() {
+                Some(prev) => (prev.hash, prev.height + 1),
+                None => (Hash::default(), 0),
+            };
+            if header.prev_block_hash != expected_prev || header.height != expected_height {
+                return Err(anyhow!("Header at height {} doesn't link", header.height));
+            }
+            if !header.validate(params)? {
+                return Err(anyhow!("Invalid proof of work at height {}", header.height));
+            }
+            headers.push(header);
+        }
+        Ok(headers)
+    }
+
+    /// Writes every block, genesis first, to `path`.
+    ///
+    /// The file starts with a magic and the chain format, see
+    /// `ChainParams::chain_format`, followed by the blocks as stored. Blocks
+    /// are read and written one at a time, only the list of hashes is kept
+    /// in memory.
+    pub fn export_chain(&self, path: impl AsRef<Path>) -> Result<usize> {
+        let mut hashes = self.get_block_hashs();
+        hashes.reverse();
+
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(CHAIN_MAGIC)?;
+        writer.write_all(&self.params.chain_format.to_be_bytes())?;
+        for hash in &hashes {
+            writer.write_all(&self.get_raw_block(hash)?)?;
+        }
+        writer.flush()?;
+        Ok(hashes.len())
+    }
+
+    /// Adds the blocks written by `export_chain` to this chain, reading them
+    /// one at a time. The blocks must link up from genesis. Returns how many
+    /// blocks were read.
+    pub fn import_chain(&mut self, path: impl AsRef<Path>) -> Result<usize> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 4];
+        let mut version = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        reader.read_exact(&mut version)?;
+        if &magic != CHAIN_MAGIC {
+            return Err(anyhow!("Not a chain export file"));
+        }
+        let version = u32::from_be_bytes(version);
+        if !(1..=CHAIN_FORMAT_VERSION).contains(&version) {
+            return Err(anyhow!("Unsupported chain format version {}", version));
+        }
+
+        let mut expected_prev = Hash::default();
+        let mut count = 0;
+        loop {
+            let block: Block = match decode_from_std_read(&mut reader, DECODE_CONFIG) {
+                Ok(b) => b,
+                Err(DecodeError::UnexpectedEnd { .. }) => break,
+                Err(DecodeError::Io { inner, .. })
+                    if inner.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    break;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if block.prev_block_hash != expected_prev || block.height != count as i32 {
+                return Err(anyhow!("Block at height {} doesn't link", block.height));
+            }
+            if count == 0 {
+                // Checked against the file's own genesis before anything
+                // is written
+                let format = genesis_params(self.params.clone(), &block)?.chain_format;
+                if format != version {
+                    return Err(anyhow!(
+                        "The file declares chain format {} but its genesis block commits to format {}",
+                        version,
+                        format
+                    ));
+                }
+            }
+            self.add_block(&block)?;
+            expected_prev = block.hash;
+            count += 1;
+        }
+        self.flush()?;
+        Ok(count)
+    }
+
+    /// Earliest block with an output to, or an input from, `pub_key_hash`.
+    pub fn address_first_seen(&self, pub_key_hash: &[u8]) -> Option<Block> {
+        let mut first_seen = None;
+        for block in self.iter() {
+            let seen = block.transactions.iter().any(|tx| {
+                tx.v_out
+                    .iter()
+                    .any(|out| out.is_locked_with_key(pub_key_hash))
+                    || (!tx.is_coinbase()
+                        && tx
+                            .v_in
+                            .iter()
+                            .any(|vin| vin.uses_key(pub_key_hash, &self.params)))
+            });
+            if seen {
+                first_seen = Some(block);
+            }
+        }
+        first_seen
+    }
+
+    pub fn get_block_by_height(&self, height: i32) -> Option<Block> {
+        self.iter().find(|b| b.height == height)
+    }
+
+    /// Sum of input values minus output values over the non-coinbase
+    /// transactions of `block`.
+    pub fn block_fees(&self, block: &Block) -> Result<Amount> {
+        self.transaction_fees(&block.transactions, block.prev_block_hash)
+    }
+
+    /// Fees of `transactions` if mined on top of `prev_block_hash`. Fails
+    /// if one of them creates value, paying out more than it spends.
+    ///
+    /// Inputs are looked up in an earlier transaction of the same list, or
+    /// through the transaction index in the chain ending at
+    /// `prev_block_hash`.
+    fn transaction_fees(
+        &self,
+        transactions: &[Transaction],
+        prev_block_hash: Hash,
+    ) -> Result<Amount> {
+        let chain = ChainMembership::new(self, prev_block_hash)?;
+        let mut fees = Amount::ZERO;
+        for (i, tx) in transactions.iter().enumerate() {
+            if tx.is_coinbase() {
+                continue;
+            }
+            let mut spent = Amount::ZERO;
+            for vin in &tx.v_in {
+                let vout = vin.prev_out.vout as usize;
+                let value = match transactions[..i]
+                    .iter()
+                    .find(|t| t.hash_val == vin.prev_out.txid)
+                {
+                    Some(prev_tx) => prev_tx.v_out.get(vout).map(|out| out.value),
+                    None => self
+                        .find_output_in(&vin.prev_out, &chain)?
+                        .map(|out| out.value),
+                }
+                .ok_or_else(|| anyhow!("Spent output {} not found", vin.prev_out))?;
+                spent = spent
+                    .checked_add(Amount::from_value(value)?)
+                    .ok_or_else(|| anyhow!("Amount overflow"))?;
+            }
+            let created = Amount::sum_values(tx.v_out.iter().map(|out| out.value))?;
+            let fee = spent.checked_sub(created).ok_or_else(|| {
+                anyhow!(
+                    "Transaction {} pays out {} but only spends {}",
+                    tx.id,
+                    created,
+                    spent
+                )
+            })?;
+            fees = fees
+                .checked_add(fee)
+                .ok_or_else(|| anyhow!("Amount overflow"))?;
+        }
+        Ok(fees)
+    }
+
+    /// Output `out_point` if the transaction creating it is in a block of
+    /// `chain`, looked up in the transaction index.
+    fn find_output_in(
+        &self,
+        out_point: &OutPoint,
+        chain: &ChainMembership,
+    ) -> Result<Option<TXOutput>> {
+        let index = self.db.open_tree(TX_INDEX_TREE)?;
+        for entry in index.scan_prefix(out_point.txid.as_ref()) {
+            let (key, _) = entry?;
+            let Ok(block_hash) = Hash::try_from(&key[32..]) else {
+                continue;
+            };
+            // An entry written just before a crash may lack its block
+            let Ok(block) = self.get_block(&block_hash) else {
+                continue;
+            };
+            if !chain.contains(&block)? {
+                continue;
+            }
+            return Ok(block
+                .transactions
+                .into_iter()
+                .find(|tx| tx.hash_val == out_point.txid)
+                .and_then(|tx| tx.v_out.get(out_point.vout as usize).cloned()));
+        }
+        Ok(None)
+    }
+
+    /// Checks the issuance rule: every transaction of a block at `height`
+    /// spends at least what it pays out, and the coinbase claims at most the
+    /// subsidy plus the fees of the other transactions.
+    fn check_coinbase_value(
+        &self,
+        transactions: &[Transaction],
+        prev_block_hash: Hash,
+        height: i32,
+    ) -> Result<()> {
+        let claimed = Amount::sum_values(
+            transactions
+                .iter()
+                .filter(|tx| tx.is_coinbase())
+                .flat_map(|tx| &tx.v_out)
+                .map(|out| out.value),
+        )?;
+        let subsidy = Amount::from_value(block_subsidy(height))?;
+        let allowed = subsidy
+            .checked_add(self.transaction_fees(transactions, prev_block_hash)?)
+            .ok_or_else(|| anyhow!("Amount overflow"))?;
+        if claimed > allowed {
+            return Err(anyhow!(
+                "Coinbase claims {} but only {} (subsidy {} plus fees) is allowed",
+                claimed,
+                allowed,
+                subsidy
+            ));
+        }
+        Ok(())
+    }
+
+    /// Coinbase revenue of `pub_key_hash`, one entry per block where it
+    /// received any, oldest first.
+    ///
+    /// What it received up to the subsidy counts as subsidy. The rest is
+    /// counted as fees up to the fees of the block's transactions, which the
+    /// issuance rule keeps it within.
+    pub fn miner_revenue(&self, pub_key_hash: &[u8]) -> Result<Vec<BlockRevenue>> {
+        let mut revenue = vec![];
+        for block in self.iter() {
+            let Some(coinbase) = block.transactions.iter().find(|tx| tx.is_coinbase()) else {
+                continue;
+            };
+            let reward = Amount::sum_values(
+                coinbase
+                    .v_out
+                    .iter()
+                    .filter(|out| out.is_locked_with_key(pub_key_hash))
+                    .map(|out| out.value),
+            )?;
+            if reward == Amount::ZERO {
+                continue;
+            }
+            let subsidy = reward.min(Amount::from_value(block_subsidy(block.height))?);
+            let claimed = reward.checked_sub(subsidy).unwrap_or_default();
+            let fees = if claimed == Amount::ZERO {
+                Amount::ZERO
+            } else {
+                claimed.min(self.block_fees(&block)?)
+            };
+            revenue.push(BlockRevenue {
+                height: block.height,
+                hash: block.hash,
+                subsidy,
+                fees,
+            });
+        }
+        revenue.reverse();
+        Ok(revenue)
+    }
+
+    pub fn get_block(&self, block_hash: &Hash) -> Result<Block> {
+        let data = self.get_raw_block(block_hash)?;
+        let (block, _) = decode_from_slice(&data, DECODE_CONFIG)?;
         Ok(block)
     }
+
+    /// The block exactly as stored, bincode encoded.
+    pub fn get_raw_block(&self, block_hash: &Hash) -> Result<Vec<u8>> {
+        let data = self
+            .db
+            .get(block_hash)?
+            .ok_or_else(|| anyhow!("Block {} not found", block_hash))?;
+        Ok(data.to_vec())
+    }
+}
+
+/// Coinbase outputs one address received in a block, see
+/// `Blockchain::miner_revenue`.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct BlockRevenue {
+    pub height: i32,
+    pub hash: Hash,
+    pub subsidy: Amount,
+    pub fees: Amount,
+}
+
+/// Where a transaction stands on the current best chain, see
+/// `Blockchain::transaction_status`.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxStatus {
+    /// In a block, with this many confirmations
+    Confirmed(i32),
+    /// Not in a block, and its inputs are still unspent on the chain
+    Pending,
+    /// Not in a block, and another transaction on the chain spends one of
+    /// its inputs, so it can never confirm
+    Conflicted,
+}
+
+/// A cheaply cloneable, read-only handle on the chain.
+///
+/// The reader shares the sled database with the `Blockchain` it was created
+/// from but keeps its own copy of the tip. Blocks are immutable once stored,
+/// so every query sees a consistent snapshot of the chain ending at that tip,
+/// even while a writer appends new blocks. Blocks added after the snapshot
+/// are invisible until a fresh reader is taken.
+#[derive(Clone)]
+pub struct BlockchainReader {
+    tip: Hash,
+    db: sled::Db,
+}
+
+impl BlockchainReader {
+    pub fn tip(&self) -> Hash {
+        self.tip
+    }
+
+    pub fn iter(&self) -> BlockchainIterator<'_> {
+        BlockchainIterator {
+            current_hash: self.tip,
+            db: &self.db,
+        }
+    }
+
+    pub fn get_block(&self, block_hash: &Hash) -> Result<Block> {
+        let data = self
+            .db
+            .get(block_hash)?
+            .ok_or_else(|| anyhow!("Block {} not found", block_hash))?;
+        let (block, _) = decode_from_slice(&data, DECODE_CONFIG)?;
+        Ok(block)
+    }
+
+    pub fn get_best_height(&self) -> Result<i32> {
+        if self.tip == Hash::default() {
+            return Ok(-1);
+        }
+        Ok(self.get_block(&self.tip)?.height)
+    }
+
+    pub fn get_block_hashs(&self) -> Vec<Hash> {
+        self.iter().map(|b| b.hash).collect()
+    }
+
+    /// Hashes describing our chain to a peer: the last ten blocks, then
+    /// exponentially further apart back to genesis, newest first, at most
+    /// `MAX_LOCATOR_LEN` of them.
+    ///
+    /// Blocks below the tip are looked up in the height index, which
+    /// follows the newest tip. After a reorg since this snapshot they may
+    /// come from the new chain, which only makes the locator more current.
+    pub fn block_locator(&self) -> Vec<Hash> {
+        let Ok(top) = self.get_best_height() else {
+            return vec![];
+        };
+        let Ok(heights) = self.db.open_tree(HEIGHTS_TREE) else {
+            return vec![];
+        };
+        let hash_at = |height: i32| {
+            let value = heights.get(height.to_be_bytes()).ok()??;
+            Hash::try_from(value.as_ref()).ok()
+        };
+        let mut locator = vec![];
+        let mut step: i32 = 1;
+        let mut height = top;
+        while height > 0 && locator.len() < MAX_LOCATOR_LEN - 1 {
+            let hash = if height == top {
+                Some(self.tip)
+            } else {
+                hash_at(height)
+            };
+            locator.extend(hash);
+            if locator.len() >= 10 {
+                step = step.saturating_mul(2);
+            }
+            height -= step;
+        }
+        if top >= 0 {
+            locator.extend(hash_at(0));
+        }
+        locator
+    }
+
+    /// Hashes of our blocks after the most recent one of `locator` that we
+    /// share, newest first. Everything if there is no common block.
+    ///
+    /// Walks back from the tip only until it reaches a block of the
+    /// locator, looked up by hash.
+    pub fn hashes_after_locator(&self, locator: &[Hash]) -> Vec<Hash> {
+        let locator: HashSet<&Hash> = locator.iter().collect();
+        self.iter()
+            .map(|b| b.hash)
+            .take_while(|hash| !locator.contains(hash))
+            .collect()
+    }
+
+    pub fn find_transaction(&self, id: &str) -> Option<Transaction> {
+        self.iter()
+            .flat_map(|b| b.transactions)
+            .find(|tx| tx.id == id)
+    }
 }
 
 pub struct BlockchainIterator<'a> {
-    bc: &'a Blockchain,
-    current_hash: HashType,
+    db: &'a sled::Db,
+    current_hash: Hash,
 }
 
 impl<'a> Iterator for BlockchainIterator<'a> {
     type Item = Block;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let encoded_block = self.bc.db.get(self.current_hash).ok()??;
+        let encoded_block = self.db.get(self.current_hash).ok()??;
 
-        let block: Block = decode_from_slice(&encoded_block, standard())
+        let block: Block = decode_from_slice(&encoded_block, DECODE_CONFIG)
             .ok()
             .map(|(b, _)| b)?;
 
@@ -219,3 +1186,850 @@ impl<'a> Iterator for BlockchainIterator<'a> {
         Some(block)
     }
 }
+
+/// The genesis block of the chain in `db`, `None` for an empty chain.
+/// Chains from before its hash was stored are walked back from the tip
+/// once.
+fn read_genesis(db: &sled::Db) -> Result<Option<Block>> {
+    let hash = match db.get(GENESIS_KEY)? {
+        Some(value) => Hash::try_from(value.as_ref()).map_err(|_| {
+            anyhow!(
+                "Corrupt genesis pointer in database: {} bytes instead of 32. {}",
+                value.len(),
+                RECOVERY_HINT
+            )
+        })?,
+        None => {
+            let Some(tip) = read_tip(db)? else {
+                return Ok(None);
+            };
+            let iter = BlockchainIterator {
+                db,
+                current_hash: tip,
+            };
+            let Some(genesis) = iter.last() else {
+                return Ok(None);
+            };
+            db.insert(GENESIS_KEY, genesis.hash.as_ref())?;
+            return Ok(Some(genesis));
+        }
+    };
+    let data = db
+        .get(hash)?
+        .ok_or_else(|| anyhow!("Genesis block {} not found. {}", hash, RECOVERY_HINT))?;
+    let (block, _) = decode_from_slice(&data, DECODE_CONFIG)?;
+    Ok(Some(block))
+}
+
+/// `params` with the ones the coinbase of `genesis` commits to.
+fn genesis_params(params: ChainParams, genesis: &Block) -> Result<ChainParams> {
+    match genesis
+        .transactions
+        .first()
+        .and_then(|tx| tx.coinbase_message())
+    {
+        Some(message) => params.with_genesis_message(&message),
+        None => Ok(params),
+    }
+}
+
+/// Points the height index of `db` at the chain ending at `tip`. Walks
+/// back only until the index agrees, so just the blocks a reorg replaced,
+/// or the index missed, are rewritten.
+fn index_heights(db: &sled::Db, tip: Hash) -> Result<()> {
+    let heights = db.open_tree(HEIGHTS_TREE)?;
+    let mut batch = sled::Batch::default();
+    let blocks = BlockchainIterator {
+        db,
+        current_hash: tip,
+    };
+    for block in blocks {
+        let key = block.height.to_be_bytes();
+        if heights.get(key)?.as_deref() == Some(block.hash.as_ref()) {
+            break;
+        }
+        batch.insert(&key, block.hash.as_ref());
+    }
+    heights.apply_batch(batch)?;
+    Ok(())
+}
+
+/// Adds the transactions of `block` to the transaction index of `db`.
+fn index_transactions(db: &sled::Db, block: &Block) -> Result<()> {
+    let index = db.open_tree(TX_INDEX_TREE)?;
+    let mut batch = sled::Batch::default();
+    for tx in &block.transactions {
+        batch.insert([tx.hash_val.as_ref(), block.hash.as_ref()].concat(), vec![]);
+    }
+    index.apply_batch(batch)?;
+    Ok(())
+}
+
+/// Indexes the transactions of every block in `db` unless that was done
+/// before, for chains stored before the index existed.
+fn backfill_tx_index(db: &sled::Db) -> Result<()> {
+    let index = db.open_tree(TX_INDEX_TREE)?;
+    if index.contains_key(TX_INDEX_MARKER)? {
+        return Ok(());
+    }
+    info!("Indexing the transactions of the stored blocks");
+    // Blocks are the only 32 byte keys
+    for entry in db.iter() {
+        let (key, value) = entry?;
+        if key.len() != 32 {
+            continue;
+        }
+        let (block, _): (Block, usize) = decode_from_slice(&value, DECODE_CONFIG)?;
+        index_transactions(db, &block)?;
+    }
+    index.insert(TX_INDEX_MARKER, vec![])?;
+    Ok(())
+}
+
+/// Which blocks make up the chain ending at some tip. The blocks of a fork
+/// are collected walking back to the best chain, below that the height
+/// index answers.
+struct ChainMembership {
+    heights: sled::Tree,
+    forked: HashSet<Hash>,
+    joins_best_at: i32,
+}
+
+impl ChainMembership {
+    fn new(bc: &Blockchain, tip: Hash) -> Result<Self> {
+        let heights = bc.db.open_tree(HEIGHTS_TREE)?;
+        let mut forked = HashSet::new();
+        let mut joins_best_at = -1;
+        let mut current = tip;
+        while current != Hash::default() {
+            let block = bc.get_block(&current)?;
+            if on_best_chain(&heights, &block)? {
+                joins_best_at = block.height;
+                break;
+            }
+            forked.insert(block.hash);
+            current = block.prev_block_hash;
+        }
+        Ok(Self {
+            heights,
+            forked,
+            joins_best_at,
+        })
+    }
+
+    fn contains(&self, block: &Block) -> Result<bool> {
+        Ok(self.forked.contains(&block.hash)
+            || (block.height <= self.joins_best_at && on_best_chain(&self.heights, block)?))
+    }
+}
+
+fn on_best_chain(heights: &sled::Tree, block: &Block) -> Result<bool> {
+    Ok(heights.get(block.height.to_be_bytes())?.as_deref() == Some(block.hash.as_ref()))
+}
+
+/// The tip hash stored in `db`, `None` for an empty chain. Fails with a
+/// descriptive error instead of panicking when the pointer is corrupt.
+fn read_tip(db: &sled::Db) -> Result<Option<Hash>> {
+    let Some(value) = db.get(TIP_KEY)? else {
+        return Ok(None);
+    };
+    match Hash::try_from(value.as_ref()) {
+        Ok(hash) => Ok(Some(hash)),
+        Err(_) => Err(anyhow!(
+            "Corrupt tip pointer in database: {} bytes instead of 32. {}",
+            value.len(),
+            RECOVERY_HINT
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    use crate::{
+        UTXOSet, Wallet, get_pub_key_hash,
+        testutil::{TestDir, TxBuilder, reopen},
+    };
+
+    #[test]
+    fn test_corrupt_tip_pointer_is_an_error() {
+        let dir = TestDir::new("corrupt-tip");
+        let addr = Wallet::new().get_address(&ChainParams::default());
+        let bc = Blockchain::create_at(dir.join("blockchain"), &addr).unwrap();
+
+        bc.db.insert(TIP_KEY, &[1, 2, 3][..]).unwrap();
+        for err in [
+            bc.get_best_height().unwrap_err(),
+            bc.get_last_hash().unwrap_err(),
+            bc.next_bits().unwrap_err(),
+        ] {
+            assert!(err.to_string().contains("Corrupt tip pointer"), "{}", err);
+        }
+        drop(bc);
+        let err = Blockchain::open(dir.join("blockchain")).err().unwrap();
+        assert!(err.to_string().contains("3 bytes instead of 32"), "{}", err);
+
+        // A well formed hash of a block that isn't there
+        let bc = Blockchain::open(dir.join("other")).unwrap();
+        bc.db.insert(TIP_KEY, &[7; 32][..]).unwrap();
+        let err = bc.get_best_height().unwrap_err();
+        assert!(err.to_string().contains("is missing"), "{}", err);
+    }
+
+    #[test]
+    fn test_retarget_steers_towards_target_spacing() {
+        let params = ChainParams::default();
+        let dir = TestDir::new("spacing");
+        let addr = Wallet::new().get_address(&params);
+        let mut bc = Blockchain::create_at(dir.join("blockchain"), &addr).unwrap();
+        bc.durability = Durability::Batch;
+        bc.params.target_spacing_ms = 1_000;
+        bc.params.retarget_interval = 2;
+
+        // A hashrate finding a block of `equilibrium` bits every second
+        let equilibrium = bc.params.min_difficulty_bits + 3;
+        let block_ms = |bits: u32| (1_000u128 << bits) >> equilibrium;
+        let mut timestamp = bc.iter().next().unwrap().timestamp();
+        let mut intervals = vec![];
+        for height in 1..=12 {
+            let bits = bc.next_bits().unwrap();
+            timestamp += block_ms(bits);
+            intervals.push(block_ms(bits));
+            let cbtx = Transaction::new_coinbase(&addr, height.to_string(), &params).unwrap();
+            let block =
+                Block::new_deterministic(vec![cbtx], bc.tip, height, bits, timestamp, &bc.params)
+                    .unwrap();
+            bc.add_block(&block).unwrap();
+        }
+        // The genesis difficulty is far too high, each window closes in
+        assert!(intervals[0] > 1_000);
+        assert!(intervals.windows(2).all(|w| w[1] <= w[0]));
+        assert_eq!(intervals.last(), Some(&1_000));
+        assert_eq!(bc.next_bits().unwrap(), equilibrium);
+    }
+
+    #[test]
+    fn test_pow_disabled_mines_at_first_nonce() {
+        let dir = TestDir::new("no-pow");
+        let params = ChainParams {
+            pow_disabled: true,
+            retarget_interval: 3,
+            target_spacing_ms: 500,
+            ..ChainParams::default()
+        };
+        let addr = Wallet::new().get_address(&params);
+        let mut bc = Blockchain::create_with_params(dir.join("blockchain"), &addr, params).unwrap();
+        assert_eq!(bc.iter().next().unwrap().bits, 0);
+        assert_eq!(bc.next_bits().unwrap(), 0);
+
+        let cbtx = Transaction::new_coinbase(&addr, "1".to_owned(), &bc.params).unwrap();
+        let block = Block::new(vec![cbtx], bc.tip, 1, 0, &bc.params).unwrap();
+        assert_eq!(block.nonce, 0);
+        bc.test_block(&block).unwrap();
+
+        // Only the work is skipped, the block must still match its contents
+        let mut tampered = block.clone();
+        tampered.transactions[0].v_out[0].value += 1;
+        assert!(bc.test_block(&tampered).is_err());
+        let mut unlinked = block.clone();
+        unlinked.prev_block_hash = Hash([1; 32]);
+        assert!(bc.test_block(&unlinked).is_err());
+
+        // A node with proof-of-work on refuses it
+        bc.params.pow_disabled = false;
+        assert!(bc.test_block(&block).is_err());
+        bc.params.pow_disabled = true;
+
+        bc.add_block(&block).unwrap();
+        drop(bc);
+        // The genesis block commits to the parameters, so reopening the
+        // chain or importing it elsewhere keeps them
+        let bc = Blockchain::open(dir.join("blockchain")).unwrap();
+        assert!(bc.params.pow_disabled);
+        assert_eq!(bc.params.retarget_interval, 3);
+        assert_eq!(bc.params.target_spacing_ms, 500);
+        assert_eq!(bc.next_bits().unwrap(), 0);
+        bc.export_chain(dir.join("chain.bin")).unwrap();
+        let mut copy = Blockchain::open(dir.join("copy")).unwrap();
+        assert!(!copy.params.pow_disabled);
+        assert_eq!(copy.import_chain(dir.join("chain.bin")).unwrap(), 2);
+        assert!(copy.params.pow_disabled);
+        assert_eq!(copy.params.retarget_interval, 3);
+    }
+
+    #[test]
+    fn test_legacy_chain_commits_to_txids() {
+        let dir = TestDir::new("legacy");
+        let params = ChainParams::for_tests();
+        let addr = Wallet::new().get_address(&params);
+        // A genesis from before the parameters were committed, mined when
+        // blocks committed to txids
+        let legacy = ChainParams {
+            chain_format: 1,
+            ..params.clone()
+        };
+        let cbtx = Transaction::new_coinbase(&addr, "The Times".to_owned(), &params).unwrap();
+        let genesis = Block::new_genesis_block(cbtx, &legacy);
+        let mut bc = Blockchain::open_with_params(dir.join("blockchain"), params.clone()).unwrap();
+        bc.add_block(&genesis).unwrap();
+        assert!(!bc.params.commits_to_wtxids());
+        let cbtx = Transaction::new_coinbase(&addr, "1".to_owned(), &bc.params).unwrap();
+        bc.mine_block(vec![cbtx]).unwrap();
+        let block = bc.iter().next().unwrap();
+        assert_eq!(block.height, 1);
+        assert!(block.header(&bc.params).unwrap().validate(&params).unwrap());
+
+        // The export carries the format, and a file claiming another is refused
+        let export = dir.join("chain.bin");
+        bc.export_chain(&export).unwrap();
+        let mut data = fs::read(&export).unwrap();
+        assert_eq!(data[4..8], 1u32.to_be_bytes());
+        data[4..8].copy_from_slice(&2u32.to_be_bytes());
+        fs::write(&export, data).unwrap();
+        let mut copy = Blockchain::open_with_params(dir.join("copy"), params).unwrap();
+        let err = copy.import_chain(&export).unwrap_err();
+        assert!(err.to_string().contains("commits to format 1"), "{}", err);
+        assert_eq!(copy.get_best_height().unwrap(), -1);
+        assert!(copy.get_block(&genesis.hash).is_err());
+    }
+
+    #[test]
+    fn test_rejects_unexpected_bits() {
+        let dir = TestDir::new("bits");
+        let params = ChainParams {
+            initial_bits: 10,
+            ..ChainParams::for_tests()
+        };
+        let addr = Wallet::new().get_address(&params);
+        let mut bc = Blockchain::create_with_params(dir.join("blockchain"), &addr, params).unwrap();
+        let bits = bc.next_bits().unwrap();
+        let mine = |bc: &Blockchain, bits: u32| {
+            let cbtx = Transaction::new_coinbase(&addr, bits.to_string(), &bc.params).unwrap();
+            Block::new(vec![cbtx], bc.tip, 1, bits, &bc.params).unwrap()
+        };
+
+        // Easier and harder than the parent calls for are both refused
+        for wrong in [bits - 1, bits + 1] {
+            let err = bc.add_block(&mine(&bc, wrong)).unwrap_err();
+            assert!(err.to_string().contains("expected 10"), "{}", err);
+        }
+        let block = mine(&bc, bits);
+        bc.add_block(&block).unwrap();
+        assert_eq!(bc.tip, block.hash);
+    }
+
+    #[test]
+    fn test_rejects_forged_block_hash() {
+        let dir = TestDir::new("forged-hash");
+        let params = ChainParams::for_tests();
+        let addr = Wallet::new().get_address(&params);
+        let mut bc = Blockchain::create_with_params(dir.join("blockchain"), &addr, params).unwrap();
+        let genesis = bc.tip;
+        let cbtx = Transaction::new_coinbase(&addr, "1".to_owned(), &bc.params).unwrap();
+        let block = Block::new(vec![cbtx], bc.tip, 1, bc.next_bits().unwrap(), &bc.params).unwrap();
+
+        // Valid work under a hash of the sender's choosing, new or taken
+        for forged in [Hash([7; 32]), genesis] {
+            let mut forged_block = block.clone();
+            forged_block.hash = forged;
+            let err = bc.add_block(&forged_block).unwrap_err();
+            assert!(err.to_string().contains("stored hash"), "{}", err);
+        }
+        assert_eq!(bc.tip, genesis);
+        bc.add_block(&block).unwrap();
+        assert_eq!(bc.tip, block.hash);
+    }
+
+    #[test]
+    fn test_rejects_transactions_over_size_caps() {
+        let params = ChainParams::default();
+        let dir = TestDir::new("tx-caps");
+        let mut bc = Blockchain::open(dir.join("blockchain")).unwrap();
+        bc.params = ChainParams {
+            max_tx_outputs: 2,
+            ..ChainParams::for_tests()
+        };
+        let bits = bc.params.min_difficulty_bits;
+        let addr = Wallet::new().get_address(&params);
+        let coinbase_with_outputs = |outputs: usize| {
+            let mut tx = Transaction::new_coinbase(&addr, String::new(), &params).unwrap();
+            tx.v_out[0].value = 1;
+            tx.v_out = vec![tx.v_out[0].clone(); outputs];
+            tx.set_id().unwrap();
+            tx
+        };
+
+        let over = Block::new(
+            vec![coinbase_with_outputs(3)],
+            Hash::default(),
+            0,
+            bits,
+            &bc.params,
+        )
+        .unwrap();
+        assert!(bc.add_block(&over).is_err());
+
+        let at_cap = Block::new(
+            vec![coinbase_with_outputs(2)],
+            Hash::default(),
+            0,
+            bits,
+            &bc.params,
+        )
+        .unwrap();
+        bc.add_block(&at_cap).unwrap();
+        assert_eq!(bc.tip, at_cap.hash);
+    }
+
+    #[test]
+    fn test_rejects_transactions_without_inputs_or_outputs() {
+        let params = ChainParams::default();
+        let dir = TestDir::new("empty-tx");
+        let wallet = Wallet::new();
+        let addr = wallet.get_address(&params);
+        let bc = Blockchain::create_at(dir.join("blockchain"), &addr).unwrap();
+        let genesis_cb = bc.get_block(&bc.tip).unwrap().transactions[0].clone();
+
+        let mut no_inputs = spend_with_fee(&bc, &wallet, &genesis_cb, 0);
+        no_inputs.v_in.clear();
+        no_inputs.set_id().unwrap();
+        assert!(!bc.verify_transaction(&no_inputs).unwrap());
+
+        let mut no_outputs = spend_with_fee(&bc, &wallet, &genesis_cb, 0);
+        no_outputs.v_out.clear();
+        no_outputs.set_id().unwrap();
+        bc.sign_transaction(&mut no_outputs, &wallet.private_key)
+            .unwrap();
+        assert!(!bc.verify_transaction(&no_outputs).unwrap());
+
+        let mut empty_coinbase = Transaction::new_coinbase(&addr, "1".to_owned(), &params).unwrap();
+        empty_coinbase.v_out.clear();
+        empty_coinbase.set_id().unwrap();
+        let block = Block::new(
+            vec![empty_coinbase],
+            bc.tip,
+            1,
+            bc.next_bits().unwrap(),
+            &bc.params,
+        )
+        .unwrap();
+        let err = bc.test_block(&block).unwrap_err();
+        assert!(
+            err.to_string().contains("without inputs or outputs"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_locator_finds_fork_point() {
+        let params = ChainParams::default();
+        let dir = TestDir::new("locator");
+        let addr = Wallet::new().get_address(&params);
+        let mut ours =
+            Blockchain::create_with_params(dir.join("ours"), &addr, ChainParams::for_tests())
+                .unwrap();
+        let mut theirs = Blockchain::open(dir.join("theirs")).unwrap();
+        theirs
+            .add_block(&ours.get_block(&ours.tip).unwrap())
+            .unwrap();
+        let bits = ours.params.min_difficulty_bits;
+        let extend = |bc: &mut Blockchain, tag: &str| {
+            let tip = bc.get_block(&bc.tip).unwrap();
+            let cbtx = Transaction::new_coinbase(&addr, format!("{} {}", tag, tip.height), &params)
+                .unwrap();
+            let block = Block::new(vec![cbtx], tip.hash, tip.height + 1, bits, &bc.params).unwrap();
+            bc.add_block(&block).unwrap();
+            block.hash
+        };
+
+        // Shared history up to height 12, then both sides diverge
+        for _ in 0..12 {
+            let hash = extend(&mut ours, "shared");
+            theirs.add_block(&ours.get_block(&hash).unwrap()).unwrap();
+        }
+        let fork_point = ours.tip;
+        let ours_only: Vec<Hash> = (0..3).map(|_| extend(&mut ours, "ours")).collect();
+        for _ in 0..15 {
+            extend(&mut theirs, "theirs");
+        }
+
+        let locator = theirs.reader().block_locator();
+        assert!(locator.len() < 29);
+        assert_eq!(locator.last(), ours.get_block_hashs().last());
+        let after = ours.reader().hashes_after_locator(&locator);
+        assert_eq!(after, ours_only.into_iter().rev().collect::<Vec<_>>());
+        assert!(!after.contains(&fork_point));
+        assert_eq!(ours.reader().hashes_after_locator(&[]).len(), 16);
+
+        // Once ours overtakes theirs, their height index follows it
+        for _ in 0..13 {
+            extend(&mut ours, "ours");
+        }
+        for hash in ours
+            .reader()
+            .hashes_after_locator(&locator)
+            .into_iter()
+            .rev()
+        {
+            theirs.add_block(&ours.get_block(&hash).unwrap()).unwrap();
+        }
+        assert_eq!(theirs.tip, ours.tip);
+        assert_eq!(
+            theirs.reader().block_locator(),
+            ours.reader().block_locator()
+        );
+        drop(theirs);
+        let theirs = Blockchain::open(dir.join("theirs")).unwrap();
+        assert_eq!(
+            theirs.reader().block_locator(),
+            ours.reader().block_locator()
+        );
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture bench_`
+    #[test]
+    #[ignore]
+    fn bench_durability_modes() {
+        let params = ChainParams::default();
+        let dir = TestDir::new("durability");
+        let addr = Wallet::new().get_address(&params);
+        let source =
+            Blockchain::create_with_params(dir.join("source"), &addr, ChainParams::for_tests())
+                .unwrap();
+        let bits = source.params.min_difficulty_bits;
+        let mut blocks = vec![source.get_block(&source.tip).unwrap()];
+        for height in 1..200 {
+            let prev = blocks.last().unwrap().hash;
+            let cbtx = Transaction::new_coinbase(&addr, height.to_string(), &params).unwrap();
+            blocks.push(Block::new(vec![cbtx], prev, height, bits, &source.params).unwrap());
+        }
+
+        for durability in [Durability::Full, Durability::Batch] {
+            let mut bc = Blockchain::open(dir.join(format!("{:?}", durability))).unwrap();
+            bc.durability = durability;
+            let start = std::time::Instant::now();
+            for block in &blocks {
+                bc.add_block(block).unwrap();
+            }
+            bc.flush().unwrap();
+            println!(
+                "{:?}: {} blocks in {:?}",
+                durability,
+                blocks.len(),
+                start.elapsed()
+            );
+        }
+    }
+
+    #[test]
+    fn test_export_import_chain_round_trip() {
+        let params = ChainParams::default();
+        let dir = TestDir::new("export-chain");
+        let addr = Wallet::new().get_address(&params);
+        let mut source =
+            Blockchain::create_with_params(dir.join("source"), &addr, ChainParams::for_tests())
+                .unwrap();
+        source.durability = Durability::Batch;
+        let bits = source.params.min_difficulty_bits;
+        for height in 1..300 {
+            let cbtx = Transaction::new_coinbase(&addr, height.to_string(), &params).unwrap();
+            let block = Block::new(vec![cbtx], source.tip, height, bits, &source.params).unwrap();
+            source.add_block(&block).unwrap();
+        }
+
+        let path = dir.join("chain.bin");
+        assert_eq!(source.export_chain(&path).unwrap(), 300);
+        let mut copy = Blockchain::open(dir.join("copy")).unwrap();
+        assert_eq!(copy.import_chain(&path).unwrap(), 300);
+        assert_eq!(copy.tip, source.tip);
+        assert_eq!(copy.get_best_height().unwrap(), 299);
+        assert_eq!(copy.get_block_hashs(), source.get_block_hashs());
+
+        let mut bumped = std::fs::read(&path).unwrap();
+        bumped[4..8].copy_from_slice(&99u32.to_be_bytes());
+        std::fs::write(&path, bumped).unwrap();
+        let err = copy.import_chain(&path).unwrap_err();
+        assert!(err.to_string().contains("version 99"), "{}", err);
+    }
+
+    #[test]
+    fn test_rejects_duplicate_transactions() {
+        let params = ChainParams::default();
+        let dir = TestDir::new("dup-tx");
+        let mut bc = Blockchain::open(dir.join("blockchain")).unwrap();
+        bc.params = ChainParams::for_tests();
+        let addr = Wallet::new().get_address(&params);
+        let cbtx = Transaction::new_coinbase(&addr, String::new(), &params).unwrap();
+        let bits = bc.params.min_difficulty_bits;
+        let block = Block::new(
+            vec![cbtx.clone(), cbtx],
+            Hash::default(),
+            0,
+            bits,
+            &bc.params,
+        )
+        .unwrap();
+
+        assert!(bc.add_block(&block).is_err());
+        assert!(bc.db.get(block.hash).unwrap().is_none());
+        let mut utxo_set = UTXOSet::with_path(bc, dir.join("utxos"));
+        utxo_set.reindex().unwrap();
+        assert!(
+            utxo_set
+                .find_utxo(&get_pub_key_hash(&addr, &params).unwrap())
+                .unwrap()
+                .outputs
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_miner_revenue_splits_subsidy_and_fees() {
+        let params = ChainParams::default();
+        let dir = TestDir::new("miner-revenue");
+        let wallet = Wallet::new();
+        let miner = wallet.get_address(&params);
+        let other = Wallet::new().get_address(&params);
+        let mut bc = Blockchain::create_with_params(
+            dir.join("blockchain"),
+            &miner,
+            ChainParams::for_tests(),
+        )
+        .unwrap();
+        let bits = bc.params.min_difficulty_bits;
+        let genesis = bc.get_block(&bc.tip).unwrap();
+
+        // A coinbase claiming 3 in fees on top of the subsidy
+        let spend = spend_with_fee(&bc, &wallet, &genesis.transactions[0], 3);
+        let mut cbtx = Transaction::new_coinbase(&miner, "with fees".to_string(), &params).unwrap();
+        cbtx.v_out[0].value += 3;
+        cbtx.set_id().unwrap();
+        let block = Block::new(vec![cbtx, spend], genesis.hash, 1, bits, &bc.params).unwrap();
+        bc.add_block(&block).unwrap();
+
+        let revenue = bc
+            .miner_revenue(&get_pub_key_hash(&miner, &params).unwrap())
+            .unwrap();
+        let subsidy = Amount::from_value(block_subsidy(0)).unwrap();
+        assert_eq!(
+            revenue,
+            vec![
+                BlockRevenue {
+                    height: 0,
+                    hash: genesis.hash,
+                    subsidy,
+                    fees: Amount::ZERO
+                },
+                BlockRevenue {
+                    height: 1,
+                    hash: block.hash,
+                    subsidy,
+                    fees: Amount::new(3)
+                },
+            ]
+        );
+        assert!(
+            bc.miner_revenue(&get_pub_key_hash(&other, &params).unwrap())
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_signing_with_missing_previous_tx_fails_cleanly() {
+        let params = ChainParams::default();
+        let dir = TestDir::new("sign-missing");
+        let wallet = Wallet::new();
+        let bc =
+            Blockchain::create_at(dir.join("blockchain"), &wallet.get_address(&params)).unwrap();
+        let missing = "ab".repeat(32);
+        let mut tx = TxBuilder::new()
+            .spend_from(missing.parse().unwrap(), 0, &wallet)
+            .pay(1, &wallet.get_address(&params), &params)
+            .build();
+
+        let err = bc
+            .sign_transaction(&mut tx, &wallet.private_key)
+            .unwrap_err();
+        assert!(err.to_string().contains(&missing));
+        let wallets = Wallets {
+            wallets: HashMap::from([(wallet.get_address(&params), wallet)]),
+            ..Default::default()
+        };
+        assert!(bc.sign_transaction_with_wallets(&mut tx, &wallets).is_err());
+        // Nor does signing with a map lacking the spent output panic
+        assert!(
+            tx.sign_with_wallets(&wallets, HashMap::new(), &bc.params)
+                .is_err()
+        );
+        assert!(tx.v_in[0].signature.is_empty());
+    }
+
+    #[test]
+    fn test_coinbase_limited_to_subsidy_plus_fees() {
+        let params = ChainParams::default();
+        let dir = TestDir::new("coinbase-value");
+        let wallet = Wallet::new();
+        let addr = wallet.get_address(&params);
+        let mut bc =
+            Blockchain::create_with_params(dir.join("blockchain"), &addr, ChainParams::for_tests())
+                .unwrap();
+        let genesis = bc.get_block(&bc.tip).unwrap();
+        let subsidy = block_subsidy(1);
+        let coinbase = |value: i32| {
+            let mut cbtx =
+                Transaction::new_coinbase(&addr, format!("claim {}", value), &params).unwrap();
+            cbtx.v_out[0].value = value;
+            cbtx.set_id().unwrap();
+            cbtx
+        };
+
+        let spend = spend_with_fee(&bc, &wallet, &genesis.transactions[0], 2);
+
+        let bits = bc.params.min_difficulty_bits;
+        let mine =
+            |bc: &Blockchain, txs| Block::new(txs, genesis.hash, 1, bits, &bc.params).unwrap();
+
+        let minting = mine(&bc, vec![coinbase(subsidy + 1)]);
+        assert!(bc.add_block(&minting).is_err());
+        // A transaction creating value is refused even when the coinbase
+        // only claims the subsidy
+        let creating = spend_with_fee(&bc, &wallet, &genesis.transactions[0], -5);
+        let err = bc
+            .add_block(&mine(&bc, vec![coinbase(subsidy), creating]))
+            .unwrap_err();
+        assert!(err.to_string().contains("pays out"), "{}", err);
+        let over = mine(&bc, vec![coinbase(subsidy + 3), spend.clone()]);
+        assert!(bc.add_block(&over).is_err());
+        assert!(
+            bc.mine_block(vec![coinbase(subsidy + 3), spend.clone()])
+                .is_err()
+        );
+        assert_eq!(bc.tip, genesis.hash);
+
+        let exact = mine(&bc, vec![coinbase(subsidy + 2), spend]);
+        bc.add_block(&exact).unwrap();
+        assert_eq!(bc.tip, exact.hash);
+        assert_eq!(bc.block_fees(&exact).unwrap(), Amount::new(2));
+
+        // Chains stored before the transaction index get it on opening
+        bc.db.drop_tree(TX_INDEX_TREE).unwrap();
+        drop(bc);
+        let bc = reopen(|| Blockchain::open(dir.join("blockchain")));
+        assert_eq!(bc.block_fees(&exact).unwrap(), Amount::new(2));
+    }
+
+    /// Signed transaction sending output 0 of `prev` back to its owner,
+    /// minus `fee`.
+    fn spend_with_fee(
+        bc: &Blockchain,
+        wallet: &Wallet,
+        prev: &Transaction,
+        fee: i32,
+    ) -> Transaction {
+        let params = ChainParams::default();
+        let mut tx = TxBuilder::new()
+            .spend_from(prev.hash_val, 0, wallet)
+            .pay(
+                prev.v_out[0].value - fee,
+                &wallet.get_address(&params),
+                &params,
+            )
+            .build();
+        bc.sign_transaction(&mut tx, &wallet.private_key).unwrap();
+        tx
+    }
+
+    #[test]
+    fn test_reorg_turns_confirmed_tx_back_to_pending() {
+        let params = ChainParams::default();
+        let dir = TestDir::new("tx-status");
+        let wallet = Wallet::new();
+        let addr = wallet.get_address(&params);
+        let mut bc =
+            Blockchain::create_with_params(dir.join("blockchain"), &addr, ChainParams::for_tests())
+                .unwrap();
+        let bits = bc.params.min_difficulty_bits;
+        let genesis = bc.get_block(&bc.tip).unwrap();
+        let mine_on = |bc: &Blockchain, prev: &Block, mut txs: Vec<Transaction>, tag: &str| {
+            let cbtx = Transaction::new_coinbase(&addr, tag.to_owned(), &params).unwrap();
+            txs.insert(0, cbtx);
+            Block::new(txs, prev.hash, prev.height + 1, bits, &bc.params).unwrap()
+        };
+
+        let spend = spend_with_fee(&bc, &wallet, &genesis.transactions[0], 0);
+        let mut wallets = Wallets::default();
+        wallets.record_transaction(&spend);
+        assert_eq!(
+            wallets.transaction_status(&spend.id, &bc).unwrap(),
+            TxStatus::Pending
+        );
+        let a1 = mine_on(&bc, &genesis, vec![spend.clone()], "a1");
+        bc.add_block(&a1).unwrap();
+        assert_eq!(
+            wallets.transaction_status(&spend.id, &bc).unwrap(),
+            TxStatus::Confirmed(1)
+        );
+
+        // A longer branch without the spend replaces a1
+        let b1 = mine_on(&bc, &genesis, vec![], "b1");
+        let b2 = mine_on(&bc, &b1, vec![], "b2");
+        bc.add_block(&b1).unwrap();
+        bc.add_block(&b2).unwrap();
+        assert_eq!(bc.tip, b2.hash);
+        assert_eq!(
+            wallets.transaction_status(&spend.id, &bc).unwrap(),
+            TxStatus::Pending
+        );
+
+        let double_spend = spend_with_fee(&bc, &wallet, &genesis.transactions[0], 1);
+        let b3 = mine_on(&bc, &b2, vec![double_spend], "b3");
+        bc.add_block(&b3).unwrap();
+        assert_eq!(
+            wallets.transaction_status(&spend.id, &bc).unwrap(),
+            TxStatus::Conflicted
+        );
+        assert!(wallets.transaction_status("unknown", &bc).is_err());
+    }
+
+    #[test]
+    fn test_block_checked_against_tip_without_adding() {
+        let params = ChainParams::default();
+        let dir = TestDir::new("testblock");
+        let wallet = Wallet::new();
+        let addr = wallet.get_address(&params);
+        let bc =
+            Blockchain::create_with_params(dir.join("blockchain"), &addr, ChainParams::for_tests())
+                .unwrap();
+        let bits = bc.params.min_difficulty_bits;
+        let genesis = bc.get_block(&bc.tip).unwrap();
+        let cbtx = Transaction::new_coinbase(&addr, "test".to_owned(), &params).unwrap();
+        let spend = spend_with_fee(&bc, &wallet, &genesis.transactions[0], 0);
+        let mine = |txs: Vec<Transaction>, height| {
+            Block::new(txs, genesis.hash, height, bits, &bc.params).unwrap()
+        };
+
+        let block = mine(vec![cbtx.clone(), spend.clone()], 1);
+        let hex = hex::encode(encode_to_vec(&block, standard()).unwrap());
+        bc.test_block(&Block::from_hex(&hex).unwrap()).unwrap();
+        assert_eq!(bc.tip, genesis.hash);
+        assert!(bc.get_block(&block.hash).is_err());
+
+        let err = bc.test_block(&mine(vec![cbtx.clone()], 2)).unwrap_err();
+        assert!(err.to_string().contains("expected 1"), "{}", err);
+
+        let mut tampered = block.clone();
+        tampered.transactions.pop();
+        let err = bc.test_block(&tampered).unwrap_err();
+        assert!(err.to_string().contains("Merkle root"), "{}", err);
+
+        let double_spend = spend_with_fee(&bc, &wallet, &genesis.transactions[0], 1);
+        let err = bc
+            .test_block(&mine(vec![cbtx, spend, double_spend.clone()], 1))
+            .unwrap_err();
+        assert!(err.to_string().contains(&double_spend.id), "{}", err);
+    }
+}
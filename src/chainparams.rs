@@ -0,0 +1,358 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::{Result, anyhow};
+use clap::ValueEnum;
+use ripemd::Ripemd160;
+use scrypt::Params;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::Hash;
+
+/// Hash function used for block proof-of-work.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PowAlgorithm {
+    /// Bitcoin style SHA256(SHA256(data))
+    #[default]
+    DoubleSha256,
+    Sha256,
+    /// Memory-hard scrypt (N=1024, r=1, p=1), as used by Litecoin
+    Scrypt,
+}
+
+impl PowAlgorithm {
+    pub fn hash(&self, data: &[u8]) -> Result<Hash> {
+        match self {
+            PowAlgorithm::DoubleSha256 => {
+                let first_hash = Sha256::digest(data);
+                Ok(Hash(Sha256::digest(first_hash).into()))
+            }
+            PowAlgorithm::Sha256 => Ok(Hash(Sha256::digest(data).into())),
+            PowAlgorithm::Scrypt => {
+                let params =
+                    Params::new(10, 1, 1, 32).map_err(|e| anyhow!("scrypt params: {}", e))?;
+                let mut out = Hash::default();
+                scrypt::scrypt(data, data, &params, &mut out.0)
+                    .map_err(|e| anyhow!("scrypt: {}", e))?;
+                Ok(out)
+            }
+        }
+    }
+}
+
+/// The network a node is on. Addresses carry it in their version byte, so
+/// coins can't be sent to an address meant for another network.
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Network {
+    #[default]
+    Mainnet,
+    Testnet,
+    /// Local testing, with testnet addresses as in Bitcoin
+    Regtest,
+}
+
+impl Network {
+    pub fn address_version(&self) -> u8 {
+        match self {
+            Network::Mainnet => 0x00,
+            Network::Testnet | Network::Regtest => 0x6f,
+        }
+    }
+
+    /// The network using `version`, testnet for the version it shares with
+    /// regtest.
+    pub fn from_address_version(version: u8) -> Option<Self> {
+        [Network::Mainnet, Network::Testnet]
+            .into_iter()
+            .find(|network| network.address_version() == version)
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Network::Mainnet => write!(f, "mainnet"),
+            Network::Testnet => write!(f, "testnet"),
+            Network::Regtest => write!(f, "regtest"),
+        }
+    }
+}
+
+/// Hash turning a public key into the payload of an address.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AddressHash {
+    /// Bitcoin style RIPEMD160(SHA256(pub_key)), 20 bytes
+    #[default]
+    Hash160,
+    /// Plain SHA256(pub_key), 32 bytes
+    Sha256,
+}
+
+impl AddressHash {
+    pub fn hash(&self, pub_key: &[u8]) -> Vec<u8> {
+        match self {
+            AddressHash::Hash160 => Ripemd160::digest(Sha256::digest(pub_key)).to_vec(),
+            AddressHash::Sha256 => Sha256::digest(pub_key).to_vec(),
+        }
+    }
+}
+
+/// Bytes of checksum at the end of a mainnet address
+pub const ADDRESS_CHECKSUM_LEN: usize = 4;
+
+pub const MAX_TX_INPUTS: usize = 1000;
+pub const MAX_TX_OUTPUTS: usize = 1000;
+/// Most bytes of data the input of a coinbase may carry
+pub const MAX_COINBASE_DATA: usize = 100;
+
+/// Default time between blocks, see `ChainParams::target_spacing_ms`
+pub const TARGET_SPACING_MS: u128 = 10_000;
+/// Hashes can't have more leading zero bits than this
+const MAX_BITS: u32 = 256;
+/// Starts the coinbase message of a genesis block that commits to the
+/// parameters of its chain, see `ChainParams::genesis_message`
+const GENESIS_PARAMS_PREFIX: &str = "params";
+/// Newest block format, see `ChainParams::chain_format`. Bump when the
+/// encoding of `Block`, or what its hash commits to, changes.
+pub const CHAIN_FORMAT_VERSION: u32 = 2;
+/// Format of chains whose genesis block doesn't name one, made when blocks
+/// committed to txids
+const LEGACY_CHAIN_FORMAT: u32 = 1;
+
+/// Consensus parameters every node on a network must agree on.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChainParams {
+    pub network: Network,
+    pub pow_algorithm: PowAlgorithm,
+    pub address_hash: AddressHash,
+    /// Byte every address starts with, `network.address_version()` unless
+    /// a fork wants its own prefix
+    pub address_version: u8,
+    /// Bytes of double SHA256 appended to an address to catch typos, at
+    /// most 32. A longer setting is treated as 32.
+    pub address_checksum_len: usize,
+    /// Inputs allowed in a non-coinbase transaction
+    pub max_tx_inputs: usize,
+    pub max_tx_outputs: usize,
+    /// Difficulty of the genesis block, in leading zero bits
+    pub initial_bits: u32,
+    /// Difficulty floor retargeting never goes below, so a hashrate drop
+    /// can't make the chain trivially rewritable
+    pub min_difficulty_bits: u32,
+    /// Blocks between difficulty adjustments
+    pub retarget_interval: i32,
+    /// Desired time between blocks, which retargeting steers towards
+    pub target_spacing_ms: u128,
+    /// Every hash meets the proof-of-work target, so a block is mined at
+    /// its first nonce and carries 0 bits. Only for trusted private
+    /// networks, anyone can rewrite such a chain for free.
+    pub pow_disabled: bool,
+    /// Block format of the chain, fixed by its genesis block. From 2 on,
+    /// blocks commit to the wtxids of their transactions, signatures
+    /// included, rather than to their txids.
+    pub chain_format: u32,
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        Self {
+            network: Network::default(),
+            pow_algorithm: PowAlgorithm::default(),
+            address_hash: AddressHash::default(),
+            address_version: Network::default().address_version(),
+            address_checksum_len: ADDRESS_CHECKSUM_LEN,
+            max_tx_inputs: MAX_TX_INPUTS,
+            max_tx_outputs: MAX_TX_OUTPUTS,
+            initial_bits: 16,
+            min_difficulty_bits: 8,
+            retarget_interval: 10,
+            target_spacing_ms: TARGET_SPACING_MS,
+            pow_disabled: false,
+            chain_format: CHAIN_FORMAT_VERSION,
+        }
+    }
+}
+
+impl ChainParams {
+    /// The default parameters on `network`, with its address version.
+    pub fn for_network(network: Network) -> Self {
+        Self {
+            network,
+            address_version: network.address_version(),
+            ..Self::default()
+        }
+    }
+
+    /// Parameters cheap enough to mine hundreds of blocks in a test: the
+    /// minimum difficulty from the genesis block on, never retargeted.
+    #[cfg(test)]
+    pub(crate) fn for_tests() -> Self {
+        let params = Self::default();
+        Self {
+            initial_bits: params.min_difficulty_bits,
+            retarget_interval: 0,
+            ..params
+        }
+    }
+
+    /// The coinbase message of a genesis block, committing its chain to
+    /// the parameters blocks are checked with. Nodes opening the chain, or
+    /// downloading it from a peer, read them back with
+    /// `with_genesis_message` instead of relying on their own.
+    pub fn genesis_message(&self) -> String {
+        format!(
+            "{} bits={} min_bits={} retarget={} spacing_ms={} pow_disabled={} format={}",
+            GENESIS_PARAMS_PREFIX,
+            self.initial_bits,
+            self.min_difficulty_bits,
+            self.retarget_interval,
+            self.target_spacing_ms,
+            self.pow_disabled,
+            self.chain_format
+        )
+    }
+
+    /// `self` with the parameters a genesis coinbase `message` written by
+    /// `genesis_message` commits to. A message committing to none, as on
+    /// chains created before they were committed, leaves `self` unchanged
+    /// but for the legacy chain format, as does a message without a format.
+    pub fn with_genesis_message(self, message: &str) -> Result<Self> {
+        let mut params = Self {
+            chain_format: LEGACY_CHAIN_FORMAT,
+            ..self
+        };
+        let Some(fields) = message.strip_prefix(GENESIS_PARAMS_PREFIX) else {
+            return Ok(params);
+        };
+        for field in fields.split_whitespace() {
+            let (key, value) = field
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Malformed genesis parameter '{}'", field))?;
+            match key {
+                "bits" => params.initial_bits = parse_genesis_field(field, value)?,
+                "min_bits" => params.min_difficulty_bits = parse_genesis_field(field, value)?,
+                "retarget" => params.retarget_interval = parse_genesis_field(field, value)?,
+                "spacing_ms" => params.target_spacing_ms = parse_genesis_field(field, value)?,
+                "pow_disabled" => params.pow_disabled = parse_genesis_field(field, value)?,
+                "format" => params.chain_format = parse_genesis_field(field, value)?,
+                _ => return Err(anyhow!("Unknown genesis parameter '{}'", key)),
+            }
+        }
+        if params.chain_format > CHAIN_FORMAT_VERSION {
+            return Err(anyhow!(
+                "Chain format {} is newer than the {} this node supports",
+                params.chain_format,
+                CHAIN_FORMAT_VERSION
+            ));
+        }
+        Ok(params)
+    }
+
+    /// Whether blocks commit to wtxids rather than txids, see
+    /// `chain_format`.
+    pub fn commits_to_wtxids(&self) -> bool {
+        self.chain_format >= 2
+    }
+
+    /// Difficulty of the genesis block, 0 without proof-of-work.
+    pub fn genesis_bits(&self) -> u32 {
+        if self.pow_disabled {
+            0
+        } else {
+            self.initial_bits
+        }
+    }
+
+    /// Computes the difficulty following a retarget window that took
+    /// `actual_ms` but should have taken `expected_ms`.
+    ///
+    /// Each bit doubles the work, so the adjustment is the rounded log2 of the
+    /// ratio, limited to a factor of 4 either way and clamped to
+    /// `min_difficulty_bits`. Always 0 without proof-of-work.
+    pub fn retarget(&self, prev_bits: u32, actual_ms: u128, expected_ms: u128) -> u32 {
+        if self.pow_disabled {
+            return 0;
+        }
+        if expected_ms == 0 {
+            return prev_bits.max(self.min_difficulty_bits);
+        }
+        let actual_ms = actual_ms.clamp(expected_ms / 4, expected_ms * 4).max(1);
+        let adjustment = (expected_ms as f64 / actual_ms as f64).log2().round() as i64;
+        let bits = (prev_bits as i64 + adjustment).clamp(0, MAX_BITS as i64) as u32;
+        bits.max(self.min_difficulty_bits)
+    }
+}
+
+fn parse_genesis_field<T: FromStr>(field: &str, value: &str) -> Result<T> {
+    value
+        .parse()
+        .map_err(|_| anyhow!("Invalid genesis parameter '{}'", field))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_default_address_hash_is_hash160() {
+        // RIPEMD160(SHA256("")), keeps existing addresses stable
+        assert_eq!(
+            hex::encode(AddressHash::default().hash(b"")),
+            "b472a266d0bd89c13706a4132ccfb16f7c3b9fcb"
+        );
+        assert_eq!(AddressHash::Sha256.hash(b"").len(), 32);
+    }
+
+    #[test]
+    fn test_retarget_clamps_to_floor() {
+        let params = ChainParams::default();
+        let floor = params.min_difficulty_bits;
+
+        // Blocks came ten times too slowly, the drop is limited to two bits
+        assert_eq!(params.retarget(floor + 5, 100_000, 10_000), floor + 3);
+        // ... and never goes below the floor
+        assert_eq!(params.retarget(floor + 1, 100_000, 10_000), floor);
+        assert_eq!(params.retarget(floor, 100_000, 10_000), floor);
+        // Blocks came four times too fast
+        assert_eq!(params.retarget(floor, 2_500, 10_000), floor + 2);
+    }
+
+    #[test]
+    fn test_genesis_message_round_trip() {
+        let params = ChainParams {
+            initial_bits: 12,
+            min_difficulty_bits: 4,
+            retarget_interval: 5,
+            target_spacing_ms: 250,
+            pow_disabled: true,
+            ..ChainParams::default()
+        };
+        let read = ChainParams::default()
+            .with_genesis_message(&params.genesis_message())
+            .unwrap();
+        assert_eq!(read.initial_bits, 12);
+        assert_eq!(read.min_difficulty_bits, 4);
+        assert_eq!(read.retarget_interval, 5);
+        assert_eq!(read.target_spacing_ms, 250);
+        assert!(read.pow_disabled);
+        assert!(read.commits_to_wtxids());
+
+        // Older genesis blocks carry a plain message, or no format, and
+        // commit to txids
+        let read = params.clone().with_genesis_message("The Times").unwrap();
+        assert_eq!(read.initial_bits, params.initial_bits);
+        assert!(!read.commits_to_wtxids());
+        let read = ChainParams::default()
+            .with_genesis_message("params bits=12")
+            .unwrap();
+        assert_eq!(read.chain_format, LEGACY_CHAIN_FORMAT);
+        for bad in [
+            "params bits=x",
+            "params bits",
+            "params size=1",
+            "params format=3",
+        ] {
+            assert!(params.clone().with_genesis_message(bad).is_err(), "{}", bad);
+        }
+    }
+}
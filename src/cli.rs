@@ -1,57 +1,532 @@
-use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
+use log::LevelFilter;
+
+use crate::{CoinSelectionStrategy, ConnectMode, DEFAULT_DATA_DIR, Network, OutPoint};
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 pub struct Cli {
     #[command(subcommand)]
     pub command: Commands,
+
+    /// Output format
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    pub format: OutputFormat,
+
+    /// Directory holding each network's data, in a subdirectory named
+    /// after the network
+    #[arg(long, global = true, default_value = DEFAULT_DATA_DIR)]
+    pub datadir: PathBuf,
+
+    /// Network whose data to use
+    #[arg(long, global = true, value_enum, default_value_t = Network::Mainnet)]
+    pub network: Network,
+
+    /// Ask the running node at this address instead of opening the
+    /// database, for getblockcount, getbalance, gettransaction, gettxout,
+    /// getmempoolfeehistogram, estimatesmartfee, getsyncstatus,
+    /// getnodeinfo, networkheight, getpeerinfo, flushmempool and replaytxs.
+    /// send --mine announces its block to it, defaulting to the central
+    /// node.
+    #[arg(long, global = true)]
+    pub node: Option<String>,
+
+    /// Log level for every module. RUST_LOG takes precedence over this and
+    /// the other log flags.
+    #[arg(long, global = true)]
+    pub log_level: Option<LevelFilter>,
+
+    /// More logging: -v for debug, -vv for trace. Ignored with --log-level.
+    #[arg(short = 'v', long = "verbosity", global = true, action = ArgAction::Count)]
+    pub verbosity: u8,
+
+    /// Log level for one module, as MODULE=LEVEL, e.g.
+    /// rs_blockchain::server=debug. Can be repeated.
+    #[arg(long = "log", global = true, value_name = "MODULE=LEVEL")]
+    pub log_modules: Vec<String>,
+}
+
+impl Cli {
+    /// The env_logger filter the log flags ask for.
+    pub fn log_filter(&self) -> String {
+        let level = self.log_level.unwrap_or(match self.verbosity {
+            0 => LevelFilter::Info,
+            1 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        });
+        let mut filter = level.to_string().to_lowercase();
+        for module in &self.log_modules {
+            filter.push(',');
+            filter.push_str(module);
+        }
+        filter
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,
+    Json,
 }
 
 #[derive(Subcommand)]
 pub enum Commands {
+    /// Check that the build works end to end: create wallets and a chain in
+    /// a temporary directory, mine, send and check the balances
+    #[command(name = "selftest")]
+    SelfTest,
     /// Create a blockchain and send genesis block reward to ADDRESS
     #[command(name = "createblockchain")]
     CreateBlockChain {
         #[arg(long)]
         address: String,
+
+        /// Accept blocks without proof-of-work, for a trusted private
+        /// network. The genesis block records it, so nodes downloading the
+        /// chain follow it too.
+        #[arg(long)]
+        no_pow: bool,
+    },
+    /// Print the height of the best block
+    #[command(name = "getblockcount")]
+    GetBlockCount,
+    /// Show how many bytes of mempool transactions pay at least each fee
+    /// rate, per 1000 bytes. Needs --node.
+    #[command(name = "getmempoolfeehistogram")]
+    GetMempoolFeeHistogram,
+    /// Show the fee rate, per 1000 bytes, a transaction needs to get into
+    /// the next block mined from the mempool. Needs --node.
+    #[command(name = "estimatesmartfee")]
+    EstimateSmartFee,
+    /// Show whether the node has caught up with its peers. Needs --node.
+    #[command(name = "getsyncstatus")]
+    GetSyncStatus,
+    /// Make a miner node mine its mempool into blocks right away and print
+    /// their hashes. Needs --node.
+    #[command(name = "flushmempool")]
+    FlushMempool,
+    /// Submit the hex transactions in FILE, one per line, to a node's
+    /// mempool as fast as possible and report how many were accepted.
+    /// Needs --node.
+    #[command(name = "replaytxs")]
+    ReplayTxs {
+        #[arg(long)]
+        file: String,
+        /// Transactions in flight at once, each on its own connection
+        #[arg(long, default_value_t = 4)]
+        concurrency: usize,
     },
+    /// Show the height, peers, mempool and orphan counters of a node.
+    /// Needs --node.
+    #[command(name = "getnodeinfo")]
+    GetNodeInfo,
+    /// Show our best height next to the highest one announced by a peer.
+    /// Needs --node.
+    #[command(name = "networkheight")]
+    NetworkHeight,
+    /// List the peers a node knows with their handshake state and
+    /// announced height. Needs --node.
+    #[command(name = "getpeerinfo")]
+    GetPeerInfo,
     /// Get balance of ADDRESS
     #[command(name = "getbalance")]
     GetBalance {
         #[arg(long)]
         address: String,
+        /// Only count outputs with at least this many confirmations
+        /// (the finality depth when given without a value)
+        #[arg(long, num_args = 0..=1)]
+        min_confirmations: Option<Option<i32>>,
+    },
+    /// Show the first block in which ADDRESS appears, and its balance
+    #[command(name = "addressfirstseen")]
+    AddressFirstSeen {
+        #[arg(long)]
+        address: String,
+    },
+    /// Show the version, public key hash and checksum of ADDRESS, even if
+    /// it is invalid
+    #[command(name = "decodeaddress")]
+    DecodeAddress {
+        #[arg(long)]
+        address: String,
+    },
+    /// Derive the address of a hex encoded SEC1 public key
+    #[command(name = "getaddressfrompubkey")]
+    GetAddressFromPubKey {
+        #[arg(long)]
+        pubkey: String,
+    },
+    /// Show a transaction and its confirmation status
+    #[command(name = "gettransaction")]
+    GetTransaction {
+        #[arg(long)]
+        id: String,
+    },
+    /// Show output VOUT of TXID if it is unspent. With --node, an output
+    /// spent by a mempool transaction counts as spent and outputs of
+    /// mempool transactions are found too.
+    #[command(name = "gettxout")]
+    GetTxOut {
+        #[arg(long)]
+        txid: String,
+        #[arg(long)]
+        vout: i32,
+        /// Ignore the mempool of the node
+        #[arg(long)]
+        confirmed_only: bool,
+    },
+    /// List every output of transaction ID and whether it is unspent, or
+    /// which transaction spent it. Finding spenders scans the chain from
+    /// the tip back to ID, so it is slow for old transactions.
+    #[command(name = "txoutputs")]
+    TxOutputs {
+        #[arg(long)]
+        id: String,
     },
-    /// Print all the blocks of the blockchain
+    /// Verify the signatures of transaction ID input by input
+    #[command(name = "verifytx")]
+    VerifyTx {
+        #[arg(long)]
+        id: String,
+    },
+    /// Estimate the network hashrate from the last BLOCKS blocks
+    #[command(name = "getnetworkhashps")]
+    GetNetworkHashPs {
+        #[arg(long, default_value_t = 120)]
+        blocks: usize,
+    },
+    /// Show the difficulty and block intervals of the last LAST blocks
+    #[command(name = "difficultyhistory")]
+    DifficultyHistory {
+        #[arg(long, default_value_t = 20)]
+        last: usize,
+    },
+    /// Print the stored bytes of block HASH as hex
+    #[command(name = "getrawblock")]
+    GetRawBlock {
+        #[arg(long)]
+        hash: String,
+        /// Also decode and print the block
+        #[arg(long)]
+        decode: bool,
+    },
+    /// Check a block given as the HEX of its bytes, as printed by
+    /// getrawblock, as the next block on our tip without adding it
+    #[command(name = "testblock")]
+    TestBlock {
+        #[arg(long)]
+        hex: String,
+    },
+    /// Recompute the Merkle root of block HASH and check it against the
+    /// root committed to by the block hash
+    #[command(name = "merkleroot")]
+    MerkleRoot {
+        #[arg(long)]
+        block: String,
+    },
+    /// Write the header of every block to OUT
+    #[command(name = "exportheaders")]
+    ExportHeaders {
+        #[arg(long)]
+        out: String,
+    },
+    /// Validate the proof-of-work chain of a header export
+    #[command(name = "importheaders")]
+    ImportHeaders {
+        #[arg(long = "in")]
+        input: String,
+    },
+    /// Write every block to OUT, one at a time
+    #[command(name = "exportchain")]
+    ExportChain {
+        #[arg(long)]
+        out: String,
+    },
+    /// Add the blocks of a chain export to the local chain and reindex the
+    /// UTXO set
+    #[command(name = "importchain")]
+    ImportChain {
+        #[arg(long = "in")]
+        input: String,
+    },
+    /// Show the block subsidy and fees at HEIGHT (defaults to the tip)
+    #[command(name = "getblocksubsidy")]
+    GetBlockSubsidy {
+        #[arg(long)]
+        height: Option<i32>,
+    },
+    /// Sum the coinbase rewards paid to ADDRESS, split into subsidy and fees
+    #[command(name = "minerrevenue")]
+    MinerRevenue {
+        #[arg(long)]
+        address: String,
+        /// Also list the reward of every block
+        #[arg(long)]
+        detailed: bool,
+    },
+    /// List the pubkey hashes holding unspent outputs, richest first
+    #[command(name = "richlist")]
+    RichList {
+        /// Only list the N richest
+        #[arg(long)]
+        top: Option<usize>,
+    },
+    /// Compare the UTXO set with the unspent outputs of the chain
+    #[command(name = "verifyutxoset")]
+    VerifyUtxoSet,
+    /// Rebuild the index of unspent outputs by address
+    #[command(name = "reindexaddrutxo")]
+    ReindexAddrUtxo,
+    /// Print all the blocks of the blockchain, one line each
     #[command(name = "printchain")]
-    PrintChain,
+    PrintChain {
+        /// Print every block in full, transactions included
+        #[arg(long)]
+        verbose: bool,
+    },
     /// Send AMOUNT of coins from FROM address to TO
     Send {
         /// Amount to send
         #[arg(long)]
         amount: i32,
-        /// Source wallet address
-        #[arg(long)]
-        from: String,
+        /// Source wallet address, repeat to spend from several addresses
+        #[arg(long, required = true)]
+        from: Vec<String>,
         /// Destination wallet address
         #[arg(long)]
         /// The from address mine immediately
         to: String,
         #[arg(long, default_value_t = false)]
         mine: bool,
+        /// Fee left to the miner
+        #[arg(long, default_value_t = 0)]
+        fee: i32,
+        /// Take the fee out of AMOUNT instead of on top of it, so exactly
+        /// AMOUNT leaves the source addresses
+        #[arg(long, default_value_t = false)]
+        subtract_fee_from_amount: bool,
+        /// Spend this txid:vout of a source address instead of selecting
+        /// coins, repeat to spend several
+        #[arg(long = "input")]
+        inputs: Vec<OutPoint>,
+    },
+    /// Commit to VALUE without revealing it: sends a coin from FROM back to
+    /// itself with SHA256(VALUE || salt) in a memo. Prints the txid and the
+    /// salt, both needed to reveal.
+    #[command(name = "commit")]
+    Commit {
+        #[arg(long)]
+        from: String,
+        /// Committed bytes, in hex
+        #[arg(long)]
+        value: String,
+        #[arg(long, default_value_t = 0)]
+        fee: i32,
+        #[arg(long, default_value_t = false)]
+        mine: bool,
+    },
+    /// Check that VALUE and SALT open the commitment of TXID
+    #[command(name = "reveal")]
+    Reveal {
+        #[arg(long)]
+        txid: String,
+        /// Committed bytes, in hex
+        #[arg(long)]
+        value: String,
+        /// Salt printed by commit, in hex
+        #[arg(long)]
+        salt: String,
+    },
+    /// Sign MESSAGE with the key of ADDRESS, which must be in the wallet
+    #[command(name = "signmessage")]
+    SignMessage {
+        #[arg(long)]
+        address: String,
+        #[arg(long)]
+        message: String,
+    },
+    /// Check a signature made by signmessage, from the address alone
+    #[command(name = "verifymessage")]
+    VerifyMessage {
+        #[arg(long)]
+        address: String,
+        #[arg(long)]
+        message: String,
+        #[arg(long)]
+        signature: String,
+    },
+    /// Build an unsigned payment to sign on another machine
+    #[command(name = "createpsbt")]
+    CreatePsbt {
+        #[arg(long)]
+        amount: i32,
+        /// Source address, repeat to spend from several addresses
+        #[arg(long, required = true)]
+        from: Vec<String>,
+        #[arg(long)]
+        to: String,
+        #[arg(long, default_value_t = 0)]
+        fee: i32,
+        #[arg(long, default_value_t = false)]
+        subtract_fee_from_amount: bool,
+    },
+    /// Sign the inputs of PSBT this wallet has keys for
+    #[command(name = "signpsbt")]
+    SignPsbt {
+        #[arg(long)]
+        psbt: String,
+    },
+    /// Check the signatures of PSBT and send the transaction
+    #[command(name = "finalizepsbt")]
+    FinalizePsbt {
+        #[arg(long)]
+        psbt: String,
+        #[arg(long, default_value_t = false)]
+        mine: bool,
+        /// Print the signed transaction as hex for broadcastraw instead of
+        /// sending it
+        #[arg(long, conflicts_with = "mine")]
+        raw: bool,
+    },
+    /// Verify a signed transaction from HEX, as printed by
+    /// finalizepsbt --raw, and send it to the central node
+    #[command(name = "broadcastraw")]
+    BroadcastRaw {
+        #[arg(long)]
+        hex: String,
+    },
+    /// Generate key pairs until an address starts with 1PREFIX and save
+    /// that wallet
+    #[command(name = "vanityaddress")]
+    VanityAddress {
+        #[arg(long)]
+        prefix: String,
+        /// Defaults to the number of CPUs
+        #[arg(long)]
+        threads: Option<usize>,
+        /// Give up after this many seconds
+        #[arg(long)]
+        timeout: Option<u64>,
     },
     /// Generates a new key-pair and saves it into the wallet file
     #[command(name = "createwallet")]
     CreateWallet,
+    /// Write an encrypted backup of every wallet to OUT
+    #[command(name = "backupwallet")]
+    BackupWallet {
+        #[arg(long)]
+        out: String,
+        #[arg(long)]
+        passphrase: String,
+    },
+    /// Import the wallets from an encrypted backup file
+    #[command(name = "restorewallet")]
+    RestoreWallet {
+        #[arg(long = "in")]
+        input: String,
+        #[arg(long)]
+        passphrase: String,
+        /// Replace wallets already stored under the same address
+        #[arg(long)]
+        force: bool,
+    },
     #[command(name = "listaddress")]
     ListAddress,
+    /// List the unspent outputs of ADDRESS with their labels
+    #[command(name = "listunspent")]
+    ListUnspent {
+        #[arg(long)]
+        address: String,
+    },
+    /// Show whether transaction TXID sent from this wallet is confirmed on
+    /// the current best chain, still pending, or conflicted
+    #[command(name = "gettxstatus")]
+    GetTxStatus {
+        #[arg(long)]
+        txid: String,
+    },
+    /// Attach a local label to an address or a TXID:VOUT output, or remove
+    /// it when no label is given. Labels stay in the wallet file.
+    #[command(name = "setlabel")]
+    SetLabel {
+        #[arg(long)]
+        target: String,
+        #[arg(long)]
+        label: Option<String>,
+    },
+    /// Sort the inputs and outputs of new transactions as in BIP69, or
+    /// stop with --off. Stored in the wallet file.
+    #[command(name = "setbip69")]
+    SetBip69 {
+        #[arg(long)]
+        off: bool,
+    },
+    /// Choose how new transactions pick the outputs they spend. Stored in
+    /// the wallet file.
+    #[command(name = "setcoinselection")]
+    SetCoinSelection {
+        #[arg(long, value_enum)]
+        strategy: CoinSelectionStrategy,
+    },
     #[command(name = "startnode")]
     StartNode {
         /// The port to listen on
-        #[arg(short, long)]
-        port: String,
+        #[arg(short, long, value_parser = clap::value_parser!(u16).range(1..))]
+        port: u16,
 
         /// Wallet address for mining (optional)
         #[arg(short, long)]
         miner_address: Option<String>,
+
+        /// Compare the UTXO set with the chain every this many seconds
+        #[arg(long)]
+        utxo_check_interval: Option<u64>,
+
+        /// Rebuild the UTXO set when the check finds a mismatch, instead of
+        /// only logging it
+        #[arg(long, requires = "utxo_check_interval")]
+        auto_reindex: bool,
+
+        /// Serve GET /health on this port, 200 once synced and 503 before,
+        /// and Prometheus metrics on GET /metrics
+        #[arg(long)]
+        health_port: Option<u16>,
+
+        /// Message to embed in the coinbase of mined blocks, at most 100
+        /// bytes
+        #[arg(long)]
+        coinbase_message: Option<String>,
+
+        /// Only dial out, only accept connections, or both
+        #[arg(long, value_enum, default_value_t = ConnectMode::Both)]
+        connect_mode: ConnectMode,
     },
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_log_filter() {
+        Cli::command().debug_assert();
+
+        let filter = |args: &[&str]| {
+            Cli::try_parse_from([&["rs-blockchain"], args, &["getblockcount"]].concat())
+                .unwrap()
+                .log_filter()
+        };
+        assert_eq!(filter(&[]), "info");
+        assert_eq!(filter(&["-v"]), "debug");
+        assert_eq!(filter(&["-vv"]), "trace");
+        assert_eq!(filter(&["-vv", "--log-level", "warn"]), "warn");
+        assert_eq!(
+            filter(&["--log", "rs_blockchain::server=debug", "--log", "sled=off"]),
+            "info,rs_blockchain::server=debug,sled=off"
+        );
+    }
+}
@@ -0,0 +1,87 @@
+use anyhow::{Result, anyhow};
+use p256::elliptic_curve::rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+
+use crate::{Blockchain, Transaction};
+
+pub const COMMITMENT_SALT_LEN: usize = 16;
+
+/// `SHA256(value || salt)`, what a commitment transaction puts in its memo.
+/// The salt keeps a small set of possible values from being guessed by
+/// hashing each of them.
+pub fn commitment_hash(value: &[u8], salt: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(value);
+    hasher.update(salt);
+    hasher.finalize().into()
+}
+
+pub fn new_commitment_salt() -> Vec<u8> {
+    let mut salt = vec![0u8; COMMITMENT_SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    salt
+}
+
+/// The commitment hash carried by the first 32 byte memo of `tx`.
+pub fn find_commitment(tx: &Transaction) -> Option<[u8; 32]> {
+    tx.v_out
+        .iter()
+        .filter_map(|out| out.memo())
+        .find_map(|memo| memo.try_into().ok())
+}
+
+/// Whether `value` and `salt` open the commitment made by transaction
+/// `tx_id` on `bc`. Fails if the transaction isn't on the chain or carries
+/// no commitment.
+pub fn verify_reveal(bc: &Blockchain, tx_id: &str, value: &[u8], salt: &[u8]) -> Result<bool> {
+    let tx = bc
+        .find_transaction(tx_id)
+        .ok_or_else(|| anyhow!("Transaction {} not found", tx_id))?;
+    let commitment =
+        find_commitment(&tx).ok_or_else(|| anyhow!("Transaction {} holds no commitment", tx_id))?;
+    Ok(commitment_hash(value, salt) == commitment)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{ChainParams, TXOutput, TransactionBuilder, UTXOSet, Wallets, testutil::TestDir};
+
+    #[test]
+    fn test_commit_and_reveal() {
+        let params = ChainParams::default();
+        let dir = TestDir::new("commitment");
+        let mut wallets = Wallets::default();
+        let addr = wallets.create_wallet(&params);
+        let bc = Blockchain::create_at(dir.join("blockchain"), &addr).unwrap();
+        let mut utxo_set = UTXOSet::with_path(bc, dir.join("utxos"));
+        utxo_set.reindex().unwrap();
+
+        let bid = b"bid 42";
+        let salt = new_commitment_salt();
+        let tx = TransactionBuilder::new()
+            .from(&addr)
+            .to(&addr)
+            .amount(1)
+            .memo(&commitment_hash(bid, &salt))
+            .build(&wallets, &utxo_set)
+            .unwrap();
+        assert!(utxo_set.bc.verify_transaction(&tx).unwrap());
+        let cbtx = Transaction::new_coinbase(&addr, String::new(), &params).unwrap();
+        let block = utxo_set.bc.mine_block(vec![cbtx, tx.clone()]).unwrap();
+        utxo_set.update(block).unwrap();
+
+        assert!(verify_reveal(&utxo_set.bc, &tx.id, bid, &salt).unwrap());
+        assert!(!verify_reveal(&utxo_set.bc, &tx.id, b"bid 43", &salt).unwrap());
+        assert!(!verify_reveal(&utxo_set.bc, &tx.id, bid, &new_commitment_salt()).unwrap());
+        let genesis_cb = &utxo_set.bc.iter().last().unwrap().transactions[0];
+        assert!(verify_reveal(&utxo_set.bc, &genesis_cb.id, bid, &salt).is_err());
+        // The memo holds nothing back from the balance: 1 to ourselves,
+        // 9 in change and the new coinbase
+        let pub_key_hash = crate::get_pub_key_hash(&addr, &params).unwrap();
+        let balance = utxo_set.find_utxo(&pub_key_hash).unwrap().total().unwrap();
+        assert_eq!(balance, crate::Amount::from_value(20).unwrap());
+
+        assert!(TXOutput::new_memo(&[0; crate::MAX_MEMO_LEN + 1]).is_err());
+    }
+}
@@ -0,0 +1,96 @@
+use std::path::{Path, PathBuf};
+
+use crate::Network;
+
+/// Base directory used when none is given
+pub const DEFAULT_DATA_DIR: &str = "db";
+
+/// Where the state of a node lives: `<base>/<network>/` holding the
+/// `blockchain`, `utxos`, `wallets` and `peers` stores. Every network gets
+/// its own directory, so nodes on different networks sharing a base never
+/// read each other's data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataDir {
+    root: PathBuf,
+}
+
+impl DataDir {
+    pub fn for_network(base: impl AsRef<Path>, network: Network) -> Self {
+        Self {
+            root: base.as_ref().join(network.to_string()),
+        }
+    }
+
+    /// The directory of this network, under the base.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn blockchain(&self) -> PathBuf {
+        self.root.join("blockchain")
+    }
+
+    pub fn utxos(&self) -> PathBuf {
+        self.root.join("utxos")
+    }
+
+    pub fn wallets(&self) -> PathBuf {
+        self.root.join("wallets")
+    }
+
+    /// Reserved for a store of known peers, which nodes don't keep yet.
+    pub fn peers(&self) -> PathBuf {
+        self.root.join("peers")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Blockchain, ChainParams, UTXOSet, Wallets, get_pub_key_hash, testutil::TestDir};
+
+    #[test]
+    fn test_networks_dont_share_data() {
+        let params = ChainParams::default();
+        let base = TestDir::new("datadir");
+        let mainnet = DataDir::for_network(&base, Network::Mainnet);
+        let regtest = DataDir::for_network(&base, Network::Regtest);
+        assert_ne!(mainnet.blockchain(), regtest.blockchain());
+        assert_ne!(mainnet.utxos(), regtest.utxos());
+        assert_ne!(mainnet.wallets(), regtest.wallets());
+        assert_ne!(mainnet.peers(), regtest.peers());
+
+        let mut ws = Wallets::open(mainnet.wallets()).unwrap();
+        let addr = ws.create_wallet(&params);
+        ws.save_at(mainnet.wallets()).unwrap();
+        let bc = Blockchain::create_at(mainnet.blockchain(), &addr).unwrap();
+        let mut utxo_set = UTXOSet::with_path(bc, mainnet.utxos());
+        utxo_set.reindex().unwrap();
+        drop((ws, utxo_set));
+
+        let regtest_bc = Blockchain::open(regtest.blockchain()).unwrap();
+        assert_eq!(regtest_bc.get_best_height().unwrap(), -1);
+        let regtest_utxos = UTXOSet::with_path(regtest_bc, regtest.utxos());
+        let pub_key_hash = get_pub_key_hash(&addr, &params).unwrap();
+        assert!(
+            regtest_utxos
+                .find_utxo(&pub_key_hash)
+                .unwrap()
+                .outputs
+                .is_empty()
+        );
+        assert!(
+            Wallets::open(regtest.wallets())
+                .unwrap()
+                .get_wallet(&addr)
+                .is_none()
+        );
+        drop(regtest_utxos);
+
+        // Mainnet still sees its own state
+        let bc = Blockchain::open(mainnet.blockchain()).unwrap();
+        assert_eq!(bc.get_best_height().unwrap(), 0);
+        let ws = Wallets::open(mainnet.wallets()).unwrap();
+        assert!(ws.get_wallet(&addr).is_some());
+    }
+}
@@ -0,0 +1,55 @@
+use std::fmt;
+
+/// Errors callers may want to tell apart, returned inside `anyhow::Error`.
+/// Use `downcast_ref` to match on them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockchainError {
+    /// No key for `address` in the local wallet file
+    WalletNotFound { address: String },
+    /// The source addresses hold `available`, less than the `needed` amount
+    /// plus fee
+    InsufficientFunds { needed: i32, available: i32 },
+    /// Like `InsufficientFunds`, but the sources also hold `immature`
+    /// coinbase outputs that can't be spent before height `spendable_at`
+    ImmatureFunds {
+        needed: i32,
+        available: i32,
+        immature: usize,
+        spendable_at: i32,
+    },
+    /// `address` is malformed or belongs to another network
+    InvalidAddress { address: String, reason: String },
+    /// The keys were found but an input couldn't be signed
+    SigningFailed { reason: String },
+}
+
+impl fmt::Display for BlockchainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockchainError::WalletNotFound { address } => {
+                write!(f, "No wallet for address {} in the wallet file", address)
+            }
+            BlockchainError::InsufficientFunds { needed, available } => {
+                write!(f, "Not enough funds: need {}, have {}", needed, available)
+            }
+            BlockchainError::ImmatureFunds {
+                needed,
+                available,
+                immature,
+                spendable_at,
+            } => write!(
+                f,
+                "Not enough funds: need {}, have {}. {} coins are immature coinbase and unavailable until height {}",
+                needed, available, immature, spendable_at
+            ),
+            BlockchainError::InvalidAddress { address, reason } => {
+                write!(f, "Invalid address {}: {}", address, reason)
+            }
+            BlockchainError::SigningFailed { reason } => {
+                write!(f, "Signing failed: {}", reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BlockchainError {}
@@ -14,3 +14,19 @@ mod utxoset;
 pub use utxoset::*;
 mod server;
 pub use server::*;
+mod chainparams;
+pub use chainparams::*;
+mod mempool;
+pub use mempool::*;
+mod psbt;
+pub use psbt::*;
+mod amount;
+pub use amount::*;
+mod error;
+pub use error::*;
+mod commitment;
+pub use commitment::*;
+mod datadir;
+pub use datadir::*;
+#[cfg(test)]
+mod testutil;
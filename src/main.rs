@@ -1,36 +1,949 @@
-use anyhow::Result;
+use std::{
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, anyhow};
 use clap::Parser;
 use env_logger::Env;
+use p256::ecdsa::VerifyingKey;
 use rs_blockchain::{
-    Blockchain, Cli, Commands, Server, ServerBuilder, Transaction, UTXOSet, Wallets,
-    get_pub_key_hash,
+    Amount, Block, Blockchain, BlockchainError, ChainParams, Cli, Commands, Config, DataDir,
+    Durability, Hash, Message, OutputFormat, OutputSpend, Psbt, Server, ServerBuilder, Transaction,
+    TransactionBuilder, TxOutInfo, TxStatus, TxVerdict, UTXOSet, WalletInsert, Wallets,
+    average_interval_ms, block_subsidy, commitment_hash, decode_address, find_vanity_wallet,
+    format_timestamp, get_pub_key_hash, hash_pub_key, new_commitment_salt, pub_key_hash_to_address,
+    validate_address, verify_message, verify_reveal,
 };
+use serde_json::json;
 
-fn main() -> Result<()> {
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+/// Prefixes expected to need more attempts than this get a warning
+const VANITY_WARN_ATTEMPTS: f64 = 1e7;
 
+fn main() -> Result<()> {
     let cli = Cli::parse();
+    let data_dir = DataDir::for_network(&cli.datadir, cli.network);
+    let params = ChainParams::for_network(cli.network);
+    env_logger::Builder::from_env(Env::default().default_filter_or(cli.log_filter())).init();
 
     match cli.command {
-        Commands::PrintChain => {
-            let bc = Blockchain::new()?;
-            bc.iter().for_each(|b| println!("{:?}", b));
+        Commands::PrintChain { verbose } => {
+            let bc = Blockchain::open_with_params(data_dir.blockchain(), params.clone())?;
+            for block in bc.iter() {
+                match cli.format {
+                    OutputFormat::Json => println!("{}", json!(block)),
+                    OutputFormat::Text if verbose => println!("{}", block),
+                    OutputFormat::Text => println!(
+                        "#{} {} prev {} {} nonce {} txs {} size {}",
+                        block.height,
+                        block.hash,
+                        block.prev_block_hash,
+                        format_timestamp(block.timestamp()),
+                        block.nonce,
+                        block.transactions.len(),
+                        block.size()?
+                    ),
+                }
+            }
         }
-        Commands::GetBalance { address } => {
-            let bc = Blockchain::new()?;
-            let mut balance = 0;
-            let pub_key_hash = get_pub_key_hash(&address);
-
-            let utxo_set = UTXOSet::new(bc);
+        Commands::GetBlockCount => {
+            let height = match &cli.node {
+                Some(node) => match Server::query(node, Message::GetHeight)? {
+                    Message::Height { best_height } => best_height,
+                    answer => return Err(anyhow!("Unexpected answer {:?}", answer)),
+                },
+                None => Blockchain::open_with_params(data_dir.blockchain(), params.clone())?
+                    .get_best_height()?,
+            };
+            println!("{}", height);
+        }
+        Commands::GetTxOut {
+            txid,
+            vout,
+            confirmed_only,
+        } => {
+            let output = match &cli.node {
+                Some(node) => match Server::query(
+                    node,
+                    Message::GetTxOut {
+                        tx_id: txid.clone(),
+                        v_out: vout,
+                        include_mempool: !confirmed_only,
+                    },
+                )? {
+                    Message::TxOut { output } => output,
+                    answer => return Err(anyhow!("Unexpected answer {:?}", answer)),
+                },
+                None => {
+                    let utxo_set = UTXOSet::with_path(
+                        Blockchain::open_with_params(data_dir.blockchain(), params.clone())?,
+                        data_dir.utxos(),
+                    );
+                    match utxo_set.get_tx_out(&txid, vout)? {
+                        Some(out) => {
+                            let best_height = utxo_set.bc.get_best_height()?;
+                            let origin = utxo_set
+                                .get_output_origin(&txid, vout)?
+                                .ok_or_else(|| anyhow!("Transaction {} not found", txid))?;
+                            Some(TxOutInfo {
+                                value: out.value,
+                                pub_key_hash: out.pub_key_hash,
+                                confirmations: best_height - origin.0 + 1,
+                                origin: Some(origin),
+                            })
+                        }
+                        None => None,
+                    }
+                }
+            };
+            match (cli.format, output) {
+                (OutputFormat::Json, output) => println!(
+                    "{}",
+                    json!(output.map(|out| json!({
+                        "value": out.value,
+                        "address": pub_key_hash_to_address(&out.pub_key_hash, &params),
+                        "confirmations": out.confirmations,
+                        "block_height": out.origin.map(|(height, _)| height),
+                        "block_hash": out.origin.map(|(_, hash)| hash.to_string()),
+                    })))
+                ),
+                (OutputFormat::Text, Some(out)) => {
+                    println!(
+                        "value: {}\naddress: {}\nconfirmations: {}",
+                        out.value,
+                        pub_key_hash_to_address(&out.pub_key_hash, &params),
+                        out.confirmations
+                    );
+                    match out.origin {
+                        Some((height, hash)) => println!("block: {} at height {}", hash, height),
+                        None => println!("block: none, in mempool"),
+                    }
+                }
+                (OutputFormat::Text, None) => {
+                    println!("Output {}:{} is spent or unknown", txid, vout)
+                }
+            }
+        }
+        Commands::TxOutputs { id } => {
+            let utxo_set = UTXOSet::with_path(
+                Blockchain::open_with_params(data_dir.blockchain(), params.clone())?,
+                data_dir.utxos(),
+            );
+            let outputs = utxo_set
+                .output_spends(&id)?
+                .ok_or_else(|| anyhow!("Transaction {} not found", id))?;
+            match cli.format {
+                OutputFormat::Json => println!(
+                    "{}",
+                    json!(
+                        outputs
+                            .iter()
+                            .enumerate()
+                            .map(|(vout, (out, spend))| json!({
+                                "vout": vout,
+                                "value": out.value,
+                                "address": pub_key_hash_to_address(&out.pub_key_hash, &params),
+                                "spent": *spend != OutputSpend::Unspent,
+                                "spent_by": match spend {
+                                    OutputSpend::Spent { by } => by.clone(),
+                                    OutputSpend::Unspent => None,
+                                },
+                            }))
+                            .collect::<Vec<_>>()
+                    )
+                ),
+                OutputFormat::Text => {
+                    for (vout, (out, spend)) in outputs.iter().enumerate() {
+                        let status = match spend {
+                            OutputSpend::Unspent => "unspent".to_string(),
+                            OutputSpend::Spent { by: Some(by) } => format!("spent by {}", by),
+                            OutputSpend::Spent { by: None } => {
+                                "spent by a transaction not on the chain".to_string()
+                            }
+                        };
+                        println!(
+                            "{}: {} to {} {}",
+                            vout,
+                            out.value,
+                            pub_key_hash_to_address(&out.pub_key_hash, &params),
+                            status
+                        );
+                    }
+                }
+            }
+        }
+        Commands::GetMempoolFeeHistogram => {
+            let node = cli
+                .node
+                .as_ref()
+                .ok_or_else(|| anyhow!("The mempool lives in the node, pass --node"))?;
+            let buckets = match Server::query(node, Message::GetFeeHistogram)? {
+                Message::FeeHistogram { buckets } => buckets,
+                answer => return Err(anyhow!("Unexpected answer {:?}", answer)),
+            };
+            match cli.format {
+                OutputFormat::Json => println!("{}", json!(buckets)),
+                OutputFormat::Text => {
+                    for (fee_rate, size) in buckets {
+                        println!(">= {:>5}/kB: {} bytes", fee_rate, size);
+                    }
+                }
+            }
+        }
+        Commands::EstimateSmartFee => {
+            let node = cli
+                .node
+                .as_ref()
+                .ok_or_else(|| anyhow!("The mempool lives in the node, pass --node"))?;
+            let fee_rate = match Server::query(node, Message::EstimateSmartFee)? {
+                Message::SmartFee { fee_rate } => fee_rate,
+                answer => return Err(anyhow!("Unexpected answer {:?}", answer)),
+            };
+            match cli.format {
+                OutputFormat::Json => println!("{}", json!({ "fee_rate": fee_rate })),
+                OutputFormat::Text => println!("{}/kB", fee_rate),
+            }
+        }
+        Commands::GetSyncStatus => {
+            let node = cli
+                .node
+                .as_ref()
+                .ok_or_else(|| anyhow!("Sync status comes from a node, pass --node"))?;
+            let status = match Server::query(node, Message::GetSyncStatus)? {
+                Message::SyncStatus { status } => status,
+                answer => return Err(anyhow!("Unexpected answer {:?}", answer)),
+            };
+            match cli.format {
+                OutputFormat::Json => println!("{}", json!(status)),
+                OutputFormat::Text => println!(
+                    "height {} of {}, {}",
+                    status.best_height,
+                    status.header_height,
+                    if status.is_synced {
+                        "synced"
+                    } else {
+                        "syncing"
+                    }
+                ),
+            }
+        }
+        Commands::FlushMempool => {
+            let node = cli
+                .node
+                .as_ref()
+                .ok_or_else(|| anyhow!("The mempool lives in the node, pass --node"))?;
+            let hashes = match Server::query(node, Message::MineNow)? {
+                Message::Mined { hashes } => hashes,
+                answer => return Err(anyhow!("Unexpected answer {:?}", answer)),
+            };
+            match cli.format {
+                OutputFormat::Json => println!(
+                    "{}",
+                    json!(hashes.iter().map(Hash::to_string).collect::<Vec<_>>())
+                ),
+                OutputFormat::Text if hashes.is_empty() => println!("Mempool is empty"),
+                OutputFormat::Text => {
+                    for hash in hashes {
+                        println!("{}", hash);
+                    }
+                }
+            }
+        }
+        Commands::ReplayTxs { file, concurrency } => {
+            let node = cli
+                .node
+                .as_ref()
+                .ok_or_else(|| anyhow!("The mempool lives in the node, pass --node"))?;
+            let txs = std::fs::read_to_string(&file)?
+                .lines()
+                .enumerate()
+                .filter(|(_, line)| !line.trim().is_empty())
+                .map(|(i, line)| {
+                    Transaction::from_hex(line.trim())
+                        .map_err(|e| anyhow!("{}:{}: {}", file, i + 1, e))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let report = replay_txs(node, txs, concurrency.max(1));
+            let secs = report.elapsed.as_secs_f64();
+            let total = report.accepted + report.orphaned + report.rejected + report.failed;
+            let rate = if secs > 0.0 { total as f64 / secs } else { 0.0 };
+            match cli.format {
+                OutputFormat::Json => println!(
+                    "{}",
+                    json!({
+                        "total": total,
+                        "accepted": report.accepted,
+                        "orphaned": report.orphaned,
+                        "rejected": report.rejected,
+                        "failed": report.failed,
+                        "seconds": secs,
+                        "tx_per_sec": rate,
+                        "accepted_per_second": report.accepted_per_second,
+                    })
+                ),
+                OutputFormat::Text => {
+                    println!(
+                        "Replayed {} transactions in {:.2}s ({:.1} tx/s): {} accepted, {} orphaned, {} rejected, {} failed",
+                        total,
+                        secs,
+                        rate,
+                        report.accepted,
+                        report.orphaned,
+                        report.rejected,
+                        report.failed
+                    );
+                    for (second, accepted) in report.accepted_per_second.iter().enumerate() {
+                        println!("{:>4}s: {} accepted", second, accepted);
+                    }
+                }
+            }
+        }
+        Commands::GetNodeInfo => {
+            let node = cli
+                .node
+                .as_ref()
+                .ok_or_else(|| anyhow!("Node info comes from a node, pass --node"))?;
+            let info = match Server::query(node, Message::GetNodeInfo)? {
+                Message::NodeInfo { info } => info,
+                answer => return Err(anyhow!("Unexpected answer {:?}", answer)),
+            };
+            match cli.format {
+                OutputFormat::Json => println!("{}", json!(info)),
+                OutputFormat::Text => {
+                    println!("Best height: {}", info.best_height);
+                    println!("Network height: {}", info.network_height);
+                    println!("Known nodes: {}", info.known_nodes);
+                    println!("Mempool transactions: {}", info.mempool_txs);
+                    println!("Orphan transactions: {}", info.orphan_txs);
+                    println!(
+                        "Orphans since start: {} received, {} connected, {} expired",
+                        info.orphans.received, info.orphans.connected, info.orphans.expired
+                    );
+                }
+            }
+        }
+        Commands::NetworkHeight => {
+            let node = cli
+                .node
+                .as_ref()
+                .ok_or_else(|| anyhow!("The network height comes from a node, pass --node"))?;
+            let (best_height, network_height) =
+                match Server::query(node, Message::GetNetworkHeight)? {
+                    Message::NetworkHeight {
+                        best_height,
+                        network_height,
+                    } => (best_height, network_height),
+                    answer => return Err(anyhow!("Unexpected answer {:?}", answer)),
+                };
+            match cli.format {
+                OutputFormat::Json => println!(
+                    "{}",
+                    json!({
+                        "best_height": best_height,
+                        "network_height": network_height,
+                        "lag": network_height - best_height,
+                    })
+                ),
+                OutputFormat::Text => println!(
+                    "height {}, network {}, {} behind",
+                    best_height,
+                    network_height,
+                    (network_height - best_height).max(0)
+                ),
+            }
+        }
+        Commands::GetPeerInfo => {
+            let node = cli
+                .node
+                .as_ref()
+                .ok_or_else(|| anyhow!("Peers are known to a node, pass --node"))?;
+            let peers = match Server::query(node, Message::GetPeerInfo)? {
+                Message::PeerInfo { peers } => peers,
+                answer => return Err(anyhow!("Unexpected answer {:?}", answer)),
+            };
+            match cli.format {
+                OutputFormat::Json => println!("{}", json!(peers)),
+                OutputFormat::Text => {
+                    for peer in peers {
+                        println!(
+                            "{} height {} {}",
+                            peer.addr,
+                            peer.best_height
+                                .map_or_else(|| "unknown".to_string(), |h| h.to_string()),
+                            if peer.handshake_complete {
+                                "connected"
+                            } else {
+                                "connecting"
+                            }
+                        );
+                    }
+                }
+            }
+        }
+        Commands::GetBalance {
+            address,
+            min_confirmations,
+        } => {
+            let (balance, immature) = match &cli.node {
+                Some(_) if min_confirmations.is_some() => {
+                    return Err(anyhow!("--min-confirmations isn't supported with --node"));
+                }
+                Some(node) => match Server::query(
+                    node,
+                    Message::GetBalance {
+                        address: address.clone(),
+                    },
+                )? {
+                    Message::Balance {
+                        balance, immature, ..
+                    } => (balance, immature),
+                    answer => return Err(anyhow!("Unexpected answer {:?}", answer)),
+                },
+                None => {
+                    let bc = Blockchain::open_with_params(data_dir.blockchain(), params.clone())?;
+                    let pub_key_hash = get_pub_key_hash(&address, &params)?;
+                    let min_confirmations = match min_confirmations {
+                        Some(depth) => depth.unwrap_or(Config::default().finality_depth()),
+                        None => 0,
+                    };
 
-            for out in utxo_set.find_utxo(&pub_key_hash)?.outputs {
-                balance += out.value;
+                    let utxo_set = spending_utxo_set(bc, &data_dir);
+                    let (spendable, immature) =
+                        utxo_set.find_utxo_by_maturity(&pub_key_hash, min_confirmations)?;
+                    let immature = immature.total()?;
+                    let balance = spendable
+                        .total()?
+                        .checked_add(immature)
+                        .ok_or_else(|| anyhow!("Balance overflow"))?;
+                    (balance, immature)
+                }
+            };
+            let spendable = balance
+                .checked_sub(immature)
+                .ok_or_else(|| anyhow!("Immature balance above the total"))?;
+            println!("Balance of '{}': {}", address, balance);
+            println!("spendable: {}, immature: {}\n", spendable, immature)
+        }
+        Commands::DecodeAddress { address } => {
+            let info = decode_address(&address, &params)?;
+            match cli.format {
+                OutputFormat::Json => println!(
+                    "{}",
+                    json!({
+                        "address": address,
+                        "version": info.version,
+                        "pub_key_hash": hex::encode(&info.pub_key_hash),
+                        "checksum": hex::encode(&info.checksum),
+                        "expected_checksum": hex::encode(&info.expected_checksum),
+                        "checksum_valid": info.checksum_valid(),
+                    })
+                ),
+                OutputFormat::Text => {
+                    println!("Version:           0x{:02x}", info.version);
+                    println!(
+                        "Public key hash:   {} ({} bytes)",
+                        hex::encode(&info.pub_key_hash),
+                        info.pub_key_hash.len()
+                    );
+                    println!("Checksum:          {}", hex::encode(&info.checksum));
+                    println!(
+                        "Expected checksum: {}",
+                        hex::encode(&info.expected_checksum)
+                    );
+                    println!(
+                        "Checksum valid:    {}",
+                        if info.checksum_valid() { "yes" } else { "no" }
+                    );
+                }
+            }
+        }
+        Commands::GetAddressFromPubKey { pubkey } => {
+            let pub_key = hex::decode(&pubkey).map_err(|e| anyhow!("Invalid hex: {}", e))?;
+            VerifyingKey::from_sec1_bytes(&pub_key)
+                .map_err(|_| anyhow!("Not a valid SEC1 encoded P-256 public key"))?;
+            let pub_key_hash = hash_pub_key(&pub_key, &params);
+            let address = pub_key_hash_to_address(&pub_key_hash, &params);
+            match cli.format {
+                OutputFormat::Json => println!(
+                    "{}",
+                    json!({
+                        "pubkey": pubkey,
+                        "pub_key_hash": hex::encode(&pub_key_hash),
+                        "address": address,
+                    })
+                ),
+                OutputFormat::Text => println!("{}", address),
+            }
+        }
+        Commands::AddressFirstSeen { address } => {
+            let bc = Blockchain::open_with_params(data_dir.blockchain(), params.clone())?;
+            let pub_key_hash = get_pub_key_hash(&address, &params)?;
+            let first_seen = bc.address_first_seen(&pub_key_hash);
+            let utxo_set = UTXOSet::with_path(bc, data_dir.utxos());
+            let balance = utxo_set.find_utxo(&pub_key_hash)?.total()?;
+            match cli.format {
+                OutputFormat::Json => println!(
+                    "{}",
+                    json!({
+                        "address": address,
+                        "first_seen_height": first_seen.as_ref().map(|b| b.height),
+                        "first_seen_timestamp": first_seen.as_ref().map(|b| b.timestamp() as u64),
+                        "balance": balance,
+                    })
+                ),
+                OutputFormat::Text => match first_seen {
+                    Some(block) => println!(
+                        "'{}' first seen at height {} ({}), balance {}",
+                        address,
+                        block.height,
+                        format_timestamp(block.timestamp()),
+                        balance
+                    ),
+                    None => println!("'{}' has never been seen on chain", address),
+                },
             }
-            println!("Balance of '{}': {}\n", address, balance)
         }
-        Commands::CreateBlockChain { address } => {
-            let bc = Blockchain::create(&address)?;
-            let utxo_set = UTXOSet::new(bc);
+        Commands::GetTransaction { id } => {
+            let (tx, height, best_height) = match &cli.node {
+                Some(node) => match Server::query(node, Message::GetTx { id: id.parse()? })? {
+                    Message::TxInfo {
+                        transaction: Some(tx),
+                        block_height,
+                        best_height,
+                    } => (tx, block_height, best_height),
+                    Message::TxInfo { .. } => {
+                        return Err(anyhow!("Transaction {} not found", id));
+                    }
+                    answer => return Err(anyhow!("Unexpected answer {:?}", answer)),
+                },
+                None => {
+                    let bc = Blockchain::open_with_params(data_dir.blockchain(), params.clone())?;
+                    let (tx, height) = bc
+                        .find_transaction_with_height(&id)
+                        .ok_or_else(|| anyhow!("Transaction {} not found", id))?;
+                    (tx, Some(height), bc.get_best_height()?)
+                }
+            };
+            // Mempool transactions have no confirmations yet
+            let confirmations = height.map_or(0, |height| best_height - height + 1);
+            let is_final = confirmations >= Config::default().finality_depth();
+            match cli.format {
+                OutputFormat::Json => println!(
+                    "{}",
+                    json!({
+                        "txid": tx.id,
+                        "block_height": height,
+                        "confirmations": confirmations,
+                        "final": is_final,
+                        "coinbase_message": tx.coinbase_message(),
+                        "transaction": tx,
+                    })
+                ),
+                OutputFormat::Text => {
+                    println!("{:?}", tx);
+                    if let Some(message) = tx.coinbase_message() {
+                        println!("Coinbase message: {}", message);
+                    }
+                    match height {
+                        Some(height) => println!("Block height: {}", height),
+                        None => println!("Block height: unconfirmed, in the mempool"),
+                    }
+                    println!("Confirmations: {}", confirmations);
+                    println!("Final: {}", is_final);
+                }
+            }
+        }
+        Commands::GetRawBlock { hash, decode } => {
+            let hash: Hash = hash.parse()?;
+            let bc = Blockchain::open_with_params(data_dir.blockchain(), params.clone())?;
+            let raw = bc.get_raw_block(&hash)?;
+            let block = if decode {
+                Some(bc.get_block(&hash)?)
+            } else {
+                None
+            };
+            match cli.format {
+                OutputFormat::Json => println!(
+                    "{}",
+                    json!({
+                        "hash": hash.to_string(),
+                        "size": raw.len(),
+                        "raw": hex::encode(&raw),
+                        "block": block,
+                    })
+                ),
+                OutputFormat::Text => {
+                    println!("{}", hex::encode(&raw));
+                    if let Some(block) = block {
+                        println!("{}", block);
+                    }
+                }
+            }
+        }
+        Commands::TestBlock { hex } => {
+            let block = Block::from_hex(&hex)?;
+            let bc = Blockchain::open_with_params(data_dir.blockchain(), params.clone())?;
+            let result = bc.test_block(&block);
+            match cli.format {
+                OutputFormat::Json => println!(
+                    "{}",
+                    json!({
+                        "block": block.hash.to_string(),
+                        "tip": bc.tip.to_string(),
+                        "valid": result.is_ok(),
+                        "error": result.as_ref().err().map(|e| e.to_string()),
+                    })
+                ),
+                OutputFormat::Text => match &result {
+                    Ok(()) => println!("Block {} is valid on top of {}", block.hash, bc.tip),
+                    Err(e) => println!("INVALID: {}", e),
+                },
+            }
+            if result.is_err() {
+                std::process::exit(1);
+            }
+        }
+        Commands::MerkleRoot { block } => {
+            let bc = Blockchain::open_with_params(data_dir.blockchain(), params.clone())?;
+            let block = bc.get_block(&block.parse()?)?;
+            let root = block.merkle_root(&bc.params)?;
+            // Blocks don't store their root, the hash commits to it instead
+            let matches = block.header(&bc.params)?.validate(&bc.params)?;
+            match cli.format {
+                OutputFormat::Json => println!(
+                    "{}",
+                    json!({
+                        "block": block.hash.to_string(),
+                        "transactions": block.transactions.len(),
+                        "merkle_root": root.to_string(),
+                        "matches_block_hash": matches,
+                    })
+                ),
+                OutputFormat::Text => {
+                    println!("block:        {}", block.hash);
+                    println!("transactions: {}", block.transactions.len());
+                    println!("merkle root:  {}", root);
+                    match block.transactions.as_slice() {
+                        [] => println!("The block has no transactions, the root is zero"),
+                        [_] if bc.params.commits_to_wtxids() => {
+                            println!("The root is the wtxid of the only transaction")
+                        }
+                        [_] => println!("The root is the txid of the only transaction"),
+                        _ => {}
+                    }
+                    if matches {
+                        println!("The root matches the block hash");
+                    } else {
+                        println!("MISMATCH: the block hash commits to a different root");
+                    }
+                }
+            }
+        }
+        Commands::VerifyTx { id } => {
+            // Only confirmed transactions are visible here, the mempool lives
+            // in the running node.
+            let bc = Blockchain::open_with_params(data_dir.blockchain(), params.clone())?;
+            let tx = bc
+                .find_transaction(&id)
+                .ok_or_else(|| anyhow!("Transaction {} not found", id))?;
+            let coinbase = tx.is_coinbase();
+            let inputs = if coinbase {
+                vec![]
+            } else {
+                bc.verify_transaction_report(&tx)
+            };
+            let valid = inputs
+                .iter()
+                .all(|input| input.signature_valid && input.key_matches);
+            match cli.format {
+                OutputFormat::Json => println!(
+                    "{}",
+                    json!({ "txid": tx.id, "coinbase": coinbase, "valid": valid, "inputs": inputs })
+                ),
+                OutputFormat::Text => {
+                    if coinbase {
+                        println!("{} is a coinbase transaction (always valid)", tx.id);
+                    }
+                    for input in &inputs {
+                        println!(
+                            "input {} ({}:{}): signature {} ({} bytes), pub key hash {} {} expected {}",
+                            input.index,
+                            input.tx_id,
+                            input.v_out,
+                            if input.signature_valid {
+                                "valid"
+                            } else {
+                                "INVALID"
+                            },
+                            input.signature_len,
+                            input.pub_key_hash,
+                            if input.key_matches {
+                                "matches"
+                            } else {
+                                "DOES NOT match"
+                            },
+                            input.expected_pub_key_hash
+                        );
+                    }
+                    println!(
+                        "Transaction {}: {}",
+                        tx.id,
+                        if valid { "valid" } else { "INVALID" }
+                    );
+                }
+            }
+        }
+        Commands::GetNetworkHashPs { blocks } => {
+            let bc = Blockchain::open_with_params(data_dir.blockchain(), params.clone())?;
+            let hashps = bc.network_hashps(blocks);
+            match cli.format {
+                OutputFormat::Json => println!("{}", json!({ "blocks": blocks, "hashps": hashps })),
+                OutputFormat::Text => println!("Network hashrate: {:.2} H/s", hashps),
+            }
+        }
+        Commands::DifficultyHistory { last } => {
+            let bc = Blockchain::open_with_params(data_dir.blockchain(), params.clone())?;
+            let history = bc.difficulty_history(last);
+            let average = average_interval_ms(&history);
+            match cli.format {
+                OutputFormat::Json => println!(
+                    "{}",
+                    json!({
+                        "blocks": history.iter().map(|p| json!({
+                            "height": p.height,
+                            "timestamp": p.timestamp as u64,
+                            "bits": p.bits,
+                            "interval_ms": p.interval_ms.map(|i| i as u64),
+                        })).collect::<Vec<_>>(),
+                        "average_interval_ms": average,
+                        "target_interval_ms": bc.params.target_spacing_ms as u64,
+                    })
+                ),
+                OutputFormat::Text => {
+                    for p in &history {
+                        let interval = match p.interval_ms {
+                            Some(ms) => format!("{:.1}s", ms as f64 / 1000.0),
+                            None => "-".to_owned(),
+                        };
+                        println!(
+                            "#{} {} bits {} interval {}",
+                            p.height,
+                            format_timestamp(p.timestamp),
+                            p.bits,
+                            interval
+                        );
+                    }
+                    match average {
+                        Some(ms) => println!(
+                            "Average block time: {:.1}s (target {:.1}s)",
+                            ms / 1000.0,
+                            bc.params.target_spacing_ms as f64 / 1000.0
+                        ),
+                        None => println!("Average block time: unknown"),
+                    }
+                }
+            }
+        }
+        Commands::ExportHeaders { out } => {
+            let bc = Blockchain::open_with_params(data_dir.blockchain(), params.clone())?;
+            let count = bc.export_headers(&out)?;
+            println!("Exported {} headers to {}", count, out);
+        }
+        Commands::ExportChain { out } => {
+            let bc = Blockchain::open_with_params(data_dir.blockchain(), params.clone())?;
+            let count = bc.export_chain(&out)?;
+            println!("Exported {} blocks to {}", count, out);
+        }
+        Commands::ImportChain { input } => {
+            let mut bc = Blockchain::open_with_params(data_dir.blockchain(), params.clone())?;
+            bc.durability = Durability::Batch;
+            let count = bc.import_chain(&input)?;
+            let mut utxo_set = UTXOSet::with_path(bc, data_dir.utxos());
+            utxo_set.reindex()?;
+            println!(
+                "Imported {} blocks, tip {} at height {}",
+                count,
+                utxo_set.bc.tip,
+                utxo_set.bc.get_best_height()?
+            );
+        }
+        Commands::ImportHeaders { input } => {
+            let headers = Blockchain::import_headers(&input, &params)?;
+            match headers.last() {
+                Some(tip) => println!(
+                    "Valid header chain of {} blocks, tip {} at height {}",
+                    headers.len(),
+                    tip.hash,
+                    tip.height
+                ),
+                None => println!("No headers in {}", input),
+            }
+        }
+        Commands::MinerRevenue { address, detailed } => {
+            let bc = Blockchain::open_with_params(data_dir.blockchain(), params.clone())?;
+            let revenue = bc.miner_revenue(&get_pub_key_hash(&address, &params)?)?;
+            let subsidy = revenue
+                .iter()
+                .map(|r| r.subsidy)
+                .sum::<Option<Amount>>()
+                .ok_or_else(|| anyhow!("Amount overflow"))?;
+            let fees = revenue
+                .iter()
+                .map(|r| r.fees)
+                .sum::<Option<Amount>>()
+                .ok_or_else(|| anyhow!("Amount overflow"))?;
+            let total = subsidy
+                .checked_add(fees)
+                .ok_or_else(|| anyhow!("Amount overflow"))?;
+            match cli.format {
+                OutputFormat::Json => {
+                    let mut out = json!({
+                        "address": address,
+                        "blocks": revenue.len(),
+                        "total": total,
+                        "subsidy": subsidy,
+                        "fees": fees,
+                    });
+                    if detailed {
+                        out["detailed"] = revenue
+                            .iter()
+                            .map(|r| {
+                                json!({
+                                    "height": r.height,
+                                    "hash": r.hash.to_string(),
+                                    "subsidy": r.subsidy,
+                                    "fees": r.fees,
+                                })
+                            })
+                            .collect();
+                    }
+                    println!("{}", out);
+                }
+                OutputFormat::Text => {
+                    if detailed {
+                        for r in &revenue {
+                            println!(
+                                "{:>6} {} subsidy {} fees {}",
+                                r.height, r.hash, r.subsidy, r.fees
+                            );
+                        }
+                    }
+                    println!(
+                        "'{}' mined {} blocks for {} (subsidy {}, fees {})",
+                        address,
+                        revenue.len(),
+                        total,
+                        subsidy,
+                        fees
+                    );
+                }
+            }
+        }
+        Commands::GetBlockSubsidy { height } => {
+            let bc = Blockchain::open_with_params(data_dir.blockchain(), params.clone())?;
+            let height = match height {
+                Some(h) => h,
+                None => bc.get_best_height()?,
+            };
+            let subsidy = block_subsidy(height);
+            let fees = match bc.get_block_by_height(height) {
+                Some(block) => Some(bc.block_fees(&block)?),
+                None => None,
+            };
+            match cli.format {
+                OutputFormat::Json => println!(
+                    "{}",
+                    json!({ "height": height, "subsidy": subsidy, "fees": fees })
+                ),
+                OutputFormat::Text => {
+                    println!("Subsidy at height {}: {}", height, subsidy);
+                    if let Some(fees) = fees {
+                        println!("Fees collected: {}", fees);
+                    }
+                }
+            }
+        }
+        Commands::RichList { top } => {
+            let utxo_set = UTXOSet::with_path(
+                Blockchain::open_with_params(data_dir.blockchain(), params.clone())?,
+                data_dir.utxos(),
+            );
+            let mut list = utxo_set.rich_list()?;
+            if let Some(top) = top {
+                list.truncate(top);
+            }
+            match cli.format {
+                OutputFormat::Json => println!(
+                    "{}",
+                    json!(
+                        list.iter()
+                            .map(|(pkh, balance)| json!({
+                                "pub_key_hash": hex::encode(pkh),
+                                "balance": balance,
+                            }))
+                            .collect::<Vec<_>>()
+                    )
+                ),
+                OutputFormat::Text => {
+                    for (rank, (pkh, balance)) in list.iter().enumerate() {
+                        println!("{:>4} {} {}", rank + 1, hex::encode(pkh), balance);
+                    }
+                }
+            }
+        }
+        Commands::VerifyUtxoSet => {
+            let utxo_set = UTXOSet::with_path(
+                Blockchain::open_with_params(data_dir.blockchain(), params.clone())?,
+                data_dir.utxos(),
+            );
+            let mismatch = utxo_set.verify_against_chain()?;
+            if mismatch.is_empty() {
+                println!("The UTXO set matches the chain.");
+            } else {
+                println!("The UTXO set doesn't match the chain: {}", mismatch);
+                for tx_id in &mismatch.missing {
+                    println!("missing     {}", tx_id);
+                }
+                for tx_id in &mismatch.unexpected {
+                    println!("unexpected  {}", tx_id);
+                }
+                for tx_id in &mismatch.different {
+                    println!("different   {}", tx_id);
+                }
+            }
+        }
+        Commands::ReindexAddrUtxo => {
+            let utxo_set = UTXOSet::with_path(
+                Blockchain::open_with_params(data_dir.blockchain(), params.clone())?,
+                data_dir.utxos(),
+            );
+            utxo_set.reindex_addresses()?;
+            println!("Done! The address index has been rebuilt.");
+        }
+        Commands::SelfTest => {
+            let dir =
+                std::env::temp_dir().join(format!("rs-blockchain-selftest-{}", std::process::id()));
+            let result = run_selftest(&dir, &params);
+            std::fs::remove_dir_all(&dir).ok();
+            match result {
+                Ok(()) => println!("Self-test passed"),
+                Err(_) => {
+                    println!("Self-test failed");
+                    std::process::exit(1);
+                }
+            }
+        }
+        Commands::CreateBlockChain { address, no_pow } => {
+            let params = ChainParams {
+                pow_disabled: no_pow,
+                ..params.clone()
+            };
+            let bc = Blockchain::create_with_params(data_dir.blockchain(), &address, params)?;
+            let mut utxo_set = UTXOSet::with_path(bc, data_dir.utxos());
             utxo_set.reindex()?;
         }
         Commands::Send {
@@ -38,41 +951,420 @@ fn main() -> Result<()> {
             from,
             to,
             mine,
+            fee,
+            subtract_fee_from_amount,
+            inputs,
         } => {
-            let bc = Blockchain::new()?;
-            let mut utxo_set = UTXOSet::new(bc);
-            let tx = Transaction::new_utxo(&from, &to, amount, &utxo_set)?;
-            let cb_tx = Transaction::new_coinbase(&from, "".to_owned())?;
+            let bc = Blockchain::open_with_params(data_dir.blockchain(), params.clone())?;
+            let mut utxo_set = spending_utxo_set(bc, &data_dir);
+            let wallets = Wallets::open(data_dir.wallets())?;
+            let builder = inputs
+                .into_iter()
+                .fold(TransactionBuilder::new(), |builder, outpoint| {
+                    builder.spend(outpoint)
+                })
+                .from_all(&from)
+                .to(&to)
+                .amount(amount)
+                .fee(fee)
+                .subtract_fee_from_amount(subtract_fee_from_amount)
+                .coin_selection(wallets.coin_selection)
+                .sort_bip69(wallets.sort_bip69);
+            let tx = match builder.build(&wallets, &utxo_set) {
+                Ok(tx) => tx,
+                Err(e) => match e.downcast_ref::<BlockchainError>() {
+                    Some(BlockchainError::WalletNotFound { address }) => {
+                        eprintln!(
+                            "Can't send from {}: it isn't one of your addresses. \
+                             Check it against listaddress.",
+                            address
+                        );
+                        std::process::exit(1);
+                    }
+                    _ => return Err(e),
+                },
+            };
+            let mut ws = Wallets::open(data_dir.wallets())?;
+            ws.record_transaction(&tx);
+            ws.save_at(data_dir.wallets())?;
+            let cb_tx = Transaction::new_coinbase(&from[0], "".to_owned(), &params)?;
             if mine {
                 let txs = vec![cb_tx, tx];
                 let block = utxo_set.bc.mine_block(txs)?;
-                utxo_set.update(block)?;
+                utxo_set.update(block.clone())?;
+                announce_block(cli.node.as_deref(), block);
             } else {
                 Server::send_transaction(tx, utxo_set)?;
             }
             println!("Success!");
         }
+        Commands::Commit {
+            from,
+            value,
+            fee,
+            mine,
+        } => {
+            let value = hex::decode(&value)?;
+            let salt = new_commitment_salt();
+            let bc = Blockchain::open_with_params(data_dir.blockchain(), params.clone())?;
+            let mut utxo_set = spending_utxo_set(bc, &data_dir);
+            let mut ws = Wallets::open(data_dir.wallets())?;
+            let tx = TransactionBuilder::new()
+                .from(&from)
+                .to(&from)
+                .amount(1)
+                .fee(fee)
+                .memo(&commitment_hash(&value, &salt))
+                .coin_selection(ws.coin_selection)
+                .sort_bip69(ws.sort_bip69)
+                .build(&ws, &utxo_set)?;
+            ws.record_transaction(&tx);
+            ws.save_at(data_dir.wallets())?;
+            let tx_id = tx.id.clone();
+            if mine {
+                let cb_tx = Transaction::new_coinbase(&from, "".to_owned(), &params)?;
+                let block = utxo_set.bc.mine_block(vec![cb_tx, tx])?;
+                utxo_set.update(block.clone())?;
+                announce_block(cli.node.as_deref(), block);
+            } else {
+                Server::send_transaction(tx, utxo_set)?;
+            }
+            match cli.format {
+                OutputFormat::Json => {
+                    println!("{}", json!({ "txid": tx_id, "salt": hex::encode(&salt) }))
+                }
+                OutputFormat::Text => {
+                    println!("Commitment txid: {}", tx_id);
+                    println!("Salt: {}", hex::encode(&salt));
+                }
+            }
+        }
+        Commands::Reveal { txid, value, salt } => {
+            let bc = Blockchain::open_with_params(data_dir.blockchain(), params.clone())?;
+            let matches = verify_reveal(&bc, &txid, &hex::decode(&value)?, &hex::decode(&salt)?)?;
+            match cli.format {
+                OutputFormat::Json => println!("{}", json!({ "txid": txid, "valid": matches })),
+                OutputFormat::Text if matches => println!("Value matches the commitment"),
+                OutputFormat::Text => println!("Value doesn't match the commitment"),
+            }
+            if !matches {
+                std::process::exit(1);
+            }
+        }
+        Commands::SignMessage { address, message } => {
+            let wallets = Wallets::open(data_dir.wallets())?;
+            let wallet = wallets
+                .get_wallet(&address)
+                .ok_or_else(|| anyhow!("Address {} is not in the wallet", address))?;
+            println!("{}", wallet.sign_message(&message)?);
+        }
+        Commands::VerifyMessage {
+            address,
+            message,
+            signature,
+        } => {
+            let valid = verify_message(&address, &message, &signature, &params)?;
+            match cli.format {
+                OutputFormat::Json => println!("{}", json!({ "address": address, "valid": valid })),
+                OutputFormat::Text if valid => println!("Signature is valid"),
+                OutputFormat::Text => println!("Signature is not valid"),
+            }
+            if !valid {
+                std::process::exit(1);
+            }
+        }
+        Commands::CreatePsbt {
+            amount,
+            from,
+            to,
+            fee,
+            subtract_fee_from_amount,
+        } => {
+            let utxo_set = spending_utxo_set(
+                Blockchain::open_with_params(data_dir.blockchain(), params.clone())?,
+                &data_dir,
+            );
+            let ws = Wallets::open(data_dir.wallets())?;
+            let mut psbt = Psbt::create(
+                &from,
+                &to,
+                amount,
+                fee,
+                subtract_fee_from_amount,
+                ws.coin_selection,
+                &utxo_set,
+            )?;
+            if ws.sort_bip69 {
+                psbt.tx.sort_bip69()?;
+            }
+            match cli.format {
+                OutputFormat::Json => println!(
+                    "{}",
+                    json!({
+                        "psbt": psbt.to_hex()?,
+                        "inputs": psbt.tx.v_in.len(),
+                        "outputs": psbt.tx.v_out.len(),
+                        "fee": psbt.fee()?,
+                    })
+                ),
+                OutputFormat::Text => println!("{}", psbt.to_hex()?),
+            }
+        }
+        Commands::SignPsbt { psbt } => {
+            let mut psbt = Psbt::from_hex(&psbt)?;
+            let signed = psbt.sign(&Wallets::open(data_dir.wallets())?, &params)?;
+            match cli.format {
+                OutputFormat::Json => println!(
+                    "{}",
+                    json!({
+                        "psbt": psbt.to_hex()?,
+                        "signed": signed,
+                        "complete": psbt.unsigned_inputs() == 0,
+                    })
+                ),
+                OutputFormat::Text => println!("{}", psbt.to_hex()?),
+            }
+        }
+        Commands::FinalizePsbt { psbt, mine, raw } => {
+            let tx = Psbt::from_hex(&psbt)?.finalize(&params)?;
+            if raw {
+                println!("{}", tx.to_hex()?);
+                return Ok(());
+            }
+            let mut utxo_set = UTXOSet::with_path(
+                Blockchain::open_with_params(data_dir.blockchain(), params.clone())?,
+                data_dir.utxos(),
+            );
+            if !utxo_set.bc.verify_transaction(&tx)? {
+                return Err(anyhow!(
+                    "Transaction {} spends unknown or spent outputs",
+                    tx.id
+                ));
+            }
+            let tx_id = tx.id.clone();
+            if mine {
+                // Like send, the reward goes to the first source address
+                let params = &utxo_set.bc.params;
+                let from =
+                    pub_key_hash_to_address(&hash_pub_key(&tx.v_in[0].pub_key, params), params);
+                let cb_tx = Transaction::new_coinbase(&from, "".to_owned(), params)?;
+                let block = utxo_set.bc.mine_block(vec![cb_tx, tx])?;
+                utxo_set.update(block.clone())?;
+                announce_block(cli.node.as_deref(), block);
+            } else {
+                Server::send_transaction(tx, utxo_set)?;
+            }
+            println!("{}", tx_id);
+        }
+        Commands::BroadcastRaw { hex } => {
+            let tx = Transaction::from_hex(&hex)?;
+            if tx.is_coinbase() {
+                return Err(anyhow!("Coinbase transactions can't be broadcast"));
+            }
+            let utxo_set = UTXOSet::with_path(
+                Blockchain::open_with_params(data_dir.blockchain(), params.clone())?,
+                data_dir.utxos(),
+            );
+            if !utxo_set.bc.verify_transaction(&tx)? {
+                return Err(anyhow!(
+                    "Transaction {} has invalid signatures or spends unknown or spent outputs",
+                    tx.id
+                ));
+            }
+            let tx_id = tx.id.clone();
+            Server::send_transaction(tx, utxo_set)?;
+            println!("{}", tx_id);
+        }
         Commands::CreateWallet => {
-            let mut ws = Wallets::new()?;
-            let addr = ws.create_wallet();
-            ws.save()?;
+            let mut ws = Wallets::open(data_dir.wallets())?;
+            let addr = ws.create_wallet(&params);
+            ws.save_at(data_dir.wallets())?;
             println!("Your new address: {}", addr);
         }
+        Commands::BackupWallet { out, passphrase } => {
+            let ws = Wallets::open(data_dir.wallets())?;
+            ws.backup(&out, &passphrase)?;
+            println!("Backed up {} wallets to {}", ws.wallets.len(), out);
+        }
+        Commands::RestoreWallet {
+            input,
+            passphrase,
+            force,
+        } => {
+            let mut ws = Wallets::open(data_dir.wallets())?;
+            let restored = ws.restore(&input, &passphrase, force, &params)?;
+            ws.save_at(data_dir.wallets())?;
+            let mut added = 0;
+            for (addr, outcome) in &restored {
+                match outcome {
+                    WalletInsert::Added => added += 1,
+                    WalletInsert::Existing => eprintln!(
+                        "Warning: kept the wallet already stored for {}, \
+                         pass --force to replace it",
+                        addr
+                    ),
+                    WalletInsert::Overwritten => {
+                        eprintln!("Warning: replaced the wallet stored for {}", addr)
+                    }
+                }
+            }
+            println!("Restored {} new wallets from {}", added, input);
+        }
+        Commands::VanityAddress {
+            prefix,
+            threads,
+            timeout,
+        } => {
+            let threads = threads
+                .or_else(|| std::thread::available_parallelism().ok().map(|n| n.get()))
+                .unwrap_or(1);
+            let expected = 58f64.powi(prefix.chars().count() as i32);
+            if expected > VANITY_WARN_ATTEMPTS {
+                println!(
+                    "A {} character prefix takes about {:.0} attempts on average, \
+                     this may run for a long time",
+                    prefix.chars().count(),
+                    expected
+                );
+            }
+            let cancel = Arc::new(AtomicBool::new(false));
+            if let Some(secs) = timeout {
+                let cancel = cancel.clone();
+                std::thread::spawn(move || {
+                    std::thread::sleep(Duration::from_secs(secs));
+                    cancel.store(true, Ordering::Relaxed);
+                });
+            }
+            let started = Instant::now();
+            match find_vanity_wallet(&prefix, threads, &cancel, &params)? {
+                Some((wallet, attempts)) => {
+                    let mut ws = Wallets::open(data_dir.wallets())?;
+                    let address = wallet.get_address(&params);
+                    if ws.insert_wallet(wallet, false, &params) == WalletInsert::Existing {
+                        eprintln!("Warning: {} is already one of your addresses", address);
+                    }
+                    ws.save_at(data_dir.wallets())?;
+                    println!(
+                        "{} after {} attempts in {:?}",
+                        address,
+                        attempts,
+                        started.elapsed()
+                    );
+                }
+                None => println!("No match for {} within the timeout", prefix),
+            }
+        }
         Commands::ListAddress => {
-            let ws = Wallets::new()?;
+            let ws = Wallets::open(data_dir.wallets())?;
             println!("addresses: ");
             for addr in ws.get_addresses() {
-                println!("{}", addr);
+                match ws.get_label(&addr) {
+                    Some(label) => println!("{} ({})", addr, label),
+                    None => println!("{}", addr),
+                }
+            }
+        }
+        Commands::ListUnspent { address } => {
+            let ws = Wallets::open(data_dir.wallets())?;
+            let utxo_set = UTXOSet::with_path(
+                Blockchain::open_with_params(data_dir.blockchain(), params.clone())?,
+                data_dir.utxos(),
+            );
+            let unspent = utxo_set.list_unspent(&get_pub_key_hash(&address, &params)?)?;
+            match cli.format {
+                OutputFormat::Json => {
+                    let unspent: Vec<_> = unspent
+                        .iter()
+                        .map(|(tx_id, v_out, out)| {
+                            json!({
+                                "txid": tx_id,
+                                "vout": v_out,
+                                "value": out.value,
+                                "label": ws.get_label(&format!("{}:{}", tx_id, v_out)),
+                            })
+                        })
+                        .collect();
+                    println!("{}", serde_json::to_string_pretty(&unspent)?);
+                }
+                OutputFormat::Text => {
+                    for (tx_id, v_out, out) in &unspent {
+                        let outpoint = format!("{}:{}", tx_id, v_out);
+                        match ws.get_label(&outpoint) {
+                            Some(label) => println!("{} {} ({})", outpoint, out.value, label),
+                            None => println!("{} {}", outpoint, out.value),
+                        }
+                    }
+                }
+            }
+        }
+        Commands::GetTxStatus { txid } => {
+            let status = Wallets::open(data_dir.wallets())?.transaction_status(
+                &txid,
+                &Blockchain::open_with_params(data_dir.blockchain(), params.clone())?,
+            )?;
+            match cli.format {
+                OutputFormat::Json => println!("{}", json!(status)),
+                OutputFormat::Text => match status {
+                    TxStatus::Confirmed(n) => println!("Confirmed, {} confirmations", n),
+                    TxStatus::Pending => println!("Pending"),
+                    TxStatus::Conflicted => {
+                        println!("Conflicted, another transaction spends its inputs")
+                    }
+                },
+            }
+        }
+        Commands::SetLabel { target, label } => {
+            let mut ws = Wallets::open(data_dir.wallets())?;
+            ws.set_label(&target, label.as_deref(), &params)?;
+            ws.save_at(data_dir.wallets())?;
+            match label {
+                Some(label) => println!("Labelled {}: {}", target, label),
+                None => println!("Removed the label of {}", target),
             }
         }
+        Commands::SetCoinSelection { strategy } => {
+            let mut ws = Wallets::open(data_dir.wallets())?;
+            ws.coin_selection = strategy;
+            ws.save_at(data_dir.wallets())?;
+            println!("Coin selection strategy set to {:?}", strategy);
+        }
+        Commands::SetBip69 { off } => {
+            let mut ws = Wallets::open(data_dir.wallets())?;
+            ws.sort_bip69 = !off;
+            ws.save_at(data_dir.wallets())?;
+            println!(
+                "BIP69 ordering {}",
+                if off { "disabled" } else { "enabled" }
+            );
+        }
         Commands::StartNode {
             port,
             miner_address,
+            utxo_check_interval,
+            auto_reindex,
+            health_port,
+            coinbase_message,
+            connect_mode,
         } => {
             println!("Start node");
-            let bc = Blockchain::new()?;
-            let utxo_set = UTXOSet::new(bc);
-            let mut server_builder = ServerBuilder::new().port(&port).utxo(utxo_set);
+            let bc = Blockchain::open_with_params(data_dir.blockchain(), params.clone())?;
+            let utxo_set = UTXOSet::with_path(bc, data_dir.utxos());
+            let mut config = Config::default()
+                .with_utxo_auto_reindex(auto_reindex)
+                .with_connect_mode(connect_mode);
+            if let Some(secs) = utxo_check_interval {
+                config = config.with_utxo_check_interval(Duration::from_secs(secs));
+            }
+            if let Some(port) = health_port {
+                config = config.with_health_port(port);
+            }
+            if let Some(message) = coinbase_message {
+                config = config.with_coinbase_message(&message);
+            }
+            let mut server_builder = ServerBuilder::new()
+                .port(&port.to_string())
+                .utxo(utxo_set)
+                .config(config);
 
             if let Some(address) = miner_address {
                 println!("Starting miner node");
@@ -87,3 +1379,189 @@ fn main() -> Result<()> {
     }
     Ok(())
 }
+
+/// Outcome of `replay_txs`.
+#[derive(Default)]
+struct ReplayReport {
+    accepted: usize,
+    orphaned: usize,
+    rejected: usize,
+    /// Not answered, e.g. the node dropped the connection
+    failed: usize,
+    /// Accepted transactions by the second, since the start, they were
+    /// answered in
+    accepted_per_second: Vec<usize>,
+    elapsed: Duration,
+}
+
+/// Submits `txs` to the node at `node` from `concurrency` threads, each
+/// taking the next transaction as soon as its last one is answered.
+fn replay_txs(node: &str, txs: Vec<Transaction>, concurrency: usize) -> ReplayReport {
+    let next = std::sync::atomic::AtomicUsize::new(0);
+    let report = std::sync::Mutex::new(ReplayReport::default());
+    let start = Instant::now();
+    std::thread::scope(|s| {
+        for _ in 0..concurrency {
+            s.spawn(|| {
+                while let Some(tx) = txs.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    let answer = Server::query(
+                        node,
+                        Message::SubmitTx {
+                            transaction: tx.clone(),
+                        },
+                    );
+                    let second = start.elapsed().as_secs() as usize;
+                    let mut report = report.lock().unwrap();
+                    match answer {
+                        Ok(Message::TxSubmitted { verdict }) => match verdict {
+                            TxVerdict::Accepted => {
+                                report.accepted += 1;
+                                if report.accepted_per_second.len() <= second {
+                                    report.accepted_per_second.resize(second + 1, 0);
+                                }
+                                report.accepted_per_second[second] += 1;
+                            }
+                            TxVerdict::Orphan => report.orphaned += 1,
+                            TxVerdict::Rejected { reason } => {
+                                log::debug!("Tx {} rejected: {}", tx.id, reason);
+                                report.rejected += 1;
+                            }
+                        },
+                        Ok(answer) => {
+                            log::warn!("Unexpected answer {:?} for tx {}", answer, tx.id);
+                            report.failed += 1;
+                        }
+                        Err(e) => {
+                            log::warn!("Submitting tx {} failed: {}", tx.id, e);
+                            report.failed += 1;
+                        }
+                    }
+                }
+            });
+        }
+    });
+    let mut report = report.into_inner().unwrap();
+    report.elapsed = start.elapsed();
+    report
+}
+
+/// Prints PASS or FAIL for `step` and passes its result on.
+fn selftest_step<T>(step: &str, result: Result<T>) -> Result<T> {
+    match &result {
+        Ok(_) => println!("PASS {}", step),
+        Err(e) => println!("FAIL {}: {}", step, e),
+    }
+    result
+}
+
+/// Builds a chain in `dir` with wallets kept in memory, mines blocks,
+/// sends between two addresses and checks the balances, stopping at the
+/// first step that fails.
+fn run_selftest(dir: &Path, params: &ChainParams) -> Result<()> {
+    const BLOCKS: i32 = 3;
+    const AMOUNT: i32 = 4;
+
+    let mut ws = Wallets::default();
+    let miner = ws.create_wallet(params);
+    let receiver = ws.create_wallet(params);
+    selftest_step(
+        "create wallets",
+        validate_address(&miner, params).and_then(|_| validate_address(&receiver, params)),
+    )?;
+    let bc = selftest_step(
+        "create blockchain",
+        Blockchain::create_with_params(dir.join("blockchain"), &miner, params.clone()),
+    )?;
+    let mut utxo_set = UTXOSet::with_path(bc, dir.join("utxos"));
+    selftest_step("index UTXO set", utxo_set.reindex())?;
+    let reward = block_subsidy(0);
+
+    let balance = |utxo_set: &UTXOSet, addr: &str| -> Result<Amount> {
+        utxo_set
+            .find_utxo(&get_pub_key_hash(addr, params)?)?
+            .total()
+    };
+    let expect = |utxo_set: &UTXOSet, addr: &str, expected: i32| -> Result<()> {
+        let actual = balance(utxo_set, addr)?;
+        if actual != Amount::from_value(expected)? {
+            return Err(anyhow!(
+                "balance of {} is {}, expected {}",
+                addr,
+                actual,
+                expected
+            ));
+        }
+        Ok(())
+    };
+
+    selftest_step(
+        &format!("mine {} blocks", BLOCKS),
+        (0..BLOCKS).try_for_each(|i| {
+            let cbtx = Transaction::new_coinbase(&miner, format!("selftest {}", i), params)?;
+            let block = utxo_set.bc.mine_block(vec![cbtx])?;
+            utxo_set.update(block)
+        }),
+    )?;
+    selftest_step(
+        "miner balance after mining",
+        expect(&utxo_set, &miner, reward * (BLOCKS + 1)),
+    )?;
+
+    selftest_step(
+        &format!("send {} and mine it", AMOUNT),
+        Transaction::new_with_wallets(
+            std::slice::from_ref(&miner),
+            &receiver,
+            AMOUNT,
+            0,
+            false,
+            &ws,
+            &utxo_set,
+        )
+        .and_then(|tx| {
+            if !utxo_set.bc.verify_transaction(&tx)? {
+                return Err(anyhow!("transaction {} doesn't verify", tx.id));
+            }
+            let cbtx = Transaction::new_coinbase(&miner, "selftest send".to_owned(), params)?;
+            let block = utxo_set.bc.mine_block(vec![cbtx, tx])?;
+            utxo_set.update(block)
+        }),
+    )?;
+    selftest_step(
+        "miner balance after sending",
+        expect(&utxo_set, &miner, reward * (BLOCKS + 2) - AMOUNT),
+    )?;
+    selftest_step(
+        "receiver balance after sending",
+        expect(&utxo_set, &receiver, AMOUNT),
+    )?;
+    selftest_step(
+        "UTXO set matches the chain",
+        utxo_set.verify_against_chain().and_then(|mismatch| {
+            if mismatch.is_empty() {
+                Ok(())
+            } else {
+                Err(anyhow!("{}", mismatch))
+            }
+        }),
+    )?;
+    Ok(())
+}
+
+/// The UTXO set of `bc` for building transactions, leaving out coinbases
+/// that haven't matured yet.
+fn spending_utxo_set(bc: Blockchain, data_dir: &DataDir) -> UTXOSet {
+    UTXOSet::with_path(bc, data_dir.utxos())
+        .with_coinbase_maturity(Config::default().coinbase_maturity())
+}
+
+/// Hands a block mined by the CLI to `node`, or the central node, for
+/// relaying. The block is already ours, so failing to reach a node is
+/// only reported.
+fn announce_block(node: Option<&str>, block: Block) {
+    let default = Config::default();
+    let node = node.unwrap_or(default.central_node());
+    if let Err(e) = Server::submit_block(node, block) {
+        println!("Mined locally, but couldn't announce the block: {}", e);
+    }
+}
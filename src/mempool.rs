@@ -0,0 +1,913 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+use crate::{Hash, OutPoint, Transaction};
+
+pub const MAX_ORPHAN_TXS: usize = 100;
+/// Most transaction hashes sent in reply to a mempool request
+pub const MAX_MEMPOOL_INV: usize = 1000;
+/// Most transactions a miner puts in a block, besides the coinbase
+pub const MAX_BLOCK_TXS: usize = 1000;
+/// Most bytes of transactions a miner puts in a block, besides the coinbase
+pub const MAX_BLOCK_SIZE: usize = 1_000_000;
+/// Transactions unconfirmed for longer than this are dropped
+pub const MEMPOOL_EXPIRY_MS: u128 = 24 * 60 * 60 * 1000;
+/// Lower bounds of the fee-rate buckets of `Mempool::fee_histogram`, in
+/// fee per 1000 bytes
+pub const FEE_RATE_BUCKETS: &[u64] = &[
+    0, 1, 2, 3, 5, 8, 10, 15, 20, 30, 50, 75, 100, 150, 200, 300, 500, 1000, 2000, 5000,
+];
+/// Fee rate, per 1000 bytes, `estimate_smart_fee` suggests when the next
+/// block has room for every pooled transaction
+pub const MIN_RELAY_FEE: u64 = 1;
+
+/// Unconfirmed transactions waiting to be mined.
+///
+/// Transactions spending outputs of transactions we haven't seen yet are
+/// parked in a bounded orphan pool until their parents arrive.
+pub struct Mempool {
+    txs: HashMap<Hash, Transaction>,
+    /// Arrival time of each pooled transaction in unix millis. Kept outside
+    /// the transaction so it doesn't affect its id.
+    received_at: HashMap<Hash, u128>,
+    /// The pooled transaction spending each outpoint, to find conflicts
+    /// without scanning the pool
+    spent_by: HashMap<OutPoint, Hash>,
+    orphans: OrphanPool,
+    orphan_stats: OrphanStats,
+}
+
+/// Counters of the orphan pool since the node started. Clearing the
+/// mempool doesn't reset them.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct OrphanStats {
+    /// Transactions held because a parent was missing when they arrived
+    pub received: u64,
+    /// Orphans that entered the pool once their parents arrived
+    pub connected: u64,
+    /// Orphans dropped because the pool was full, oldest first
+    pub expired: u64,
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Self::new(MAX_ORPHAN_TXS)
+    }
+}
+
+impl Mempool {
+    pub fn new(max_orphans: usize) -> Self {
+        Self {
+            txs: HashMap::new(),
+            received_at: HashMap::new(),
+            spent_by: HashMap::new(),
+            orphans: OrphanPool::new(max_orphans),
+            orphan_stats: OrphanStats::default(),
+        }
+    }
+
+    pub fn get(&self, hash: &Hash) -> Option<&Transaction> {
+        self.txs.get(hash)
+    }
+
+    pub fn contains_id(&self, id: &str) -> bool {
+        self.txs.values().any(|tx| tx.id == id)
+    }
+
+    pub fn transactions(&self) -> &HashMap<Hash, Transaction> {
+        &self.txs
+    }
+
+    /// Hashes of up to `limit` pooled transactions, to announce our mempool
+    /// to a peer. Parents come before the children spending them, so the
+    /// peer can fetch them in order without creating orphans.
+    pub fn inventory(&self, limit: usize) -> Vec<Hash> {
+        let mut hashes = vec![];
+        let mut seen = HashSet::new();
+        for tx in self.txs.values() {
+            self.push_with_parents(tx, &mut seen, &mut hashes);
+        }
+        hashes.truncate(limit);
+        hashes
+    }
+
+    fn push_with_parents(
+        &self,
+        tx: &Transaction,
+        seen: &mut HashSet<Hash>,
+        hashes: &mut Vec<Hash>,
+    ) {
+        if !seen.insert(tx.hash_val) {
+            return;
+        }
+        for vin in &tx.v_in {
+            if let Some(parent) = self.txs.get(&vin.prev_out.txid) {
+                self.push_with_parents(parent, seen, hashes);
+            }
+        }
+        hashes.push(tx.hash_val);
+    }
+
+    /// Pooled transactions bucketed by fee rate, highest first, as pairs of
+    /// the bucket's lower bound from `FEE_RATE_BUCKETS` and the total size
+    /// of the transactions paying at least that much. Only buckets holding
+    /// a transaction are listed.
+    ///
+    /// `output_value` resolves the value of confirmed outputs, outputs of
+    /// pooled parents are looked up here. Transactions whose fee can't be
+    /// computed are left out.
+    pub fn fee_histogram<F>(&self, mut output_value: F) -> Result<Vec<(u64, usize)>>
+    where
+        F: FnMut(&str, i32) -> Option<i32>,
+    {
+        let mut sizes = vec![0; FEE_RATE_BUCKETS.len()];
+        for tx in self.txs.values() {
+            let Some(fee) = pooled_tx_fee(tx, &self.txs, &mut output_value) else {
+                continue;
+            };
+            let size = tx.size()?;
+            let rate = fee_rate(fee, size);
+            let bucket = FEE_RATE_BUCKETS
+                .iter()
+                .rposition(|&bound| rate >= bound)
+                .unwrap_or_default();
+            sizes[bucket] += size;
+        }
+
+        let mut histogram = vec![];
+        let mut cumulative = 0;
+        for (bucket, size) in sizes.into_iter().enumerate().rev() {
+            if size > 0 {
+                cumulative += size;
+                histogram.push((FEE_RATE_BUCKETS[bucket], cumulative));
+            }
+        }
+        Ok(histogram)
+    }
+
+    pub fn len(&self) -> usize {
+        self.txs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.txs.is_empty()
+    }
+
+    /// Whether `hash` is held as an orphan, waiting for a parent.
+    pub fn is_orphan(&self, hash: &Hash) -> bool {
+        self.orphans.contains(hash)
+    }
+
+    pub fn orphan_count(&self) -> usize {
+        self.orphans.len()
+    }
+
+    pub fn orphan_stats(&self) -> OrphanStats {
+        self.orphan_stats
+    }
+
+    /// When `hash` entered the pool, in unix millis.
+    pub fn received_at(&self, hash: &Hash) -> Option<u128> {
+        self.received_at.get(hash).copied()
+    }
+
+    /// The pooled transaction spending output `v_out` of `tx_id`, if any.
+    pub fn spender(&self, tx_id: &str, v_out: i32) -> Option<&Transaction> {
+        let outpoint = OutPoint::new(tx_id.parse().ok()?, u32::try_from(v_out).ok()?);
+        let hash = self.spent_by.get(&outpoint)?;
+        self.txs.get(hash)
+    }
+
+    /// Pooled transactions spending an output `tx` also spends.
+    pub fn conflicts(&self, tx: &Transaction) -> Vec<Hash> {
+        let mut conflicts = vec![];
+        for outpoint in outpoints(tx) {
+            if let Some(&hash) = self.spent_by.get(&outpoint)
+                && hash != tx.hash_val
+                && !conflicts.contains(&hash)
+            {
+                conflicts.push(hash);
+            }
+        }
+        conflicts
+    }
+
+    pub fn clear(&mut self) {
+        self.txs.clear();
+        self.received_at.clear();
+        self.spent_by.clear();
+    }
+
+    /// Drops transactions received more than `max_age_ms` before `now`,
+    /// returning them oldest first.
+    pub fn expire(&mut self, now: u128, max_age_ms: u128) -> Vec<Transaction> {
+        let mut expired: Vec<(u128, Hash)> = self
+            .received_at
+            .iter()
+            .filter(|(_, received_at)| now.saturating_sub(**received_at) > max_age_ms)
+            .map(|(hash, received_at)| (*received_at, *hash))
+            .collect();
+        expired.sort();
+        expired
+            .into_iter()
+            .filter_map(|(_, hash)| {
+                let tx = self.remove(&hash)?;
+                info!("Expiring mempool tx {}", tx.id);
+                Some(tx)
+            })
+            .collect()
+    }
+
+    fn remove(&mut self, hash: &Hash) -> Option<Transaction> {
+        self.received_at.remove(hash);
+        let tx = self.txs.remove(hash)?;
+        for outpoint in outpoints(&tx) {
+            if self.spent_by.get(&outpoint) == Some(hash) {
+                self.spent_by.remove(&outpoint);
+            }
+        }
+        Some(tx)
+    }
+
+    /// Adds `tx` to the pool, or holds it as an orphan when one of its
+    /// parents is neither confirmed nor in the pool. A transaction spending
+    /// an output a pooled one already spends is dropped, the first seen
+    /// wins, and so is one spending an output more than once.
+    ///
+    /// `is_confirmed` tells whether a transaction id is already on chain.
+    /// Returns every transaction that entered the pool: `tx` itself followed
+    /// by any orphans it unblocked. Empty if `tx` was orphaned.
+    pub fn accept<F>(&mut self, tx: Transaction, is_confirmed: F) -> Vec<Transaction>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let mut accepted = vec![];
+        let original = tx.hash_val;
+        let mut queue = VecDeque::from([tx]);
+
+        while let Some(tx) = queue.pop_front() {
+            let is_original = tx.hash_val == original;
+            if spends_an_output_twice(&tx) {
+                info!("Rejecting tx {}, it spends an output twice", tx.id);
+                continue;
+            }
+            let missing = self.missing_parents(&tx, &is_confirmed);
+            if !missing.is_empty() {
+                debug!("Holding orphan tx {}, missing {:?}", tx.id, missing);
+                // A promoted orphan still missing another parent goes back
+                // without counting as received twice
+                if is_original && !self.orphans.contains(&tx.hash_val) {
+                    self.orphan_stats.received += 1;
+                }
+                self.orphan_stats.expired += self.orphans.insert(tx, missing) as u64;
+                continue;
+            }
+
+            if let Some(conflict) = self.conflicts(&tx).first() {
+                info!(
+                    "Rejecting tx {}, it double spends pooled tx {}",
+                    tx.id, conflict
+                );
+                continue;
+            }
+
+            queue.extend(self.orphans.take_children(&tx.id));
+            if !is_original {
+                info!("Promoting orphan tx {}", tx.id);
+                self.orphan_stats.connected += 1;
+            }
+            self.received_at
+                .entry(tx.hash_val)
+                .or_insert_with(unix_millis);
+            for outpoint in outpoints(&tx) {
+                self.spent_by.insert(outpoint, tx.hash_val);
+            }
+            self.txs.insert(tx.hash_val, tx.clone());
+            accepted.push(tx);
+        }
+
+        accepted
+    }
+
+    fn missing_parents<F>(&self, tx: &Transaction, is_confirmed: &F) -> Vec<String>
+    where
+        F: Fn(&str) -> bool,
+    {
+        if tx.is_coinbase() {
+            return vec![];
+        }
+        let mut missing: Vec<String> = tx
+            .v_in
+            .iter()
+            .filter(|vin| !self.txs.contains_key(&vin.prev_out.txid))
+            .map(|vin| vin.prev_out.txid.to_string())
+            .filter(|tx_id| !is_confirmed(tx_id))
+            .collect();
+        missing.dedup();
+        missing
+    }
+}
+
+fn unix_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or_default()
+}
+
+/// Fee of `tx`, with spent outputs looked up in `pooled` first and then with
+/// `output_value`. `None` when a spent output can't be found.
+fn pooled_tx_fee<F>(
+    tx: &Transaction,
+    pooled: &HashMap<Hash, Transaction>,
+    output_value: &mut F,
+) -> Option<i64>
+where
+    F: FnMut(&str, i32) -> Option<i32>,
+{
+    let mut fee = 0;
+    for vin in &tx.v_in {
+        let prev_out = vin.prev_out;
+        let in_pool = pooled
+            .get(&prev_out.txid)
+            .and_then(|parent| parent.v_out.get(prev_out.vout as usize))
+            .map(|out| out.value);
+        let confirmed = || output_value(&prev_out.txid.to_string(), prev_out.vout as i32);
+        fee += in_pool.or_else(confirmed)? as i64;
+    }
+    Some(fee - tx.v_out.iter().map(|out| out.value as i64).sum::<i64>())
+}
+
+/// Fee per 1000 bytes of a transaction of `size` bytes paying `fee`.
+fn fee_rate(fee: i64, size: usize) -> u64 {
+    (fee.max(0) as u64 * 1000) / size as u64
+}
+
+/// Picks up to `max_txs` transactions, of up to `max_size` bytes in total,
+/// for the next block.
+///
+/// Each candidate is taken together with its unselected ancestors in the
+/// pool, and the package paying the highest fee rate goes first, so a
+/// high-fee child pulls in a low-fee parent. Parents are placed before their
+/// children. A transaction is skipped if it spends an output already spent
+/// by an earlier pick or `is_valid` rejects it, given the picks so far;
+/// its descendants go with it. Candidates whose fee can't be computed from
+/// the pool and `output_value` are left out.
+///
+/// Ties are broken by hash so the template is deterministic. The result is
+/// only empty when no candidate is valid, so a miner never seals a
+/// coinbase-only block while valid transactions are waiting.
+pub fn select_block_transactions<V, F>(
+    candidates: &HashMap<Hash, Transaction>,
+    max_txs: usize,
+    max_size: usize,
+    mut output_value: V,
+    mut is_valid: F,
+) -> Result<Vec<Transaction>>
+where
+    V: FnMut(&str, i32) -> Option<i32>,
+    F: FnMut(&Transaction, &[Transaction]) -> Result<bool>,
+{
+    let mut fees = HashMap::new();
+    for (hash, tx) in candidates {
+        if let Some(fee) = pooled_tx_fee(tx, candidates, &mut output_value) {
+            fees.insert(*hash, (fee, tx.size()? as i64));
+        }
+    }
+
+    let mut done: HashSet<Hash> = HashSet::new();
+    let mut spent = HashSet::new();
+    let mut selected: Vec<Transaction> = vec![];
+    let mut selected_size = 0;
+    loop {
+        // The best package among the candidates not handled yet
+        let mut best: Option<(i64, i64, Hash, Vec<Hash>)> = None;
+        let mut hashes: Vec<&Hash> = fees.keys().filter(|h| !done.contains(*h)).collect();
+        hashes.sort();
+        for hash in hashes {
+            let mut package = vec![];
+            push_ancestors(hash, candidates, &done, &mut package);
+            let Some((fee, size)) = package.iter().try_fold((0, 0), |(fee, size), h| {
+                let (f, s) = fees.get(h)?;
+                Some((fee + f, size + s))
+            }) else {
+                continue;
+            };
+            let better = match &best {
+                // fee / size > best_fee / best_size
+                Some((best_fee, best_size, ..)) => fee * best_size > best_fee * size,
+                None => true,
+            };
+            if better {
+                best = Some((fee, size, *hash, package));
+            }
+        }
+        let Some((_, size, _, package)) = best else {
+            break;
+        };
+
+        let fits =
+            selected.len() + package.len() <= max_txs && selected_size + size as usize <= max_size;
+        for hash in package {
+            done.insert(hash);
+            let tx = &candidates[&hash];
+            if fits && !spends_any(tx, &spent) && is_valid(tx, &selected)? {
+                spent.extend(outpoints(tx));
+                selected_size += fees[&hash].1 as usize;
+                selected.push(tx.clone());
+            }
+        }
+    }
+    Ok(selected)
+}
+
+/// Fee rate, per 1000 bytes, a transaction has to pay to get into the next
+/// block `select_block_transactions` assembles from `candidates`.
+///
+/// When the block has room for every valid candidate anything confirms,
+/// and this is `MIN_RELAY_FEE`. Otherwise it is the fee rate of the last
+/// transaction that made it in, the marginal one.
+pub fn estimate_smart_fee<V, F>(
+    candidates: &HashMap<Hash, Transaction>,
+    max_txs: usize,
+    max_size: usize,
+    mut output_value: V,
+    mut is_valid: F,
+) -> Result<u64>
+where
+    V: FnMut(&str, i32) -> Option<i32>,
+    F: FnMut(&Transaction, &[Transaction]) -> Result<bool>,
+{
+    let selected = select_block_transactions(
+        candidates,
+        max_txs,
+        max_size,
+        &mut output_value,
+        &mut is_valid,
+    )?;
+    let all_valid = select_block_transactions(
+        candidates,
+        usize::MAX,
+        usize::MAX,
+        &mut output_value,
+        &mut is_valid,
+    )?;
+    if selected.len() == all_valid.len() {
+        return Ok(MIN_RELAY_FEE);
+    }
+    let Some(marginal) = selected.last() else {
+        return Ok(MIN_RELAY_FEE);
+    };
+    let fee = pooled_tx_fee(marginal, candidates, &mut output_value).unwrap_or_default();
+    Ok(fee_rate(fee, marginal.size()?).max(MIN_RELAY_FEE))
+}
+
+/// Appends `hash` to `package` after its ancestors in `candidates` that
+/// aren't `done`, parents first.
+fn push_ancestors(
+    hash: &Hash,
+    candidates: &HashMap<Hash, Transaction>,
+    done: &HashSet<Hash>,
+    package: &mut Vec<Hash>,
+) {
+    if done.contains(hash) || package.contains(hash) {
+        return;
+    }
+    for vin in &candidates[hash].v_in {
+        let parent = &vin.prev_out.txid;
+        if candidates.contains_key(parent) {
+            push_ancestors(parent, candidates, done, package);
+        }
+    }
+    package.push(*hash);
+}
+
+/// Drops from `candidates` every transaction spending an output also spent
+/// by one of `mined`, including the mined transactions themselves.
+pub fn remove_conflicts(candidates: &mut HashMap<Hash, Transaction>, mined: &[Transaction]) {
+    let spent: HashSet<OutPoint> = mined.iter().flat_map(outpoints).collect();
+    candidates
+        .retain(|hash, tx| !spends_any(tx, &spent) && !mined.iter().any(|m| m.hash_val == *hash));
+}
+
+fn outpoints(tx: &Transaction) -> Vec<OutPoint> {
+    if tx.is_coinbase() {
+        return vec![];
+    }
+    tx.v_in.iter().map(|vin| vin.prev_out).collect()
+}
+
+fn spends_an_output_twice(tx: &Transaction) -> bool {
+    let mut seen = HashSet::new();
+    !outpoints(tx)
+        .into_iter()
+        .all(|outpoint| seen.insert(outpoint))
+}
+
+fn spends_any(tx: &Transaction, spent: &HashSet<OutPoint>) -> bool {
+    outpoints(tx)
+        .iter()
+        .any(|outpoint| spent.contains(outpoint))
+}
+
+/// Orphan transactions indexed by the parent they are waiting for.
+struct OrphanPool {
+    txs: HashMap<Hash, Transaction>,
+    by_parent: HashMap<String, Vec<Hash>>,
+    /// Insertion order, oldest first, for eviction
+    order: VecDeque<Hash>,
+    capacity: usize,
+}
+
+impl OrphanPool {
+    fn new(capacity: usize) -> Self {
+        Self {
+            txs: HashMap::new(),
+            by_parent: HashMap::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.txs.len()
+    }
+
+    fn contains(&self, hash: &Hash) -> bool {
+        self.txs.contains_key(hash)
+    }
+
+    /// Holds `tx` until `missing_parents` arrive, returning how many orphans
+    /// were dropped to make room, `tx` itself included when nothing fits.
+    fn insert(&mut self, tx: Transaction, missing_parents: Vec<String>) -> usize {
+        if self.capacity == 0 {
+            return 1;
+        }
+        if self.txs.contains_key(&tx.hash_val) {
+            return 0;
+        }
+        let mut evicted_count = 0;
+        while self.txs.len() >= self.capacity {
+            let Some(oldest) = self.order.pop_front() else {
+                break;
+            };
+            if let Some(evicted) = self.txs.remove(&oldest) {
+                debug!("Evicting orphan tx {}", evicted.id);
+                evicted_count += 1;
+            }
+            self.by_parent.retain(|_, hashes| {
+                hashes.retain(|h| *h != oldest);
+                !hashes.is_empty()
+            });
+        }
+
+        for parent in missing_parents {
+            self.by_parent.entry(parent).or_default().push(tx.hash_val);
+        }
+        self.order.push_back(tx.hash_val);
+        self.txs.insert(tx.hash_val, tx);
+        evicted_count
+    }
+
+    /// Removes and returns the orphans waiting on `parent_id`.
+    fn take_children(&mut self, parent_id: &str) -> Vec<Transaction> {
+        let Some(hashes) = self.by_parent.remove(parent_id) else {
+            return vec![];
+        };
+        let mut children = vec![];
+        for hash in hashes {
+            if let Some(tx) = self.txs.remove(&hash) {
+                self.order.retain(|h| *h != hash);
+                children.push(tx);
+            }
+        }
+        children
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{TXOutput, testutil::TxBuilder};
+
+    /// A made up transaction id spelling `name`.
+    fn txid(name: &str) -> Hash {
+        let mut id = [0; 32];
+        id[..name.len()].copy_from_slice(name.as_bytes());
+        Hash(id)
+    }
+
+    /// Whether `tx_id` is one of the made up ids starting with `prefix`.
+    fn named(tx_id: &str, prefix: &str) -> bool {
+        hex::decode(tx_id).is_ok_and(|id| id.starts_with(prefix.as_bytes()))
+    }
+
+    fn tx_spending(parent: Hash, value: i32) -> Transaction {
+        TxBuilder::new()
+            .spend(parent, 0)
+            .output(TXOutput {
+                value,
+                pub_key_hash: vec![],
+            })
+            .build()
+    }
+
+    #[test]
+    fn test_orphan_promoted_when_parent_arrives() {
+        let mut mempool = Mempool::default();
+        let confirmed = |id: &str| id == txid("confirmed").to_string();
+
+        let parent = tx_spending(txid("confirmed"), 5);
+        let child = tx_spending(parent.hash_val, 4);
+
+        assert!(mempool.accept(child.clone(), confirmed).is_empty());
+        assert_eq!(mempool.orphan_count(), 1);
+        assert!(mempool.get(&child.hash_val).is_none());
+
+        let accepted = mempool.accept(parent.clone(), confirmed);
+        assert_eq!(accepted.len(), 2);
+        assert_eq!(mempool.orphan_count(), 0);
+        assert!(mempool.get(&parent.hash_val).is_some());
+        assert!(mempool.get(&child.hash_val).is_some());
+    }
+
+    #[test]
+    fn test_inventory_is_capped_and_parents_first() {
+        let mut mempool = Mempool::default();
+        let confirmed = |id: &str| id == txid("confirmed").to_string();
+        let parent = tx_spending(txid("confirmed"), 5);
+        let child = tx_spending(parent.hash_val, 4);
+        mempool.accept(child.clone(), confirmed);
+        mempool.accept(parent.clone(), confirmed);
+
+        assert_eq!(mempool.inventory(10), vec![parent.hash_val, child.hash_val]);
+        assert_eq!(mempool.inventory(1).len(), 1);
+    }
+
+    #[test]
+    fn test_expire_by_received_at() {
+        let mut mempool = Mempool::default();
+        let old = tx_spending(txid("a"), 1);
+        let new = tx_spending(txid("b"), 1);
+        let old_id = old.id.clone();
+        mempool.accept(old.clone(), |_| true);
+        mempool.accept(new.clone(), |_| true);
+        mempool.received_at.insert(old.hash_val, 1_000);
+        mempool.received_at.insert(new.hash_val, 5_000);
+
+        let expired = mempool.expire(6_000, 2_000);
+        assert_eq!(expired.len(), 1);
+        // Tracking arrival doesn't change the txid
+        assert_eq!(expired[0].id, old_id);
+        assert_eq!(expired[0].hash().unwrap(), old.hash_val);
+        assert!(mempool.get(&old.hash_val).is_none());
+        assert_eq!(mempool.received_at(&new.hash_val), Some(5_000));
+    }
+
+    #[test]
+    fn test_block_template_skips_conflicts_only() {
+        let a = tx_spending(txid("confirmed"), 5);
+        let b = tx_spending(txid("other"), 3);
+        let mut double_spend = tx_spending(txid("confirmed"), 4);
+        double_spend.v_out[0].pub_key_hash = vec![1];
+        double_spend.set_id().unwrap();
+
+        let candidates: HashMap<Hash, Transaction> = [a, b, double_spend]
+            .into_iter()
+            .map(|tx| (tx.hash_val, tx))
+            .collect();
+        let selected = select_block_transactions(
+            &candidates,
+            MAX_BLOCK_TXS,
+            MAX_BLOCK_SIZE,
+            |_, _| Some(5),
+            |_, _| Ok(true),
+        )
+        .unwrap();
+        assert_eq!(selected.len(), 2);
+        assert!(
+            selected
+                .iter()
+                .any(|tx| tx.v_in[0].prev_out.txid == txid("other"))
+        );
+
+        let mut remaining = candidates.clone();
+        remove_conflicts(&mut remaining, &selected);
+        assert!(remaining.is_empty());
+
+        let none = select_block_transactions(
+            &candidates,
+            MAX_BLOCK_TXS,
+            MAX_BLOCK_SIZE,
+            |_, _| Some(5),
+            |_, _| Ok(false),
+        )
+        .unwrap();
+        assert!(none.is_empty());
+    }
+
+    #[test]
+    fn test_double_spend_found_by_outpoint() {
+        let mut mempool = Mempool::default();
+        let confirmed = |id: &str| id == txid("confirmed").to_string();
+        let first = tx_spending(txid("confirmed"), 5);
+        let mut second = tx_spending(txid("confirmed"), 4);
+        second.v_out[0].pub_key_hash = vec![1];
+        second.set_id().unwrap();
+        assert_eq!(mempool.accept(first.clone(), confirmed).len(), 1);
+
+        let outpoint = OutPoint::new(txid("confirmed"), 0);
+        assert_eq!(mempool.spent_by.get(&outpoint), Some(&first.hash_val));
+        assert_eq!(mempool.conflicts(&second), vec![first.hash_val]);
+        assert!(mempool.conflicts(&first).is_empty());
+        assert!(mempool.accept(second.clone(), confirmed).is_empty());
+        assert!(mempool.get(&second.hash_val).is_none());
+
+        // Once the first spend leaves the pool the output is free again
+        mempool.received_at.insert(first.hash_val, 0);
+        mempool.expire(MEMPOOL_EXPIRY_MS + 1, MEMPOOL_EXPIRY_MS);
+        assert!(mempool.spent_by.is_empty());
+        assert_eq!(mempool.accept(second, confirmed).len(), 1);
+    }
+
+    #[test]
+    fn test_rejects_tx_spending_an_output_twice() {
+        let mut mempool = Mempool::default();
+        let confirmed = |id: &str| id == txid("confirmed").to_string();
+        let twice = TxBuilder::new()
+            .spend(txid("confirmed"), 0)
+            .spend(txid("confirmed"), 0)
+            .output(TXOutput {
+                value: 5,
+                pub_key_hash: vec![],
+            })
+            .build();
+
+        assert!(mempool.accept(twice.clone(), confirmed).is_empty());
+        assert!(mempool.get(&twice.hash_val).is_none());
+        assert!(mempool.spent_by.is_empty());
+        assert_eq!(mempool.orphan_count(), 0);
+    }
+
+    #[test]
+    fn test_high_fee_child_pulls_in_parent() {
+        let confirmed = |tx_id: &str, _| named(tx_id, "confirmed").then_some(100);
+        // Fees of 1, 60 and 0
+        let parent = tx_spending(txid("confirmed-1"), 99);
+        let child = tx_spending(parent.hash_val, 39);
+        let other = tx_spending(txid("confirmed-2"), 100);
+        let candidates: HashMap<Hash, Transaction> = [&parent, &child, &other]
+            .into_iter()
+            .map(|tx| (tx.hash_val, tx.clone()))
+            .collect();
+
+        let selected =
+            select_block_transactions(&candidates, 2, MAX_BLOCK_SIZE, confirmed, |tx, earlier| {
+                // The child is only valid with its parent in the block
+                let parent = tx.v_in[0].prev_out.txid;
+                Ok(named(&parent.to_string(), "confirmed")
+                    || earlier.iter().any(|t| t.hash_val == parent))
+            })
+            .unwrap();
+        let ids: Vec<&str> = selected.iter().map(|tx| tx.id.as_str()).collect();
+        assert_eq!(ids, vec![parent.id.as_str(), child.id.as_str()]);
+
+        // The free transaction only gets in once the package is placed
+        let all =
+            select_block_transactions(&candidates, 3, MAX_BLOCK_SIZE, confirmed, |_, _| Ok(true))
+                .unwrap();
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[2].id, other.id);
+    }
+
+    #[test]
+    fn test_estimate_smart_fee() {
+        let size = tx_spending(txid("confirmed"), 0).size().unwrap() as i32;
+        let confirmed = |tx_id: &str, _| named(tx_id, "confirmed").then_some(100 * size);
+        // A bit less than 6000, 4000 and 2000 per 1000 bytes
+        let candidates: HashMap<Hash, Transaction> = [94, 96, 98]
+            .into_iter()
+            .enumerate()
+            .map(|(i, value)| tx_spending(txid(&format!("confirmed-{}", i)), value * size))
+            .map(|tx| (tx.hash_val, tx))
+            .collect();
+
+        // Everything fits, so anything confirms
+        let fee =
+            estimate_smart_fee(&candidates, 3, MAX_BLOCK_SIZE, confirmed, |_, _| Ok(true)).unwrap();
+        assert_eq!(fee, MIN_RELAY_FEE);
+
+        // The block is full, the middle transaction only just gets in
+        let fee =
+            estimate_smart_fee(&candidates, 2, MAX_BLOCK_SIZE, confirmed, |_, _| Ok(true)).unwrap();
+        assert!((3000..4000).contains(&fee), "{}", fee);
+        let fee =
+            estimate_smart_fee(&candidates, 1, MAX_BLOCK_SIZE, confirmed, |_, _| Ok(true)).unwrap();
+        assert!((5000..6000).contains(&fee), "{}", fee);
+
+        // Room for both valid transactions, the third one never confirms
+        let fee = estimate_smart_fee(&candidates, 2, MAX_BLOCK_SIZE, confirmed, |tx, _| {
+            Ok(tx.id != candidates.values().max_by_key(|tx| &tx.id).unwrap().id)
+        })
+        .unwrap();
+        assert_eq!(fee, MIN_RELAY_FEE);
+
+        // Only as many transactions as there are bytes for
+        let room = 2 * candidates
+            .values()
+            .map(|tx| tx.size().unwrap())
+            .max()
+            .unwrap();
+        let selected =
+            select_block_transactions(&candidates, MAX_BLOCK_TXS, room, confirmed, |_, _| Ok(true))
+                .unwrap();
+        assert_eq!(selected.len(), 2);
+        let fee = estimate_smart_fee(&candidates, MAX_BLOCK_TXS, room, confirmed, |_, _| Ok(true))
+            .unwrap();
+        assert!((3000..4000).contains(&fee), "{}", fee);
+    }
+
+    #[test]
+    fn test_orphan_pool_evicts_oldest() {
+        let mut mempool = Mempool::new(2);
+        let confirmed = |_: &str| false;
+
+        let first = tx_spending(txid("a"), 1);
+        mempool.accept(first.clone(), confirmed);
+        mempool.accept(tx_spending(txid("b"), 1), confirmed);
+        mempool.accept(tx_spending(txid("c"), 1), confirmed);
+        assert_eq!(mempool.orphan_count(), 2);
+        assert!(
+            mempool
+                .orphans
+                .take_children(&txid("a").to_string())
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_orphan_stats() {
+        let mut mempool = Mempool::new(2);
+        let confirmed = |id: &str| id == txid("root").to_string();
+        let parent = tx_spending(txid("root"), 5);
+        let child = tx_spending(parent.hash_val, 4);
+
+        mempool.accept(child.clone(), confirmed);
+        mempool.accept(child, confirmed);
+        assert_eq!(mempool.orphan_stats().received, 1);
+        assert_eq!(mempool.accept(parent, confirmed).len(), 2);
+        for parent_id in ["x", "y", "z"] {
+            mempool.accept(tx_spending(txid(parent_id), 1), confirmed);
+        }
+        mempool.clear();
+        assert_eq!(
+            mempool.orphan_stats(),
+            OrphanStats {
+                received: 4,
+                connected: 1,
+                expired: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_fee_histogram() {
+        let mut mempool = Mempool::default();
+        let confirmed = |id: &str| named(id, "confirmed");
+        let size = tx_spending(txid("confirmed"), 0).size().unwrap() as i32;
+        let output_value = |tx_id: &str, _| confirmed(tx_id).then_some(100 * size);
+
+        // Larger values take a few more bytes, so these pay a bit less than
+        // 0, 2000 and 6000 per 1000 bytes
+        let free = tx_spending(txid("confirmed-1"), 100 * size);
+        let normal = tx_spending(txid("confirmed-2"), 98 * size);
+        let high = tx_spending(txid("confirmed-3"), 94 * size);
+        // Spends a pooled parent without paying a fee
+        let child = tx_spending(normal.hash_val, 98 * size);
+        // Its parent is unknown, so its fee is too
+        let unknown = tx_spending(txid("elsewhere"), 0);
+        for tx in [&free, &normal, &high, &child] {
+            assert_eq!(mempool.accept(tx.clone(), confirmed).len(), 1);
+        }
+        mempool.txs.insert(unknown.hash_val, unknown);
+
+        let sizes: Vec<usize> = [&high, &normal, &free, &child]
+            .iter()
+            .map(|tx| tx.size().unwrap())
+            .collect();
+        assert_eq!(
+            mempool.fee_histogram(output_value).unwrap(),
+            vec![
+                (5000, sizes[0]),
+                (1000, sizes[0] + sizes[1]),
+                (0, sizes.iter().sum()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+
+use anyhow::{Result, anyhow};
+use bincode::{
+    config::standard,
+    serde::{decode_from_slice, encode_to_vec},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Amount, ChainParams, CoinSelectionStrategy, DECODE_CONFIG, OutPoint, TXOutput, Transaction,
+    UTXOSet, Wallets,
+};
+
+/// A transaction passed between an online node, which has the UTXO set, and
+/// an offline machine, which has the keys.
+///
+/// The online side builds it with `create`, the offline side signs it with
+/// `sign` and the online side checks and extracts the transaction with
+/// `finalize`. The spent transactions travel along so signing needs no
+/// chain.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Psbt {
+    pub tx: Transaction,
+    /// Transactions whose outputs `tx` spends, by id
+    pub prev_txs: HashMap<String, Transaction>,
+}
+
+impl Psbt {
+    /// Builds an unsigned payment, see `Transaction::new_unsigned`.
+    pub fn create(
+        from: &[String],
+        to: &str,
+        amount: i32,
+        fee: i32,
+        subtract_fee_from_amount: bool,
+        strategy: CoinSelectionStrategy,
+        utxo_set: &UTXOSet,
+    ) -> Result<Self> {
+        let tx = Transaction::new_unsigned(
+            from,
+            to,
+            amount,
+            fee,
+            subtract_fee_from_amount,
+            strategy,
+            utxo_set,
+        )?;
+        let mut prev_txs = HashMap::new();
+        for vin in &tx.v_in {
+            let prev_tx = utxo_set
+                .bc
+                .find_transaction(&vin.prev_out.txid.to_string())
+                .ok_or_else(|| anyhow!("Previous transaction {} not found", vin.prev_out.txid))?;
+            prev_txs.insert(prev_tx.id.clone(), prev_tx);
+        }
+        Ok(Self { tx, prev_txs })
+    }
+
+    pub fn to_hex(&self) -> Result<String> {
+        Ok(hex::encode(encode_to_vec(self, standard())?))
+    }
+
+    /// Decodes a PSBT, checking that its previous transactions are the
+    /// ones the inputs spend, see `check_prev_txs`.
+    pub fn from_hex(data: &str) -> Result<Self> {
+        let bytes = hex::decode(data.trim()).map_err(|e| anyhow!("Invalid hex: {}", e))?;
+        let (psbt, _): (Psbt, usize) = decode_from_slice(&bytes, DECODE_CONFIG)?;
+        psbt.check_prev_txs()?;
+        Ok(psbt)
+    }
+
+    /// Fails unless every previous transaction hashes to the id it is kept
+    /// under and every input spends one of them, so a tampered file can't
+    /// misreport what the inputs are worth.
+    fn check_prev_txs(&self) -> Result<()> {
+        for (id, prev_tx) in &self.prev_txs {
+            let hash = prev_tx.hash()?;
+            if hash.to_string() != *id {
+                return Err(anyhow!(
+                    "Previous transaction {} hashes to {} instead",
+                    id,
+                    hash
+                ));
+            }
+        }
+        if let Some(vin) = self
+            .tx
+            .v_in
+            .iter()
+            .find(|vin| !self.prev_txs.contains_key(&vin.prev_out.txid.to_string()))
+        {
+            return Err(anyhow!(
+                "Missing previous transaction {}",
+                vin.prev_out.txid
+            ));
+        }
+        Ok(())
+    }
+
+    /// Value of the spent outputs minus the value of the new ones. Fails
+    /// when the new ones are worth more, or a sum overflows.
+    pub fn fee(&self) -> Result<Amount> {
+        let mut spent = Amount::ZERO;
+        for vin in &self.tx.v_in {
+            let value = Amount::from_value(self.spent_output(&vin.prev_out)?.value)?;
+            spent = spent
+                .checked_add(value)
+                .ok_or_else(|| anyhow!("Amount overflow"))?;
+        }
+        let created = Amount::sum_values(self.tx.v_out.iter().map(|out| out.value))?;
+        spent
+            .checked_sub(created)
+            .ok_or_else(|| anyhow!("Transaction pays out {} but only spends {}", created, spent))
+    }
+
+    /// Signs every input `wallets` holds the key for on a chain using
+    /// `params`, leaving the others to another signer. Returns how many
+    /// inputs were signed.
+    pub fn sign(&mut self, wallets: &Wallets, params: &ChainParams) -> Result<usize> {
+        let mut signed = 0;
+        for in_id in 0..self.tx.v_in.len() {
+            let vin = &self.tx.v_in[in_id];
+            let out = self.spent_output(&vin.prev_out)?;
+            let Some(wallet) = wallets.find_by_pub_key_hash(&out.pub_key_hash, params) else {
+                continue;
+            };
+            let private_key = wallet.private_key.clone();
+            self.tx.v_in[in_id].pub_key = wallet.public_key.clone();
+            self.tx.sign_input(in_id, &private_key, &self.prev_txs)?;
+            signed += 1;
+        }
+        self.tx.set_id()?;
+        Ok(signed)
+    }
+
+    /// Number of inputs still waiting for a signature.
+    pub fn unsigned_inputs(&self) -> usize {
+        self.tx
+            .v_in
+            .iter()
+            .filter(|vin| vin.signature.is_empty())
+            .count()
+    }
+
+    /// Checks every signature against the keys of a chain using `params`
+    /// and returns the transaction ready to be sent.
+    pub fn finalize(self, params: &ChainParams) -> Result<Transaction> {
+        let missing = self.unsigned_inputs();
+        if missing > 0 {
+            return Err(anyhow!("{} inputs are not signed yet", missing));
+        }
+        if !self.tx.verify(self.prev_txs, params)? {
+            return Err(anyhow!("Transaction {} has invalid signatures", self.tx.id));
+        }
+        Ok(self.tx)
+    }
+
+    fn spent_output(&self, prev_out: &OutPoint) -> Result<&TXOutput> {
+        self.prev_txs
+            .get(&prev_out.txid.to_string())
+            .and_then(|tx| tx.v_out.get(prev_out.vout as usize))
+            .ok_or_else(|| anyhow!("Missing spent output {}", prev_out))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Blockchain, testutil::TestDir};
+
+    #[test]
+    fn test_offline_signing() {
+        let params = ChainParams::default();
+        let dir = TestDir::new("psbt");
+        // The keys only live on the offline machine
+        let mut offline = Wallets::default();
+        let online = Wallets::default();
+        let from = offline.create_wallet(&params);
+        let to = offline.create_wallet(&params);
+
+        let bc = Blockchain::create_at(dir.join("blockchain"), &from).unwrap();
+        let mut utxo_set = UTXOSet::with_path(bc, dir.join("utxos"));
+        utxo_set.reindex().unwrap();
+
+        let psbt = Psbt::create(
+            std::slice::from_ref(&from),
+            &to,
+            4,
+            1,
+            false,
+            CoinSelectionStrategy::default(),
+            &utxo_set,
+        )
+        .unwrap();
+        assert_eq!(psbt.fee().unwrap(), Amount::new(1));
+        // Outputs worth more than the inputs are an error, not a negative fee
+        let mut greedy = psbt.clone();
+        greedy.tx.v_out[0].value = i32::MAX;
+        assert!(greedy.fee().is_err());
+        let mut psbt = Psbt::from_hex(&psbt.to_hex().unwrap()).unwrap();
+
+        // A file misreporting what an input is worth, or lacking what it
+        // spends, is refused
+        let spent = psbt.tx.v_in[0].prev_out.txid.to_string();
+        let mut inflated = psbt.clone();
+        inflated.prev_txs.get_mut(&spent).unwrap().v_out[0].value += 100;
+        assert!(Psbt::from_hex(&inflated.to_hex().unwrap()).is_err());
+        let mut missing = psbt.clone();
+        missing.prev_txs.clear();
+        assert!(Psbt::from_hex(&missing.to_hex().unwrap()).is_err());
+
+        assert_eq!(psbt.sign(&online, &utxo_set.bc.params).unwrap(), 0);
+        assert!(psbt.clone().finalize(&utxo_set.bc.params).is_err());
+
+        assert_eq!(psbt.sign(&offline, &utxo_set.bc.params).unwrap(), 1);
+        let signed = Psbt::from_hex(&psbt.to_hex().unwrap()).unwrap();
+        let tx = signed.finalize(&utxo_set.bc.params).unwrap();
+        assert!(utxo_set.bc.verify_transaction(&tx).unwrap());
+        assert_eq!(tx.id, tx.hash().unwrap().to_string());
+    }
+}
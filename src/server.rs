@@ -1,10 +1,11 @@
 use std::{
     collections::{HashMap, HashSet},
+    fmt,
     io::{Read, Write},
     net::{TcpListener, TcpStream},
     sync::{Arc, RwLock},
     thread,
-    time::Duration,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::{Result, anyhow};
@@ -12,10 +13,17 @@ use bincode::{
     config::standard,
     serde::{decode_from_slice, encode_to_vec},
 };
-use log::{error, info};
+use clap::ValueEnum;
+use log::{Level, error, info, log_enabled, trace};
 use serde::{Deserialize, Serialize};
 
-use crate::{Block, HashType, Transaction, UTXOSet};
+use crate::{
+    Amount, Block, BlockchainReader, DECODE_CONFIG, Durability, Hash, MAX_BLOCK_SIZE,
+    MAX_BLOCK_TXS, MAX_COINBASE_DATA, MAX_DECODE_BYTES, MAX_LOCATOR_LEN, MAX_MEMPOOL_INV,
+    MAX_TX_INPUTS, MAX_TX_OUTPUTS, MEMPOOL_EXPIRY_MS, Mempool, OrphanStats, Transaction, UTXOSet,
+    UtxoMismatch, estimate_smart_fee, get_pub_key_hash, remove_conflicts,
+    select_block_transactions, validate_address,
+};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
@@ -29,15 +37,18 @@ pub enum Message {
     Inv {
         addr_from: String,
         kind: String,
-        items: Vec<HashType>,
+        items: Vec<Hash>,
     },
+    /// Asks for the hashes of the blocks following the newest block of
+    /// `locator` the peer also has
     GetBlocks {
         addr_from: String,
+        locator: Vec<Hash>,
     },
     GetData {
         addr_from: String,
         kind: String,
-        id: HashType,
+        id: Hash,
     },
     Tx {
         addr_from: String,
@@ -48,6 +59,147 @@ pub enum Message {
         version: i32,
         best_height: i32,
     },
+    /// Acknowledges a `Version`, completing the sender's side of the handshake
+    VerAck {
+        addr_from: String,
+    },
+    /// Local query for the best height, answered with `Height` on the same
+    /// connection. Queries are only accepted from loopback by default.
+    GetHeight,
+    GetBalance {
+        address: String,
+    },
+    /// Looks a transaction up in the chain, then in the mempool
+    GetTx {
+        id: Hash,
+    },
+    GetFeeHistogram,
+    EstimateSmartFee,
+    /// Looks an unspent output up, see `Server::get_tx_out`
+    GetTxOut {
+        tx_id: String,
+        v_out: i32,
+        include_mempool: bool,
+    },
+    GetSyncStatus,
+    GetNodeInfo,
+    GetNetworkHeight,
+    GetPeerInfo,
+    /// Asks a miner to mine its mempool right away, see `Server::mine_now`
+    MineNow,
+    /// Hands a transaction to the node like a `Tx` message, but waits for
+    /// what the node did with it
+    SubmitTx {
+        transaction: Transaction,
+    },
+    Height {
+        best_height: i32,
+    },
+    /// Answer to `GetBalance`, `immature` is the part of `balance` in
+    /// coinbases that can't be spent yet
+    Balance {
+        address: String,
+        balance: Amount,
+        immature: Amount,
+    },
+    /// Answer to `GetTx`, `block_height` is `None` for mempool transactions
+    TxInfo {
+        transaction: Option<Transaction>,
+        block_height: Option<i32>,
+        best_height: i32,
+    },
+    /// Answer to `GetFeeHistogram`, see `Mempool::fee_histogram`
+    FeeHistogram {
+        buckets: Vec<(u64, usize)>,
+    },
+    /// Answer to `EstimateSmartFee`, see `mempool::estimate_smart_fee`
+    SmartFee {
+        fee_rate: u64,
+    },
+    /// Answer to `GetTxOut`, `None` when the output is spent or unknown
+    TxOut {
+        output: Option<TxOutInfo>,
+    },
+    SyncStatus {
+        status: SyncStatus,
+    },
+    NodeInfo {
+        info: NodeInfo,
+    },
+    /// Answer to `GetNetworkHeight`, see `Server::network_height`
+    NetworkHeight {
+        best_height: i32,
+        network_height: i32,
+    },
+    /// Answer to `GetPeerInfo`, see `Server::peer_info`
+    PeerInfo {
+        peers: Vec<PeerInfo>,
+    },
+    /// Answer to `MineNow`
+    Mined {
+        hashes: Vec<Hash>,
+    },
+    /// Answer to `SubmitTx`
+    TxSubmitted {
+        verdict: TxVerdict,
+    },
+}
+
+/// What a node did with a transaction it received, see
+/// `Server::receive_tx`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum TxVerdict {
+    /// In the mempool, and relayed or mined
+    Accepted,
+    /// Held until a parent shows up
+    Orphan,
+    Rejected {
+        reason: String,
+    },
+}
+
+/// An unspent output, see `Server::get_tx_out`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TxOutInfo {
+    pub value: i32,
+    pub pub_key_hash: Vec<u8>,
+    /// 0 for an output of a mempool transaction
+    pub confirmations: i32,
+    /// Height and hash of the block that created the output, `None` for
+    /// an output of a mempool transaction
+    pub origin: Option<(i32, Hash)>,
+}
+
+/// How far along a node is in catching up with its peers, see
+/// `Server::sync_status`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyncStatus {
+    pub best_height: i32,
+    /// Highest height we know of, ours or one announced by a peer
+    pub header_height: i32,
+    pub is_synced: bool,
+}
+
+/// A known peer, see `Server::peer_info`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PeerInfo {
+    pub addr: String,
+    /// Blocks and transactions are only relayed once it is
+    pub handshake_complete: bool,
+    /// From the peer's latest `Version`, `None` if it hasn't sent one
+    pub best_height: Option<i32>,
+}
+
+/// Overview of a running node, see `Server::node_info`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct NodeInfo {
+    pub best_height: i32,
+    /// See `Server::network_height`
+    pub network_height: i32,
+    pub known_nodes: usize,
+    pub mempool_txs: usize,
+    pub orphan_txs: usize,
+    pub orphans: OrphanStats,
 }
 
 impl Message {
@@ -61,6 +213,190 @@ impl Message {
             Message::GetData { addr_from, .. } => addr_from,
             Message::Tx { addr_from, .. } => addr_from,
             Message::Version { addr_from, .. } => addr_from,
+            Message::VerAck { addr_from } => addr_from,
+            _ => "", // Queries and their answers come from local tools
+        }
+    }
+
+    fn is_query(&self) -> bool {
+        matches!(
+            self,
+            Message::GetHeight
+                | Message::GetBalance { .. }
+                | Message::GetTx { .. }
+                | Message::GetFeeHistogram
+                | Message::EstimateSmartFee
+                | Message::GetTxOut { .. }
+                | Message::GetSyncStatus
+                | Message::GetNodeInfo
+                | Message::GetNetworkHeight
+                | Message::GetPeerInfo
+                | Message::MineNow
+                | Message::SubmitTx { .. }
+        )
+    }
+
+    /// Copy for logging, with the signature and public key of every input
+    /// cut down to their first `LOGGED_KEY_BYTES` bytes.
+    fn redacted(&self) -> Message {
+        let mut msg = self.clone();
+        let txs: Vec<&mut Transaction> = match &mut msg {
+            Message::Block { block, .. } => block.transactions.iter_mut().collect(),
+            Message::Tx { transaction, .. } | Message::SubmitTx { transaction } => {
+                vec![transaction]
+            }
+            Message::TxInfo {
+                transaction: Some(transaction),
+                ..
+            } => vec![transaction],
+            _ => vec![],
+        };
+        for tx in txs {
+            for vin in &mut tx.v_in {
+                vin.signature.truncate(LOGGED_KEY_BYTES);
+                vin.pub_key.truncate(LOGGED_KEY_BYTES);
+            }
+        }
+        msg
+    }
+}
+
+/// Logs `msg` as a one line summary at `info`, and in full, redacted, at
+/// `trace`. Run with `RUST_LOG=rs_blockchain::server=trace` to see the
+/// contents.
+fn log_message(action: &str, peer: &str, msg: &Message) {
+    info!("{} {} ({})", action, msg, peer);
+    if log_enabled!(Level::Trace) {
+        trace!("{} {:?}", action, msg.redacted());
+    }
+}
+
+/// One line summary: the kind of message, who sent it, and the hashes or
+/// counts of what it carries, but never keys or signatures.
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Message::Addr { nodes } => write!(f, "addr: {} nodes", nodes.len()),
+            Message::Block { addr_from, block } => write!(
+                f,
+                "block from {}: {} at height {}, {} txs",
+                addr_from,
+                block.hash,
+                block.height,
+                block.transactions.len()
+            ),
+            Message::Inv {
+                addr_from,
+                kind,
+                items,
+            } => {
+                write!(f, "inv from {}: {} {}", addr_from, items.len(), kind)?;
+                for item in items.iter().take(LOGGED_INV_ITEMS) {
+                    write!(f, " {}", item)?;
+                }
+                if items.len() > LOGGED_INV_ITEMS {
+                    write!(f, " ...")?;
+                }
+                Ok(())
+            }
+            Message::GetBlocks { addr_from, locator } => write!(
+                f,
+                "getblocks from {}: {} locator hashes",
+                addr_from,
+                locator.len()
+            ),
+            Message::GetData {
+                addr_from,
+                kind,
+                id,
+            } => write!(f, "getdata from {}: {} {}", addr_from, kind, id),
+            Message::Tx {
+                addr_from,
+                transaction,
+            } => write!(
+                f,
+                "tx from {}: {}, {} in, {} out",
+                addr_from,
+                transaction.id,
+                transaction.v_in.len(),
+                transaction.v_out.len()
+            ),
+            Message::Version {
+                addr_from,
+                version,
+                best_height,
+            } => write!(
+                f,
+                "version from {}: version {}, best height {}",
+                addr_from, version, best_height
+            ),
+            Message::VerAck { addr_from } => write!(f, "verack from {}", addr_from),
+            Message::GetHeight => write!(f, "getheight"),
+            Message::GetBalance { address } => write!(f, "getbalance {}", address),
+            Message::GetTx { id } => write!(f, "gettx {}", id),
+            Message::GetFeeHistogram => write!(f, "getfeehistogram"),
+            Message::EstimateSmartFee => write!(f, "estimatesmartfee"),
+            Message::GetTxOut { tx_id, v_out, .. } => write!(f, "gettxout {}:{}", tx_id, v_out),
+            Message::GetSyncStatus => write!(f, "getsyncstatus"),
+            Message::GetNodeInfo => write!(f, "getnodeinfo"),
+            Message::GetNetworkHeight => write!(f, "getnetworkheight"),
+            Message::GetPeerInfo => write!(f, "getpeerinfo"),
+            Message::MineNow => write!(f, "minenow"),
+            Message::SubmitTx { transaction } => write!(f, "submittx {}", transaction.id),
+            Message::Height { best_height } => write!(f, "height {}", best_height),
+            Message::Balance {
+                address,
+                balance,
+                immature,
+            } => {
+                write!(
+                    f,
+                    "balance of {}: {}, {} immature",
+                    address, balance, immature
+                )
+            }
+            Message::TxInfo {
+                transaction,
+                block_height,
+                ..
+            } => match (transaction, block_height) {
+                (Some(tx), Some(height)) => write!(f, "txinfo {} at height {}", tx.id, height),
+                (Some(tx), None) => write!(f, "txinfo {} in mempool", tx.id),
+                (None, _) => write!(f, "txinfo: not found"),
+            },
+            Message::FeeHistogram { buckets } => {
+                write!(f, "feehistogram: {} buckets", buckets.len())
+            }
+            Message::SmartFee { fee_rate } => write!(f, "smartfee: {}/kB", fee_rate),
+            Message::TxOut { output } => match output {
+                Some(out) => write!(
+                    f,
+                    "txout: value {}, {} confirmations",
+                    out.value, out.confirmations
+                ),
+                None => write!(f, "txout: not found"),
+            },
+            Message::SyncStatus { status } => write!(
+                f,
+                "syncstatus: height {} of {}, synced {}",
+                status.best_height, status.header_height, status.is_synced
+            ),
+            Message::NodeInfo { info } => write!(
+                f,
+                "nodeinfo: height {}, {} mempool txs, {} orphans",
+                info.best_height, info.mempool_txs, info.orphan_txs
+            ),
+            Message::NetworkHeight {
+                best_height,
+                network_height,
+            } => write!(f, "networkheight: {} of {}", best_height, network_height),
+            Message::PeerInfo { peers } => write!(f, "peerinfo: {} peers", peers.len()),
+            Message::Mined { hashes } => write!(f, "mined {} blocks", hashes.len()),
+            Message::TxSubmitted { verdict } => match verdict {
+                TxVerdict::Accepted => write!(f, "txsubmitted: accepted"),
+                TxVerdict::Orphan => write!(f, "txsubmitted: orphan"),
+                TxVerdict::Rejected { reason } => write!(f, "txsubmitted: rejected, {}", reason),
+            },
         }
     }
 }
@@ -75,12 +411,15 @@ impl MessageHandler for Message {
             Message::Addr { nodes } => {
                 log::info!("Receive address msg: {:?}", nodes);
                 for node in nodes {
-                    server.add_node(node);
+                    if !server.node_is_known(node) {
+                        server.add_node(node);
+                        server.send_version(node)?;
+                    }
                 }
                 Ok(())
             }
             Message::Block { addr_from, block } => {
-                log::info!("Receive block msg: {}, {:?}", addr_from, block,);
+                log::info!("Receive block msg: {}, {}", addr_from, block.hash);
                 server.add_block(block)?;
                 let mut in_transit = server.get_in_transit();
                 if !in_transit.is_empty() {
@@ -95,8 +434,11 @@ impl MessageHandler for Message {
                     )?;
                     in_transit.remove(0);
                     server.replace_in_transit(in_transit);
+                    server.track_download(addr_from);
                 } else {
-                    server.utxo_reindex()?;
+                    server.finish_download();
+                    server.flush_chain()?;
+                    server.utxo_sync()?;
                 }
                 Ok(())
             }
@@ -111,8 +453,12 @@ impl MessageHandler for Message {
                     kind,
                     items
                 );
-                if kind == "block" {
-                    let block_hash = items[0];
+                if kind == "block"
+                    && let Some(&block_hash) = items.last()
+                {
+                    // Inventories list blocks newest first, fetch them oldest
+                    // first so the outputs a block spends are already stored
+                    // when it gets validated
                     server.send_message(
                         addr_from,
                         Message::GetData {
@@ -121,41 +467,44 @@ impl MessageHandler for Message {
                             id: block_hash,
                         },
                     )?;
-                    let new_in_transit: Vec<HashType> = items
+                    let new_in_transit: Vec<Hash> = items
                         .iter()
+                        .rev()
                         .filter(|b| **b != block_hash)
                         .cloned()
                         .collect();
                     server.replace_in_transit(new_in_transit);
+                    server.track_download(addr_from);
                 } else if kind == "tx" {
-                    let txid = items[0];
-                    match server.get_mempool_tx(&txid) {
-                        Some(tx) if tx.id.is_empty() => {
+                    for txid in items {
+                        if server.get_mempool_tx(txid).is_none() {
                             server.send_message(
                                 addr_from,
                                 Message::GetData {
                                     addr_from: server.node_address.clone(),
                                     kind: "tx".to_string(),
-                                    id: txid,
+                                    id: *txid,
                                 },
                             )?;
                         }
-                        None => server.send_message(
-                            addr_from,
-                            Message::GetData {
-                                addr_from: server.node_address.clone(),
-                                kind: "tx".to_string(),
-                                id: txid,
-                            },
-                        )?,
-                        _ => {}
                     }
                 }
                 Ok(())
             }
-            Message::GetBlocks { addr_from } => {
+            Message::GetBlocks { addr_from, locator } => {
                 log::info!("Receive get blocks msg: addr_from={}", addr_from);
-                let block_hashs = server.get_block_hashs();
+                if locator.len() > MAX_LOCATOR_LEN {
+                    return Err(anyhow!(
+                        "Locator of {} hashes from {}, at most {} are allowed",
+                        locator.len(),
+                        addr_from,
+                        MAX_LOCATOR_LEN
+                    ));
+                }
+                let block_hashs = server.hashes_after_locator(locator);
+                if block_hashs.is_empty() {
+                    return Ok(());
+                }
                 server.send_message(
                     addr_from,
                     Message::Inv {
@@ -175,7 +524,7 @@ impl MessageHandler for Message {
                     "Receive get data msg: addr_from={}, kind={}, id={}",
                     addr_from,
                     kind,
-                    hex::encode(id)
+                    id
                 );
                 if kind == "block" {
                     let block = server.get_block(id)?;
@@ -186,16 +535,26 @@ impl MessageHandler for Message {
                             block,
                         },
                     )?;
-                } else if kind == "tx" {
-                    if let Some(tx) = server.get_mempool_tx(id) {
-                        server.send_message(
-                            addr_from,
-                            Message::Tx {
-                                addr_from: server.node_address.clone(),
-                                transaction: tx,
-                            },
-                        )?;
-                    }
+                } else if kind == "mempool" {
+                    // `id` is unused, the reply lists the whole pool
+                    server.send_message(
+                        addr_from,
+                        Message::Inv {
+                            addr_from: server.node_address.clone(),
+                            kind: "tx".to_string(),
+                            items: server.mempool_inventory(),
+                        },
+                    )?;
+                } else if kind == "tx"
+                    && let Some(tx) = server.get_mempool_tx(id)
+                {
+                    server.send_message(
+                        addr_from,
+                        Message::Tx {
+                            addr_from: server.node_address.clone(),
+                            transaction: tx,
+                        },
+                    )?;
                 }
                 Ok(())
             }
@@ -208,66 +567,7 @@ impl MessageHandler for Message {
                     addr_from,
                     transaction.id
                 );
-                server.insert_mempool(transaction.clone());
-                if server.node_address == server.config.centeral_node {
-                    for node in server.get_known_nodes() {
-                        if node != server.node_address && node != *addr_from {
-                            server.send_message(
-                                &node,
-                                Message::Inv {
-                                    addr_from: server.node_address.clone(),
-                                    kind: "tx".to_string(),
-                                    items: vec![transaction.hash_val],
-                                },
-                            )?;
-                        }
-                    }
-                } else if !server.mining_address.is_empty() {
-                    let mut mempool = server.get_mempool();
-                    log::info!("Current mempool: {:#?}", &mempool);
-                    if !mempool.is_empty() {
-                        loop {
-                            let mut txs = Vec::new();
-                            for tx in mempool.values() {
-                                if server.verify_tx(tx)? {
-                                    txs.push(tx.clone());
-                                }
-                            }
-                            if txs.is_empty() {
-                                return Ok(());
-                            }
-
-                            let cbtx =
-                                Transaction::new_coinbase(&server.mining_address, String::new())?;
-                            txs.push(cbtx);
-
-                            for tx in &txs {
-                                mempool.remove(&tx.hash_val);
-                            }
-
-                            let new_block = server.mine_block(txs)?;
-                            server.utxo_reindex()?;
-
-                            for node in server.get_known_nodes() {
-                                if node != server.node_address {
-                                    server.send_message(
-                                        &node,
-                                        Message::Inv {
-                                            addr_from: server.node_address.clone(),
-                                            kind: "block".to_string(),
-                                            items: vec![new_block.hash],
-                                        },
-                                    )?;
-                                }
-                            }
-
-                            if mempool.is_empty() {
-                                break;
-                            }
-                        }
-                        server.clear_mempool();
-                    }
-                }
+                server.receive_tx(addr_from, transaction)?;
                 Ok(())
             }
             Message::Version {
@@ -281,23 +581,39 @@ impl MessageHandler for Message {
                     version,
                     best_height
                 );
+                if !server.node_is_known(addr_from) {
+                    server.add_node(addr_from);
+                }
+                // Unless it answers ours, a version starts a new session: the
+                // peer is new, or reconnected and lost our height and mempool,
+                // so the handshake starts over
+                let answer = server.take_version_request(addr_from);
+                if !answer {
+                    server.set_peer_state(addr_from, PeerState::Connecting);
+                }
+                server.note_peer_height(addr_from, *best_height);
+                server.send_message(
+                    addr_from,
+                    Message::VerAck {
+                        addr_from: server.node_address.clone(),
+                    },
+                )?;
                 let my_best_height = server.get_best_height()?;
                 if my_best_height < *best_height {
                     server.send_message(
                         addr_from,
                         Message::GetBlocks {
                             addr_from: server.node_address.clone(),
+                            locator: server.block_locator(),
                         },
                     )?;
-                } else if my_best_height > *best_height {
-                    server.send_message(
-                        addr_from,
-                        Message::Version {
-                            addr_from: server.node_address.clone(),
-                            version: server.config.version,
-                            best_height: my_best_height,
-                        },
-                    )?;
+                }
+                // Answer with our own version, so the peer learns our height
+                // and both sides finish the handshake. An answer to ours
+                // already follows our height, and answering it in turn would
+                // never end.
+                if !answer {
+                    server.answer_version(addr_from)?;
                 }
                 server.send_message(
                     addr_from,
@@ -305,11 +621,44 @@ impl MessageHandler for Message {
                         nodes: server.get_known_nodes(),
                     },
                 )?;
-                if !server.node_is_known(addr_from) {
-                    server.add_node(addr_from);
+                Ok(())
+            }
+            Message::VerAck { addr_from } => {
+                log::info!("Receive verack msg: addr_from={}", addr_from);
+                if server.complete_handshake(addr_from) {
+                    server.send_mempool_inv(addr_from)?;
                 }
                 Ok(())
             }
+            Message::GetHeight
+            | Message::GetBalance { .. }
+            | Message::GetTx { .. }
+            | Message::GetFeeHistogram
+            | Message::EstimateSmartFee
+            | Message::GetTxOut { .. }
+            | Message::GetSyncStatus
+            | Message::GetNodeInfo
+            | Message::GetNetworkHeight
+            | Message::GetPeerInfo
+            | Message::MineNow
+            | Message::SubmitTx { .. } => {
+                Err(anyhow!("Query messages are answered on their connection"))
+            }
+            Message::Height { .. }
+            | Message::Balance { .. }
+            | Message::TxInfo { .. }
+            | Message::FeeHistogram { .. }
+            | Message::SmartFee { .. }
+            | Message::TxOut { .. }
+            | Message::SyncStatus { .. }
+            | Message::NodeInfo { .. }
+            | Message::NetworkHeight { .. }
+            | Message::PeerInfo { .. }
+            | Message::Mined { .. }
+            | Message::TxSubmitted { .. } => {
+                log::warn!("Ignoring unsolicited query answer");
+                Ok(())
+            }
         }
     }
 }
@@ -319,20 +668,93 @@ pub struct Server {
     node_address: String,
     mining_address: String,
     inner: Arc<RwLock<ServerInner>>,
+    /// Snapshot of the chain for queries that shouldn't wait on `inner`
+    reader: Arc<RwLock<BlockchainReader>>,
+    /// Kept apart from `inner` so mempool traffic doesn't contend with chain
+    /// updates. When both are needed, lock `inner` first, then `mempool`.
+    mempool: Arc<RwLock<Mempool>>,
     config: Config,
 }
 
+/// Handshake progress with a peer. Blocks and transactions are only relayed
+/// to peers in `HandshakeComplete`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PeerState {
+    /// Known, but we haven't sent our `Version` yet
+    Connecting,
+    /// Our `Version` is out, waiting for the peer's `VerAck`
+    VersionSent,
+    HandshakeComplete,
+}
+
 struct ServerInner {
     known_nodes: HashSet<String>,
+    peer_states: HashMap<String, PeerState>,
     utxo: UTXOSet,
-    blocks_in_transit: Vec<HashType>,
-    mempool: HashMap<HashType, Transaction>,
+    blocks_in_transit: Vec<Hash>,
+    download: Option<BlockDownload>,
+    /// Peers we recently failed to connect to
+    backoff: HashMap<String, PeerBackoff>,
+    /// Last time our tip moved, for noticing when we stop hearing of blocks
+    tip_changed_at: Instant,
+    /// Best height each peer announced in its latest `Version`
+    peer_heights: HashMap<String, i32>,
+    /// Peers we sent a `Version` to first, whose own hasn't come back yet
+    version_requests: HashSet<String>,
+}
+
+/// Highest best height announced by the peers we know, `None` until one
+/// has sent its `Version`.
+fn best_peer_height(inner: &ServerInner) -> Option<i32> {
+    inner.peer_heights.values().copied().max()
+}
+
+/// Consecutive connection failures to a peer and when to try it again.
+struct PeerBackoff {
+    failures: u32,
+    retry_at: Instant,
+}
+
+/// A block download in progress, watched for stalls.
+struct BlockDownload {
+    peer: String,
+    /// When the last requested block arrived, or the download started
+    last_progress: Instant,
+}
+
+/// Which connections a node makes and accepts.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ConnectMode {
+    /// Dial the central node but never listen, e.g. for privacy behind NAT.
+    /// Peers answer on new connections, so only what is sent on the
+    /// connection we open gets through.
+    Outbound,
+    /// Listen but never dial the central node, e.g. for a well known server
+    Inbound,
+    #[default]
+    Both,
 }
 
 #[derive(Clone)]
 pub struct Config {
     centeral_node: String,
     version: i32,
+    finality_depth: i32,
+    download_stall_timeout: Duration,
+    reconnect_backoff: Duration,
+    max_reconnect_backoff: Duration,
+    max_tx_inputs: usize,
+    max_tx_outputs: usize,
+    stale_tip_interval: Duration,
+    durability: Durability,
+    allow_remote_queries: bool,
+    utxo_check_interval: Option<Duration>,
+    utxo_auto_reindex: bool,
+    health_port: Option<u16>,
+    connect_mode: ConnectMode,
+    coinbase_message: String,
+    coinbase_maturity: i32,
+    utxo_confirmation_depth: usize,
 }
 
 impl Default for Config {
@@ -340,11 +762,183 @@ impl Default for Config {
         Config {
             centeral_node: CENTERAL_NODE.to_owned(),
             version: 1,
+            finality_depth: DEFAULT_FINALITY_DEPTH,
+            download_stall_timeout: DEFAULT_DOWNLOAD_STALL_TIMEOUT,
+            reconnect_backoff: DEFAULT_RECONNECT_BACKOFF,
+            max_reconnect_backoff: DEFAULT_MAX_RECONNECT_BACKOFF,
+            max_tx_inputs: MAX_TX_INPUTS,
+            max_tx_outputs: MAX_TX_OUTPUTS,
+            stale_tip_interval: DEFAULT_STALE_TIP_INTERVAL,
+            durability: Durability::Full,
+            allow_remote_queries: false,
+            utxo_check_interval: None,
+            utxo_auto_reindex: false,
+            health_port: None,
+            connect_mode: ConnectMode::default(),
+            coinbase_message: String::new(),
+            coinbase_maturity: DEFAULT_COINBASE_MATURITY,
+            utxo_confirmation_depth: DEFAULT_FINALITY_DEPTH as usize,
         }
     }
 }
 
+impl Config {
+    /// Number of confirmations after which a block is treated as final.
+    ///
+    /// This is the default for `getbalance --min-confirmations`, decides when
+    /// `gettransaction` reports a transaction as final, and bounds reorgs: a
+    /// competing chain that would disconnect more blocks than this is logged
+    /// as a critical event and refused instead of being applied.
+    pub fn finality_depth(&self) -> i32 {
+        self.finality_depth
+    }
+
+    /// Confirmations a coinbase needs before its outputs are spent by new
+    /// transactions. Until then `getbalance` reports them as immature.
+    pub fn coinbase_maturity(&self) -> i32 {
+        self.coinbase_maturity
+    }
+
+    pub fn central_node(&self) -> &str {
+        &self.centeral_node
+    }
+
+    pub fn with_finality_depth(mut self, depth: i32) -> Self {
+        self.finality_depth = depth;
+        self
+    }
+
+    pub fn with_coinbase_maturity(mut self, maturity: i32) -> Self {
+        self.coinbase_maturity = maturity;
+        self
+    }
+
+    /// Blocks whose effects on the UTXO set are kept in memory before being
+    /// written, so a reorg disconnecting them needs no reindex. Defaults to
+    /// the finality depth, deeper reorgs being refused anyway.
+    pub fn with_utxo_confirmation_depth(mut self, depth: usize) -> Self {
+        self.utxo_confirmation_depth = depth;
+        self
+    }
+
+    /// How long a block download may go without receiving a block before
+    /// it is abandoned and restarted from another peer.
+    pub fn with_download_stall_timeout(mut self, timeout: Duration) -> Self {
+        self.download_stall_timeout = timeout;
+        self
+    }
+
+    /// After a failed connection, a peer isn't contacted again for `base`,
+    /// doubling with each further failure up to `max`. A successful
+    /// connection resets the schedule.
+    pub fn with_reconnect_backoff(mut self, base: Duration, max: Duration) -> Self {
+        self.reconnect_backoff = base;
+        self.max_reconnect_backoff = max;
+        self
+    }
+
+    /// With `Durability::Batch`, blocks received while catching up with a
+    /// peer are flushed once the download completes instead of one by one.
+    /// Blocks we mine or receive on their own are always flushed right away.
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Answers `GetHeight`, `GetBalance` and `GetTx` from any address
+    /// instead of only from loopback.
+    pub fn with_remote_queries(mut self, allow: bool) -> Self {
+        self.allow_remote_queries = allow;
+        self
+    }
+
+    /// If no new block arrives for this long, the tip is considered stale
+    /// and known peers are asked for blocks again.
+    pub fn with_stale_tip_interval(mut self, interval: Duration) -> Self {
+        self.stale_tip_interval = interval;
+        self
+    }
+
+    /// Compares the UTXO set with the chain every `interval` and logs an
+    /// error when they disagree. Off by default, the check rescans the
+    /// whole chain.
+    pub fn with_utxo_check_interval(mut self, interval: Duration) -> Self {
+        self.utxo_check_interval = Some(interval);
+        self
+    }
+
+    /// Rebuilds the UTXO set when the periodic check finds it doesn't match
+    /// the chain, instead of only logging it.
+    pub fn with_utxo_auto_reindex(mut self, auto_reindex: bool) -> Self {
+        self.utxo_auto_reindex = auto_reindex;
+        self
+    }
+
+    /// Serves `GET /health` over HTTP on `port`, answering 200 once the
+    /// node is synced and 503 before, for load balancers and orchestrators.
+    /// `GET /metrics` on the same port gives Prometheus metrics.
+    pub fn with_health_port(mut self, port: u16) -> Self {
+        self.health_port = Some(port);
+        self
+    }
+
+    /// Whether `Server::start` listens for peers, dials the central node,
+    /// or both.
+    pub fn with_connect_mode(mut self, mode: ConnectMode) -> Self {
+        self.connect_mode = mode;
+        self
+    }
+
+    /// Data to put in the coinbase of the blocks we mine. By default they
+    /// carry a note of the miner's address.
+    pub fn with_coinbase_message(mut self, message: &str) -> Self {
+        self.coinbase_message = message.to_owned();
+        self
+    }
+
+    /// Node new peers first connect to, and the only one relaying
+    /// transactions instead of mining them.
+    pub fn with_central_node(mut self, addr: &str) -> Self {
+        self.centeral_node = addr.to_owned();
+        self
+    }
+
+    /// Caps the inputs and outputs of a transaction. Larger transactions
+    /// are refused by the mempool and make any block containing them
+    /// invalid, so every node on a network must use the same caps.
+    pub fn with_tx_limits(mut self, max_inputs: usize, max_outputs: usize) -> Self {
+        self.max_tx_inputs = max_inputs;
+        self.max_tx_outputs = max_outputs;
+        self
+    }
+
+    /// How long to wait before reconnecting after `failures` consecutive
+    /// failed attempts.
+    pub fn reconnect_delay(&self, failures: u32) -> Duration {
+        let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+        self.reconnect_backoff
+            .saturating_mul(factor)
+            .min(self.max_reconnect_backoff)
+    }
+}
+
 const CENTERAL_NODE: &str = "localhost:3000";
+pub const DEFAULT_FINALITY_DEPTH: i32 = 6;
+/// Confirmations a coinbase needs before it can be spent, see
+/// `Config::coinbase_maturity`
+pub const DEFAULT_COINBASE_MATURITY: i32 = 100;
+pub const DEFAULT_DOWNLOAD_STALL_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_STALE_TIP_INTERVAL: Duration = Duration::from_secs(600);
+pub const DEFAULT_RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+pub const DEFAULT_MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(300);
+/// How long a local query waits for the node to answer
+const QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+/// Delay before confirming a UTXO set mismatch
+const UTXO_RECHECK_DELAY: Duration = Duration::from_secs(1);
+/// Bytes of each signature and public key kept in `trace` message dumps
+const LOGGED_KEY_BYTES: usize = 4;
+/// Inventory hashes listed in a message summary
+const LOGGED_INV_ITEMS: usize = 4;
 
 #[derive(Default)]
 pub struct ServerBuilder {
@@ -381,19 +975,52 @@ impl ServerBuilder {
 
     pub fn build(self) -> Result<Server> {
         let port = self.port.ok_or_else(|| anyhow!("Missing port"))?;
+        let port: u16 = port
+            .parse()
+            .map_err(|_| anyhow!("Invalid port '{}', expected 0 to 65535", port))?;
         let miner_address = self.miner_address.unwrap_or_default();
-        let utxo = self.utxo.ok_or_else(|| anyhow!("Missing UTXO set"))?;
+        if self.config.coinbase_message.len() > MAX_COINBASE_DATA {
+            return Err(anyhow!(
+                "Coinbase message is {} bytes, at most {} are allowed",
+                self.config.coinbase_message.len(),
+                MAX_COINBASE_DATA
+            ));
+        }
+        let mut utxo = self
+            .utxo
+            .ok_or_else(|| anyhow!("Missing UTXO set"))?
+            .with_coinbase_maturity(self.config.coinbase_maturity)
+            .with_confirmation_depth(self.config.utxo_confirmation_depth);
+        // An empty address, like none, means the node doesn't mine
+        if !miner_address.is_empty() {
+            validate_address(&miner_address, &utxo.bc.params)
+                .map_err(|e| anyhow!("Bad miner address: {}", e))?;
+        }
+        utxo.bc.params.max_tx_inputs = self.config.max_tx_inputs;
+        utxo.bc.params.max_tx_outputs = self.config.max_tx_outputs;
+        utxo.bc.durability = self.config.durability;
         let mut known_nodes = HashSet::new();
         known_nodes.insert(self.config.centeral_node.clone());
+        let reader = utxo.bc.reader();
         Ok(Server {
             node_address: format!("localhost:{}", port).to_string(),
             mining_address: miner_address,
+            reader: Arc::new(RwLock::new(reader)),
             inner: Arc::new(RwLock::new(ServerInner {
                 known_nodes,
+                peer_states: HashMap::from([(
+                    self.config.centeral_node.clone(),
+                    PeerState::Connecting,
+                )]),
                 utxo,
                 blocks_in_transit: Vec::new(),
-                mempool: HashMap::new(),
+                download: None,
+                backoff: HashMap::new(),
+                tip_changed_at: Instant::now(),
+                peer_heights: HashMap::new(),
+                version_requests: HashSet::new(),
             })),
+            mempool: Arc::new(RwLock::new(Mempool::default())),
             config: self.config,
         })
     }
@@ -416,23 +1043,101 @@ impl Server {
         Ok(())
     }
 
+    /// Hands a block mined outside the node, like by `send --mine`, to the
+    /// node at `addr` so the network learns about it.
+    pub fn submit_block(addr: &str, block: Block) -> Result<()> {
+        let mut stream = TcpStream::connect(addr)
+            .map_err(|e| anyhow!("Can't connect to node {}: {}", addr, e))?;
+        stream.set_write_timeout(Some(QUERY_TIMEOUT))?;
+        let message = Message::Block {
+            // There is nothing to download from us
+            addr_from: String::new(),
+            block,
+        };
+        write_frame(&mut stream, &encode_to_vec(message, standard())?)
+    }
+
+    /// Sends a query message to the node at `addr` and waits for its answer.
+    pub fn query(addr: &str, message: Message) -> Result<Message> {
+        let mut stream = TcpStream::connect(addr)
+            .map_err(|e| anyhow!("Can't connect to node {}: {}", addr, e))?;
+        stream.set_read_timeout(Some(QUERY_TIMEOUT))?;
+        stream.set_write_timeout(Some(QUERY_TIMEOUT))?;
+        write_frame(&mut stream, &encode_to_vec(message, standard())?)?;
+        bytes_to_msg(&read_frame(&mut stream)?)
+    }
+
     pub fn start(&self) -> Result<()> {
+        // Replays the blocks the overlay of the set held when we stopped
+        self.utxo_sync()?;
+        if self.config.connect_mode != ConnectMode::Inbound {
+            let server = self.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(2000));
+                // A peer ahead of us answers with its own version, to which we
+                // respond with GetBlocks
+                server.send_version(&server.config.centeral_node)
+            });
+        }
+
         let server = self.clone();
         thread::spawn(move || {
-            thread::sleep(Duration::from_millis(2000));
-            match server.get_best_height()? {
-                -1 => server.request_blocks(),
-                v => server.send_message(
-                    &server.config.centeral_node,
-                    Message::Version {
-                        addr_from: server.node_address.clone(),
-                        version: server.config.version,
-                        best_height: v,
-                    },
-                ),
+            loop {
+                thread::sleep(server.config.download_stall_timeout / 2);
+                if let Err(e) = server.restart_stalled_download() {
+                    error!("Error restarting stalled download: {}", e);
+                }
+            }
+        });
+
+        let server = self.clone();
+        thread::spawn(move || {
+            loop {
+                thread::sleep(server.config.stale_tip_interval / 4);
+                if let Err(e) = server.resync_stale_tip() {
+                    error!("Error resyncing stale tip: {}", e);
+                }
             }
         });
 
+        if let Some(interval) = self.config.utxo_check_interval {
+            let server = self.clone();
+            thread::spawn(move || {
+                loop {
+                    thread::sleep(interval);
+                    if let Err(e) = server.check_utxo_set() {
+                        error!("Error checking UTXO set: {}", e);
+                    }
+                }
+            });
+        }
+
+        if let Some(port) = self.config.health_port {
+            let health = TcpListener::bind(("0.0.0.0", port))?;
+            info!("Health endpoint listening on port {}", port);
+            let server = self.clone();
+            thread::spawn(move || {
+                for stream in health.incoming() {
+                    let result = stream
+                        .map_err(anyhow::Error::from)
+                        .and_then(|stream| server.handle_health_request(stream));
+                    if let Err(e) = result {
+                        error!("Error answering health request: {}", e);
+                    }
+                }
+            });
+        }
+
+        if self.config.connect_mode == ConnectMode::Outbound {
+            info!(
+                "Not listening, outbound only, mining_address: {}",
+                &self.mining_address
+            );
+            loop {
+                thread::park();
+            }
+        }
+
         let listener = TcpListener::bind(&self.node_address)?;
         info!(
             "Server listening on {}, mining_address: {}",
@@ -455,44 +1160,470 @@ impl Server {
     fn handle_connection(&self, mut stream: TcpStream) -> Result<()> {
         info!("handle new connection");
 
-        let mut len_buf = [0; 4];
-        stream.read_exact(&mut len_buf)?;
-        let len = u32::from_be_bytes(len_buf) as usize;
-        info!("Received message length: {}", len);
-
-        let mut buf = vec![0; len];
-        stream.read_exact(&mut buf)?;
+        let buf = read_frame(&mut stream)?;
         let msg = bytes_to_msg(&buf)?;
-        info!("Deserialized message: {:?}", msg);
+        let peer = stream
+            .peer_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_default();
+        log_message("Received", &peer, &msg);
 
+        if msg.is_query() {
+            let peer = stream.peer_addr()?;
+            if !peer.ip().is_loopback() && !self.config.allow_remote_queries {
+                return Err(anyhow!("Refusing query from non-loopback {}", peer));
+            }
+            let answer = self.answer_query(&msg)?;
+            stream.set_write_timeout(Some(QUERY_TIMEOUT))?;
+            return write_frame(&mut stream, &encode_to_vec(answer, standard())?);
+        }
         msg.handle(self)
     }
 
-    fn with_read_lock<T, F>(&self, f: F) -> T
-    where
-        F: FnOnce(&ServerInner) -> T,
-    {
-        let inner = self.inner.read().unwrap();
-        f(&inner)
+    fn answer_query(&self, query: &Message) -> Result<Message> {
+        match query {
+            Message::GetHeight => Ok(Message::Height {
+                best_height: self.get_best_height()?,
+            }),
+            Message::GetBalance { address } => {
+                // The UTXO set opens its database per call, which only one
+                // thread may do at a time
+                let (spendable, immature) = self.with_write_lock(|inner| {
+                    let pub_key_hash = get_pub_key_hash(address, &inner.utxo.bc.params)?;
+                    inner.utxo.find_utxo_by_maturity(&pub_key_hash, 0)
+                })?;
+                let immature = immature.total()?;
+                let balance = spendable
+                    .total()?
+                    .checked_add(immature)
+                    .ok_or_else(|| anyhow!("Balance overflow"))?;
+                Ok(Message::Balance {
+                    address: address.clone(),
+                    balance,
+                    immature,
+                })
+            }
+            Message::GetTx { id } => {
+                let best_height = self.get_best_height()?;
+                let found = self.with_read_lock(|inner| {
+                    inner.utxo.bc.find_transaction_with_height(&id.to_string())
+                });
+                let (transaction, block_height) = match found {
+                    Some((tx, height)) => (Some(tx), Some(height)),
+                    None => (self.get_mempool_tx(id), None),
+                };
+                Ok(Message::TxInfo {
+                    transaction,
+                    block_height,
+                    best_height,
+                })
+            }
+            Message::GetFeeHistogram => Ok(Message::FeeHistogram {
+                buckets: self.mempool_fee_histogram()?,
+            }),
+            Message::EstimateSmartFee => Ok(Message::SmartFee {
+                fee_rate: self.estimate_smart_fee()?,
+            }),
+            Message::GetTxOut {
+                tx_id,
+                v_out,
+                include_mempool,
+            } => Ok(Message::TxOut {
+                output: self.get_tx_out(tx_id, *v_out, *include_mempool)?,
+            }),
+            Message::GetSyncStatus => Ok(Message::SyncStatus {
+                status: self.sync_status()?,
+            }),
+            Message::GetNodeInfo => Ok(Message::NodeInfo {
+                info: self.node_info()?,
+            }),
+            Message::GetNetworkHeight => Ok(Message::NetworkHeight {
+                best_height: self.get_best_height()?,
+                network_height: self.network_height()?,
+            }),
+            Message::GetPeerInfo => Ok(Message::PeerInfo {
+                peers: self.peer_info(),
+            }),
+            Message::MineNow => Ok(Message::Mined {
+                hashes: self.mine_now()?,
+            }),
+            Message::SubmitTx { transaction } => Ok(Message::TxSubmitted {
+                verdict: self.receive_tx("", transaction)?,
+            }),
+            _ => Err(anyhow!("Not a query message")),
+        }
     }
 
-    fn with_write_lock<T, F>(&self, f: F) -> T
-    where
-        F: FnOnce(&mut ServerInner) -> T,
-    {
-        let mut inner = self.inner.write().unwrap();
-        f(&mut inner)
+    /// Whether we have caught up with our peers: nothing is being
+    /// downloaded and no peer announced a height above ours. A node that
+    /// hasn't heard from any peer yet counts as synced, as a lone central
+    /// node would otherwise never become ready.
+    pub fn sync_status(&self) -> Result<SyncStatus> {
+        let best_height = self.get_best_height()?;
+        self.with_read_lock(|inner| {
+            let header_height = best_peer_height(inner)
+                .unwrap_or(best_height)
+                .max(best_height);
+            Ok(SyncStatus {
+                best_height,
+                header_height,
+                is_synced: inner.blocks_in_transit.is_empty()
+                    && inner.download.is_none()
+                    && best_height >= header_height,
+            })
+        })
     }
 
-    fn verify_tx(&self, tx: &Transaction) -> Result<bool> {
-        self.with_read_lock(|inner| inner.utxo.bc.verify_transaction(tx))
+    /// Highest best height a peer announced in its `Version`, or ours when
+    /// no peer has been heard from.
+    pub fn network_height(&self) -> Result<i32> {
+        let best_height = self.get_best_height()?;
+        Ok(self.with_read_lock(|inner| best_peer_height(inner).unwrap_or(best_height)))
     }
 
-    fn utxo_reindex(&self) -> Result<()> {
+    /// Mines the mempool into blocks, announcing each to our peers, until
+    /// nothing minable is left. This is what a miner does on receiving a
+    /// transaction. Returns the hashes of the new blocks, none when the
+    /// mempool is empty.
+    pub fn mine_now(&self) -> Result<Vec<Hash>> {
+        if self.mining_address.is_empty() {
+            return Err(anyhow!("Not a miner node, start it with --miner-address"));
+        }
+        let mut mined = vec![];
+        let mut mempool = self.get_mempool();
+        info!("Current mempool: {} txs", mempool.len());
+        if mempool.is_empty() {
+            return Ok(mined);
+        }
+        loop {
+            let mut txs = self.select_block_transactions(&mempool)?;
+            if txs.is_empty() {
+                return Ok(mined);
+            }
+            remove_conflicts(&mut mempool, &txs);
+
+            let params = self.with_read_lock(|inner| inner.utxo.bc.params.clone());
+            let cbtx = Transaction::new_coinbase(
+                &self.mining_address,
+                self.config.coinbase_message.clone(),
+                &params,
+            )?;
+            txs.insert(0, cbtx);
+
+            let new_block = self.mine_block(txs)?;
+            self.utxo_sync()?;
+            mined.push(new_block.hash);
+
+            for node in self.relay_peers() {
+                self.send_message(
+                    &node,
+                    Message::Inv {
+                        addr_from: self.node_address.clone(),
+                        kind: "block".to_string(),
+                        items: vec![new_block.hash],
+                    },
+                )?;
+            }
+
+            if mempool.is_empty() {
+                break;
+            }
+        }
+        self.clear_mempool();
+        Ok(mined)
+    }
+
+    pub fn node_info(&self) -> Result<NodeInfo> {
+        let best_height = self.get_best_height()?;
+        let network_height = self.network_height()?;
+        let known_nodes = self.with_read_lock(|inner| inner.known_nodes.len());
+        Ok(self.with_mempool_read(|mempool| NodeInfo {
+            best_height,
+            network_height,
+            known_nodes,
+            mempool_txs: mempool.len(),
+            orphan_txs: mempool.orphan_count(),
+            orphans: mempool.orphan_stats(),
+        }))
+    }
+
+    /// `node_info` in the Prometheus text format.
+    fn metrics(&self) -> Result<String> {
+        let info = self.node_info()?;
+        let metrics = [
+            (
+                "best_height",
+                "gauge",
+                "Height of our best block",
+                info.best_height as u64,
+            ),
+            (
+                "network_height",
+                "gauge",
+                "Highest best height announced by a peer",
+                info.network_height as u64,
+            ),
+            (
+                "known_nodes",
+                "gauge",
+                "Peers we know of",
+                info.known_nodes as u64,
+            ),
+            (
+                "mempool_txs",
+                "gauge",
+                "Transactions in the mempool",
+                info.mempool_txs as u64,
+            ),
+            (
+                "orphan_txs",
+                "gauge",
+                "Transactions waiting for their parents",
+                info.orphan_txs as u64,
+            ),
+            (
+                "orphans_received_total",
+                "counter",
+                "Transactions held because a parent was missing",
+                info.orphans.received,
+            ),
+            (
+                "orphans_connected_total",
+                "counter",
+                "Orphans that entered the mempool once their parents arrived",
+                info.orphans.connected,
+            ),
+            (
+                "orphans_expired_total",
+                "counter",
+                "Orphans dropped because the orphan pool was full",
+                info.orphans.expired,
+            ),
+        ];
+        let mut out = String::new();
+        for (name, kind, help, value) in metrics {
+            out += &format!(
+                "# HELP rs_blockchain_{name} {help}\n# TYPE rs_blockchain_{name} {kind}\nrs_blockchain_{name} {value}\n"
+            );
+        }
+        Ok(out)
+    }
+
+    /// Answers one HTTP request on the health port: `GET /health` with the
+    /// sync status as JSON, and `GET /metrics` with `metrics`.
+    fn handle_health_request(&self, mut stream: TcpStream) -> Result<()> {
+        stream.set_read_timeout(Some(QUERY_TIMEOUT))?;
+        stream.set_write_timeout(Some(QUERY_TIMEOUT))?;
+        let mut buf = [0; 1024];
+        let n = stream.read(&mut buf)?;
+        let request = String::from_utf8_lossy(&buf[..n]);
+        let path = request
+            .lines()
+            .next()
+            .and_then(|line| line.strip_prefix("GET "))
+            .and_then(|rest| rest.split_whitespace().next());
+        let (status_line, content_type, body) = match path {
+            Some("/health") => {
+                let status = self.sync_status()?;
+                let status_line = if status.is_synced {
+                    "200 OK"
+                } else {
+                    "503 Service Unavailable"
+                };
+                (
+                    status_line,
+                    "application/json",
+                    serde_json::to_string(&status)?,
+                )
+            }
+            Some("/metrics") => ("200 OK", "text/plain; version=0.0.4", self.metrics()?),
+            _ => ("404 Not Found", "text/plain", String::new()),
+        };
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status_line,
+            content_type,
+            body.len(),
+            body
+        );
+        stream.write_all(response.as_bytes())?;
+        Ok(())
+    }
+
+    /// Output `v_out` of `tx_id` if it is unspent.
+    ///
+    /// With `include_mempool` the output must also not be spent by a
+    /// mempool transaction, and outputs of mempool transactions are found
+    /// too, with 0 confirmations.
+    pub fn get_tx_out(
+        &self,
+        tx_id: &str,
+        v_out: i32,
+        include_mempool: bool,
+    ) -> Result<Option<TxOutInfo>> {
+        // Lock order: chain, then mempool. The UTXO set opens its database
+        // per call, which only one thread may do at a time.
+        self.with_write_lock(|inner| {
+            let confirmed = match inner.utxo.get_tx_out(tx_id, v_out)? {
+                Some(out) => {
+                    let best_height = inner.utxo.bc.get_best_height()?;
+                    let origin = inner
+                        .utxo
+                        .get_output_origin(tx_id, v_out)?
+                        .ok_or_else(|| anyhow!("Unspent output of unknown tx {}", tx_id))?;
+                    Some((out, best_height - origin.0 + 1, Some(origin)))
+                }
+                None => None,
+            };
+            if !include_mempool {
+                return Ok(confirmed.map(|(out, confirmations, origin)| TxOutInfo {
+                    value: out.value,
+                    pub_key_hash: out.pub_key_hash,
+                    confirmations,
+                    origin,
+                }));
+            }
+
+            Ok(self.with_mempool_read(|mempool| {
+                if mempool.spender(tx_id, v_out).is_some() {
+                    return None;
+                }
+                let (out, confirmations, origin) = confirmed.or_else(|| {
+                    let tx = mempool.transactions().values().find(|tx| tx.id == tx_id)?;
+                    Some((tx.v_out.get(usize::try_from(v_out).ok()?)?.clone(), 0, None))
+                })?;
+                Some(TxOutInfo {
+                    value: out.value,
+                    pub_key_hash: out.pub_key_hash,
+                    confirmations,
+                    origin,
+                })
+            }))
+        })
+    }
+
+    fn with_read_lock<T, F>(&self, f: F) -> T
+    where
+        F: FnOnce(&ServerInner) -> T,
+    {
+        let inner = self.inner.read().unwrap();
+        f(&inner)
+    }
+
+    fn with_write_lock<T, F>(&self, f: F) -> T
+    where
+        F: FnOnce(&mut ServerInner) -> T,
+    {
+        let mut inner = self.inner.write().unwrap();
+        f(&mut inner)
+    }
+
+    fn with_mempool_read<T, F>(&self, f: F) -> T
+    where
+        F: FnOnce(&Mempool) -> T,
+    {
+        let mempool = self.mempool.read().unwrap();
+        f(&mempool)
+    }
+
+    fn with_mempool_write<T, F>(&self, f: F) -> T
+    where
+        F: FnOnce(&mut Mempool) -> T,
+    {
+        let mut mempool = self.mempool.write().unwrap();
+        f(&mut mempool)
+    }
+
+    /// Picks the transactions of the next block from `candidates`, see
+    /// `mempool::select_block_transactions`.
+    fn select_block_transactions(
+        &self,
+        candidates: &HashMap<Hash, Transaction>,
+    ) -> Result<Vec<Transaction>> {
+        self.with_read_lock(|inner| {
+            let bc = &inner.utxo.bc;
+            select_block_transactions(
+                candidates,
+                MAX_BLOCK_TXS,
+                MAX_BLOCK_SIZE,
+                |tx_id, v_out| {
+                    let tx = bc.find_transaction(tx_id)?;
+                    tx.v_out.get(v_out as usize).map(|out| out.value)
+                },
+                |tx, earlier| bc.verify_transaction_in_block(tx, earlier),
+            )
+        })
+    }
+
+    fn tx_within_size_limits(&self, tx: &Transaction) -> bool {
+        self.with_read_lock(|inner| tx.within_size_limits(&inner.utxo.bc.params))
+    }
+
+    /// Brings the UTXO set to the tip of the chain, see `UTXOSet::sync`.
+    fn utxo_sync(&self) -> Result<()> {
+        self.with_write_lock(|inner| inner.utxo.sync())
+    }
+
+    fn utxo_reindex(&self) -> Result<()> {
         info!("utxo reindex");
         self.with_write_lock(|inner| inner.utxo.reindex())
     }
 
+    /// Compares the UTXO set with the chain, reindexing on a mismatch if
+    /// configured to. Returns the mismatch found, if any.
+    ///
+    /// Blocks reach the chain a moment before the set, so the check is
+    /// skipped during downloads and a mismatch only counts if it is still
+    /// there `UTXO_RECHECK_DELAY` later.
+    fn check_utxo_set(&self) -> Result<Option<UtxoMismatch>> {
+        let check = || {
+            self.with_read_lock(|inner| match inner.download {
+                Some(_) => Ok(None),
+                None => inner.utxo.verify_against_chain().map(Some),
+            })
+        };
+        if check()?.is_none_or(|mismatch| mismatch.is_empty()) {
+            return Ok(None);
+        }
+        thread::sleep(UTXO_RECHECK_DELAY);
+        let Some(mismatch) = check()?.filter(|mismatch| !mismatch.is_empty()) else {
+            return Ok(None);
+        };
+
+        error!("UTXO set doesn't match the chain: {}", mismatch);
+        if self.config.utxo_auto_reindex {
+            log::warn!("Rebuilding the UTXO set from the chain");
+            self.utxo_reindex()?;
+        }
+        Ok(Some(mismatch))
+    }
+
+    fn note_peer_height(&self, addr: &str, height: i32) {
+        self.with_write_lock(|inner| {
+            inner.peer_heights.insert(addr.to_string(), height);
+        })
+    }
+
+    /// Known peers with their handshake progress and announced height,
+    /// sorted by address.
+    pub fn peer_info(&self) -> Vec<PeerInfo> {
+        self.with_read_lock(|inner| {
+            let mut peers: Vec<PeerInfo> = inner
+                .known_nodes
+                .iter()
+                .filter(|node| **node != self.node_address)
+                .map(|node| PeerInfo {
+                    addr: node.clone(),
+                    handshake_complete: inner.peer_states.get(node)
+                        == Some(&PeerState::HandshakeComplete),
+                    best_height: inner.peer_heights.get(node).copied(),
+                })
+                .collect();
+            peers.sort_by(|a, b| a.addr.cmp(&b.addr));
+            peers
+        })
+    }
+
     fn node_is_known(&self, addr: &str) -> bool {
         self.with_read_lock(|inner| inner.known_nodes.contains(addr))
     }
@@ -500,38 +1631,123 @@ impl Server {
     fn remove_node(&self, addr: &str) {
         self.with_write_lock(|inner| {
             inner.known_nodes.remove(addr);
+            inner.peer_states.remove(addr);
+            inner.peer_heights.remove(addr);
+            inner.version_requests.remove(addr);
         });
     }
 
     fn add_node(&self, addr: &str) {
         self.with_write_lock(|inner| {
             inner.known_nodes.insert(addr.to_string());
+            inner
+                .peer_states
+                .entry(addr.to_string())
+                .or_insert(PeerState::Connecting);
         });
     }
 
-    fn get_best_height(&self) -> Result<i32> {
-        self.with_read_lock(|inner| inner.utxo.bc.get_best_height())
+    fn peer_state(&self, addr: &str) -> Option<PeerState> {
+        self.with_read_lock(|inner| inner.peer_states.get(addr).copied())
     }
 
-    fn get_block_hashs(&self) -> Vec<HashType> {
-        self.with_read_lock(|inner| inner.utxo.bc.get_block_hashs())
+    fn set_peer_state(&self, addr: &str, state: PeerState) {
+        self.with_write_lock(|inner| {
+            inner.peer_states.insert(addr.to_string(), state);
+        });
     }
 
-    fn request_blocks(&self) -> Result<()> {
-        info!("request_blocks");
-        for node in self.get_known_nodes() {
-            self.send_message(
-                &node,
-                Message::GetBlocks {
-                    addr_from: self.node_address.clone(),
-                },
-            )?;
+    /// Whether a version from `addr` answers one we sent with
+    /// `send_version`, which it then no longer waits for.
+    fn take_version_request(&self, addr: &str) -> bool {
+        self.with_write_lock(|inner| inner.version_requests.remove(addr))
+    }
+
+    /// Returns whether this completed the handshake, rather than it
+    /// being complete already or never started.
+    fn complete_handshake(&self, addr: &str) -> bool {
+        if self.peer_state(addr) != Some(PeerState::VersionSent) {
+            return false;
         }
-        Ok(())
+        info!("Handshake with {} complete", addr);
+        self.set_peer_state(addr, PeerState::HandshakeComplete);
+        true
+    }
+
+    /// Announces our mempool to a peer that just finished the handshake,
+    /// so it can fetch the transactions it lacks instead of waiting for
+    /// new ones to be relayed.
+    fn send_mempool_inv(&self, addr: &str) -> Result<()> {
+        let items = self.mempool_inventory();
+        if items.is_empty() {
+            return Ok(());
+        }
+        self.send_message(
+            addr,
+            Message::Inv {
+                addr_from: self.node_address.clone(),
+                kind: "tx".to_string(),
+                items,
+            },
+        )
+    }
+
+    /// Known peers that finished the handshake, the only ones we relay to.
+    fn relay_peers(&self) -> Vec<String> {
+        self.with_read_lock(|inner| {
+            inner
+                .known_nodes
+                .iter()
+                .filter(|node| **node != self.node_address)
+                .filter(|node| inner.peer_states.get(*node) == Some(&PeerState::HandshakeComplete))
+                .cloned()
+                .collect()
+        })
+    }
+
+    /// Starts a handshake with `addr`, which answers with its own version.
+    fn send_version(&self, addr: &str) -> Result<()> {
+        self.with_write_lock(|inner| inner.version_requests.insert(addr.to_string()));
+        self.answer_version(addr)
+    }
+
+    /// Sends our version to `addr`, in answer to the one it sent.
+    fn answer_version(&self, addr: &str) -> Result<()> {
+        if self.peer_state(addr) != Some(PeerState::HandshakeComplete) {
+            self.set_peer_state(addr, PeerState::VersionSent);
+        }
+        self.send_message(
+            addr,
+            Message::Version {
+                addr_from: self.node_address.clone(),
+                version: self.config.version,
+                best_height: self.get_best_height()?,
+            },
+        )
+    }
+
+    fn chain_reader(&self) -> BlockchainReader {
+        self.reader.read().unwrap().clone()
+    }
+
+    fn refresh_reader(&self, inner: &ServerInner) {
+        *self.reader.write().unwrap() = inner.utxo.bc.reader();
+    }
+
+    fn get_best_height(&self) -> Result<i32> {
+        self.chain_reader().get_best_height()
+    }
+
+    fn block_locator(&self) -> Vec<Hash> {
+        self.chain_reader().block_locator()
+    }
+
+    fn hashes_after_locator(&self, locator: &[Hash]) -> Vec<Hash> {
+        self.chain_reader().hashes_after_locator(locator)
     }
 
     fn send_message(&self, addr: &str, message: Message) -> Result<()> {
-        log::info!("Sending message:={:?}  to={}", message, addr);
+        log_message("Sending", addr, &message);
         let data = encode_to_vec(message, standard())?;
         self.send_data(addr, &data)
     }
@@ -542,84 +1758,367 @@ impl Server {
             return Ok(());
         }
 
+        if self.is_backing_off(addr) {
+            info!("skip: backing off from {}", addr);
+            return Ok(());
+        }
+
         let mut stream = match TcpStream::connect(addr) {
             Ok(s) => s,
             Err(_) => {
+                self.record_connect_failure(addr);
                 self.remove_node(addr);
                 return Ok(());
             }
         };
+        self.with_write_lock(|inner| inner.backoff.remove(addr));
 
         stream.set_write_timeout(Some(Duration::from_secs(5)))?;
-        let len = data.len() as u32;
-        stream.write_all(&len.to_be_bytes())?;
-        stream.write_all(data)?;
+        write_frame(&mut stream, data)?;
         log::info!("Data sent successfully to {}", addr);
         Ok(())
     }
 
+    fn is_backing_off(&self, addr: &str) -> bool {
+        self.with_read_lock(|inner| {
+            inner
+                .backoff
+                .get(addr)
+                .is_some_and(|backoff| Instant::now() < backoff.retry_at)
+        })
+    }
+
+    fn record_connect_failure(&self, addr: &str) {
+        self.with_write_lock(|inner| {
+            let backoff = inner
+                .backoff
+                .entry(addr.to_string())
+                .or_insert(PeerBackoff {
+                    failures: 0,
+                    retry_at: Instant::now(),
+                });
+            backoff.failures += 1;
+            let delay = self.config.reconnect_delay(backoff.failures);
+            backoff.retry_at = Instant::now() + delay;
+            log::warn!(
+                "Can't reach {}, retrying in {:?} ({} failures)",
+                addr,
+                delay,
+                backoff.failures
+            );
+        });
+    }
+
     fn get_known_nodes(&self) -> HashSet<String> {
         self.with_read_lock(|inner| inner.known_nodes.clone())
     }
 
-    fn replace_in_transit(&self, hashs: Vec<HashType>) {
+    fn replace_in_transit(&self, hashs: Vec<Hash>) {
         self.with_write_lock(|inner| inner.blocks_in_transit = hashs);
     }
 
-    fn get_in_transit(&self) -> Vec<HashType> {
+    fn track_download(&self, peer: &str) {
+        self.with_write_lock(|inner| {
+            inner.download = Some(BlockDownload {
+                peer: peer.to_string(),
+                last_progress: Instant::now(),
+            });
+        });
+    }
+
+    fn finish_download(&self) {
+        self.with_write_lock(|inner| inner.download = None);
+    }
+
+    /// Abandons a download that made no progress within the stall timeout
+    /// and asks another peer for blocks, the one that announced the highest
+    /// height, or the same one if it's the only peer we know. Returns the
+    /// peer asked.
+    fn restart_stalled_download(&self) -> Result<Option<String>> {
+        let stalled = self.with_write_lock(|inner| {
+            let download = inner.download.as_ref()?;
+            if download.last_progress.elapsed() < self.config.download_stall_timeout {
+                return None;
+            }
+            let peer = download.peer.clone();
+            inner.download = None;
+            inner.blocks_in_transit.clear();
+            Some(peer)
+        });
+        let Some(stalled) = stalled else {
+            return Ok(None);
+        };
+
+        let peer = self
+            .with_read_lock(|inner| {
+                inner
+                    .known_nodes
+                    .iter()
+                    .filter(|node| **node != stalled && **node != self.node_address)
+                    .max_by_key(|node| inner.peer_heights.get(*node).copied().unwrap_or(-1))
+                    .cloned()
+            })
+            .unwrap_or(stalled.clone());
+        log::warn!(
+            "Block download from {} stalled, requesting blocks from {}",
+            stalled,
+            peer
+        );
+        self.send_message(
+            &peer,
+            Message::GetBlocks {
+                addr_from: self.node_address.clone(),
+                locator: self.block_locator(),
+            },
+        )?;
+        Ok(Some(peer))
+    }
+
+    /// Asks every known peer for blocks if the tip hasn't moved within the
+    /// stale tip interval. Returns whether it did.
+    fn resync_stale_tip(&self) -> Result<bool> {
+        let stale_for = self.with_write_lock(|inner| {
+            let stale_for = inner.tip_changed_at.elapsed();
+            if stale_for < self.config.stale_tip_interval {
+                return None;
+            }
+            // Wait another full interval before firing again
+            inner.tip_changed_at = Instant::now();
+            Some(stale_for)
+        });
+        let Some(stale_for) = stale_for else {
+            return Ok(false);
+        };
+
+        log::warn!(
+            "No new block for {:?}, requesting blocks from known peers",
+            stale_for
+        );
+        for node in self.get_known_nodes() {
+            self.send_message(
+                &node,
+                Message::GetBlocks {
+                    addr_from: self.node_address.clone(),
+                    locator: self.block_locator(),
+                },
+            )?;
+        }
+        Ok(true)
+    }
+
+    fn get_in_transit(&self) -> Vec<Hash> {
         self.with_read_lock(|inner| inner.blocks_in_transit.clone())
     }
 
-    fn get_mempool_tx(&self, addr: &HashType) -> Option<Transaction> {
-        self.with_read_lock(|inner| inner.mempool.get(addr).cloned())
+    fn get_mempool_tx(&self, addr: &Hash) -> Option<Transaction> {
+        self.with_mempool_read(|mempool| mempool.get(addr).cloned())
+    }
+
+    fn mempool_inventory(&self) -> Vec<Hash> {
+        self.with_mempool_read(|mempool| mempool.inventory(MAX_MEMPOOL_INV))
+    }
+
+    /// Fee histogram of the mempool, see `Mempool::fee_histogram`.
+    pub fn mempool_fee_histogram(&self) -> Result<Vec<(u64, usize)>> {
+        let reader = self.chain_reader();
+        self.with_mempool_read(|mempool| {
+            mempool.fee_histogram(|tx_id, v_out| {
+                let tx = reader.find_transaction(tx_id)?;
+                tx.v_out.get(v_out as usize).map(|out| out.value)
+            })
+        })
     }
 
-    fn get_mempool(&self) -> HashMap<HashType, Transaction> {
-        self.with_read_lock(|inner| inner.mempool.clone())
+    /// Fee rate a transaction needs to get into the next block mined from
+    /// our mempool, see `mempool::estimate_smart_fee`.
+    pub fn estimate_smart_fee(&self) -> Result<u64> {
+        let mempool = self.get_mempool();
+        self.with_read_lock(|inner| {
+            let bc = &inner.utxo.bc;
+            estimate_smart_fee(
+                &mempool,
+                MAX_BLOCK_TXS,
+                MAX_BLOCK_SIZE,
+                |tx_id, v_out| {
+                    let tx = bc.find_transaction(tx_id)?;
+                    tx.v_out.get(v_out as usize).map(|out| out.value)
+                },
+                |tx, earlier| bc.verify_transaction_in_block(tx, earlier),
+            )
+        })
     }
 
-    fn insert_mempool(&self, tx: Transaction) {
-        self.with_write_lock(|inner| inner.mempool.insert(tx.hash_val, tx));
+    fn get_mempool(&self) -> HashMap<Hash, Transaction> {
+        self.with_mempool_read(|mempool| mempool.transactions().clone())
+    }
+
+    /// Applies the mempool acceptance policy to `transaction` from
+    /// `addr_from`. An accepted transaction is relayed by the central node
+    /// and mined right away by a miner.
+    fn receive_tx(&self, addr_from: &str, transaction: &Transaction) -> Result<TxVerdict> {
+        let rejected = |reason: &str| {
+            log::warn!("Rejecting tx {}: {}", transaction.id, reason);
+            Ok(TxVerdict::Rejected {
+                reason: reason.to_owned(),
+            })
+        };
+        if !transaction.is_well_formed() {
+            return rejected("no inputs or outputs");
+        }
+        if !self.tx_within_size_limits(transaction) {
+            return rejected("too many inputs or outputs");
+        }
+        if self.get_mempool_tx(&transaction.hash_val).is_some() {
+            return rejected("already in the mempool");
+        }
+        let accepted = self.accept_to_mempool(transaction.clone());
+        if accepted.is_empty() {
+            let (is_orphan, stats) = self.with_mempool_read(|mempool| {
+                (
+                    mempool.is_orphan(&transaction.hash_val),
+                    mempool.orphan_stats(),
+                )
+            });
+            if !is_orphan {
+                return rejected("double spends a mempool transaction");
+            }
+            log::info!(
+                "Tx {} is an orphan, waiting for parents. Orphans so far: {} received, {} connected, {} expired",
+                transaction.id,
+                stats.received,
+                stats.connected,
+                stats.expired
+            );
+            return Ok(TxVerdict::Orphan);
+        }
+        if self.node_address == self.config.centeral_node {
+            let items: Vec<Hash> = accepted.iter().map(|tx| tx.hash_val).collect();
+            for node in self.relay_peers() {
+                if node != addr_from {
+                    self.send_message(
+                        &node,
+                        Message::Inv {
+                            addr_from: self.node_address.clone(),
+                            kind: "tx".to_string(),
+                            items: items.clone(),
+                        },
+                    )?;
+                }
+            }
+        } else if !self.mining_address.is_empty() {
+            self.mine_now()?;
+        }
+        Ok(TxVerdict::Accepted)
+    }
+
+    fn accept_to_mempool(&self, tx: Transaction) -> Vec<Transaction> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default();
+        // Lock order: chain, then mempool
+        self.with_read_lock(|inner| {
+            let bc = &inner.utxo.bc;
+            self.with_mempool_write(|mempool| {
+                mempool.expire(now, MEMPOOL_EXPIRY_MS);
+                mempool.accept(tx, |id| bc.find_transaction(id).is_some())
+            })
+        })
     }
 
     fn clear_mempool(&self) {
-        self.with_write_lock(|inner| inner.mempool.clear());
+        self.with_mempool_write(|mempool| mempool.clear());
     }
 
-    fn get_block(&self, block_hash: &HashType) -> Result<Block> {
-        self.with_read_lock(|inner| inner.utxo.bc.get_block(block_hash))
+    fn get_block(&self, block_hash: &Hash) -> Result<Block> {
+        self.chain_reader().get_block(block_hash)
     }
 
     fn add_block(&self, block: &Block) -> Result<()> {
-        self.with_write_lock(|inner| inner.utxo.bc.add_block(block))
+        self.with_write_lock(|inner| {
+            let depth = inner.utxo.bc.reorg_depth(block)?;
+            if depth > self.config.finality_depth {
+                error!(
+                    "CRITICAL: refusing reorg of depth {} to block {} (finality depth {})",
+                    depth, block.hash, self.config.finality_depth
+                );
+                return Ok(());
+            }
+            let old_tip = inner.utxo.bc.tip;
+            inner.utxo.bc.add_block(block)?;
+            if inner.utxo.bc.tip != old_tip {
+                inner.tip_changed_at = Instant::now();
+            }
+            self.refresh_reader(inner);
+            Ok(())
+        })
+    }
+
+    fn flush_chain(&self) -> Result<()> {
+        self.with_read_lock(|inner| inner.utxo.bc.flush())
     }
 
     fn mine_block(&self, txs: Vec<Transaction>) -> Result<Block> {
-        self.with_write_lock(|inner| inner.utxo.bc.mine_block(txs))
+        self.with_write_lock(|inner| {
+            let block = inner.utxo.bc.mine_block(txs)?;
+            inner.utxo.bc.flush()?;
+            inner.tip_changed_at = Instant::now();
+            self.refresh_reader(inner);
+            Ok(block)
+        })
+    }
+}
+
+/// Writes `data` prefixed with its big endian u32 length.
+fn write_frame(stream: &mut TcpStream, data: &[u8]) -> Result<()> {
+    let len = data.len() as u32;
+    stream.write_all(&len.to_be_bytes())?;
+    stream.write_all(data)?;
+    Ok(())
+}
+
+fn read_frame(stream: &mut TcpStream) -> Result<Vec<u8>> {
+    let mut len_buf = [0; 4];
+    stream.read_exact(&mut len_buf)?;
+    let len = u32::from_be_bytes(len_buf) as usize;
+    info!("Received message length: {}", len);
+    if len > MAX_DECODE_BYTES {
+        return Err(anyhow!(
+            "Message of {} bytes is over the {} byte limit",
+            len,
+            MAX_DECODE_BYTES
+        ));
     }
+
+    let mut buf = vec![0; len];
+    stream.read_exact(&mut buf)?;
+    Ok(buf)
 }
 
 fn bytes_to_msg(bytes: &[u8]) -> Result<Message> {
-    let (message, _) = decode_from_slice(bytes, standard())?;
+    let (message, _) = decode_from_slice(bytes, DECODE_CONFIG)?;
     Ok(message)
 }
 
 #[cfg(test)]
 mod test {
+    use std::path::Path;
+
     use super::*;
+    use crate::ChainParams;
     use crate::blockchain::*;
+    use crate::testutil::{TestDir, TxBuilder};
     use crate::wallet::*;
 
     #[test]
     fn test_cmd() {
-        let mut ws = Wallets::new().unwrap();
-        let wa1 = ws.create_wallet();
-        let bc = Blockchain::create(&wa1).unwrap();
-        let utxo_set = UTXOSet::new(bc);
+        let dir = TestDir::new("cmd");
+        let wa1 = Wallet::new().get_address(&ChainParams::default());
+        let bc = Blockchain::create_at(dir.join("blockchain"), &wa1).unwrap();
         let server = Server::builder()
-            .port("7878")
-            .miner_address("localhost:3001")
-            .utxo(utxo_set)
+            .port("0")
+            .miner_address(&wa1)
+            .utxo(UTXOSet::with_path(bc, dir.join("utxos")))
             .build()
             .unwrap();
 
@@ -643,4 +2142,822 @@ mod test {
             _ => panic!("Expected Version message"),
         }
     }
+
+    #[test]
+    fn test_no_relay_before_handshake() {
+        let dir = TestDir::new("handshake");
+        let bc = Blockchain::open(dir.join("blockchain")).unwrap();
+        let server = Server::builder()
+            .port("0")
+            .utxo(UTXOSet::with_path(bc, dir.join("utxos")))
+            .build()
+            .unwrap();
+
+        let peer = &format!("localhost:{}", free_port());
+        server.add_node(peer);
+        assert!(server.relay_peers().is_empty());
+
+        // A stray VerAck doesn't skip sending our version
+        Message::VerAck {
+            addr_from: peer.to_string(),
+        }
+        .handle(&server)
+        .unwrap();
+        assert!(server.relay_peers().is_empty());
+
+        server.set_peer_state(peer, PeerState::VersionSent);
+        assert!(server.relay_peers().is_empty());
+
+        Message::VerAck {
+            addr_from: peer.to_string(),
+        }
+        .handle(&server)
+        .unwrap();
+        assert_eq!(server.relay_peers(), vec![peer.to_string()]);
+    }
+
+    #[test]
+    fn test_stalled_download_restarts_from_other_peer() {
+        let dir = TestDir::new("stall");
+        let bc = Blockchain::open(dir.join("blockchain")).unwrap();
+        let (listener, healthy) = listen();
+        let healthy = healthy.as_str();
+        let config = Config {
+            centeral_node: healthy.to_string(),
+            ..Default::default()
+        }
+        .with_download_stall_timeout(Duration::ZERO);
+        let server = Server::builder()
+            .port("0")
+            .utxo(UTXOSet::with_path(bc, dir.join("utxos")))
+            .config(config)
+            .build()
+            .unwrap();
+
+        // The peer announced two blocks, delivered none and went silent
+        let stalled = &format!("localhost:{}", free_port());
+        server.add_node(stalled);
+        server.replace_in_transit(vec![Hash([1; 32])]);
+        server.track_download(stalled);
+
+        let asked = server.restart_stalled_download().unwrap();
+        assert_eq!(asked.as_deref(), Some(healthy));
+        assert!(server.get_in_transit().is_empty());
+        assert!(server.restart_stalled_download().unwrap().is_none());
+
+        assert!(matches!(
+            receive_message(&listener),
+            Message::GetBlocks { .. }
+        ));
+    }
+
+    #[test]
+    fn test_outbound_only_node_dials_without_listening() {
+        let dir = TestDir::new("outbound");
+        let bc = Blockchain::open(dir.join("blockchain")).unwrap();
+        let (listener, central) = listen();
+        let port = free_port();
+        let config = Config::default()
+            .with_central_node(&central)
+            .with_connect_mode(ConnectMode::Outbound);
+        let server = Server::builder()
+            .port(&port)
+            .utxo(UTXOSet::with_path(bc, dir.join("utxos")))
+            .config(config)
+            .build()
+            .unwrap();
+        thread::spawn(move || server.start());
+
+        assert!(matches!(
+            receive_message(&listener),
+            Message::Version { .. }
+        ));
+        // Nothing holds the node's own port
+        TcpListener::bind(format!("localhost:{}", port)).unwrap();
+    }
+
+    /// A port nothing listens on once this returns.
+    fn free_port() -> String {
+        TcpListener::bind("localhost:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+            .to_string()
+    }
+
+    /// A listener on a free port, with the address peers reach it at.
+    fn listen() -> (TcpListener, String) {
+        let listener = TcpListener::bind("localhost:0").unwrap();
+        let addr = format!("localhost:{}", listener.local_addr().unwrap().port());
+        (listener, addr)
+    }
+
+    fn receive_message(listener: &TcpListener) -> Message {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut len_buf = [0; 4];
+        stream.read_exact(&mut len_buf).unwrap();
+        let mut buf = vec![0; u32::from_be_bytes(len_buf) as usize];
+        stream.read_exact(&mut buf).unwrap();
+        bytes_to_msg(&buf).unwrap()
+    }
+
+    #[test]
+    fn test_stale_tip_resyncs_with_peers() {
+        let dir = TestDir::new("stale-tip");
+        let bc = Blockchain::open(dir.join("blockchain")).unwrap();
+        let (listener, peer) = listen();
+        let server = Server::builder()
+            .port("0")
+            .utxo(UTXOSet::with_path(bc, dir.join("utxos")))
+            .config(Config::default().with_central_node(&peer))
+            .build()
+            .unwrap();
+        assert!(!server.resync_stale_tip().unwrap());
+
+        server.with_write_lock(|inner| {
+            inner.tip_changed_at = Instant::now() - DEFAULT_STALE_TIP_INTERVAL;
+        });
+        assert!(server.resync_stale_tip().unwrap());
+        assert!(matches!(
+            receive_message(&listener),
+            Message::GetBlocks { .. }
+        ));
+        // Doesn't fire again right away
+        assert!(!server.resync_stale_tip().unwrap());
+    }
+
+    #[test]
+    fn test_reconnect_backoff() {
+        let dir = TestDir::new("backoff");
+        let bc = Blockchain::open(dir.join("blockchain")).unwrap();
+        let config = Config::default()
+            .with_reconnect_backoff(Duration::from_secs(60), Duration::from_secs(200));
+        assert_eq!(config.reconnect_delay(1), Duration::from_secs(60));
+        assert_eq!(config.reconnect_delay(2), Duration::from_secs(120));
+        assert_eq!(config.reconnect_delay(3), Duration::from_secs(200));
+        let server = Server::builder()
+            .port("0")
+            .utxo(UTXOSet::with_path(bc, dir.join("utxos")))
+            .config(config)
+            .build()
+            .unwrap();
+
+        let peer = &format!("localhost:{}", free_port());
+        server.send_data(peer, b"ping").unwrap();
+        assert!(server.is_backing_off(peer));
+        // Suppressed attempts don't count as failures
+        server.send_data(peer, b"ping").unwrap();
+        assert_eq!(
+            server.with_read_lock(|inner| inner.backoff[peer].failures),
+            1
+        );
+
+        // Once the peer is back and the delay has passed, success resets it
+        let _listener = TcpListener::bind(peer).unwrap();
+        server.with_write_lock(|inner| {
+            inner.backoff.get_mut(peer).unwrap().retry_at = Instant::now();
+        });
+        server.send_data(peer, b"ping").unwrap();
+        assert!(server.with_read_lock(|inner| inner.backoff.is_empty()));
+    }
+
+    /// Starts a node on a free port with its databases under `dir`, peered
+    /// with `central`, or acting as the central node itself when `None`.
+    fn spawn_node(dir: &Path, bc: Blockchain, central: Option<&str>, miner: &str) -> Server {
+        spawn_node_on(&free_port(), dir, bc, central, miner)
+    }
+
+    fn spawn_node_on(
+        port: &str,
+        dir: &Path,
+        bc: Blockchain,
+        central: Option<&str>,
+        miner: &str,
+    ) -> Server {
+        let central = central
+            .map(str::to_owned)
+            .unwrap_or(format!("localhost:{}", port));
+        let server = Server::builder()
+            .port(port)
+            .miner_address(miner)
+            .utxo(UTXOSet::with_path(bc, dir.join("utxos")))
+            .config(Config::default().with_central_node(&central))
+            .build()
+            .unwrap();
+        let node = server.clone();
+        thread::spawn(move || node.start());
+        server
+    }
+
+    fn wait_for_height(server: &Server, height: i32) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(60);
+        while Instant::now() < deadline {
+            if server.get_best_height().unwrap() >= height {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        false
+    }
+
+    #[test]
+    fn test_block_propagates_between_nodes() {
+        let params = ChainParams::default();
+        let dir = TestDir::new("p2p");
+        let wallet = Wallet::new();
+        let addr = wallet.get_address(&params);
+        let bc = Blockchain::create_at(dir.join("central/blockchain"), &addr).unwrap();
+        let genesis_cb = bc.get_block(&bc.tip).unwrap().transactions[0].clone();
+
+        let central = spawn_node(&dir.join("central"), bc, None, "");
+        let miner_bc = Blockchain::open(dir.join("miner/blockchain")).unwrap();
+        let miner = spawn_node(
+            &dir.join("miner"),
+            miner_bc,
+            Some(&central.node_address),
+            &addr,
+        );
+
+        // The miner syncs the genesis block during the handshake
+        assert!(wait_for_height(&miner, 0));
+
+        let mut tx = TxBuilder::new()
+            .spend_from(genesis_cb.hash_val, 0, &wallet)
+            .pay(10, &Wallet::new().get_address(&params), &params)
+            .build();
+        tx.sign(
+            &wallet.private_key,
+            HashMap::from([(genesis_cb.id.clone(), genesis_cb)]),
+        )
+        .unwrap();
+        central
+            .send_message(
+                &miner.node_address,
+                Message::Tx {
+                    addr_from: central.node_address.clone(),
+                    transaction: tx.clone(),
+                },
+            )
+            .unwrap();
+
+        assert!(wait_for_height(&central, 1));
+        let tip = central.get_block(&central.chain_reader().tip()).unwrap();
+        assert!(tip.transactions.iter().any(|t| t.id == tx.id));
+    }
+
+    #[test]
+    fn test_connecting_node_pulls_mempool() {
+        let params = ChainParams::default();
+        let dir = TestDir::new("mempool-sync");
+        let addr = Wallet::new().get_address(&params);
+        let bc = Blockchain::create_at(dir.join("central/blockchain"), &addr).unwrap();
+        let genesis_cb = bc.get_block(&bc.tip).unwrap().transactions[0].clone();
+        // The peer starts on the same chain, so the pooled transaction's
+        // parent is already confirmed on both sides
+        bc.export_chain(dir.join("chain.bin")).unwrap();
+        let mut peer_bc = Blockchain::open(dir.join("peer/blockchain")).unwrap();
+        peer_bc.import_chain(dir.join("chain.bin")).unwrap();
+
+        let central = spawn_node(&dir.join("central"), bc, None, "");
+        let tx = TxBuilder::new()
+            .spend(genesis_cb.hash_val, 0)
+            .pay(10, &addr, &params)
+            .build();
+        assert_eq!(central.accept_to_mempool(tx.clone()).len(), 1);
+
+        let peer = spawn_node(&dir.join("peer"), peer_bc, Some(&central.node_address), "");
+        let deadline = Instant::now() + Duration::from_secs(30);
+        while peer.get_mempool_tx(&tx.hash_val).is_none() {
+            assert!(Instant::now() < deadline, "peer never pulled the mempool");
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    #[test]
+    fn test_reconnecting_node_pulls_mempool() {
+        let params = ChainParams::default();
+        let dir = TestDir::new("mempool-resync");
+        let addr = Wallet::new().get_address(&params);
+        let bc = Blockchain::create_at(dir.join("central/blockchain"), &addr).unwrap();
+        let genesis_cb = bc.get_block(&bc.tip).unwrap().transactions[0].clone();
+        bc.export_chain(dir.join("chain.bin")).unwrap();
+        let mut peer_bc = Blockchain::open(dir.join("peer/blockchain")).unwrap();
+        peer_bc.import_chain(dir.join("chain.bin")).unwrap();
+
+        let central = spawn_node(&dir.join("central"), bc, None, "");
+        let tx = TxBuilder::new()
+            .spend(genesis_cb.hash_val, 0)
+            .pay(10, &addr, &params)
+            .build();
+        assert_eq!(central.accept_to_mempool(tx.clone()).len(), 1);
+
+        // A first session completes the handshake, then the peer stops
+        // and its mempool is lost with it
+        let listener = TcpListener::bind("localhost:0").unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        let peer_addr = format!("localhost:{}", port);
+        Message::Version {
+            addr_from: peer_addr.clone(),
+            version: 1,
+            best_height: central.get_best_height().unwrap(),
+        }
+        .handle(&central)
+        .unwrap();
+        Message::VerAck {
+            addr_from: peer_addr.clone(),
+        }
+        .handle(&central)
+        .unwrap();
+        assert_eq!(central.relay_peers(), vec![peer_addr]);
+        drop(listener);
+
+        let peer = spawn_node_on(
+            &port,
+            &dir.join("peer"),
+            peer_bc,
+            Some(&central.node_address),
+            "",
+        );
+        let deadline = Instant::now() + Duration::from_secs(30);
+        while peer.get_mempool_tx(&tx.hash_val).is_none() {
+            assert!(
+                Instant::now() < deadline,
+                "reconnected peer never pulled the mempool"
+            );
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    #[test]
+    fn test_utxo_check_reindexes_corrupt_set() {
+        let dir = TestDir::new("server-utxo-check");
+        let addr = Wallet::new().get_address(&ChainParams::default());
+        let bc = Blockchain::create_at(dir.join("blockchain"), &addr).unwrap();
+        let server = Server::builder()
+            .port("0")
+            .utxo(UTXOSet::with_path(bc, dir.join("utxos")))
+            .config(Config::default().with_utxo_auto_reindex(true))
+            .build()
+            .unwrap();
+        server.utxo_reindex().unwrap();
+        assert!(server.check_utxo_set().unwrap().is_none());
+
+        let db = server.with_read_lock(|inner| inner.utxo.open_db().unwrap());
+        db.insert(
+            "bogus",
+            encode_to_vec(crate::TXOutputs::default(), standard()).unwrap(),
+        )
+        .unwrap();
+        let mismatch = server.check_utxo_set().unwrap().unwrap();
+        assert_eq!(mismatch.unexpected, vec!["bogus".to_owned()]);
+        // Repaired by the automatic reindex
+        assert!(server.check_utxo_set().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_message_log_redacts_keys() {
+        let params = ChainParams::default();
+        let mut tx =
+            Transaction::new_coinbase(&Wallet::new().get_address(&params), String::new(), &params)
+                .unwrap();
+        tx.v_in[0].signature = vec![0xab; 64];
+        tx.v_in[0].pub_key = vec![0xcd; 65];
+        let msg = Message::Tx {
+            addr_from: "localhost:3001".to_owned(),
+            transaction: tx.clone(),
+        };
+
+        let summary = msg.to_string();
+        assert!(summary.contains(&tx.id));
+        assert!(summary.contains("localhost:3001"));
+        assert!(!summary.contains("abab"));
+
+        let Message::Tx { transaction, .. } = msg.redacted() else {
+            panic!("redacting changed the message kind");
+        };
+        assert_eq!(transaction.v_in[0].signature, vec![0xab; LOGGED_KEY_BYTES]);
+        assert_eq!(transaction.v_in[0].pub_key, vec![0xcd; LOGGED_KEY_BYTES]);
+        assert_eq!(transaction.id, tx.id);
+    }
+
+    #[test]
+    fn test_tx_out_spent_in_mempool() {
+        let params = ChainParams::default();
+        let dir = TestDir::new("txout");
+        let addr = Wallet::new().get_address(&params);
+        let bc = Blockchain::create_at(dir.join("blockchain"), &addr).unwrap();
+        let genesis_cb = bc.get_block(&bc.tip).unwrap().transactions[0].clone();
+        let server = Server::builder()
+            .port("0")
+            .utxo(UTXOSet::with_path(bc, dir.join("utxos")))
+            .build()
+            .unwrap();
+        server.utxo_reindex().unwrap();
+
+        let confirmed = server.get_tx_out(&genesis_cb.id, 0, true).unwrap().unwrap();
+        assert_eq!(confirmed.value, 10);
+        assert_eq!(confirmed.confirmations, 1);
+        assert!(
+            server
+                .get_tx_out(&genesis_cb.id, 1, true)
+                .unwrap()
+                .is_none()
+        );
+
+        let spend = TxBuilder::new()
+            .spend(genesis_cb.hash_val, 0)
+            .pay(9, &addr, &params)
+            .build();
+        assert_eq!(server.accept_to_mempool(spend.clone()).len(), 1);
+
+        assert!(
+            server
+                .get_tx_out(&genesis_cb.id, 0, true)
+                .unwrap()
+                .is_none()
+        );
+        assert_eq!(
+            server.get_tx_out(&genesis_cb.id, 0, false).unwrap(),
+            Some(confirmed)
+        );
+        let unconfirmed = server.get_tx_out(&spend.id, 0, true).unwrap().unwrap();
+        assert_eq!((unconfirmed.value, unconfirmed.confirmations), (9, 0));
+        assert!(server.get_tx_out(&spend.id, 0, false).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_submit_tx_verdicts() {
+        let params = ChainParams::default();
+        let dir = TestDir::new("submit-tx");
+        let addr = Wallet::new().get_address(&params);
+        let bc = Blockchain::create_at(dir.join("blockchain"), &addr).unwrap();
+        let genesis_cb = bc.get_block(&bc.tip).unwrap().transactions[0].clone();
+        let server = Server::builder()
+            .port("0")
+            .utxo(UTXOSet::with_path(bc, dir.join("utxos")))
+            .build()
+            .unwrap();
+        let spend = |txid: Hash, value: i32| {
+            TxBuilder::new()
+                .spend(txid, 0)
+                .pay(value, &addr, &params)
+                .build()
+        };
+        let submit = |transaction: Transaction| match server
+            .answer_query(&Message::SubmitTx { transaction })
+        {
+            Ok(Message::TxSubmitted { verdict }) => verdict,
+            answer => panic!("unexpected answer {:?}", answer),
+        };
+
+        let first = spend(genesis_cb.hash_val, 9);
+        assert_eq!(submit(first.clone()), TxVerdict::Accepted);
+        assert!(matches!(
+            submit(first),
+            TxVerdict::Rejected { reason } if reason.contains("already")
+        ));
+        assert!(matches!(
+            submit(spend(genesis_cb.hash_val, 8)),
+            TxVerdict::Rejected { reason } if reason.contains("double spends")
+        ));
+        assert_eq!(submit(spend(Hash([0xab; 32]), 1)), TxVerdict::Orphan);
+        let mut empty = spend(genesis_cb.hash_val, 1);
+        empty.v_out.clear();
+        assert!(matches!(submit(empty), TxVerdict::Rejected { .. }));
+    }
+
+    #[test]
+    fn test_local_queries() {
+        let dir = TestDir::new("query");
+        let addr = Wallet::new().get_address(&ChainParams::default());
+        let bc = Blockchain::create_at(dir.join("blockchain"), &addr).unwrap();
+        let genesis_cb = bc.get_block(&bc.tip).unwrap().transactions[0].clone();
+        let node = spawn_node(&dir, bc, None, "");
+        node.utxo_reindex().unwrap();
+
+        let query = |message: Message| {
+            let deadline = Instant::now() + Duration::from_secs(10);
+            loop {
+                match Server::query(&node.node_address, message.clone()) {
+                    Ok(answer) => return answer,
+                    Err(e) if Instant::now() > deadline => panic!("{}", e),
+                    // The node may still be starting up
+                    Err(_) => thread::sleep(Duration::from_millis(100)),
+                }
+            }
+        };
+
+        assert!(matches!(
+            query(Message::GetHeight),
+            Message::Height { best_height: 0 }
+        ));
+        assert!(matches!(
+            query(Message::GetBalance { address: addr }),
+            Message::Balance { balance, immature, .. }
+                if balance == Amount::new(10) && immature == Amount::new(10)
+        ));
+        match query(Message::GetTx {
+            id: genesis_cb.hash_val,
+        }) {
+            Message::TxInfo {
+                transaction: Some(tx),
+                block_height: Some(0),
+                best_height: 0,
+            } => assert_eq!(tx.id, genesis_cb.id),
+            answer => panic!("unexpected answer {:?}", answer),
+        }
+        assert!(matches!(
+            query(Message::GetTx { id: Hash([7; 32]) }),
+            Message::TxInfo {
+                transaction: None,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_submitted_block_reaches_node() {
+        let params = ChainParams::default();
+        let dir = TestDir::new("submit");
+        let addr = Wallet::new().get_address(&params);
+        let bc = Blockchain::create_at(dir.join("node/blockchain"), &addr).unwrap();
+        let genesis = bc.get_block(&bc.tip).unwrap();
+        let node = spawn_node(&dir.join("node"), bc, None, "");
+
+        // A wallet with its own copy of the chain mines a block
+        let mut local = Blockchain::open(dir.join("local/blockchain")).unwrap();
+        local.add_block(&genesis).unwrap();
+        let cbtx = Transaction::new_coinbase(&addr, "local".to_owned(), &params).unwrap();
+        let block = local.mine_block(vec![cbtx]).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        // The node may still be starting up
+        while Server::submit_block(&node.node_address, block.clone()).is_err() {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert!(wait_for_height(&node, 1));
+        assert_eq!(node.chain_reader().tip(), block.hash);
+    }
+
+    #[test]
+    fn test_sync_status_follows_peer_height() {
+        let params = ChainParams::default();
+        let dir = TestDir::new("sync");
+        let addr = Wallet::new().get_address(&params);
+        let bc = Blockchain::create_at(dir.join("blockchain"), &addr).unwrap();
+        let server = Server::builder()
+            .port("0")
+            .utxo(UTXOSet::with_path(bc, dir.join("utxos")))
+            .build()
+            .unwrap();
+
+        // No peer heard from yet
+        assert!(server.sync_status().unwrap().is_synced);
+        assert_eq!(server.network_height().unwrap(), 0);
+
+        server.note_peer_height("localhost:1", 1);
+        server.note_peer_height("localhost:2", 0);
+        assert!(matches!(
+            server.answer_query(&Message::GetNetworkHeight).unwrap(),
+            Message::NetworkHeight {
+                best_height: 0,
+                network_height: 1
+            }
+        ));
+        let status = server.sync_status().unwrap();
+        assert_eq!(
+            status,
+            SyncStatus {
+                best_height: 0,
+                header_height: 1,
+                is_synced: false,
+            }
+        );
+
+        let cbtx = Transaction::new_coinbase(&addr, String::new(), &params).unwrap();
+        let block = server.mine_block(vec![cbtx]).unwrap();
+        assert!(server.sync_status().unwrap().is_synced);
+
+        server.with_write_lock(|inner| inner.blocks_in_transit.push(block.hash));
+        assert!(!server.sync_status().unwrap().is_synced);
+        server.with_write_lock(|inner| inner.blocks_in_transit.clear());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let health = server.clone();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            health.handle_health_request(stream).unwrap();
+        });
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.write_all(b"GET /health HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        handle.join().unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with(r#""is_synced":true}"#));
+        assert!(
+            server
+                .metrics()
+                .unwrap()
+                .contains("\nrs_blockchain_orphans_received_total 0\n")
+        );
+    }
+
+    #[test]
+    fn test_peer_height_follows_latest_version() {
+        let dir = TestDir::new("peer-height");
+        let bc = Blockchain::open(dir.join("blockchain")).unwrap();
+        let server = Server::builder()
+            .port("0")
+            .utxo(UTXOSet::with_path(bc, dir.join("utxos")))
+            .build()
+            .unwrap();
+        // Accepts the answers to its versions without reading them
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = listener.local_addr().unwrap().to_string();
+        let version = |best_height| Message::Version {
+            addr_from: peer.clone(),
+            version: 1,
+            best_height,
+        };
+        let peer_height = || {
+            server
+                .peer_info()
+                .into_iter()
+                .find(|info| info.addr == peer)
+                .and_then(|info| info.best_height)
+        };
+
+        assert_eq!(peer_height(), None);
+        version(3).handle(&server).unwrap();
+        assert_eq!(peer_height(), Some(3));
+        assert_eq!(server.network_height().unwrap(), 3);
+        version(5).handle(&server).unwrap();
+        assert_eq!(peer_height(), Some(5));
+        // A peer that went back after a reorg
+        version(4).handle(&server).unwrap();
+        assert_eq!(peer_height(), Some(4));
+        assert_eq!(server.network_height().unwrap(), 4);
+        assert!(!server.sync_status().unwrap().is_synced);
+    }
+
+    #[test]
+    fn test_new_version_restarts_handshake() {
+        let dir = TestDir::new("rehandshake");
+        let bc = Blockchain::open(dir.join("blockchain")).unwrap();
+        let server = Server::builder()
+            .port("0")
+            .utxo(UTXOSet::with_path(bc, dir.join("utxos")))
+            .build()
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let peer = listener.local_addr().unwrap().to_string();
+        // Versions sent to the peer since the last call
+        let versions_sent = || {
+            let mut count = 0;
+            while let Ok((mut stream, _)) = listener.accept() {
+                stream.set_nonblocking(false).unwrap();
+                let msg = bytes_to_msg(&read_frame(&mut stream).unwrap()).unwrap();
+                count += matches!(msg, Message::Version { .. }) as usize;
+            }
+            count
+        };
+        let version = Message::Version {
+            addr_from: peer.clone(),
+            version: 1,
+            best_height: 0,
+        };
+        let verack = Message::VerAck {
+            addr_from: peer.clone(),
+        };
+
+        version.handle(&server).unwrap();
+        assert_eq!(versions_sent(), 1);
+        verack.handle(&server).unwrap();
+        assert_eq!(server.relay_peers(), vec![peer.clone()]);
+
+        // The peer restarted and connects again
+        version.handle(&server).unwrap();
+        assert_eq!(versions_sent(), 1);
+        assert!(server.relay_peers().is_empty());
+        verack.handle(&server).unwrap();
+        assert_eq!(server.relay_peers(), vec![peer.clone()]);
+
+        // The answer to our own version isn't answered in turn
+        server.send_version(&peer).unwrap();
+        assert_eq!(versions_sent(), 1);
+        version.handle(&server).unwrap();
+        assert_eq!(versions_sent(), 0);
+        assert_eq!(server.relay_peers(), vec![peer]);
+    }
+
+    #[test]
+    fn test_builder_rejects_bad_port_and_miner_address() {
+        let dir = TestDir::new("args");
+        let addr = Wallet::new().get_address(&ChainParams::default());
+        let utxo = |name: &str| {
+            let bc = Blockchain::create_at(dir.join(name).join("blockchain"), &addr).unwrap();
+            UTXOSet::with_path(bc, dir.join(name).join("utxos"))
+        };
+        for port in ["abc", "70000", "-1"] {
+            let err = Server::builder()
+                .port(port)
+                .utxo(utxo(port))
+                .build()
+                .err()
+                .unwrap();
+            assert!(err.to_string().contains("Invalid port"));
+        }
+        let mut bad = addr.clone();
+        bad.push('1');
+        for (i, miner) in [bad.as_str(), "0OIl"].into_iter().enumerate() {
+            let built = Server::builder()
+                .port("0")
+                .miner_address(miner)
+                .utxo(utxo(&format!("miner-{}", i)))
+                .build();
+            assert!(built.is_err());
+        }
+        let err = Server::builder()
+            .port("0")
+            .utxo(utxo("coinbase-message"))
+            .config(Config::default().with_coinbase_message(&"x".repeat(MAX_COINBASE_DATA + 1)))
+            .build()
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("Coinbase message"), "{}", err);
+        let built = Server::builder()
+            .port("0")
+            .miner_address(&addr)
+            .utxo(utxo("ok"))
+            .build();
+        assert!(built.is_ok());
+    }
+
+    #[test]
+    fn test_mine_now_seals_mempool() {
+        let params = ChainParams::default();
+        let dir = TestDir::new("mine-now");
+        let wallet = Wallet::new();
+        let addr = wallet.get_address(&params);
+        let bc = Blockchain::create_at(dir.join("blockchain"), &addr).unwrap();
+        let genesis_cb = bc.get_block(&bc.tip).unwrap().transactions[0].clone();
+        let mut spend = TxBuilder::new()
+            .spend_from(genesis_cb.hash_val, 0, &wallet)
+            .pay(10, &addr, &params)
+            .build();
+        bc.sign_transaction(&mut spend, &wallet.private_key)
+            .unwrap();
+        let server = Server::builder()
+            .port("0")
+            .miner_address(&addr)
+            .utxo(UTXOSet::with_path(bc, dir.join("utxos")))
+            .config(Config::default().with_coinbase_message("mined on demand"))
+            .build()
+            .unwrap();
+        server.utxo_reindex().unwrap();
+
+        assert!(server.mine_now().unwrap().is_empty());
+        assert_eq!(server.accept_to_mempool(spend.clone()).len(), 1);
+        let mined = server.mine_now().unwrap();
+        assert_eq!(mined.len(), 1);
+        assert_eq!(server.chain_reader().tip(), mined[0]);
+        let block = server.get_block(&mined[0]).unwrap();
+        assert!(block.transactions.iter().any(|tx| tx.id == spend.id));
+        assert_eq!(
+            block.transactions[0].coinbase_message().as_deref(),
+            Some("mined on demand")
+        );
+        assert!(server.get_mempool().is_empty());
+    }
+
+    #[test]
+    fn test_oversized_length_is_a_decode_error() {
+        // A Tx message whose addr_from claims to be 1 TiB long, followed by
+        // no data. Without a limit this would be allocated before decoding
+        // noticed the missing bytes.
+        let mut payload = vec![5, 253];
+        payload.extend_from_slice(&(1u64 << 40).to_le_bytes());
+        let err = bytes_to_msg(&payload).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(bincode::error::DecodeError::LimitExceeded)
+        ));
+
+        // Just over the limit, but small enough to allocate: still refused
+        let mut payload = vec![5, 252];
+        payload.extend_from_slice(&(MAX_DECODE_BYTES as u32 + 1).to_le_bytes());
+        assert!(bytes_to_msg(&payload).is_err());
+
+        // A normal message still decodes
+        let msg = Message::VerAck {
+            addr_from: "localhost:1".to_owned(),
+        };
+        let bytes = encode_to_vec(&msg, standard()).unwrap();
+        assert!(matches!(bytes_to_msg(&bytes), Ok(Message::VerAck { .. })));
+    }
 }
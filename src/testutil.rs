@@ -0,0 +1,121 @@
+//! Fixtures shared by the tests of the other modules.
+
+use std::{
+    ops::Deref,
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+use crate::{ChainParams, Hash, OutPoint, TXInput, TXOutput, Transaction, Wallet};
+
+/// A directory under the system temp dir, unique to the test process and
+/// removed again when dropped, even if the test panics.
+pub struct TestDir {
+    path: PathBuf,
+}
+
+impl TestDir {
+    /// A fresh `rs-blockchain-<name>-<pid>`, cleared of what an earlier run
+    /// may have left behind.
+    pub fn new(name: &str) -> Self {
+        let path =
+            std::env::temp_dir().join(format!("rs-blockchain-{}-{}", name, std::process::id()));
+        std::fs::remove_dir_all(&path).ok();
+        Self { path }
+    }
+}
+
+impl Deref for TestDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl AsRef<Path> for TestDir {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.path).ok();
+    }
+}
+
+/// Runs `open` until sled stops reporting its lock as held, for reopening
+/// a database right after dropping it: sled releases the lock from a
+/// background thread, so the first attempts may still find it taken.
+pub fn reopen<T>(mut open: impl FnMut() -> anyhow::Result<T>) -> T {
+    for _ in 0..50 {
+        match open() {
+            Err(e) if e.to_string().contains("acquire lock") => {
+                thread::sleep(Duration::from_millis(100));
+            }
+            result => return result.unwrap(),
+        }
+    }
+    open().unwrap()
+}
+
+/// Builds the transactions the tests spend and mine, without going through
+/// a wallet and the UTXO set.
+#[derive(Default)]
+pub struct TxBuilder {
+    v_in: Vec<TXInput>,
+    v_out: Vec<TXOutput>,
+}
+
+impl TxBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn input(mut self, input: TXInput) -> Self {
+        self.v_in.push(input);
+        self
+    }
+
+    /// Spends output `vout` of `txid`, unsigned and without a key.
+    pub fn spend(self, txid: Hash, vout: u32) -> Self {
+        self.input(TXInput::new(OutPoint::new(txid, vout)))
+    }
+
+    /// Spends output `vout` of `txid` with the key of `wallet`, ready to be
+    /// signed.
+    pub fn spend_from(self, txid: Hash, vout: u32, wallet: &Wallet) -> Self {
+        self.input(TXInput {
+            pub_key: wallet.public_key.clone(),
+            ..TXInput::new(OutPoint::new(txid, vout))
+        })
+    }
+
+    pub fn output(mut self, output: TXOutput) -> Self {
+        self.v_out.push(output);
+        self
+    }
+
+    pub fn pay(self, value: i32, address: &str, params: &ChainParams) -> Self {
+        self.output(TXOutput::new(value, address, params).unwrap())
+    }
+
+    /// The transaction, with its id set.
+    pub fn build(self) -> Transaction {
+        let mut tx = self.build_without_id();
+        tx.set_id().unwrap();
+        tx
+    }
+
+    /// The transaction as is, for the ones `set_id` refuses.
+    pub fn build_without_id(self) -> Transaction {
+        Transaction {
+            id: String::new(),
+            hash_val: Hash::default(),
+            v_in: self.v_in,
+            v_out: self.v_out,
+        }
+    }
+}
@@ -1,87 +1,528 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, fmt, str::FromStr};
 
-use anyhow::{Context, Ok, Result, anyhow};
-use bincode::{config::standard, serde::encode_to_vec};
+use anyhow::{Ok, Result, anyhow};
+use bincode::{
+    config::standard,
+    serde::{decode_from_slice, encode_to_vec},
+};
 use log::{debug, error};
 use p256::ecdsa::{Signature, SigningKey, VerifyingKey, signature::SignerMut, signature::Verifier};
 use serde::{Deserialize, Serialize};
 
 use sha2::{Digest, Sha256};
 
-use crate::{HashType, UTXOSet, Wallets, get_pub_key_hash, hash_pub_key};
+use crate::{
+    Amount, Blockchain, BlockchainError, ChainParams, CoinSelectionStrategy, DECODE_CONFIG, Hash,
+    MAX_COINBASE_DATA, UTXOSet, Wallets, get_pub_key_hash, hash_pub_key, pub_key_hash_to_address,
+    validate_address,
+};
 
 const SUBSIDY: i32 = 10;
+/// Starts the key hash of a memo output. Key hashes are 20 bytes, so no key
+/// can ever unlock a memo.
+const MEMO_MARKER: &[u8] = b"memo";
+/// Most bytes of data a memo output may carry
+pub const MAX_MEMO_LEN: usize = 80;
+
+/// Block reward paid to the miner of the block at `height`.
+///
+/// Emission is currently flat, so every height pays the same subsidy.
+pub fn block_subsidy(_height: i32) -> i32 {
+    SUBSIDY
+}
+
+/// Splits `funded`, the value of the selected inputs, into the payment to
+/// the recipient and the change, leaving `fee` to neither.
+fn split_payment(
+    funded: i32,
+    amount: i32,
+    fee: i32,
+    subtract_fee_from_amount: bool,
+) -> Result<(i32, i32)> {
+    let (value, needed) = if subtract_fee_from_amount {
+        (amount - fee, amount)
+    } else {
+        let needed = amount
+            .checked_add(fee)
+            .ok_or_else(|| anyhow!("Amount overflow"))?;
+        (amount, needed)
+    };
+    if funded < needed {
+        error!("Not enough funds");
+        return Err(BlockchainError::InsufficientFunds {
+            needed,
+            available: funded,
+        }
+        .into());
+    }
+    if value <= 0 {
+        return Err(anyhow!("Amount {} doesn't cover the fee {}", amount, fee));
+    }
+    Ok((value, funded - value - fee))
+}
+
+/// The output `vin` spends, looked up in `prev_txs`.
+fn spent_output<'a>(
+    vin: &TXInput,
+    prev_txs: &'a HashMap<String, Transaction>,
+) -> Result<&'a TXOutput> {
+    prev_txs
+        .get(&vin.prev_out.txid.to_string())
+        .and_then(|tx| tx.v_out.get(vin.prev_out.vout as usize))
+        .ok_or_else(|| anyhow!("Spent output {} not found", vin.prev_out))
+}
+
+/// Checks the signature of input `input_index` of `tx`, given only the
+/// output it spends, so an entry of the UTXO set is enough.
+///
+/// The public key of the input must hash to the key hash of
+/// `prev_output` under `params`, so only its owner can spend it. The
+/// signed message is the txid of a copy of `tx` without signatures or
+/// public keys, except for this input which carries that key hash.
+pub fn verify_input_signature(
+    tx: &Transaction,
+    input_index: usize,
+    prev_output: &TXOutput,
+    params: &ChainParams,
+) -> bool {
+    let Some(vin) = tx.v_in.get(input_index) else {
+        debug!("Transaction {} has no input {}", tx.id, input_index);
+        return false;
+    };
+    if !vin.uses_key(&prev_output.pub_key_hash, params) {
+        debug!(
+            "Input {} of transaction {} doesn't carry the key of the output it spends",
+            input_index, tx.id
+        );
+        return false;
+    }
+    let mut tx_copy = tx.trimmed_copy();
+    tx_copy.v_in[input_index].pub_key = prev_output.pub_key_hash.clone();
+    if tx_copy.set_id().is_err() {
+        return false;
+    }
+
+    // Extract signature (r, s)
+    let signature_bytes = &vin.signature;
+    if signature_bytes.len() != 64 {
+        debug!(
+            "Signature must be 64 bytes (32 for r, 32 for s) : {}",
+            signature_bytes.len()
+        );
+        return false;
+    }
+    let Some(signature) = Signature::from_slice(signature_bytes).ok() else {
+        debug!("Failed to construct signature");
+        return false;
+    };
+
+    // Handle public key
+    let Some(pub_key) = VerifyingKey::from_sec1_bytes(&vin.pub_key).ok() else {
+        debug!("Invalid public key format");
+        return false;
+    };
+
+    // Verify signature
+    if pub_key.verify(tx_copy.id.as_bytes(), &signature).is_err() {
+        debug!("Verify signature fail");
+        return false;
+    }
+    true
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Transaction {
     pub id: String,
-    pub hash_val: HashType,
+    pub hash_val: Hash,
     pub v_in: Vec<TXInput>,
     pub v_out: Vec<TXOutput>,
 }
 
-impl Transaction {
-    pub fn new_utxo(from: &str, to: &str, amount: i32, utxo_set: &UTXOSet) -> Result<Transaction> {
-        let mut inputs = vec![];
-        let mut outputs = vec![];
+/// Builds a payment in stages, for callers that need to tell failures
+/// apart. Every stage fails with a `BlockchainError` inside the
+/// `anyhow::Error`, see the stages for which.
+#[derive(Debug, Clone, Default)]
+pub struct TransactionBuilder {
+    from: Vec<String>,
+    to: String,
+    amount: i32,
+    fee: i32,
+    subtract_fee_from_amount: bool,
+    strategy: CoinSelectionStrategy,
+    sort_bip69: bool,
+    memo: Option<Vec<u8>>,
+    inputs: Vec<OutPoint>,
+}
+
+impl TransactionBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a source address. Addresses are drawn from in the order they
+    /// are added and change returns to the first one. Adding one again does
+    /// nothing, its outputs would be selected twice otherwise.
+    pub fn from(mut self, address: &str) -> Self {
+        if !self.from.iter().any(|from| from == address) {
+            self.from.push(address.to_owned());
+        }
+        self
+    }
+
+    pub fn from_all(self, addresses: &[String]) -> Self {
+        addresses
+            .iter()
+            .fold(self, |builder, addr| builder.from(addr))
+    }
+
+    pub fn to(mut self, address: &str) -> Self {
+        self.to = address.to_owned();
+        self
+    }
+
+    pub fn amount(mut self, amount: i32) -> Self {
+        self.amount = amount;
+        self
+    }
+
+    /// Left unclaimed by the outputs for the miner, on top of the amount
+    /// unless `subtract_fee_from_amount` is set.
+    pub fn fee(mut self, fee: i32) -> Self {
+        self.fee = fee;
+        self
+    }
+
+    pub fn subtract_fee_from_amount(mut self, subtract: bool) -> Self {
+        self.subtract_fee_from_amount = subtract;
+        self
+    }
 
-        let wallets = Wallets::new()?;
-        let wallet = wallets.get_wallet(from).unwrap();
-        let pub_key_hash = hash_pub_key(&wallet.public_key);
+    pub fn coin_selection(mut self, strategy: CoinSelectionStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
 
-        let (acc, valid_outputs) = utxo_set.find_spendable_outputs(&pub_key_hash, amount)?;
+    /// Adds a memo output carrying `data`, see `TXOutput::new_memo`
+    pub fn memo(mut self, data: &[u8]) -> Self {
+        self.memo = Some(data.to_vec());
+        self
+    }
 
-        if acc < amount {
-            error!("Not enough funds");
-            return Err(anyhow!("Not enough funds: {}", acc));
+    /// Sort inputs and outputs as in BIP69 before signing
+    pub fn sort_bip69(mut self, sort: bool) -> Self {
+        self.sort_bip69 = sort;
+        self
+    }
+
+    /// Spends `outpoint`, which has to belong to a source address. Once an
+    /// input is given this way, only the given ones are spent and no coins
+    /// are selected.
+    pub fn spend(mut self, outpoint: OutPoint) -> Self {
+        if !self.inputs.contains(&outpoint) {
+            self.inputs.push(outpoint);
         }
+        self
+    }
 
-        for (tx_id, outs) in valid_outputs {
-            for out in outs {
-                let input = TXInput {
-                    tx_id: tx_id.to_owned(),
-                    v_out: out,
-                    signature: vec![],
-                    pub_key: wallet.public_key.clone(),
-                };
-                inputs.push(input);
-            }
+    /// Selects the inputs and creates the outputs without needing any
+    /// keys. Fails with `BlockchainError::InvalidAddress` for a bad source
+    /// or destination and `BlockchainError::InsufficientFunds` when the
+    /// sources can't cover the payment, or `BlockchainError::ImmatureFunds`
+    /// when part of what they hold is immature coinbase. With inputs given
+    /// through `spend`, any immature coinbase among them is an
+    /// `ImmatureFunds` error.
+    pub fn build_unsigned(&self, utxo_set: &UTXOSet) -> Result<Transaction> {
+        if self.fee < 0 {
+            return Err(anyhow!("Fee can't be negative: {}", self.fee));
+        }
+        for addr in self.from.iter().chain([&self.to]) {
+            validate_address(addr, &utxo_set.bc.params).map_err(|e| {
+                BlockchainError::InvalidAddress {
+                    address: addr.clone(),
+                    reason: e.to_string(),
+                }
+            })?;
         }
+        let target = if self.subtract_fee_from_amount {
+            self.amount
+        } else {
+            self.amount
+                .checked_add(self.fee)
+                .ok_or_else(|| anyhow!("Amount overflow"))?
+        };
+        let mut outputs = vec![];
+
+        let change_address = self
+            .from
+            .first()
+            .ok_or_else(|| anyhow!("At least one source address is required"))?;
 
-        outputs.push(TXOutput::new(amount, to));
-        if acc > amount {
-            outputs.push(TXOutput::new(acc - amount, from));
+        let (acc, inputs) = if self.inputs.is_empty() {
+            self.select_inputs(utxo_set, target)?
+        } else {
+            self.given_inputs(utxo_set, target)?
+        };
+        let (value, change) =
+            split_payment(acc, self.amount, self.fee, self.subtract_fee_from_amount)?;
+        let params = &utxo_set.bc.params;
+        outputs.push(TXOutput::new(value, &self.to, params)?);
+        if change > 0 {
+            outputs.push(TXOutput::new(change, change_address, params)?);
+        }
+        if let Some(data) = &self.memo {
+            outputs.push(TXOutput::new_memo(data)?);
         }
         let mut tx = Transaction {
             id: "".to_owned(),
-            hash_val: HashType::default(),
+            hash_val: Hash::default(),
             v_in: inputs,
             v_out: outputs,
         };
-        tx.set_id()?;
-        utxo_set.bc.sign_transaction(&mut tx, &wallet.private_key)?;
+        if self.sort_bip69 {
+            tx.sort_bip69()?;
+        } else {
+            tx.set_id()?;
+        }
+        Ok(tx)
+    }
+
+    /// Selects outputs of the sources, in order, until they cover `target`,
+    /// returning their total and the inputs spending them.
+    fn select_inputs(&self, utxo_set: &UTXOSet, target: i32) -> Result<(i32, Vec<TXInput>)> {
+        let mut inputs = vec![];
+        let mut acc = 0;
+        for addr in &self.from {
+            if acc >= target {
+                break;
+            }
+            let pub_key_hash = get_pub_key_hash(addr, &utxo_set.bc.params)?;
+            let (found, valid_outputs) =
+                utxo_set.find_spendable_outputs_with(&pub_key_hash, target - acc, self.strategy)?;
+            acc = acc
+                .checked_add(found)
+                .ok_or_else(|| anyhow!("Amount overflow"))?;
+
+            for (tx_id, outs) in valid_outputs {
+                let txid: Hash = tx_id.parse()?;
+                for out in outs {
+                    let vout = u32::try_from(out)?;
+                    inputs.push(TXInput::new(OutPoint::new(txid, vout)));
+                }
+            }
+        }
+
+        if acc < target {
+            self.check_immature(utxo_set, target, acc)?;
+        }
+        Ok((acc, inputs))
+    }
 
+    /// The inputs given through `spend` and their total. Each has to be an
+    /// unspent output of a source address, and none an immature coinbase:
+    /// those fail with `BlockchainError::ImmatureFunds`, `available` being
+    /// the total of the mature ones.
+    fn given_inputs(&self, utxo_set: &UTXOSet, target: i32) -> Result<(i32, Vec<TXInput>)> {
+        let params = &utxo_set.bc.params;
+        let sources = self
+            .from
+            .iter()
+            .map(|addr| get_pub_key_hash(addr, params))
+            .collect::<Result<Vec<_>>>()?;
+        let mut acc: i32 = 0;
+        let mut immature = 0;
+        let mut spendable_at = 0;
+        for outpoint in &self.inputs {
+            let tx_id = outpoint.txid.to_string();
+            let out = utxo_set
+                .get_tx_out(&tx_id, i32::try_from(outpoint.vout)?)?
+                .ok_or_else(|| anyhow!("Output {} is spent or doesn't exist", outpoint))?;
+            if !sources.iter().any(|key| out.is_locked_with_key(key)) {
+                return Err(anyhow!(
+                    "Output {} doesn't belong to a source address",
+                    outpoint
+                ));
+            }
+            match utxo_set.coinbase_spendable_at(&tx_id)? {
+                Some(height) => {
+                    immature += 1;
+                    spendable_at = spendable_at.max(height);
+                }
+                None => {
+                    acc = acc
+                        .checked_add(out.value)
+                        .ok_or_else(|| anyhow!("Amount overflow"))?;
+                }
+            }
+        }
+        if immature > 0 {
+            return Err(BlockchainError::ImmatureFunds {
+                needed: target,
+                available: acc,
+                immature,
+                spendable_at,
+            }
+            .into());
+        }
+        let inputs = self.inputs.iter().map(|o| TXInput::new(*o)).collect();
+        Ok((acc, inputs))
+    }
+
+    /// Fails with `BlockchainError::ImmatureFunds` when the sources, short of
+    /// `needed` with the `available` spendable outputs, hold immature
+    /// coinbase outputs that were left out.
+    fn check_immature(&self, utxo_set: &UTXOSet, needed: i32, available: i32) -> Result<()> {
+        let mut immature = 0;
+        let mut spendable_at = 0;
+        for addr in &self.from {
+            let pub_key_hash = get_pub_key_hash(addr, &utxo_set.bc.params)?;
+            for (_, height) in utxo_set.immature_outputs(&pub_key_hash)? {
+                immature += 1;
+                spendable_at = spendable_at.max(height);
+            }
+        }
+        if immature == 0 {
+            return Ok(());
+        }
+        Err(BlockchainError::ImmatureFunds {
+            needed,
+            available,
+            immature,
+            spendable_at,
+        }
+        .into())
+    }
+
+    /// Signs `tx` with the keys in `wallets`. Fails with
+    /// `BlockchainError::WalletNotFound` if a source address or spent
+    /// output has no key there and `BlockchainError::SigningFailed` for
+    /// any other signing error.
+    pub fn sign(
+        &self,
+        mut tx: Transaction,
+        wallets: &Wallets,
+        bc: &Blockchain,
+    ) -> Result<Transaction> {
+        self.check_wallets(wallets, &bc.params)?;
+        bc.sign_transaction_with_wallets(&mut tx, wallets)
+            .map_err(|e| {
+                if e.is::<BlockchainError>() {
+                    e
+                } else {
+                    BlockchainError::SigningFailed {
+                        reason: e.to_string(),
+                    }
+                    .into()
+                }
+            })?;
+        // Signing filled in the public keys, which are part of the id
+        tx.set_id()?;
         Ok(tx)
     }
 
-    pub fn new_coinbase(to: &str, data: String) -> Result<Transaction> {
+    /// `build_unsigned` then `sign`, checking for the keys first so that a
+    /// missing wallet is reported before any coin selection error.
+    pub fn build(&self, wallets: &Wallets, utxo_set: &UTXOSet) -> Result<Transaction> {
+        self.check_wallets(wallets, &utxo_set.bc.params)?;
+        let tx = self.build_unsigned(utxo_set)?;
+        self.sign(tx, wallets, &utxo_set.bc)
+    }
+
+    fn check_wallets(&self, wallets: &Wallets, params: &ChainParams) -> Result<()> {
+        for addr in &self.from {
+            // Wallets may be stored under their address with other
+            // parameters, so they are looked up by key hash instead
+            let known = validate_address(addr, params).is_ok()
+                && get_pub_key_hash(addr, params).is_ok_and(|pub_key_hash| {
+                    wallets
+                        .find_by_pub_key_hash(&pub_key_hash, params)
+                        .is_some()
+                });
+            known
+                .then_some(())
+                .ok_or_else(|| BlockchainError::WalletNotFound {
+                    address: addr.clone(),
+                })?;
+        }
+        Ok(())
+    }
+}
+
+impl Transaction {
+    /// Builds a payment funded by the outputs of several owned addresses,
+    /// signed with the keys in `wallets`. Addresses are drawn from in order
+    /// and change returns to the first one. Fails with
+    /// `BlockchainError::WalletNotFound` if a `from` address has no key
+    /// there.
+    ///
+    /// `fee` is left unclaimed by the outputs for the miner. It is paid on
+    /// top of `amount` unless `subtract_fee_from_amount` is set, in which
+    /// case the recipient gets `amount - fee`.
+    pub fn new_with_wallets(
+        from: &[String],
+        to: &str,
+        amount: i32,
+        fee: i32,
+        subtract_fee_from_amount: bool,
+        wallets: &Wallets,
+        utxo_set: &UTXOSet,
+    ) -> Result<Transaction> {
+        TransactionBuilder::new()
+            .from_all(from)
+            .to(to)
+            .amount(amount)
+            .fee(fee)
+            .subtract_fee_from_amount(subtract_fee_from_amount)
+            .coin_selection(wallets.coin_selection)
+            .sort_bip69(wallets.sort_bip69)
+            .build(wallets, utxo_set)
+    }
+
+    /// The build half of `new_with_wallets`: selects the inputs and creates the
+    /// outputs without needing any keys. Inputs are left without public keys
+    /// and signatures, see `Psbt` for signing them elsewhere. The inputs of
+    /// each address are picked with `strategy`.
+    pub fn new_unsigned(
+        from: &[String],
+        to: &str,
+        amount: i32,
+        fee: i32,
+        subtract_fee_from_amount: bool,
+        strategy: CoinSelectionStrategy,
+        utxo_set: &UTXOSet,
+    ) -> Result<Transaction> {
+        TransactionBuilder::new()
+            .from_all(from)
+            .to(to)
+            .amount(amount)
+            .fee(fee)
+            .subtract_fee_from_amount(subtract_fee_from_amount)
+            .coin_selection(strategy)
+            .build_unsigned(utxo_set)
+    }
+
+    /// A coinbase paying the subsidy to `to`, an address made with `params`,
+    /// carrying `data` in its input, or a note of who it rewards when
+    /// `data` is empty.
+    pub fn new_coinbase(to: &str, data: String, params: &ChainParams) -> Result<Transaction> {
         let data = if data.is_empty() {
             format!("Reward to '{}'", to).to_owned()
         } else {
             data
         };
+        if data.len() > MAX_COINBASE_DATA {
+            return Err(anyhow!(
+                "Coinbase data is {} bytes, at most {} are allowed",
+                data.len(),
+                MAX_COINBASE_DATA
+            ));
+        }
 
-        let tx_in = TXInput {
-            tx_id: "".to_owned(),
-            v_out: -1,
-            signature: vec![],
-            pub_key: data.into(),
-        };
+        let tx_in = TXInput::coinbase(data.into());
 
-        let tx_out = TXOutput::new(SUBSIDY, to);
+        let tx_out = TXOutput::new(SUBSIDY, to, params)?;
         let mut tx = Transaction {
             id: "".to_owned(),
-            hash_val: HashType::default(),
+            hash_val: Hash::default(),
             v_in: vec![tx_in],
             v_out: vec![tx_out],
         };
@@ -89,25 +530,122 @@ impl Transaction {
         Ok(tx)
     }
 
+    /// Orders inputs by txid then output index, and outputs by value then
+    /// public key hash, as in BIP69. Must happen before signing, as the
+    /// order is part of what is signed.
+    pub fn sort_bip69(&mut self) -> Result<()> {
+        self.v_in.sort_by_key(|vin| vin.prev_out);
+        self.v_out.sort_by(|a, b| {
+            a.value
+                .cmp(&b.value)
+                .then_with(|| a.pub_key_hash.cmp(&b.pub_key_hash))
+        });
+        self.set_id()
+    }
+
     pub fn set_id(&mut self) -> Result<()> {
         let hash = self.hash()?;
-        self.id = hex::encode(hash);
+        self.id = hash.to_string();
         self.hash_val = hash;
         Ok(())
     }
 
-    pub fn hash(&self) -> Result<[u8; 32]> {
+    /// The txid. Input signatures are witness data and left out, so
+    /// re-encoding a signature can't change the id of a transaction.
+    pub fn hash(&self) -> Result<Hash> {
+        self.digest(false)
+    }
+
+    /// Hash over the whole transaction, signatures included. Blocks commit
+    /// to these so the witness can't be swapped out after mining.
+    pub fn wtxid(&self) -> Result<Hash> {
+        self.digest(true)
+    }
+
+    fn digest(&self, with_witness: bool) -> Result<Hash> {
         let mut data = self.clone();
         data.id = "".to_owned();
-        data.hash_val = HashType::default();
+        data.hash_val = Hash::default();
+        if !with_witness {
+            for vin in &mut data.v_in {
+                vin.signature.clear();
+            }
+        }
         let data = encode_to_vec(data, standard())?;
         let mut hasher = Sha256::new();
         hasher.update(data);
-        Ok(hasher.finalize().into())
+        Ok(Hash(hasher.finalize().into()))
+    }
+
+    /// Hex of the transaction as stored and sent, for handing it to someone
+    /// else to broadcast.
+    pub fn to_hex(&self) -> Result<String> {
+        Ok(hex::encode(encode_to_vec(self, standard())?))
+    }
+
+    /// Decodes `to_hex` output. The id must match the contents, anything
+    /// else is checked when the transaction is verified.
+    pub fn from_hex(data: &str) -> Result<Self> {
+        let bytes = hex::decode(data.trim()).map_err(|e| anyhow!("Invalid hex: {}", e))?;
+        let (tx, len): (Transaction, usize) = decode_from_slice(&bytes, DECODE_CONFIG)?;
+        if len != bytes.len() {
+            return Err(anyhow!(
+                "{} trailing bytes after transaction",
+                bytes.len() - len
+            ));
+        }
+        let hash = tx.hash()?;
+        if tx.hash_val != hash || tx.id != hash.to_string() {
+            return Err(anyhow!(
+                "Transaction id {} doesn't match its contents",
+                tx.id
+            ));
+        }
+        Ok(tx)
+    }
+
+    /// Size of the transaction as stored and sent, in bytes.
+    pub fn size(&self) -> Result<usize> {
+        Ok(encode_to_vec(self, standard())?.len())
+    }
+
+    /// Checks the input and output counts against the network limits.
+    /// Coinbase transactions are exempt from the input limit.
+    pub fn within_size_limits(&self, params: &ChainParams) -> bool {
+        if !self.is_coinbase() && self.v_in.len() > params.max_tx_inputs {
+            debug!("Transaction {} has too many inputs", self.id);
+            return false;
+        }
+        if self.v_out.len() > params.max_tx_outputs {
+            debug!("Transaction {} has too many outputs", self.id);
+            return false;
+        }
+        true
+    }
+
+    /// The data a coinbase carries in its input, like the message of the
+    /// genesis block. `None` for other transactions.
+    pub fn coinbase_message(&self) -> Option<String> {
+        self.is_coinbase()
+            .then(|| String::from_utf8_lossy(&self.v_in[0].pub_key).into_owned())
+    }
+
+    /// Every transaction needs at least one input and one output. A
+    /// coinbase has its synthetic input, so the rule is the same for it.
+    pub fn is_well_formed(&self) -> bool {
+        if self.v_in.is_empty() {
+            debug!("Transaction {} has no inputs", self.id);
+            return false;
+        }
+        if self.v_out.is_empty() {
+            debug!("Transaction {} has no outputs", self.id);
+            return false;
+        }
+        true
     }
 
     pub fn is_coinbase(&self) -> bool {
-        self.v_in.len() == 1 && self.v_in[0].tx_id.is_empty() && self.v_in[0].v_out == -1
+        self.v_in.len() == 1 && self.v_in[0].coinbase
     }
 
     pub fn sign(
@@ -119,87 +657,130 @@ impl Transaction {
             return Ok(());
         }
 
+        for in_id in 0..self.v_in.len() {
+            self.sign_input(in_id, private_key, &prev_txs)?;
+        }
+        Ok(())
+    }
+
+    /// Signs every input with the wallet owning the output it spends, the
+    /// one whose key hashes to it under `params`.
+    pub fn sign_with_wallets(
+        &mut self,
+        wallets: &Wallets,
+        prev_txs: HashMap<String, Transaction>,
+        params: &ChainParams,
+    ) -> Result<()> {
+        if self.is_coinbase() {
+            return Ok(());
+        }
+
+        for in_id in 0..self.v_in.len() {
+            let vin = &self.v_in[in_id];
+            let pub_key_hash = &spent_output(vin, &prev_txs)?.pub_key_hash;
+            let wallet = wallets
+                .find_by_pub_key_hash(pub_key_hash, params)
+                .ok_or_else(|| BlockchainError::WalletNotFound {
+                    address: pub_key_hash_to_address(pub_key_hash, params),
+                })?;
+            let private_key = wallet.private_key.clone();
+            self.v_in[in_id].pub_key = wallet.public_key.clone();
+            self.sign_input(in_id, &private_key, &prev_txs)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn sign_input(
+        &mut self,
+        in_id: usize,
+        private_key: &[u8],
+        prev_txs: &HashMap<String, Transaction>,
+    ) -> Result<()> {
         let mut tx_copy = self.trimmed_copy();
 
-        for in_id in 0..tx_copy.v_in.len() {
-            let prev_tx = prev_txs.get(&tx_copy.v_in[in_id].tx_id).unwrap();
-            tx_copy.v_in[in_id].signature.clear();
-            tx_copy.v_in[in_id].pub_key = prev_tx.v_out[tx_copy.v_in[in_id].v_out as usize]
-                .pub_key_hash
-                .clone();
-            tx_copy.set_id()?;
-            tx_copy.v_in[in_id].pub_key = vec![];
+        let pub_key_hash = spent_output(&self.v_in[in_id], prev_txs)?
+            .pub_key_hash
+            .clone();
+        tx_copy.v_in[in_id].signature.clear();
+        tx_copy.v_in[in_id].pub_key = pub_key_hash;
+        tx_copy.set_id()?;
 
-            let mut signing_key = SigningKey::from_bytes(private_key.into())?;
-            let signature: p256::ecdsa::Signature = signing_key.sign(tx_copy.id.as_bytes());
+        let mut signing_key = SigningKey::from_bytes(private_key.into())?;
+        let signature: p256::ecdsa::Signature = signing_key.sign(tx_copy.id.as_bytes());
 
-            let r = signature.r().to_bytes();
-            let s = signature.s().to_bytes();
+        let r = signature.r().to_bytes();
+        let s = signature.s().to_bytes();
 
-            let mut signature_bytes = Vec::new();
-            signature_bytes.extend_from_slice(&r);
-            signature_bytes.extend_from_slice(&s);
+        let mut signature_bytes = Vec::new();
+        signature_bytes.extend_from_slice(&r);
+        signature_bytes.extend_from_slice(&s);
 
-            self.v_in[in_id].signature = signature_bytes;
-        }
+        self.v_in[in_id].signature = signature_bytes;
         Ok(())
     }
 
-    pub fn verify(&self, prev_txs: HashMap<String, Transaction>) -> Result<bool> {
-        let mut tx_copy = self.trimmed_copy();
-
-        for in_id in 0..tx_copy.v_in.len() {
-            let prev_tx = prev_txs.get(&tx_copy.v_in[in_id].tx_id).unwrap();
-
-            tx_copy.v_in[in_id].signature.clear();
-            tx_copy.v_in[in_id].pub_key = prev_tx.v_out[tx_copy.v_in[in_id].v_out as usize]
-                .pub_key_hash
-                .clone();
-            tx_copy.set_id()?;
-            tx_copy.v_in[in_id].pub_key = vec![];
-
-            // Extract signature (r, s)
-            let signature_bytes = &self.v_in[in_id].signature;
-            if signature_bytes.len() != 64 {
-                debug!(
-                    "Signature must be 64 bytes (32 for r, 32 for s) : {}",
-                    signature_bytes.len()
-                );
-                return Ok(false);
-            }
-            let r_bytes: [u8; 32] = signature_bytes[0..32]
-                .try_into()
-                .context("Invalid r length")?;
-            let s_bytes: [u8; 32] = signature_bytes[32..64]
-                .try_into()
-                .context("Invalid s length")?;
-            let signature = Signature::from_scalars(r_bytes, s_bytes)
-                .context("Failed to construct signature")?;
-
-            // Handle public key
-            let pub_key_bytes = &self.v_in[in_id].pub_key;
-            let pub_key = VerifyingKey::from_sec1_bytes(pub_key_bytes)
-                .context("Invalid public key format")?;
-
-            // Verify signature
-            if pub_key.verify(tx_copy.id.as_bytes(), &signature).is_err() {
-                debug!("Verify signature fail");
+    pub fn verify(
+        &self,
+        prev_txs: HashMap<String, Transaction>,
+        params: &ChainParams,
+    ) -> Result<bool> {
+        for in_id in 0..self.v_in.len() {
+            if !self.verify_input(in_id, &prev_txs, params)? {
                 return Ok(false);
             }
         }
         Ok(true)
     }
 
+    /// Checks the signature of a single input against the output it spends,
+    /// see `verify_input_signature`.
+    pub fn verify_input(
+        &self,
+        in_id: usize,
+        prev_txs: &HashMap<String, Transaction>,
+        params: &ChainParams,
+    ) -> Result<bool> {
+        let prev_output = spent_output(&self.v_in[in_id], prev_txs)?;
+        Ok(verify_input_signature(self, in_id, prev_output, params))
+    }
+
+    /// Verifies every input and reports the details of each, for diagnosing
+    /// signing problems.
+    pub fn verify_report(
+        &self,
+        prev_txs: &HashMap<String, Transaction>,
+        params: &ChainParams,
+    ) -> Vec<InputReport> {
+        let mut reports = vec![];
+        for (in_id, vin) in self.v_in.iter().enumerate() {
+            let expected_pub_key_hash = spent_output(vin, prev_txs)
+                .map(|out| out.pub_key_hash.clone())
+                .unwrap_or_default();
+            let pub_key_hash = hash_pub_key(&vin.pub_key, params);
+            let signature_valid = self.verify_input(in_id, prev_txs, params).unwrap_or(false);
+            reports.push(InputReport {
+                index: in_id,
+                tx_id: vin.prev_out.txid.to_string(),
+                v_out: vin.prev_out.vout,
+                signature_len: vin.signature.len(),
+                pub_key_hash: hex::encode(&pub_key_hash),
+                expected_pub_key_hash: hex::encode(&expected_pub_key_hash),
+                key_matches: pub_key_hash == expected_pub_key_hash,
+                signature_valid,
+            });
+        }
+        reports
+    }
+
     fn trimmed_copy(&self) -> Self {
         let mut inputs = vec![];
         let mut outputs = vec![];
 
         for ele in &self.v_in {
             inputs.push(TXInput {
-                tx_id: ele.tx_id.clone(),
-                v_out: ele.v_out,
                 signature: vec![],
                 pub_key: vec![],
+                ..ele.clone()
             });
         }
 
@@ -219,15 +800,59 @@ impl Transaction {
     }
 }
 
+impl fmt::Display for Transaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total = Amount::sum_values(self.v_out.iter().map(|out| out.value))
+            .map(|total| total.to_string())
+            .unwrap_or_else(|_| "out of range".to_owned());
+        write!(
+            f,
+            "tx {}: {} in, {} out, value {}",
+            self.id,
+            self.v_in.len(),
+            self.v_out.len(),
+            total
+        )?;
+        if self.is_coinbase() {
+            write!(f, " (coinbase)")?;
+        }
+        fmt::Result::Ok(())
+    }
+}
+
+/// Per-input result of `Transaction::verify_report`.
+#[derive(Serialize, Debug, Clone)]
+pub struct InputReport {
+    pub index: usize,
+    pub tx_id: String,
+    pub v_out: u32,
+    pub signature_len: usize,
+    /// Hash of the public key carried by the input
+    pub pub_key_hash: String,
+    /// Hash the spent output is locked to
+    pub expected_pub_key_hash: String,
+    pub key_matches: bool,
+    pub signature_valid: bool,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct TXOutputs {
     pub outputs: Vec<TXOutput>,
+    /// Height of the block the transaction was mined in
+    pub height: i32,
+    /// Hash of the block the transaction was mined in
+    pub block_hash: Hash,
 }
 
 impl TXOutputs {
     pub fn push(&mut self, val: TXOutput) {
         self.outputs.push(val);
     }
+
+    /// Value of all the outputs, failing instead of overflowing.
+    pub fn total(&self) -> Result<Amount> {
+        Amount::sum_values(self.outputs.iter().map(|out| out.value))
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -237,35 +862,788 @@ pub struct TXOutput {
 }
 
 impl TXOutput {
-    pub fn new(value: i32, address: &str) -> Self {
+    /// An output paying `value` to `address`, an address made with
+    /// `params`.
+    pub fn new(value: i32, address: &str, params: &ChainParams) -> Result<Self> {
         let mut v = Self {
             value,
             pub_key_hash: vec![],
         };
-        v.lock(address);
-        v
+        v.lock(address, params)?;
+        Ok(v)
+    }
+
+    /// An unspendable output worth nothing that carries `data` on the chain.
+    pub fn new_memo(data: &[u8]) -> Result<Self> {
+        if data.len() > MAX_MEMO_LEN {
+            return Err(anyhow!(
+                "Memo is {} bytes, at most {} are allowed",
+                data.len(),
+                MAX_MEMO_LEN
+            ));
+        }
+        Ok(Self {
+            value: 0,
+            pub_key_hash: [MEMO_MARKER, data].concat(),
+        })
+    }
+
+    /// The data of a memo output, see `new_memo`.
+    pub fn memo(&self) -> Option<&[u8]> {
+        if self.value != 0 {
+            return None;
+        }
+        self.pub_key_hash.strip_prefix(MEMO_MARKER)
     }
 
     pub fn is_locked_with_key(&self, pub_key_hash: &[u8]) -> bool {
         self.pub_key_hash == pub_key_hash
     }
 
-    fn lock(&mut self, address: &str) {
-        self.pub_key_hash = get_pub_key_hash(address);
+    fn lock(&mut self, address: &str, params: &ChainParams) -> Result<()> {
+        self.pub_key_hash = get_pub_key_hash(address, params)?;
+        Ok(())
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// An output of a mined transaction, as spent by an input.
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default,
+)]
+pub struct OutPoint {
+    pub txid: Hash,
+    pub vout: u32,
+}
+
+impl OutPoint {
+    pub fn new(txid: Hash, vout: u32) -> Self {
+        Self { txid, vout }
+    }
+}
+
+impl fmt::Display for OutPoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.txid, self.vout)
+    }
+}
+
+impl FromStr for OutPoint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (txid, vout) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Invalid outpoint {}, expected txid:vout", s))?;
+        let vout = vout
+            .parse()
+            .map_err(|e| anyhow!("Invalid outpoint {}: {}", s, e))?;
+        Ok(Self::new(txid.parse()?, vout))
+    }
+}
+
+/// Encoded as `LegacyTXInput`, so txids, signatures and stored chains are
+/// the same as before inputs had an `OutPoint`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(try_from = "LegacyTXInput")]
 pub struct TXInput {
-    pub tx_id: String,
-    pub v_out: i32,
+    /// The output spent, unused for the input of a coinbase
+    pub prev_out: OutPoint,
+    /// Set on the only input of a coinbase, which spends nothing
+    pub coinbase: bool,
     pub signature: Vec<u8>,
+    /// The public key of the spender, or the data of a coinbase
     pub pub_key: Vec<u8>,
 }
 
 impl TXInput {
-    pub fn uses_key(&self, pub_key_hash: &[u8]) -> bool {
-        let v = hash_pub_key(&self.pub_key);
+    /// An input spending `prev_out`, not signed yet.
+    pub fn new(prev_out: OutPoint) -> Self {
+        Self {
+            prev_out,
+            coinbase: false,
+            signature: vec![],
+            pub_key: vec![],
+        }
+    }
+
+    /// The input of a coinbase, carrying `data`.
+    pub fn coinbase(data: Vec<u8>) -> Self {
+        Self {
+            prev_out: OutPoint::default(),
+            coinbase: true,
+            signature: vec![],
+            pub_key: data,
+        }
+    }
+
+    /// Whether the public key of this input hashes, under `params`, to
+    /// `pub_key_hash`.
+    pub fn uses_key(&self, pub_key_hash: &[u8], params: &ChainParams) -> bool {
+        let v = hash_pub_key(&self.pub_key, params);
         v == pub_key_hash
     }
 }
+
+/// The first encoding of `TXInput`: the spent txid in hex and the output
+/// index, with an empty txid and an index of -1 for a coinbase. A new
+/// encoding would change every txid, so it needs a new
+/// `CHAIN_FORMAT_VERSION` and protocol version.
+#[derive(Serialize, Deserialize)]
+struct LegacyTXInput {
+    tx_id: String,
+    v_out: i32,
+    signature: Vec<u8>,
+    pub_key: Vec<u8>,
+}
+
+impl TryFrom<TXInput> for LegacyTXInput {
+    type Error = anyhow::Error;
+
+    fn try_from(input: TXInput) -> Result<Self> {
+        let (tx_id, v_out) = if input.coinbase {
+            (String::new(), -1)
+        } else {
+            let v_out = i32::try_from(input.prev_out.vout).map_err(|_| {
+                anyhow!(
+                    "Input spends output {}, past the last encodable index {}",
+                    input.prev_out.vout,
+                    i32::MAX
+                )
+            })?;
+            (input.prev_out.txid.to_string(), v_out)
+        };
+        Ok(Self {
+            tx_id,
+            v_out,
+            signature: input.signature,
+            pub_key: input.pub_key,
+        })
+    }
+}
+
+/// Fails for an input the legacy encoding can't hold, rather than
+/// encoding another output than the one spent.
+impl Serialize for TXInput {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        LegacyTXInput::try_from(self.clone())
+            .map_err(serde::ser::Error::custom)?
+            .serialize(serializer)
+    }
+}
+
+impl TryFrom<LegacyTXInput> for TXInput {
+    type Error = anyhow::Error;
+
+    fn try_from(input: LegacyTXInput) -> Result<Self> {
+        let mut res = if input.tx_id.is_empty() && input.v_out == -1 {
+            TXInput::coinbase(input.pub_key)
+        } else {
+            let vout = u32::try_from(input.v_out)
+                .map_err(|_| anyhow!("Input spends negative output {}", input.v_out))?;
+            let mut res = TXInput::new(OutPoint::new(input.tx_id.parse()?, vout));
+            res.pub_key = input.pub_key;
+            res
+        };
+        res.signature = input.signature;
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::{TestDir, TxBuilder};
+
+    fn tx_with_counts(inputs: usize, outputs: usize) -> Transaction {
+        let output = TXOutput {
+            value: 1,
+            pub_key_hash: vec![],
+        };
+        let tx = (0..inputs).fold(TxBuilder::new(), |tx, _| tx.spend(Hash::default(), 0));
+        (0..outputs)
+            .fold(tx, |tx, _| tx.output(output.clone()))
+            .build_without_id()
+    }
+
+    #[test]
+    fn test_size_limits() {
+        let params = ChainParams::default();
+        let max_in = params.max_tx_inputs;
+        let max_out = params.max_tx_outputs;
+
+        assert!(tx_with_counts(max_in, max_out).within_size_limits(&params));
+        assert!(!tx_with_counts(max_in + 1, 1).within_size_limits(&params));
+        assert!(!tx_with_counts(1, max_out + 1).within_size_limits(&params));
+
+        let mut cbtx = tx_with_counts(1, 1);
+        cbtx.v_in[0].coinbase = true;
+        assert!(cbtx.within_size_limits(&params));
+    }
+
+    #[test]
+    fn test_empty_inputs_or_outputs_are_malformed() {
+        assert!(tx_with_counts(1, 1).is_well_formed());
+        assert!(!tx_with_counts(0, 1).is_well_formed());
+        assert!(!tx_with_counts(1, 0).is_well_formed());
+        assert!(!tx_with_counts(0, 0).is_well_formed());
+
+        let mut cbtx = tx_with_counts(1, 0);
+        cbtx.v_in[0].coinbase = true;
+        assert!(cbtx.is_coinbase());
+        assert!(!cbtx.is_well_formed());
+    }
+
+    #[test]
+    fn test_subtract_fee_empties_address() {
+        // An address holding 10 can send all of it only if the fee comes out
+        // of the amount
+        assert_eq!(split_payment(10, 10, 1, true).unwrap(), (9, 0));
+        assert!(split_payment(10, 10, 1, false).is_err());
+        assert_eq!(split_payment(15, 10, 1, false).unwrap(), (10, 4));
+        assert!(split_payment(10, 1, 1, true).is_err());
+    }
+
+    #[test]
+    fn test_txid_excludes_signatures() {
+        let params = ChainParams::default();
+        let wallet = crate::Wallet::new();
+        let addr = wallet.get_address(&params);
+        let prev = Transaction::new_coinbase(&addr, String::new(), &params).unwrap();
+        let mut tx = TxBuilder::new()
+            .spend_from(prev.hash_val, 0, &wallet)
+            .pay(10, &addr, &params)
+            .build();
+        let prev_txs = HashMap::from([(prev.id.clone(), prev)]);
+        tx.sign(&wallet.private_key, prev_txs.clone()).unwrap();
+        assert_eq!(tx.hash().unwrap(), tx.hash_val);
+        assert!(tx.verify(prev_txs.clone(), &params).unwrap());
+
+        let mut mutated = tx.clone();
+        mutated.v_in[0].signature[63] ^= 1;
+        assert_eq!(mutated.hash().unwrap(), tx.hash().unwrap());
+        assert_ne!(mutated.wtxid().unwrap(), tx.wtxid().unwrap());
+        assert!(!mutated.verify(prev_txs, &params).unwrap());
+    }
+
+    #[test]
+    fn test_hex_round_trip() {
+        let params = ChainParams::default();
+        let addr = crate::Wallet::new().get_address(&params);
+        let tx = Transaction::new_coinbase(&addr, "hex".to_owned(), &params).unwrap();
+        let hex = tx.to_hex().unwrap();
+        let decoded = Transaction::from_hex(&format!("{}\n", hex)).unwrap();
+        assert_eq!(decoded.id, tx.id);
+        assert_eq!(decoded.wtxid().unwrap(), tx.wtxid().unwrap());
+
+        assert!(Transaction::from_hex("zz").is_err());
+        assert!(Transaction::from_hex(&format!("{}00", hex)).is_err());
+        let mut tampered = tx.clone();
+        tampered.v_out[0].value += 1;
+        assert!(Transaction::from_hex(&tampered.to_hex().unwrap()).is_err());
+        // An id claiming to be 1 TiB long
+        assert!(Transaction::from_hex("fd0000000000010000").is_err());
+    }
+
+    #[test]
+    fn test_input_encoding_round_trip() {
+        let legacy = |tx_id: &str, v_out| {
+            encode_to_vec(
+                LegacyTXInput {
+                    tx_id: tx_id.to_owned(),
+                    v_out,
+                    signature: vec![1, 2],
+                    pub_key: vec![3],
+                },
+                standard(),
+            )
+            .unwrap()
+        };
+        let decode =
+            |bytes: &[u8]| -> Result<TXInput> { Ok(decode_from_slice(bytes, standard())?.0) };
+
+        let prev_out = OutPoint::new(Hash([0xab; 32]), 7);
+        let input = TXInput {
+            signature: vec![1, 2],
+            pub_key: vec![3],
+            ..TXInput::new(prev_out)
+        };
+        // Encoded as before, so txids don't change
+        let bytes = encode_to_vec(&input, standard()).unwrap();
+        assert_eq!(bytes, legacy(&"ab".repeat(32), 7));
+        let decoded = decode(&bytes).unwrap();
+        assert_eq!(decoded.prev_out, prev_out);
+        assert!(!decoded.coinbase);
+        assert_eq!(decoded.signature, input.signature);
+        assert_eq!(decoded.pub_key, input.pub_key);
+
+        assert!(decode(&legacy("zz", 0)).is_err());
+        assert!(decode(&legacy(&"ab".repeat(32), -1)).is_err());
+
+        // Past i32::MAX the index can't be encoded, and isn't clamped
+        let far = TXInput::new(OutPoint::new(prev_out.txid, i32::MAX as u32 + 1));
+        assert!(encode_to_vec(&far, standard()).is_err());
+        let mut tx = TxBuilder::new().input(far).build_without_id();
+        assert!(tx.set_id().is_err());
+    }
+
+    #[test]
+    fn test_coinbase_detection() {
+        let params = ChainParams::default();
+        let addr = crate::Wallet::new().get_address(&params);
+        let cbtx = Transaction::new_coinbase(&addr, String::new(), &params).unwrap();
+        assert!(cbtx.is_coinbase());
+        let decoded = Transaction::from_hex(&cbtx.to_hex().unwrap()).unwrap();
+        assert!(decoded.is_coinbase());
+        assert_eq!(decoded.id, cbtx.id);
+
+        // Only the flag makes a coinbase, not spending the zero outpoint
+        assert!(!tx_with_counts(1, 1).is_coinbase());
+        let bytes = encode_to_vec(&tx_with_counts(1, 1).v_in[0], standard()).unwrap();
+        let (input, _): (TXInput, usize) = decode_from_slice(&bytes, standard()).unwrap();
+        assert!(!input.coinbase);
+        // A coinbase has a single input
+        let mut two_inputs = cbtx.clone();
+        two_inputs.v_in.push(TXInput::new(OutPoint::default()));
+        assert!(!two_inputs.is_coinbase());
+    }
+
+    #[test]
+    fn test_coinbase_message_round_trip() {
+        let params = ChainParams::default();
+        let addr = crate::Wallet::new().get_address(&params);
+        let message = "Chancellor on brink of second bailout for banks";
+        let tx = Transaction::new_coinbase(&addr, message.to_owned(), &params).unwrap();
+        let decoded = Transaction::from_hex(&tx.to_hex().unwrap()).unwrap();
+        assert_eq!(decoded.coinbase_message().as_deref(), Some(message));
+
+        let default = Transaction::new_coinbase(&addr, String::new(), &params).unwrap();
+        assert_eq!(
+            default.coinbase_message(),
+            Some(format!("Reward to '{}'", addr))
+        );
+        assert!(Transaction::new_coinbase(&addr, "x".repeat(MAX_COINBASE_DATA), &params).is_ok());
+        assert!(
+            Transaction::new_coinbase(&addr, "x".repeat(MAX_COINBASE_DATA + 1), &params).is_err()
+        );
+        assert_eq!(tx_with_counts(1, 1).coinbase_message(), None);
+    }
+
+    #[test]
+    fn test_verify_input_signature_with_spent_output() {
+        let wallet = crate::Wallet::new();
+        let addr = wallet.get_address(&ChainParams::default());
+        let prev =
+            Transaction::new_coinbase(&addr, String::new(), &ChainParams::default()).unwrap();
+        let mut tx = TxBuilder::new()
+            .spend_from(prev.hash_val, 0, &wallet)
+            .pay(10, &addr, &ChainParams::default())
+            .build();
+        tx.sign(
+            &wallet.private_key,
+            HashMap::from([(prev.id.clone(), prev.clone())]),
+        )
+        .unwrap();
+
+        let params = ChainParams::default();
+        assert!(verify_input_signature(&tx, 0, &prev.v_out[0], &params));
+        assert!(!verify_input_signature(&tx, 1, &prev.v_out[0], &params));
+        // The signature commits to the key hash of the spent output
+        let other = TXOutput::new(
+            10,
+            &crate::Wallet::new().get_address(&ChainParams::default()),
+            &ChainParams::default(),
+        )
+        .unwrap();
+        assert!(!verify_input_signature(&tx, 0, &other, &params));
+    }
+
+    #[test]
+    fn test_foreign_key_cant_spend_output() {
+        let params = ChainParams::default();
+        let owner = crate::Wallet::new();
+        let thief = crate::Wallet::new();
+        let prev =
+            Transaction::new_coinbase(&owner.get_address(&params), String::new(), &params).unwrap();
+        let mut tx = TxBuilder::new()
+            .spend_from(prev.hash_val, 0, &thief)
+            .pay(10, &thief.get_address(&params), &params)
+            .build();
+        let prev_txs = HashMap::from([(prev.id.clone(), prev.clone())]);
+        // A valid signature of the thief, over the owner's key hash
+        tx.sign(&thief.private_key, prev_txs.clone()).unwrap();
+
+        assert!(!verify_input_signature(&tx, 0, &prev.v_out[0], &params));
+        assert!(!tx.verify(prev_txs.clone(), &params).unwrap());
+        let report = &tx.verify_report(&prev_txs, &params)[0];
+        assert!(!report.key_matches);
+        assert!(!report.signature_valid);
+    }
+
+    #[test]
+    fn test_sort_bip69() {
+        let input = |txid: u8, vout| TXInput::new(OutPoint::new(Hash([txid; 32]), vout));
+        let output = |value, pub_key_hash: u8| TXOutput {
+            value,
+            pub_key_hash: vec![pub_key_hash],
+        };
+        let mut tx = TxBuilder::new()
+            .input(input(0xbb, 0))
+            .input(input(0xaa, 1))
+            .input(input(0xaa, 0))
+            .output(output(5, 2))
+            .output(output(3, 9))
+            .output(output(5, 1))
+            .build();
+        let unsorted_id = tx.id.clone();
+        tx.sort_bip69().unwrap();
+
+        let inputs: Vec<_> = tx.v_in.iter().map(|i| i.prev_out).collect();
+        assert_eq!(
+            inputs,
+            [
+                OutPoint::new(Hash([0xaa; 32]), 0),
+                OutPoint::new(Hash([0xaa; 32]), 1),
+                OutPoint::new(Hash([0xbb; 32]), 0),
+            ]
+        );
+        let outputs: Vec<_> = tx
+            .v_out
+            .iter()
+            .map(|o| (o.value, o.pub_key_hash[0]))
+            .collect();
+        assert_eq!(outputs, [(3, 9), (5, 1), (5, 2)]);
+        assert_ne!(tx.id, unsorted_id);
+        assert_eq!(tx.hash_val, tx.hash().unwrap());
+    }
+
+    #[test]
+    fn test_send_from_unknown_address() {
+        let params = ChainParams::default();
+        let dir = TestDir::new("unknown-from");
+        let mut wallets = Wallets::default();
+        let owned = wallets.create_wallet(&params);
+        let unknown = crate::Wallet::new().get_address(&params);
+        let bc = crate::Blockchain::create_at(dir.join("blockchain"), &unknown).unwrap();
+        let mut utxo_set = UTXOSet::with_path(bc, dir.join("utxos"));
+        utxo_set.reindex().unwrap();
+
+        let err = Transaction::new_with_wallets(
+            std::slice::from_ref(&unknown),
+            &owned,
+            1,
+            0,
+            false,
+            &wallets,
+            &utxo_set,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<BlockchainError>(),
+            Some(&BlockchainError::WalletNotFound { address: unknown })
+        );
+    }
+
+    #[test]
+    fn test_send_from_address_with_other_hash() {
+        let dir = TestDir::new("sha256-from");
+        let params = ChainParams {
+            address_hash: crate::AddressHash::Sha256,
+            ..ChainParams::default()
+        };
+        let mut wallets = Wallets::default();
+        wallets.create_wallet(&ChainParams::default());
+        wallets.create_wallet(&ChainParams::default());
+        let [from, to] = [0, 1].map(|i| {
+            wallets
+                .wallets
+                .values()
+                .nth(i)
+                .unwrap()
+                .get_address(&params)
+        });
+        let bc =
+            crate::Blockchain::create_with_params(dir.join("blockchain"), &from, params.clone())
+                .unwrap();
+        let mut utxo_set = UTXOSet::with_path(bc, dir.join("utxos"));
+        utxo_set.reindex().unwrap();
+
+        let tx = Transaction::new_with_wallets(
+            std::slice::from_ref(&from),
+            &to,
+            4,
+            1,
+            false,
+            &wallets,
+            &utxo_set,
+        )
+        .unwrap();
+        assert!(utxo_set.bc.verify_transaction(&tx).unwrap());
+        assert!(tx.v_in[0].uses_key(
+            &get_pub_key_hash(&from, &ChainParams::default()).unwrap(),
+            &params
+        ));
+    }
+
+    #[test]
+    fn test_transaction_builder_errors() {
+        let params = ChainParams::default();
+        let dir = TestDir::new("tx-builder");
+        let mut wallets = Wallets::default();
+        let owned = wallets.create_wallet(&params);
+        let to = wallets.create_wallet(&params);
+        let bc = crate::Blockchain::create_at(dir.join("blockchain"), &owned).unwrap();
+        let mut utxo_set = UTXOSet::with_path(bc, dir.join("utxos"));
+        utxo_set.reindex().unwrap();
+        let builder = TransactionBuilder::new()
+            .from(&owned)
+            .to(&to)
+            .amount(4)
+            .fee(1);
+        let error = |result: Result<Transaction>| {
+            result.unwrap_err().downcast::<BlockchainError>().unwrap()
+        };
+
+        let err = error(
+            builder
+                .clone()
+                .to("not-an-address")
+                .build_unsigned(&utxo_set),
+        );
+        assert!(
+            matches!(&err, BlockchainError::InvalidAddress { address, .. } if address == "not-an-address"),
+            "{}",
+            err
+        );
+
+        let err = error(builder.clone().amount(100).build_unsigned(&utxo_set));
+        assert_eq!(
+            err,
+            BlockchainError::InsufficientFunds {
+                needed: 101,
+                available: SUBSIDY
+            }
+        );
+
+        // Amount plus fee past i32::MAX fails instead of wrapping
+        let err = builder
+            .clone()
+            .amount(i32::MAX)
+            .build_unsigned(&utxo_set)
+            .unwrap_err();
+        assert!(err.to_string().contains("overflow"), "{}", err);
+
+        let stranger = crate::Wallet::new().get_address(&params);
+        let err = error(builder.clone().from(&stranger).build(&wallets, &utxo_set));
+        assert_eq!(err, BlockchainError::WalletNotFound { address: stranger });
+
+        let mut tx = builder.build_unsigned(&utxo_set).unwrap();
+        tx.v_in[0].prev_out.txid = Hash([0xff; 32]);
+        let err = error(builder.sign(tx, &wallets, &utxo_set.bc));
+        assert!(
+            matches!(err, BlockchainError::SigningFailed { .. }),
+            "{}",
+            err
+        );
+
+        let tx = builder.build(&wallets, &utxo_set).unwrap();
+        assert!(utxo_set.bc.verify_transaction(&tx).unwrap());
+    }
+
+    #[test]
+    fn test_builder_ignores_repeated_sources() {
+        let params = ChainParams::default();
+        let dir = TestDir::new("tx-repeated-from");
+        let mut wallets = Wallets::default();
+        let owned = wallets.create_wallet(&params);
+        let to = wallets.create_wallet(&params);
+        let bc = crate::Blockchain::create_at(dir.join("blockchain"), &owned).unwrap();
+        let mut utxo_set = UTXOSet::with_path(bc, dir.join("utxos"));
+        utxo_set.reindex().unwrap();
+
+        let builder = TransactionBuilder::new()
+            .from_all(&[owned.clone(), owned.clone()])
+            .from(&owned)
+            .to(&to);
+
+        // The repeats add no funds, the coinbase isn't selected again
+        let err = builder
+            .clone()
+            .amount(SUBSIDY + 1)
+            .build_unsigned(&utxo_set)
+            .unwrap_err()
+            .downcast::<BlockchainError>()
+            .unwrap();
+        assert_eq!(
+            err,
+            BlockchainError::InsufficientFunds {
+                needed: SUBSIDY + 1,
+                available: SUBSIDY
+            }
+        );
+        let tx = builder.amount(SUBSIDY).build(&wallets, &utxo_set).unwrap();
+        assert_eq!(tx.v_in.len(), 1);
+        assert!(utxo_set.bc.verify_transaction(&tx).unwrap());
+    }
+
+    #[test]
+    fn test_send_reports_immature_coinbase() {
+        let params = ChainParams::default();
+        let dir = TestDir::new("tx-immature");
+        let mut wallets = Wallets::default();
+        let owned = wallets.create_wallet(&params);
+        let to = wallets.create_wallet(&params);
+        let bc = crate::Blockchain::create_at(dir.join("blockchain"), &owned).unwrap();
+        let mut utxo_set = UTXOSet::with_path(bc, dir.join("utxos")).with_coinbase_maturity(3);
+        utxo_set.reindex().unwrap();
+        let builder = TransactionBuilder::new()
+            .from(&owned)
+            .to(&to)
+            .amount(4)
+            .fee(1);
+
+        // The genesis coinbase is all there is, and it needs 3 confirmations
+        let err = builder.build(&wallets, &utxo_set).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<BlockchainError>(),
+            Some(&BlockchainError::ImmatureFunds {
+                needed: 5,
+                available: 0,
+                immature: 1,
+                spendable_at: 2
+            })
+        );
+        assert!(
+            err.to_string()
+                .ends_with("1 coins are immature coinbase and unavailable until height 2"),
+            "{}",
+            err
+        );
+
+        for height in 1..3 {
+            let cbtx = Transaction::new_coinbase(&to, height.to_string(), &params).unwrap();
+            let block = utxo_set.bc.mine_block(vec![cbtx]).unwrap();
+            utxo_set.update(block).unwrap();
+        }
+        let tx = builder.build(&wallets, &utxo_set).unwrap();
+        assert!(utxo_set.bc.verify_transaction(&tx).unwrap());
+    }
+
+    #[test]
+    fn test_send_reports_selected_immature_coinbase() {
+        let params = ChainParams::default();
+        let dir = TestDir::new("tx-immature-input");
+        let mut wallets = Wallets::default();
+        let owned = wallets.create_wallet(&params);
+        let to = wallets.create_wallet(&params);
+        let bc = crate::Blockchain::create_at(dir.join("blockchain"), &owned).unwrap();
+        let genesis = bc.iter().last().unwrap().transactions[0]
+            .id
+            .parse()
+            .unwrap();
+        let mut utxo_set = UTXOSet::with_path(bc, dir.join("utxos")).with_coinbase_maturity(3);
+        utxo_set.reindex().unwrap();
+        let mut fresh = Hash::default();
+        for height in 1..3 {
+            let cbtx = Transaction::new_coinbase(&owned, height.to_string(), &params).unwrap();
+            fresh = cbtx.id.parse().unwrap();
+            let block = utxo_set.bc.mine_block(vec![cbtx]).unwrap();
+            utxo_set.update(block).unwrap();
+        }
+        let builder = TransactionBuilder::new()
+            .from(&owned)
+            .to(&to)
+            .amount(4)
+            .fee(1);
+
+        // The newest coinbase is picked even though the genesis one is
+        // mature and would cover the payment
+        let err = builder
+            .clone()
+            .spend(OutPoint::new(fresh, 0))
+            .build(&wallets, &utxo_set)
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<BlockchainError>(),
+            Some(&BlockchainError::ImmatureFunds {
+                needed: 5,
+                available: 0,
+                immature: 1,
+                spendable_at: 4
+            })
+        );
+        assert!(
+            err.to_string()
+                .ends_with("1 coins are immature coinbase and unavailable until height 4"),
+            "{}",
+            err
+        );
+
+        let foreign = TransactionBuilder::new()
+            .from(&to)
+            .to(&owned)
+            .amount(4)
+            .spend(OutPoint::new(genesis, 0));
+        assert!(foreign.build_unsigned(&utxo_set).is_err());
+
+        let tx = builder
+            .spend(OutPoint::new(genesis, 0))
+            .build(&wallets, &utxo_set)
+            .unwrap();
+        assert_eq!(tx.v_in.len(), 1);
+        assert_eq!(tx.v_in[0].prev_out, OutPoint::new(genesis, 0));
+        assert!(utxo_set.bc.verify_transaction(&tx).unwrap());
+    }
+
+    #[test]
+    fn test_outpoint_from_str() {
+        let txid = Hash::try_from([7; 32].as_slice()).unwrap();
+        let outpoint = OutPoint::new(txid, 3);
+        assert_eq!(outpoint.to_string().parse::<OutPoint>().unwrap(), outpoint);
+        assert!(txid.to_string().parse::<OutPoint>().is_err());
+        assert!(format!("{}:x", txid).parse::<OutPoint>().is_err());
+    }
+
+    #[test]
+    fn test_send_to_address_of_another_network() {
+        let params = ChainParams::default();
+        let dir = TestDir::new("network");
+        let addr = crate::Wallet::new().get_address(&params);
+        let pub_key_hash = get_pub_key_hash(&addr, &params).unwrap();
+        let testnet_addr = crate::pub_key_hash_to_address(
+            &pub_key_hash,
+            &ChainParams::for_network(crate::Network::Testnet),
+        );
+        assert!(
+            crate::validate_address(
+                &testnet_addr,
+                &ChainParams::for_network(crate::Network::Testnet)
+            )
+            .is_ok()
+        );
+        let err = crate::validate_address(
+            &testnet_addr,
+            &ChainParams::for_network(crate::Network::Mainnet),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("testnet address"), "{}", err);
+
+        let bc = crate::Blockchain::create_at(dir.join("blockchain"), &addr).unwrap();
+        let mut utxo_set = UTXOSet::with_path(bc, dir.join("utxos"));
+        utxo_set.reindex().unwrap();
+        let err = Transaction::new_unsigned(
+            std::slice::from_ref(&addr),
+            &testnet_addr,
+            1,
+            0,
+            false,
+            CoinSelectionStrategy::default(),
+            &utxo_set,
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string().contains("this node is on mainnet"),
+            "{}",
+            err
+        );
+    }
+}
@@ -1,20 +1,57 @@
-use base58::FromBase58;
-use ripemd::Ripemd160;
+use anyhow::{Result, anyhow};
+use bincode::config::{self, Configuration, Limit, LittleEndian, Varint};
 
-use sha2::{Digest, Sha256};
+use crate::{ChainParams, decode_address};
 
-pub fn hash_pub_key(pub_key: &[u8]) -> Vec<u8> {
-    let mut sha256 = Sha256::new();
-    sha256.update(pub_key);
-    let public_sha256 = sha256.finalize();
+/// Most bytes a single decode of peer or on-disk data may allocate, far
+/// above any valid block. Without it a length prefix claiming gigabytes
+/// is allocated before the decoder notices the data isn't there.
+pub(crate) const MAX_DECODE_BYTES: usize = 32 * 1024 * 1024;
 
-    let mut ripemd160 = Ripemd160::new();
-    ripemd160.update(public_sha256);
-    ripemd160.finalize().to_vec()
+/// `standard()` with the `MAX_DECODE_BYTES` limit, for decoding anything
+/// that didn't come from our own encoder in this process.
+pub(crate) const DECODE_CONFIG: Configuration<LittleEndian, Varint, Limit<MAX_DECODE_BYTES>> =
+    config::standard().with_limit::<MAX_DECODE_BYTES>();
+
+/// Hashes a public key into an address payload with the `AddressHash` of
+/// `params`.
+pub fn hash_pub_key(pub_key: &[u8], params: &ChainParams) -> Vec<u8> {
+    params.address_hash.hash(pub_key)
+}
+
+/// The public key hash paid by `address`, an address made with `params`.
+/// Fails unless it is base58 with a valid checksum.
+pub fn get_pub_key_hash(address: &str, params: &ChainParams) -> Result<Vec<u8>> {
+    let info = decode_address(address, params)?;
+    if !info.checksum_valid() {
+        return Err(anyhow!("Invalid address {}", address));
+    }
+    Ok(info.pub_key_hash)
 }
 
-pub fn get_pub_key_hash(address: &str) -> Vec<u8> {
-    let pub_key_hash = address.from_base58().unwrap();
-    let pub_key_hash = &pub_key_hash[1..pub_key_hash.len() - 4];
-    pub_key_hash.to_vec()
+/// Formats a millisecond unix timestamp as a UTC date time.
+pub fn format_timestamp(millis: u128) -> String {
+    let secs = (millis / 1000) as i64;
+    let (days, rem) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+
+    // Civil-from-days, see https://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
 }
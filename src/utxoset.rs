@@ -1,34 +1,355 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    fs::{self, File, OpenOptions},
+    mem,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard, mpsc},
+    thread,
+};
 
-use crate::{Block, Blockchain, TXOutputs};
-use anyhow::Result;
+use crate::{Block, Blockchain, Hash, OutPoint, TXOutput, TXOutputs};
+use anyhow::{Result, anyhow};
 use bincode::{
     config::standard,
     serde::{decode_from_slice, encode_to_vec},
 };
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use sled::transaction::{ConflictableTransactionError, TransactionError, Transactional};
+
+/// Tree mapping `pub_key_hash ++ tx_id` to nothing, for every transaction
+/// with an unspent output to that key
+const ADDRESS_INDEX_TREE: &str = "addresses";
+/// Present in the address index once it has been built
+const ADDRESS_INDEX_MARKER: &[u8] = b"";
+/// Tree mapping `tx_id ++ v_out` of every spent output to the id of the
+/// transaction spending it. An output is either here or in the set.
+const SPENT_INDEX_TREE: &str = "spent";
+/// Present in the spent index once it has been built
+const SPENT_INDEX_MARKER: &[u8] = b"";
+/// Tree holding, under `TIP_KEY`, the hash of the last block written to
+/// the set. Missing from sets written before it was kept.
+const TIP_TREE: &str = "tip";
+const TIP_KEY: &[u8] = b"";
+
+/// Most threads writing the UTXO set during a reindex
+const REINDEX_MAX_THREADS: usize = 4;
+/// Transactions handed to a reindex thread at a time. At most three
+/// chunks per thread are in flight.
+const REINDEX_CHUNK_LEN: usize = 1024;
+
+/// Most subsets branch and bound selection tries before giving up
+const BNB_MAX_TRIES: usize = 100_000;
+
+/// How `find_spendable_outputs_with` picks the outputs to spend.
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CoinSelectionStrategy {
+    /// Outputs in outpoint order until the amount is covered
+    #[default]
+    FirstFit,
+    /// Largest outputs first, for the fewest inputs
+    LargestFirst,
+    /// The smallest single output covering the amount, for the least
+    /// change, falling back to largest first
+    MinimizeChange,
+    /// A set of outputs adding up to exactly the amount, so there is no
+    /// change output at all, falling back to first fit
+    BranchAndBound,
+}
+
+/// Transactions on which a UTXO set and its chain disagree, see
+/// `UTXOSet::verify_against_chain`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct UtxoMismatch {
+    /// Unspent in the chain but absent from the set
+    pub missing: Vec<String>,
+    /// In the set but fully spent, or unknown, in the chain
+    pub unexpected: Vec<String>,
+    /// In both, with different outputs
+    pub different: Vec<String>,
+}
+
+impl UtxoMismatch {
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.unexpected.is_empty() && self.different.is_empty()
+    }
+}
+
+impl fmt::Display for UtxoMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} missing, {} unexpected, {} different",
+            self.missing.len(),
+            self.unexpected.len(),
+            self.different.len()
+        )
+    }
+}
+
+/// Whether an output is still unspent, see `UTXOSet::output_spends`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputSpend {
+    Unspent,
+    /// Spent by transaction `by`, `None` when it isn't on the chain
+    Spent {
+        by: Option<String>,
+    },
+}
 
 pub struct UTXOSet {
     pub bc: Blockchain,
+    path: PathBuf,
+    /// The set, opened on first use and kept open. sled locks its
+    /// directory until a handle is fully released, so reopening it for
+    /// every call can find it still locked.
+    db: Mutex<Option<sled::Db>>,
+    /// Blocks are only written to the database once this many newer blocks
+    /// are on top of them
+    confirmation_depth: usize,
+    /// Confirmations a coinbase needs before `find_spendable_outputs`
+    /// spends it
+    coinbase_maturity: i32,
+    /// Recent blocks not yet written, oldest first. Queries see them, and
+    /// a reorg within the window just drops them.
+    overlay: Vec<Block>,
 }
 
 impl UTXOSet {
-    pub fn new(bc: Blockchain) -> Self {
-        Self { bc }
+    pub fn with_path(bc: Blockchain, path: impl Into<PathBuf>) -> Self {
+        Self {
+            bc,
+            path: path.into(),
+            db: Mutex::new(None),
+            confirmation_depth: 0,
+            coinbase_maturity: 0,
+            overlay: vec![],
+        }
+    }
+
+    /// Keeps the effects of the last `depth` blocks passed to `update` in
+    /// memory so they can be rolled back with `disconnect_tip`. The default
+    /// of 0 writes every block immediately.
+    pub fn with_confirmation_depth(mut self, depth: usize) -> Self {
+        self.confirmation_depth = depth;
+        self
+    }
+
+    /// Leaves coinbase outputs out of `find_spendable_outputs` until they
+    /// have `maturity` confirmations. The default of 0 spends them right
+    /// away.
+    pub fn with_coinbase_maturity(mut self, maturity: i32) -> Self {
+        self.coinbase_maturity = maturity;
+        self
     }
 
-    pub fn reindex(&self) -> Result<()> {
-        std::fs::remove_dir_all("db/utxos").ok();
-        let db = sled::open("db/utxos")?;
+    /// Ids of the coinbases without enough confirmations to be spent,
+    /// those of the newest `coinbase_maturity - 1` blocks.
+    fn immature_coinbases(&self) -> HashSet<String> {
+        let recent = usize::try_from(self.coinbase_maturity - 1).unwrap_or(0);
+        self.bc
+            .iter()
+            .take(recent)
+            .filter_map(|block| {
+                let tx = block.transactions.into_iter().next()?;
+                tx.is_coinbase().then_some(tx.id)
+            })
+            .collect()
+    }
+
+    /// The database of the set, opened once and shared by every call.
+    pub(crate) fn open_db(&self) -> Result<sled::Db> {
+        if let Some(db) = self.held_db()?.as_ref() {
+            return Ok(db.clone());
+        }
+        // Either a swap was interrupted, or one is in progress and we wait
+        // for it to finish
+        let _lock = self.lock()?;
+        self.open_db_locked()
+    }
+
+    /// Like `open_db`, for callers already holding `lock`.
+    fn open_db_locked(&self) -> Result<sled::Db> {
+        let mut held = self.held_db()?;
+        if let Some(db) = held.as_ref() {
+            return Ok(db.clone());
+        }
+        recover_swap(&self.path)?;
+        let db = sled::open(&self.path)?;
+        *held = Some(db.clone());
+        Ok(db)
+    }
+
+    fn held_db(&self) -> Result<MutexGuard<'_, Option<sled::Db>>> {
+        self.db
+            .lock()
+            .map_err(|_| anyhow!("UTXO set handle poisoned"))
+    }
+
+    /// Takes the lock every write to the set is made under.
+    ///
+    /// It is a lock on a file next to the set, so it also keeps a `reindex`
+    /// from the CLI and the updates of a running node from interleaving,
+    /// for example a block committed to the old set while the new one is
+    /// being built and then lost in the swap. Released when dropped.
+    fn lock(&self) -> Result<File> {
+        let lock_path = self.path.with_extension("lock");
+        if let Some(parent) = lock_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)?;
+        file.lock()?;
+        Ok(file)
+    }
+
+    /// Rebuilds the UTXO set from the chain, then its spent index.
+    ///
+    /// The new set is written to a temporary directory and only swapped in
+    /// after it has been flushed, so a failure or crash part way through
+    /// leaves the previous set intact. It is safe to call while another
+    /// process updates the same set: whichever comes second waits for the
+    /// other to finish.
+    ///
+    /// The chain is streamed rather than loaded, and the set is written by
+    /// several threads, so memory stays bounded on a long chain.
+    pub fn reindex(&mut self) -> Result<()> {
         log::info!("Reindexing UTXO set");
+        self.rebuild(self.bc.iter_utxo().map(Ok))?;
+        self.overlay.clear();
+        self.reindex_spends()?;
+        log::info!("UTXO reindex completed");
+        Ok(())
+    }
+
+    fn rebuild<I>(&self, utxos: I) -> Result<()>
+    where
+        I: IntoIterator<Item = Result<(String, TXOutputs)>>,
+    {
+        let threads = thread::available_parallelism()
+            .map_or(1, |n| n.get())
+            .min(REINDEX_MAX_THREADS);
+        self.rebuild_with(utxos, threads, REINDEX_CHUNK_LEN)
+    }
+
+    /// `rebuild`, writing chunks of `chunk_len` entries from `threads`
+    /// threads.
+    fn rebuild_with<I>(&self, utxos: I, threads: usize, chunk_len: usize) -> Result<()>
+    where
+        I: IntoIterator<Item = Result<(String, TXOutputs)>>,
+    {
+        let _lock = self.lock()?;
+        let tmp_path = self.path.with_extension("tmp");
+        let old_path = self.path.with_extension("old");
+        fs::remove_dir_all(&tmp_path).ok();
+
+        let build = || -> Result<()> {
+            let db = sled::open(&tmp_path)?;
+            let index = db.open_tree(ADDRESS_INDEX_TREE)?;
+            let (sender, receiver) = mpsc::sync_channel::<Vec<(String, TXOutputs)>>(threads);
+            let receiver = Mutex::new(receiver);
+            thread::scope(|s| -> Result<()> {
+                let workers: Vec<_> = (0..threads)
+                    .map(|_| {
+                        s.spawn(|| -> Result<()> {
+                            // Ends once the sender is dropped and drained
+                            while let Ok(chunk) = receiver.lock().unwrap().recv() {
+                                for (tx_id, outs) in chunk {
+                                    index_outputs(&index, &tx_id, &outs)?;
+                                    let data = encode_to_vec(outs, standard())?;
+                                    db.insert(tx_id.as_bytes(), data)?;
+                                }
+                            }
+                            Ok(())
+                        })
+                    })
+                    .collect();
+
+                let produced = (|| -> Result<()> {
+                    let mut chunk = Vec::with_capacity(chunk_len);
+                    for item in utxos {
+                        chunk.push(item?);
+                        // Fails only once every worker has given up
+                        if chunk.len() == chunk_len && sender.send(mem::take(&mut chunk)).is_err() {
+                            return Ok(());
+                        }
+                    }
+                    if !chunk.is_empty() {
+                        sender.send(chunk).ok();
+                    }
+                    Ok(())
+                })();
+                drop(sender);
+                for worker in workers {
+                    worker
+                        .join()
+                        .map_err(|_| anyhow!("UTXO reindex thread panicked"))??;
+                }
+                produced
+            })?;
+            index.insert(ADDRESS_INDEX_MARKER, vec![])?;
+            db.open_tree(TIP_TREE)?
+                .insert(TIP_KEY, self.bc.tip.as_ref())?;
+            db.flush()?;
+            Ok(())
+        };
+        if let Err(e) = build() {
+            fs::remove_dir_all(&tmp_path).ok();
+            return Err(e);
+        }
 
-        for (tx_id, outs) in self.bc.find_utxo() {
-            let data = encode_to_vec(outs, standard())?;
-            db.insert(tx_id.as_bytes(), data)?;
+        // Reopened from the new set on next use
+        self.held_db()?.take();
+        recover_swap(&self.path)?;
+        if self.path.exists() {
+            fs::rename(&self.path, &old_path)?;
         }
+        fs::rename(&tmp_path, &self.path)?;
+        fs::remove_dir_all(&old_path).ok();
+        Ok(())
+    }
 
+    /// Rebuilds the address index from the committed set, for sets written
+    /// before the index existed.
+    pub fn reindex_addresses(&self) -> Result<()> {
+        let _lock = self.lock()?;
+        let db = self.open_db_locked()?;
+        let index = db.open_tree(ADDRESS_INDEX_TREE)?;
+        index.clear()?;
+        for ele in db.iter() {
+            let (k, v) = ele?;
+            let tx_id = String::from_utf8(k.to_vec())?;
+            let outs: TXOutputs = decode_from_slice(&v, standard()).map(|(w, _)| w)?;
+            index_outputs(&index, &tx_id, &outs)?;
+        }
+        index.insert(ADDRESS_INDEX_MARKER, vec![])?;
         db.flush()?;
-        log::info!("UTXO reindex completed at");
+        Ok(())
+    }
 
+    /// Rebuilds the spent index from the chain. Until it is done, spenders
+    /// are found by scanning the chain, see `spenders`.
+    pub fn reindex_spends(&self) -> Result<()> {
+        let _lock = self.lock()?;
+        let db = self.open_db_locked()?;
+        let spent = db.open_tree(SPENT_INDEX_TREE)?;
+        spent.clear()?;
+        for block in self.bc.iter() {
+            for tx in block.transactions {
+                if tx.is_coinbase() {
+                    continue;
+                }
+                for vin in &tx.v_in {
+                    spent.insert(spent_index_key(&vin.prev_out), tx.id.as_bytes())?;
+                }
+            }
+        }
+        spent.insert(SPENT_INDEX_MARKER, vec![])?;
+        db.flush()?;
         Ok(())
     }
 
@@ -37,40 +358,82 @@ impl UTXOSet {
         pub_key_hash: &[u8],
         amount: i32,
     ) -> Result<(i32, HashMap<String, Vec<i32>>)> {
-        let mut unspent_outputs: HashMap<String, Vec<i32>> = HashMap::new();
-        let mut accumulated = 0;
-        let db = sled::open("db/utxos")?;
-
-        for ele in db.iter() {
-            let (k, v) = ele?;
-            let tx_id = String::from_utf8(k.to_vec())?;
-            let outs: TXOutputs = decode_from_slice(&v, standard()).map(|(w, _)| w)?;
+        self.find_spendable_outputs_with(pub_key_hash, amount, CoinSelectionStrategy::default())
+    }
 
+    /// Outputs of `pub_key_hash` worth at least `amount`, picked with
+    /// `strategy`, and their total. The total is below `amount` only when
+    /// all outputs together don't cover it.
+    pub fn find_spendable_outputs_with(
+        &self,
+        pub_key_hash: &[u8],
+        amount: i32,
+        strategy: CoinSelectionStrategy,
+    ) -> Result<(i32, HashMap<String, Vec<i32>>)> {
+        let immature = self.immature_coinbases();
+        let mut candidates = vec![];
+        for (tx_id, outs) in self.unspent_for(pub_key_hash)? {
+            if immature.contains(&tx_id) {
+                continue;
+            }
             for (out_idx, out) in outs.outputs.iter().enumerate() {
-                if out.is_locked_with_key(pub_key_hash) && accumulated < amount {
-                    accumulated += out.value;
-                    unspent_outputs
-                        .entry(tx_id.to_owned())
-                        .or_default()
-                        .push(out_idx as i32);
+                if out.is_locked_with_key(pub_key_hash) {
+                    candidates.push((tx_id.clone(), out_idx as i32, out.value));
                 }
+            }
+        }
+        candidates.sort();
+        let values: Vec<i32> = candidates.iter().map(|(_, _, value)| *value).collect();
+
+        let mut unspent_outputs: HashMap<String, Vec<i32>> = HashMap::new();
+        let mut accumulated = 0;
+        for i in select_coins(&values, amount, strategy) {
+            let (tx_id, out_idx, value) = &candidates[i];
+            accumulated = value
+                .checked_add(accumulated)
+                .ok_or_else(|| anyhow!("Amount overflow"))?;
+            unspent_outputs
+                .entry(tx_id.to_owned())
+                .or_default()
+                .push(*out_idx);
+        }
+        Ok((accumulated, unspent_outputs))
+    }
 
-                if accumulated >= amount {
-                    return Ok((accumulated, unspent_outputs));
+    /// The coinbase outputs of `pub_key_hash` that `find_spendable_outputs`
+    /// leaves out for being immature, each with the height from which it
+    /// can be spent.
+    pub fn immature_outputs(&self, pub_key_hash: &[u8]) -> Result<Vec<(TXOutput, i32)>> {
+        let immature = self.immature_coinbases();
+        let mut res = vec![];
+        for (tx_id, outs) in self.unspent_for(pub_key_hash)? {
+            if !immature.contains(&tx_id) {
+                continue;
+            }
+            let spendable_at = outs.height + self.coinbase_maturity - 1;
+            for out in outs.outputs {
+                if out.is_locked_with_key(pub_key_hash) {
+                    res.push((out, spendable_at));
                 }
             }
         }
+        Ok(res)
+    }
 
-        Ok((accumulated, unspent_outputs))
+    /// The height from which the outputs of `tx_id` can be spent, if it is
+    /// an immature coinbase with unspent outputs.
+    pub fn coinbase_spendable_at(&self, tx_id: &str) -> Result<Option<i32>> {
+        if !self.immature_coinbases().contains(tx_id) {
+            return Ok(None);
+        }
+        Ok(self
+            .get_tx_outs(tx_id)?
+            .map(|outs| outs.height + self.coinbase_maturity - 1))
     }
 
     pub fn find_utxo(&self, pub_key_hash: &[u8]) -> Result<TXOutputs> {
         let mut res = TXOutputs::default();
-        let db = sled::open("db/utxos")?;
-
-        for ele in db.iter() {
-            let (_, v) = ele?;
-            let outs: TXOutputs = decode_from_slice(&v, standard()).map(|(w, _)| w)?;
+        for (_, outs) in self.unspent_for(pub_key_hash)? {
             for out in outs.outputs {
                 if out.is_locked_with_key(pub_key_hash) {
                     res.outputs.push(out);
@@ -80,43 +443,1294 @@ impl UTXOSet {
         Ok(res)
     }
 
-    pub fn update(&self, block: Block) -> Result<()> {
-        let db = sled::open("db/utxos")?;
+    /// Unspent outputs of `pub_key_hash` as `(txid, vout, output)`, in
+    /// the outpoint form `find_spendable_outputs` spends them with.
+    pub fn list_unspent(&self, pub_key_hash: &[u8]) -> Result<Vec<(String, i32, TXOutput)>> {
+        let mut res = vec![];
+        for (tx_id, outs) in self.unspent_for(pub_key_hash)? {
+            for (out_idx, out) in outs.outputs.into_iter().enumerate() {
+                if out.is_locked_with_key(pub_key_hash) {
+                    res.push((tx_id.clone(), out_idx as i32, out));
+                }
+            }
+        }
+        res.sort_by(|a, b| (&a.0, a.1).cmp(&(&b.0, b.1)));
+        Ok(res)
+    }
 
-        for tx in block.transactions {
-            if !tx.is_coinbase() {
-                for vin in tx.v_in {
-                    let outs: TXOutputs =
-                        decode_from_slice(&db.get(&vin.tx_id)?.unwrap(), standard())
-                            .map(|(w, _)| w)?;
+    /// Output `v_out` of `tx_id` if it is unspent, numbered like
+    /// `find_spendable_outputs` numbers them.
+    pub fn get_tx_out(&self, tx_id: &str, v_out: i32) -> Result<Option<TXOutput>> {
+        Ok(self
+            .get_tx_outs(tx_id)?
+            .and_then(|outs| outs.outputs.into_iter().nth(v_out.try_into().ok()?)))
+    }
 
-                    let mut updated_outs = TXOutputs::default();
-                    for (out_idx, out) in outs.outputs.iter().enumerate() {
-                        if out_idx != vin.v_out as usize {
-                            updated_outs.outputs.push(out.clone());
-                        }
-                    }
+    /// Height and hash of the block that created output `v_out` of
+    /// `tx_id`, if the output is unspent.
+    pub fn get_output_origin(&self, tx_id: &str, v_out: i32) -> Result<Option<(i32, Hash)>> {
+        Ok(self.get_tx_outs(tx_id)?.and_then(|outs| {
+            (usize::try_from(v_out).ok()? < outs.outputs.len())
+                .then_some((outs.height, outs.block_hash))
+        }))
+    }
+
+    /// Every output of the mined transaction `tx_id` with whether it is
+    /// still in the set, and if not the transaction spending it. `None`
+    /// when `tx_id` isn't on the chain.
+    ///
+    /// Spenders come from `spenders`. An output missing from the set with
+    /// no spender, as when the set is behind, is reported spent by an
+    /// unknown transaction.
+    pub fn output_spends(&self, tx_id: &str) -> Result<Option<Vec<(TXOutput, OutputSpend)>>> {
+        let Some(tx) = self.bc.find_transaction(tx_id) else {
+            return Ok(None);
+        };
+        let spenders = self.spenders(tx_id)?;
+        // The set keeps the unspent outputs in order, without the spent ones
+        let unspent = self.get_tx_outs(tx_id)?.unwrap_or_default().outputs;
+        let mut unspent = unspent.into_iter().peekable();
+        let mut res = vec![];
+        for (idx, out) in tx.v_out.into_iter().enumerate() {
+            let spend = match spenders.get(&(idx as i32)) {
+                Some(by) => OutputSpend::Spent {
+                    by: Some(by.clone()),
+                },
+                None if unspent
+                    .next_if(|u| u.value == out.value && u.pub_key_hash == out.pub_key_hash)
+                    .is_some() =>
+                {
+                    OutputSpend::Unspent
+                }
+                None => OutputSpend::Spent { by: None },
+            };
+            res.push((out, spend));
+        }
+        Ok(Some(res))
+    }
+
+    /// The transaction spending output `v_out` of `tx_id`, `None` when it
+    /// is unspent or unknown.
+    pub fn spender(&self, tx_id: &str, v_out: i32) -> Result<Option<String>> {
+        Ok(self.spenders(tx_id)?.remove(&v_out))
+    }
 
-                    if updated_outs.outputs.is_empty() {
-                        db.remove(&vin.tx_id)?;
-                    } else {
-                        db.insert(
-                            vin.tx_id.as_bytes(),
-                            encode_to_vec(updated_outs, standard())?,
-                        )?;
+    /// The transactions spending outputs of `tx_id`, by output index,
+    /// overlay included. Read from the spent index when it has been built,
+    /// found by scanning the chain back to `tx_id` otherwise.
+    pub fn spenders(&self, tx_id: &str) -> Result<HashMap<i32, String>> {
+        let db = self.open_db()?;
+        let spent = db.open_tree(SPENT_INDEX_TREE)?;
+        if !spent.contains_key(SPENT_INDEX_MARKER)? {
+            return Ok(self
+                .bc
+                .find_spenders(tx_id)
+                .map(|(_, spenders)| spenders)
+                .unwrap_or_default());
+        }
+
+        let mut spenders = HashMap::new();
+        for ele in spent.scan_prefix(tx_id.as_bytes()) {
+            let (k, v) = ele?;
+            let Ok(v_out) = <[u8; 4]>::try_from(&k[tx_id.len()..]) else {
+                continue;
+            };
+            spenders.insert(i32::from_be_bytes(v_out), String::from_utf8(v.to_vec())?);
+        }
+        for block in &self.overlay {
+            for tx in block.transactions.iter().filter(|tx| !tx.is_coinbase()) {
+                for vin in &tx.v_in {
+                    if vin.prev_out.txid.to_string() == tx_id {
+                        spenders.insert(vin.prev_out.vout as i32, tx.id.clone());
                     }
                 }
             }
+        }
+        Ok(spenders)
+    }
 
-            let mut new_outputs = TXOutputs::default();
+    /// The unspent outputs of `tx_id`, overlay included.
+    fn get_tx_outs(&self, tx_id: &str) -> Result<Option<TXOutputs>> {
+        let db = self.open_db()?;
+        let mut utxos = HashMap::new();
+        if let Some(v) = db.get(tx_id)? {
+            let outs: TXOutputs = decode_from_slice(&v, standard()).map(|(w, _)| w)?;
+            utxos.insert(tx_id.to_owned(), outs);
+        }
+        for block in &self.overlay {
+            apply_block(&mut utxos, block);
+        }
+        Ok(utxos.remove(tx_id))
+    }
+
+    /// Like `find_utxo`, but only counts outputs whose transaction has at
+    /// least `min_confirmations` confirmations.
+    pub fn find_utxo_confirmed(
+        &self,
+        pub_key_hash: &[u8],
+        min_confirmations: i32,
+    ) -> Result<TXOutputs> {
+        if min_confirmations <= 0 {
+            return self.find_utxo(pub_key_hash);
+        }
 
-            for out in tx.v_out {
-                new_outputs.outputs.push(out);
+        let heights = self.bc.transaction_heights();
+        let best_height = self.bc.get_best_height()?;
+        let mut res = TXOutputs::default();
+        for (tx_id, outs) in self.unspent_for(pub_key_hash)? {
+            let confirmations = match heights.get(&tx_id) {
+                Some(height) => best_height - height + 1,
+                None => 0,
+            };
+            if confirmations < min_confirmations {
+                continue;
+            }
+            for out in outs.outputs {
+                if out.is_locked_with_key(pub_key_hash) {
+                    res.outputs.push(out);
+                }
             }
-            db.insert(tx.id.as_bytes(), encode_to_vec(new_outputs, standard())?)?;
         }
+        Ok(res)
+    }
 
+    /// Like `find_utxo_confirmed`, split into the outputs that can be spent
+    /// and the coinbase outputs that haven't matured yet, see
+    /// `with_coinbase_maturity`.
+    pub fn find_utxo_by_maturity(
+        &self,
+        pub_key_hash: &[u8],
+        min_confirmations: i32,
+    ) -> Result<(TXOutputs, TXOutputs)> {
+        let immature_ids = self.immature_coinbases();
+        let heights = if min_confirmations > 0 {
+            self.bc.transaction_heights()
+        } else {
+            HashMap::new()
+        };
+        let best_height = self.bc.get_best_height()?;
+        let mut spendable = TXOutputs::default();
+        let mut immature = TXOutputs::default();
+        for (tx_id, outs) in self.unspent_for(pub_key_hash)? {
+            if min_confirmations > 0 {
+                let confirmations = match heights.get(&tx_id) {
+                    Some(height) => best_height - height + 1,
+                    None => 0,
+                };
+                if confirmations < min_confirmations {
+                    continue;
+                }
+            }
+            let res = if immature_ids.contains(&tx_id) {
+                &mut immature
+            } else {
+                &mut spendable
+            };
+            for out in outs.outputs {
+                if out.is_locked_with_key(pub_key_hash) {
+                    res.outputs.push(out);
+                }
+            }
+        }
+        Ok((spendable, immature))
+    }
+
+    /// Applies `block` on top of the set. With a confirmation depth the
+    /// block first goes to the overlay and is written once it is buried
+    /// deep enough.
+    pub fn update(&mut self, block: Block) -> Result<()> {
+        self.overlay.push(block);
+        while self.overlay.len() > self.confirmation_depth {
+            let block = self.overlay.remove(0);
+            self.commit(&block)?;
+        }
+        Ok(())
+    }
+
+    /// Undoes the most recent `update` for a reorg. Fails once the block has
+    /// been written, a reorg that deep needs a `reindex`.
+    pub fn disconnect_tip(&mut self) -> Result<Block> {
+        self.overlay
+            .pop()
+            .ok_or_else(|| anyhow!("Tip is already committed to the UTXO set, reindex instead"))
+    }
+
+    /// Brings the set to the tip of the chain. Blocks of a branch the chain
+    /// has left are taken off the overlay with `disconnect_tip` and the new
+    /// ones applied with `update`, so at startup this replays the overlay
+    /// from the last written block. A set that doesn't know its last block,
+    /// or a fork below it, is reindexed instead.
+    pub fn sync(&mut self) -> Result<()> {
+        let Some(committed) = self.committed_tip()? else {
+            return self.reindex();
+        };
+        let committed_height = if committed == Hash::default() {
+            -1
+        } else {
+            match self.bc.get_block(&committed) {
+                Ok(block) => block.height,
+                Err(_) => return self.reindex(),
+            }
+        };
+
+        let mut connect = vec![];
+        let mut fork = None;
+        for block in self.bc.iter() {
+            if block.hash == committed || self.overlay.iter().any(|b| b.hash == block.hash) {
+                fork = Some(block.hash);
+                break;
+            }
+            if block.height <= committed_height {
+                break;
+            }
+            connect.push(block);
+        }
+        let fork = match fork {
+            Some(fork) => fork,
+            // Nothing written yet, the whole chain is new
+            None if committed_height < 0 => committed,
+            None => {
+                log::info!("Fork below the last block written to the UTXO set");
+                return self.reindex();
+            }
+        };
+        while self.overlay.last().is_some_and(|b| b.hash != fork) {
+            self.disconnect_tip()?;
+        }
+        for block in connect.into_iter().rev() {
+            self.update(block)?;
+        }
+        Ok(())
+    }
+
+    /// The last block written to the set, `None` when the set predates it
+    /// being kept.
+    fn committed_tip(&self) -> Result<Option<Hash>> {
+        let tip = self.open_db()?.open_tree(TIP_TREE)?.get(TIP_KEY)?;
+        Ok(tip.and_then(|value| Hash::try_from(value.as_ref()).ok()))
+    }
+
+    /// Writes `block` to the set and its indexes in one transaction, so a
+    /// crash leaves them all either before or after the block.
+    fn commit(&self, block: &Block) -> Result<()> {
+        let _lock = self.lock()?;
+        let db = self.open_db_locked()?;
+        let writes = block_writes(&db, block)?;
+        apply_writes(&db, &writes, |_| Ok(()))?;
         db.flush()?;
         Ok(())
     }
+
+    /// Compares the set, overlay included, with the unspent outputs
+    /// recomputed from the chain.
+    pub fn verify_against_chain(&self) -> Result<UtxoMismatch> {
+        let mut expected = self.bc.find_utxo();
+        let mut mismatch = UtxoMismatch::default();
+        for (tx_id, outs) in self.unspent()? {
+            match expected.remove(&tx_id) {
+                // `TXOutput` has no `PartialEq`, the encodings are compared
+                Some(chain_outs) => {
+                    if encode_to_vec(&outs, standard())? != encode_to_vec(&chain_outs, standard())?
+                    {
+                        mismatch.different.push(tx_id);
+                    }
+                }
+                None => mismatch.unexpected.push(tx_id),
+            }
+        }
+        mismatch.missing = expected.into_keys().collect();
+        mismatch.missing.sort();
+        mismatch.unexpected.sort();
+        mismatch.different.sort();
+        Ok(mismatch)
+    }
+
+    /// Total unspent value per pubkey hash, largest balance first, leaving
+    /// out keys whose outputs are all worth nothing.
+    pub fn rich_list(&self) -> Result<Vec<(Vec<u8>, u64)>> {
+        let mut balances: HashMap<Vec<u8>, u64> = HashMap::new();
+        for outs in self.unspent()?.into_values() {
+            for out in outs.outputs {
+                *balances.entry(out.pub_key_hash).or_default() += out.value as u64;
+            }
+        }
+        let mut list: Vec<_> = balances.into_iter().filter(|(_, v)| *v > 0).collect();
+        list.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        Ok(list)
+    }
+
+    /// Every unspent output: the committed set with the overlay applied.
+    fn unspent(&self) -> Result<HashMap<String, TXOutputs>> {
+        let db = self.open_db()?;
+        let mut utxos = HashMap::new();
+        for ele in db.iter() {
+            let (k, v) = ele?;
+            let tx_id = String::from_utf8(k.to_vec())?;
+            let outs: TXOutputs = decode_from_slice(&v, standard()).map(|(w, _)| w)?;
+            utxos.insert(tx_id, outs);
+        }
+        for block in &self.overlay {
+            apply_block(&mut utxos, block);
+        }
+        Ok(utxos)
+    }
+
+    /// Like `unspent`, but only guaranteed to hold the entries with an output
+    /// to `pub_key_hash`. Uses the address index when it has been built and
+    /// falls back to a full scan otherwise.
+    fn unspent_for(&self, pub_key_hash: &[u8]) -> Result<HashMap<String, TXOutputs>> {
+        let db = self.open_db()?;
+        let index = db.open_tree(ADDRESS_INDEX_TREE)?;
+        if !index.contains_key(ADDRESS_INDEX_MARKER)? {
+            return self.unspent();
+        }
+
+        let mut utxos = HashMap::new();
+        for ele in index.scan_prefix(pub_key_hash) {
+            let (k, _) = ele?;
+            // A longer key hash sharing the prefix leaves non-hex bytes
+            let Ok(tx_id) = String::from_utf8(k[pub_key_hash.len()..].to_vec()) else {
+                continue;
+            };
+            if let Some(v) = db.get(&tx_id)? {
+                let outs: TXOutputs = decode_from_slice(&v, standard()).map(|(w, _)| w)?;
+                utxos.insert(tx_id, outs);
+            }
+        }
+        for block in &self.overlay {
+            apply_block(&mut utxos, block);
+        }
+        Ok(utxos)
+    }
+}
+
+fn address_index_key(pub_key_hash: &[u8], tx_id: &str) -> Vec<u8> {
+    [pub_key_hash, tx_id.as_bytes()].concat()
+}
+
+fn spent_index_key(prev_out: &OutPoint) -> Vec<u8> {
+    let v_out = prev_out.vout as i32;
+    [prev_out.txid.to_string().as_bytes(), &v_out.to_be_bytes()].concat()
+}
+
+/// Adds `tx_id` to the address index of every key `outs` pays to.
+fn index_outputs(index: &sled::Tree, tx_id: &str, outs: &TXOutputs) -> Result<()> {
+    for out in &outs.outputs {
+        index.insert(address_index_key(&out.pub_key_hash, tx_id), vec![])?;
+    }
+    Ok(())
+}
+
+/// The writes `block` makes to the set and its indexes, see
+/// `UTXOSet::commit`. The address index gains the block's entries and
+/// loses the spent ones, and the spends go to the spent index.
+#[derive(Default)]
+struct BlockWrites {
+    index_added: Option<sled::Batch>,
+    spends: Option<sled::Batch>,
+    utxos: Option<sled::Batch>,
+    index_removed: Option<sled::Batch>,
+    tip: Option<sled::Batch>,
+}
+
+/// Applies `writes` to the set and its indexes in one sled transaction.
+/// `after_write` is called with the name of each tree once its writes are
+/// staged, and an error from it aborts the transaction as a whole.
+fn apply_writes<F>(db: &sled::Db, writes: &BlockWrites, after_write: F) -> Result<()>
+where
+    F: Fn(&str) -> Result<()>,
+{
+    let index = db.open_tree(ADDRESS_INDEX_TREE)?;
+    let spent = db.open_tree(SPENT_INDEX_TREE)?;
+    let tip = db.open_tree(TIP_TREE)?;
+    let staged = [
+        (ADDRESS_INDEX_TREE, 1, &writes.index_added),
+        (SPENT_INDEX_TREE, 2, &writes.spends),
+        ("utxos", 0, &writes.utxos),
+        (ADDRESS_INDEX_TREE, 1, &writes.index_removed),
+        (TIP_TREE, 3, &writes.tip),
+    ];
+    (&**db, &index, &spent, &tip)
+        .transaction(|(utxos, index, spent, tip)| {
+            for (name, tree, batch) in &staged {
+                let Some(batch) = batch else {
+                    continue;
+                };
+                [utxos, index, spent, tip][*tree].apply_batch(batch)?;
+                after_write(name).map_err(ConflictableTransactionError::Abort)?;
+            }
+            Ok(())
+        })
+        .map_err(|e| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e.into(),
+        })
+}
+
+/// Works out the writes of `block` from `db` without applying them.
+fn block_writes(db: &sled::Db, block: &Block) -> Result<BlockWrites> {
+    let mut writes = BlockWrites::default();
+    let mut touched = HashMap::new();
+    for tx in &block.transactions {
+        if !tx.is_coinbase() {
+            for vin in &tx.v_in {
+                writes
+                    .spends
+                    .get_or_insert_default()
+                    .insert(spent_index_key(&vin.prev_out), tx.id.as_bytes());
+                let tx_id = vin.prev_out.txid.to_string();
+                if let Some(v) = db.get(&tx_id)? {
+                    let outs: TXOutputs = decode_from_slice(&v, standard()).map(|(w, _)| w)?;
+                    touched.insert(tx_id, outs);
+                }
+            }
+        }
+    }
+    let mut unindexed = HashSet::new();
+    for (tx_id, outs) in &touched {
+        for out in &outs.outputs {
+            unindexed.insert(address_index_key(&out.pub_key_hash, tx_id));
+        }
+    }
+    let spent: Vec<String> = touched.keys().cloned().collect();
+    apply_block(&mut touched, block);
+
+    for tx_id in spent {
+        if !touched.contains_key(&tx_id) {
+            writes
+                .utxos
+                .get_or_insert_default()
+                .remove(tx_id.as_bytes());
+        }
+    }
+    for (tx_id, outs) in touched {
+        for out in &outs.outputs {
+            let key = address_index_key(&out.pub_key_hash, &tx_id);
+            unindexed.remove(&key);
+            writes
+                .index_added
+                .get_or_insert_default()
+                .insert(key, vec![]);
+        }
+        writes
+            .utxos
+            .get_or_insert_default()
+            .insert(tx_id.as_bytes(), encode_to_vec(outs, standard())?);
+    }
+    for key in unindexed {
+        writes.index_removed.get_or_insert_default().remove(key);
+    }
+    writes
+        .tip
+        .get_or_insert_default()
+        .insert(TIP_KEY, block.hash.as_ref());
+    Ok(writes)
+}
+
+/// Spends the inputs of every transaction in `block` and adds its outputs.
+/// Entries left without outputs are removed.
+fn apply_block(utxos: &mut HashMap<String, TXOutputs>, block: &Block) {
+    for tx in &block.transactions {
+        if !tx.is_coinbase() {
+            for vin in &tx.v_in {
+                let tx_id = vin.prev_out.txid.to_string();
+                let Some(outs) = utxos.get_mut(&tx_id) else {
+                    continue;
+                };
+                let mut out_idx = 0;
+                outs.outputs.retain(|_| {
+                    out_idx += 1;
+                    out_idx - 1 != vin.prev_out.vout as usize
+                });
+                if outs.outputs.is_empty() {
+                    utxos.remove(&tx_id);
+                }
+            }
+        }
+
+        let new_outputs = TXOutputs {
+            outputs: tx.v_out.clone(),
+            height: block.height,
+            block_hash: block.hash,
+        };
+        utxos.insert(tx.id.clone(), new_outputs);
+    }
+}
+
+/// Finishes a swap interrupted between moving the old set away and moving
+/// the new one in, by putting the old set back.
+fn recover_swap(path: &Path) -> Result<()> {
+    let old_path = path.with_extension("old");
+    if !path.exists() && old_path.exists() {
+        log::warn!("Restoring UTXO set from {}", old_path.display());
+        fs::rename(&old_path, path)?;
+    }
+    Ok(())
+}
+
+/// Indices into `values` of the outputs `strategy` spends for `amount`.
+fn select_coins(values: &[i32], amount: i32, strategy: CoinSelectionStrategy) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..values.len()).collect();
+    match strategy {
+        CoinSelectionStrategy::FirstFit => {}
+        CoinSelectionStrategy::LargestFirst => {
+            order.sort_by_key(|&i| std::cmp::Reverse(values[i]));
+        }
+        CoinSelectionStrategy::MinimizeChange => {
+            let single = order
+                .iter()
+                .copied()
+                .filter(|&i| values[i] >= amount)
+                .min_by_key(|&i| values[i]);
+            if let Some(i) = single {
+                return vec![i];
+            }
+            return select_coins(values, amount, CoinSelectionStrategy::LargestFirst);
+        }
+        CoinSelectionStrategy::BranchAndBound => {
+            return branch_and_bound(values, amount)
+                .unwrap_or_else(|| select_coins(values, amount, CoinSelectionStrategy::FirstFit));
+        }
+    }
+
+    let mut selected = vec![];
+    let mut accumulated: i64 = 0;
+    for i in order {
+        if accumulated >= amount as i64 {
+            break;
+        }
+        accumulated += values[i] as i64;
+        selected.push(i);
+    }
+    selected
+}
+
+/// Depth first search, largest values first, for outputs adding up to
+/// exactly `amount`. Gives up after `BNB_MAX_TRIES` steps.
+fn branch_and_bound(values: &[i32], amount: i32) -> Option<Vec<usize>> {
+    let mut order: Vec<usize> = (0..values.len()).collect();
+    order.sort_by_key(|&i| std::cmp::Reverse(values[i]));
+    // remaining[k] is what the outputs from position k on add up to
+    let mut remaining = vec![0i64; order.len() + 1];
+    for k in (0..order.len()).rev() {
+        remaining[k] = remaining[k + 1] + values[order[k]] as i64;
+    }
+
+    fn search(
+        values: &[i32],
+        order: &[usize],
+        remaining: &[i64],
+        k: usize,
+        left: i64,
+        picked: &mut Vec<usize>,
+        tries: &mut usize,
+    ) -> bool {
+        if left == 0 {
+            return true;
+        }
+        *tries += 1;
+        if k == order.len() || left < 0 || remaining[k] < left || *tries > BNB_MAX_TRIES {
+            return false;
+        }
+        picked.push(order[k]);
+        let value = values[order[k]] as i64;
+        if search(values, order, remaining, k + 1, left - value, picked, tries) {
+            return true;
+        }
+        picked.pop();
+        search(values, order, remaining, k + 1, left, picked, tries)
+    }
+
+    let mut picked = vec![];
+    let mut tries = 0;
+    (amount > 0
+        && search(
+            values,
+            &order,
+            &remaining,
+            0,
+            amount as i64,
+            &mut picked,
+            &mut tries,
+        ))
+    .then_some(picked)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        ChainParams, Durability, Hash, TXOutput, Transaction, Wallet, get_pub_key_hash,
+        testutil::{TestDir, TxBuilder, reopen},
+    };
+
+    #[test]
+    fn test_coin_selection_strategies() {
+        use CoinSelectionStrategy::*;
+        let values = [5, 20, 3, 12, 7];
+        let total = |picked: &[usize]| picked.iter().map(|&i| values[i]).sum::<i32>();
+
+        // The old behaviour: in order until covered
+        assert_eq!(select_coins(&values, 22, FirstFit), vec![0, 1]);
+        // Fewest inputs
+        assert_eq!(select_coins(&values, 22, LargestFirst), vec![1, 3]);
+        // One output just over the amount, else largest first
+        assert_eq!(select_coins(&values, 10, MinimizeChange), vec![3]);
+        assert_eq!(select_coins(&values, 30, MinimizeChange), vec![1, 3]);
+        // An exact subset, no change
+        let picked = select_coins(&values, 15, BranchAndBound);
+        assert_eq!(total(&picked), 15);
+        let picked = select_coins(&values, 47, BranchAndBound);
+        assert_eq!(total(&picked), 47);
+        // No exact subset, falls back to first fit
+        assert_eq!(
+            select_coins(&values, 48, BranchAndBound),
+            vec![0, 1, 2, 3, 4]
+        );
+        assert_eq!(select_coins(&values, 1, BranchAndBound), vec![0]);
+        // Not enough funds spends everything for every strategy
+        for strategy in [FirstFit, LargestFirst, MinimizeChange, BranchAndBound] {
+            assert_eq!(total(&select_coins(&values, 100, strategy)), 47);
+        }
+    }
+
+    #[test]
+    fn test_find_spendable_outputs_with_strategy() {
+        let params = ChainParams::default();
+        let dir = TestDir::new("coin-selection");
+        let bc = Blockchain::open(dir.join("blockchain")).unwrap();
+        let utxo_set = UTXOSet::with_path(bc, dir.join("utxos"));
+        let wallet = Wallet::new();
+        let pub_key_hash = get_pub_key_hash(&wallet.get_address(&params), &params).unwrap();
+        let outputs = [4, 9, 6]
+            .iter()
+            .map(|&value| TXOutput {
+                value,
+                pub_key_hash: pub_key_hash.clone(),
+            })
+            .collect();
+        utxo_set
+            .rebuild(vec![Ok((
+                "aa".to_owned(),
+                TXOutputs {
+                    outputs,
+                    ..Default::default()
+                },
+            ))])
+            .unwrap();
+
+        let (found, picked) = utxo_set
+            .find_spendable_outputs_with(&pub_key_hash, 10, CoinSelectionStrategy::BranchAndBound)
+            .unwrap();
+        assert_eq!(found, 10);
+        let mut idxs = picked["aa"].clone();
+        idxs.sort();
+        assert_eq!(idxs, vec![0, 2]);
+
+        let (found, picked) = utxo_set
+            .find_spendable_outputs_with(&pub_key_hash, 10, CoinSelectionStrategy::LargestFirst)
+            .unwrap();
+        assert_eq!((found, picked["aa"].clone()), (15, vec![1, 2]));
+
+        let (found, _) = utxo_set.find_spendable_outputs(&pub_key_hash, 10).unwrap();
+        assert_eq!(found, 13);
+    }
+
+    #[test]
+    fn test_immature_coinbase_is_not_spent() {
+        let params = ChainParams::default();
+        let dir = TestDir::new("maturity");
+        let addr = Wallet::new().get_address(&params);
+        let pub_key_hash = get_pub_key_hash(&addr, &params).unwrap();
+        let bc = Blockchain::create_at(dir.join("blockchain"), &addr).unwrap();
+        let mut utxo_set = UTXOSet::with_path(bc, dir.join("utxos")).with_coinbase_maturity(3);
+        utxo_set.reindex().unwrap();
+        let value = |outs: &TXOutputs| outs.outputs.iter().map(|out| out.value).sum::<i32>();
+
+        // Genesis alone has a single confirmation
+        let (spendable, immature) = utxo_set.find_utxo_by_maturity(&pub_key_hash, 0).unwrap();
+        assert_eq!((value(&spendable), value(&immature)), (0, 10));
+        assert_eq!(
+            utxo_set.find_spendable_outputs(&pub_key_hash, 5).unwrap().0,
+            0
+        );
+
+        for height in 1..3 {
+            let cbtx = Transaction::new_coinbase(&addr, height.to_string(), &params).unwrap();
+            let block = utxo_set.bc.mine_block(vec![cbtx]).unwrap();
+            utxo_set.update(block).unwrap();
+        }
+        // Now genesis has 3, the two newer coinbases 2 and 1
+        let (spendable, immature) = utxo_set.find_utxo_by_maturity(&pub_key_hash, 0).unwrap();
+        assert_eq!((value(&spendable), value(&immature)), (10, 20));
+        assert_eq!(
+            utxo_set
+                .find_spendable_outputs(&pub_key_hash, 15)
+                .unwrap()
+                .0,
+            10
+        );
+        let (spendable, immature) = utxo_set.find_utxo_by_maturity(&pub_key_hash, 2).unwrap();
+        assert_eq!((value(&spendable), value(&immature)), (10, 10));
+
+        let utxo_set = utxo_set.with_coinbase_maturity(0);
+        assert_eq!(
+            utxo_set
+                .find_spendable_outputs(&pub_key_hash, 30)
+                .unwrap()
+                .0,
+            30
+        );
+    }
+
+    #[test]
+    fn test_failed_reindex_keeps_old_set() {
+        let dir = TestDir::new("reindex");
+        let bc = Blockchain::open(dir.join("blockchain")).unwrap();
+        let utxo_set = UTXOSet::with_path(bc, dir.join("utxos"));
+
+        let outs = TXOutputs {
+            outputs: vec![TXOutput {
+                value: 7,
+                pub_key_hash: vec![1; 20],
+            }],
+            ..Default::default()
+        };
+        utxo_set
+            .rebuild(vec![Ok(("old".to_owned(), outs.clone()))])
+            .unwrap();
+
+        let failing = vec![
+            Ok(("new".to_owned(), outs)),
+            Err(anyhow!("simulated failure")),
+        ];
+        assert!(utxo_set.rebuild(failing).is_err());
+
+        assert_eq!(utxo_set.find_utxo(&[1; 20]).unwrap().outputs.len(), 1);
+        let db = utxo_set.open_db().unwrap();
+        assert!(db.get("old").unwrap().is_some());
+        assert!(db.get("new").unwrap().is_none());
+        drop(db);
+    }
+
+    fn mine(transactions: Vec<Transaction>, height: i32, bc: &Blockchain) -> Block {
+        Block::new(
+            transactions,
+            Hash::default(),
+            height,
+            bc.params.min_difficulty_bits,
+            &bc.params,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_reorg_within_overlay() {
+        let params = ChainParams::default();
+        let dir = TestDir::new("overlay");
+        let bc = Blockchain::open(dir.join("blockchain")).unwrap();
+        let mut utxo_set = UTXOSet::with_path(bc, dir.join("utxos")).with_confirmation_depth(2);
+        let [a, b, c, d] = [(); 4].map(|_| Wallet::new().get_address(&params));
+        let balance = |utxo_set: &UTXOSet, addr: &str| -> i32 {
+            let outs = utxo_set
+                .find_utxo(&get_pub_key_hash(addr, &params).unwrap())
+                .unwrap();
+            outs.outputs.iter().map(|out| out.value).sum()
+        };
+
+        let cb_a = Transaction::new_coinbase(&a, String::new(), &params).unwrap();
+        let pay_b = TxBuilder::new()
+            .spend(cb_a.hash_val, 0)
+            .pay(10, &b, &params)
+            .build();
+        let bc = &utxo_set.bc;
+        let block1 = mine(vec![cb_a], 1, bc);
+        let block2 = mine(vec![pay_b], 2, bc);
+        let fork2 = mine(
+            vec![Transaction::new_coinbase(&c, String::new(), &params).unwrap()],
+            2,
+            bc,
+        );
+        let block3 = mine(
+            vec![Transaction::new_coinbase(&d, String::new(), &params).unwrap()],
+            3,
+            bc,
+        );
+
+        utxo_set.update(block1).unwrap();
+        utxo_set.update(block2).unwrap();
+        assert_eq!(balance(&utxo_set, &a), 0);
+        assert_eq!(balance(&utxo_set, &b), 10);
+
+        // Replace block 2 with a competing block
+        assert_eq!(utxo_set.disconnect_tip().unwrap().height, 2);
+        assert_eq!(balance(&utxo_set, &a), 10);
+        assert_eq!(balance(&utxo_set, &b), 0);
+        utxo_set.update(fork2).unwrap();
+        assert_eq!(balance(&utxo_set, &c), 10);
+
+        // Block 1 is now buried deep enough to be written
+        utxo_set.update(block3).unwrap();
+        assert_eq!(utxo_set.overlay.len(), 2);
+        let db = utxo_set.open_db().unwrap();
+        assert_eq!(db.len(), 1);
+        drop(db);
+        assert_eq!(balance(&utxo_set, &a), 10);
+        assert_eq!(balance(&utxo_set, &b), 0);
+        assert_eq!(balance(&utxo_set, &d), 10);
+    }
+
+    #[test]
+    fn test_sync_reorgs_within_overlay_and_replays_it() {
+        let params = ChainParams::default();
+        let dir = TestDir::new("sync");
+        let mut utxo_set =
+            UTXOSet::with_path(spend_chain(&dir, 3), dir.join("utxos")).with_confirmation_depth(2);
+        let extend = |bc: &mut Blockchain, prev: Hash, height: i32, tag: &str| -> Hash {
+            let addr = Wallet::new().get_address(&params);
+            let cbtx = Transaction::new_coinbase(&addr, tag.to_owned(), &params).unwrap();
+            let bits = bc.params.min_difficulty_bits;
+            let block = Block::new(vec![cbtx], prev, height, bits, &bc.params).unwrap();
+            bc.add_block(&block).unwrap();
+            block.hash
+        };
+
+        // A set that doesn't know its last block is reindexed
+        utxo_set.sync().unwrap();
+        assert!(utxo_set.overlay.is_empty());
+        assert_eq!(utxo_set.committed_tip().unwrap(), Some(utxo_set.bc.tip));
+
+        let block3 = utxo_set.bc.tip;
+        let block4 = extend(&mut utxo_set.bc, block3, 4, "4");
+        extend(&mut utxo_set.bc, block4, 5, "5");
+        utxo_set.sync().unwrap();
+        assert_eq!(utxo_set.overlay.len(), 2);
+
+        // The fork is in the overlay: block 5 is disconnected, block 4
+        // written, and nothing reindexed
+        let fork5 = extend(&mut utxo_set.bc, block4, 5, "fork 5");
+        let fork6 = extend(&mut utxo_set.bc, fork5, 6, "fork 6");
+        utxo_set.sync().unwrap();
+        let overlay: Vec<_> = utxo_set.overlay.iter().map(|b| b.hash).collect();
+        assert_eq!(overlay, [fork5, fork6]);
+        assert_eq!(utxo_set.committed_tip().unwrap(), Some(block4));
+        assert!(utxo_set.verify_against_chain().unwrap().is_empty());
+
+        // After a restart the overlay is replayed from the last written block
+        drop(utxo_set);
+        let utxo_set = reopen(|| {
+            let bc =
+                Blockchain::open_with_params(dir.join("blockchain"), ChainParams::for_tests())?;
+            let mut utxo_set = UTXOSet::with_path(bc, dir.join("utxos")).with_confirmation_depth(2);
+            utxo_set.sync()?;
+            Ok(utxo_set)
+        });
+        let overlay: Vec<_> = utxo_set.overlay.iter().map(|b| b.hash).collect();
+        assert_eq!(overlay, [fork5, fork6]);
+        assert_eq!(utxo_set.committed_tip().unwrap(), Some(block4));
+        assert!(utxo_set.verify_against_chain().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_address_index_follows_spends() {
+        let params = ChainParams::default();
+        let dir = TestDir::new("addr-index");
+        let bc = Blockchain::open(dir.join("blockchain")).unwrap();
+        let mut utxo_set = UTXOSet::with_path(bc, dir.join("utxos"));
+        utxo_set.reindex().unwrap();
+        let [a, b] = [(); 2].map(|_| Wallet::new().get_address(&params));
+        let (pkh_a, pkh_b) = (
+            get_pub_key_hash(&a, &params).unwrap(),
+            get_pub_key_hash(&b, &params).unwrap(),
+        );
+
+        let cb_a = Transaction::new_coinbase(&a, String::new(), &params).unwrap();
+        let pay_b = TxBuilder::new()
+            .spend(cb_a.hash_val, 0)
+            .pay(4, &b, &params)
+            .pay(6, &a, &params)
+            .build();
+        let block1 = mine(vec![cb_a.clone()], 1, &utxo_set.bc);
+        let block2 = mine(vec![pay_b.clone()], 2, &utxo_set.bc);
+        utxo_set.update(block1).unwrap();
+        utxo_set.update(block2).unwrap();
+
+        let indexed = |pkh: &[u8]| -> Vec<String> {
+            let db = utxo_set.open_db().unwrap();
+            let index = db.open_tree(ADDRESS_INDEX_TREE).unwrap();
+            index
+                .scan_prefix(pkh)
+                .map(|e| String::from_utf8(e.unwrap().0[pkh.len()..].to_vec()).unwrap())
+                .collect()
+        };
+        assert_eq!(indexed(&pkh_a), vec![pay_b.id.clone()]);
+        assert_eq!(indexed(&pkh_b), vec![pay_b.id.clone()]);
+        assert_eq!(utxo_set.find_utxo(&pkh_a).unwrap().outputs[0].value, 6);
+        assert_eq!(utxo_set.find_utxo(&pkh_b).unwrap().outputs[0].value, 4);
+
+        // A set written without the index gets it back from reindex_addresses
+        let db = utxo_set.open_db().unwrap();
+        db.open_tree(ADDRESS_INDEX_TREE).unwrap().clear().unwrap();
+        drop(db);
+        assert_eq!(utxo_set.find_utxo(&pkh_b).unwrap().outputs[0].value, 4);
+        utxo_set.reindex_addresses().unwrap();
+        assert_eq!(indexed(&pkh_b), vec![pay_b.id.clone()]);
+        assert_eq!(utxo_set.find_utxo(&pkh_b).unwrap().outputs[0].value, 4);
+    }
+
+    #[test]
+    fn test_verify_against_chain() {
+        let dir = TestDir::new("utxo-check");
+        let addr = Wallet::new().get_address(&ChainParams::default());
+        let bc = Blockchain::create_at(dir.join("blockchain"), &addr).unwrap();
+        let genesis_tx = bc.iter().next().unwrap().transactions[0].id.clone();
+        let mut utxo_set = UTXOSet::with_path(bc, dir.join("utxos"));
+        utxo_set.reindex().unwrap();
+        assert!(utxo_set.verify_against_chain().unwrap().is_empty());
+
+        let db = utxo_set.open_db().unwrap();
+        db.remove(&genesis_tx).unwrap();
+        db.insert(
+            "bogus",
+            encode_to_vec(TXOutputs::default(), standard()).unwrap(),
+        )
+        .unwrap();
+        drop(db);
+        let mismatch = utxo_set.verify_against_chain().unwrap();
+        assert_eq!(mismatch.missing, vec![genesis_tx]);
+        assert_eq!(mismatch.unexpected, vec!["bogus".to_owned()]);
+        assert!(mismatch.different.is_empty());
+
+        utxo_set.reindex().unwrap();
+        assert!(utxo_set.verify_against_chain().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_rich_list() {
+        let params = ChainParams::default();
+        let dir = TestDir::new("rich-list");
+        let addr = Wallet::new().get_address(&params);
+        let bc = Blockchain::create_at(dir.join("blockchain"), &addr).unwrap();
+        let utxo_set = UTXOSet::with_path(bc, dir.join("utxos"));
+        let (a, b) = (get_pub_key_hash(&addr, &params).unwrap(), vec![2; 20]);
+        let outs = |outputs: Vec<TXOutput>| TXOutputs {
+            outputs,
+            ..Default::default()
+        };
+        let out = |value: i32, pkh: &[u8]| TXOutput {
+            value,
+            pub_key_hash: pkh.to_vec(),
+        };
+        utxo_set
+            .rebuild(vec![
+                Ok(("t1".to_owned(), outs(vec![out(10, &a), out(30, &b)]))),
+                Ok(("t2".to_owned(), outs(vec![out(5, &a), out(0, &[3; 20])]))),
+            ])
+            .unwrap();
+
+        assert_eq!(utxo_set.rich_list().unwrap(), vec![(b, 30), (a, 15)]);
+    }
+
+    /// Chain of `len` blocks after genesis, each spending the coinbase of
+    /// the one before, so most spends cross reindex chunks.
+    fn spend_chain(dir: &Path, len: i32) -> Blockchain {
+        let params = ChainParams::default();
+        let wallet = Wallet::new();
+        let addr = wallet.get_address(&params);
+        let mut bc =
+            Blockchain::create_with_params(dir.join("blockchain"), &addr, ChainParams::for_tests())
+                .unwrap();
+        bc.durability = Durability::Batch;
+        let bits = bc.params.min_difficulty_bits;
+        for height in 1..=len {
+            let prev = bc.iter().next().unwrap().transactions[0].clone();
+            let mut spend = TxBuilder::new()
+                .spend_from(prev.hash_val, 0, &wallet)
+                .pay(prev.v_out[0].value, &addr, &params)
+                .build();
+            bc.sign_transaction(&mut spend, &wallet.private_key)
+                .unwrap();
+            let cbtx = Transaction::new_coinbase(&addr, height.to_string(), &params).unwrap();
+            let block = Block::new(vec![cbtx, spend], bc.tip, height, bits, &bc.params).unwrap();
+            bc.add_block(&block).unwrap();
+        }
+        bc
+    }
+
+    #[test]
+    fn test_parallel_reindex_tracks_spends_across_chunks() {
+        let dir = TestDir::new("parallel-reindex");
+        let utxo_set = UTXOSet::with_path(spend_chain(&dir, 20), dir.join("utxos"));
+        let expected = utxo_set.bc.find_utxo();
+        // Genesis and each block's coinbase are spent, leaving the last
+        // coinbase and the spends
+        assert_eq!(expected.len(), 21);
+
+        utxo_set
+            .rebuild_with(utxo_set.bc.iter_utxo().map(Ok), 3, 2)
+            .unwrap();
+        assert!(utxo_set.verify_against_chain().unwrap().is_empty());
+        assert_eq!(utxo_set.unspent().unwrap().len(), expected.len());
+    }
+
+    #[test]
+    fn test_block_applies_all_or_nothing() {
+        let dir = TestDir::new("utxo-batch");
+        let utxo_set = UTXOSet::with_path(spend_chain(&dir, 3), dir.join("utxos"));
+        let mut blocks: Vec<Block> = utxo_set.bc.iter().collect();
+        let last = blocks.remove(0);
+        for block in blocks.iter().rev() {
+            utxo_set.commit(block).unwrap();
+        }
+        let before = utxo_set.unspent().unwrap();
+        let spent = &last.transactions[1].v_in[0].prev_out.txid.to_string();
+        assert!(before.contains_key(spent));
+
+        let indexes = |utxo_set: &UTXOSet| {
+            let db = utxo_set.open_db().unwrap();
+            [ADDRESS_INDEX_TREE, SPENT_INDEX_TREE].map(|name| {
+                let tree = db.open_tree(name).unwrap();
+                tree.iter().map(|ele| ele.unwrap()).collect::<Vec<_>>()
+            })
+        };
+        let indexes_before = indexes(&utxo_set);
+
+        // The node dies once the set itself is written, before the address
+        // index loses the spent entries
+        let db = utxo_set.open_db().unwrap();
+        let writes = block_writes(&db, &last).unwrap();
+        let crash = |tree: &str| match tree {
+            "utxos" => Err(anyhow!("simulated crash")),
+            _ => Ok(()),
+        };
+        assert!(apply_writes(&db, &writes, crash).is_err());
+        let after_crash = utxo_set.unspent().unwrap();
+        assert_eq!(after_crash.len(), before.len());
+        assert!(after_crash.contains_key(spent));
+        assert!(!after_crash.contains_key(&last.transactions[0].id));
+        assert_eq!(indexes(&utxo_set), indexes_before);
+
+        utxo_set.commit(&last).unwrap();
+        assert!(utxo_set.verify_against_chain().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_output_spends() {
+        let dir = TestDir::new("utxo-spends");
+        let mut utxo_set = UTXOSet::with_path(spend_chain(&dir, 3), dir.join("utxos"));
+        utxo_set.reindex().unwrap();
+        assert!(utxo_set.output_spends("unknown").unwrap().is_none());
+        let blocks: Vec<Block> = utxo_set.bc.iter().collect();
+        for (i, block) in blocks.iter().enumerate() {
+            let [cbtx, spend] = &block.transactions[..] else {
+                continue;
+            };
+            let spends = utxo_set.output_spends(&spend.id).unwrap().unwrap();
+            let spends: Vec<OutputSpend> = spends.into_iter().map(|(_, s)| s).collect();
+            assert_eq!(spends.len(), spend.v_out.len());
+            assert_eq!(spends[0], OutputSpend::Unspent);
+
+            let cb_spends = utxo_set.output_spends(&cbtx.id).unwrap().unwrap();
+            // Spent by the next block, except for the last one
+            let expected = match i {
+                0 => OutputSpend::Unspent,
+                _ => OutputSpend::Spent {
+                    by: Some(blocks[i - 1].transactions[1].id.clone()),
+                },
+            };
+            assert_eq!(cb_spends[0].1, expected);
+        }
+    }
+
+    #[test]
+    fn test_spent_index() {
+        let dir = TestDir::new("utxo-spent");
+        let mut utxo_set =
+            UTXOSet::with_path(spend_chain(&dir, 3), dir.join("utxos")).with_confirmation_depth(1);
+        let spent_index = |utxo_set: &UTXOSet| -> Vec<(sled::IVec, sled::IVec)> {
+            let db = utxo_set.open_db().unwrap();
+            let spent = db.open_tree(SPENT_INDEX_TREE).unwrap();
+            spent.iter().map(|ele| ele.unwrap()).collect()
+        };
+        utxo_set
+            .open_db()
+            .unwrap()
+            .open_tree(SPENT_INDEX_TREE)
+            .unwrap()
+            .insert(SPENT_INDEX_MARKER, vec![])
+            .unwrap();
+        let blocks: Vec<Block> = utxo_set.bc.iter().collect();
+        for block in blocks.iter().rev() {
+            utxo_set.update(block.clone()).unwrap();
+        }
+
+        // The tip is only in the overlay, its spend is found all the same
+        let tip_spend = &blocks[0].transactions[1];
+        let spent_cb = &blocks[1].transactions[0];
+        assert_eq!(
+            utxo_set.spender(&spent_cb.id, 0).unwrap(),
+            Some(tip_spend.id.clone())
+        );
+        assert_eq!(utxo_set.spender(&tip_spend.id, 0).unwrap(), None);
+        let committed = spent_index(&utxo_set);
+        utxo_set.disconnect_tip().unwrap();
+        assert_eq!(utxo_set.spender(&spent_cb.id, 0).unwrap(), None);
+
+        // Commits kept the index as a reindex builds it
+        utxo_set.reindex().unwrap();
+        let mut expected = committed;
+        expected.push((
+            spent_index_key(&OutPoint::new(spent_cb.hash_val, 0)).into(),
+            tip_spend.id.as_bytes().into(),
+        ));
+        expected.sort();
+        assert_eq!(spent_index(&utxo_set), expected);
+
+        // Every output, one per transaction here, is in exactly one of them
+        let unspent = utxo_set.unspent().unwrap();
+        for block in &blocks {
+            for tx in &block.transactions {
+                assert_ne!(
+                    unspent.contains_key(&tx.id),
+                    utxo_set.spender(&tx.id, 0).unwrap().is_some()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_output_origin() {
+        let params = ChainParams::default();
+        let dir = TestDir::new("utxo-origin");
+        let mut utxo_set =
+            UTXOSet::with_path(spend_chain(&dir, 3), dir.join("utxos")).with_confirmation_depth(1);
+        utxo_set.reindex().unwrap();
+        for block in utxo_set.bc.iter() {
+            let [cbtx, spend] = &block.transactions[..] else {
+                continue;
+            };
+            let origin = Some((block.height, block.hash));
+            assert_eq!(utxo_set.get_output_origin(&spend.id, 0).unwrap(), origin);
+            assert_eq!(utxo_set.get_output_origin(&spend.id, 1).unwrap(), None);
+            // Spent by the next block, except for the last one
+            if block.height < 3 {
+                assert_eq!(utxo_set.get_output_origin(&cbtx.id, 0).unwrap(), None);
+            }
+        }
+
+        // Blocks passed to `update`, both in the overlay and once written
+        let addr = Wallet::new().get_address(&params);
+        let mut mined = vec![];
+        for height in 4..6 {
+            let bc = &mut utxo_set.bc;
+            let cbtx = Transaction::new_coinbase(&addr, height.to_string(), &params).unwrap();
+            let bits = bc.params.min_difficulty_bits;
+            let block = Block::new(vec![cbtx], bc.tip, height, bits, &bc.params).unwrap();
+            bc.add_block(&block).unwrap();
+            mined.push((block.transactions[0].id.clone(), block.height, block.hash));
+            utxo_set.update(block).unwrap();
+            for (tx_id, height, hash) in &mined {
+                assert_eq!(
+                    utxo_set.get_output_origin(tx_id, 0).unwrap(),
+                    Some((*height, *hash))
+                );
+            }
+        }
+        assert!(utxo_set.verify_against_chain().unwrap().is_empty());
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture bench_`
+    #[test]
+    #[ignore]
+    fn bench_reindex() {
+        let dir = TestDir::new("reindex-bench");
+        let utxo_set = UTXOSet::with_path(spend_chain(&dir, 1000), dir.join("utxos"));
+
+        let start = std::time::Instant::now();
+        utxo_set
+            .rebuild_with(
+                utxo_set.bc.find_utxo().into_iter().map(Ok),
+                1,
+                REINDEX_CHUNK_LEN,
+            )
+            .unwrap();
+        println!("serial: {:?}", start.elapsed());
+        let start = std::time::Instant::now();
+        utxo_set.rebuild(utxo_set.bc.iter_utxo().map(Ok)).unwrap();
+        println!("parallel: {:?}", start.elapsed());
+        assert!(utxo_set.verify_against_chain().unwrap().is_empty());
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture bench_`
+    #[test]
+    #[ignore]
+    fn bench_address_index() {
+        let dir = TestDir::new("addr-index-bench");
+        let bc = Blockchain::open(dir.join("blockchain")).unwrap();
+        let utxo_set = UTXOSet::with_path(bc, dir.join("utxos"));
+        let utxos = (0..50_000u32).map(|i| {
+            let outs = TXOutputs {
+                outputs: vec![TXOutput {
+                    value: 1,
+                    pub_key_hash: (i % 1000).to_be_bytes().repeat(5),
+                }],
+                ..Default::default()
+            };
+            Ok((format!("{:064x}", i), outs))
+        });
+        utxo_set.rebuild(utxos).unwrap();
+        let pkh = 7u32.to_be_bytes().repeat(5);
+
+        for indexed in [true, false] {
+            if !indexed {
+                let db = utxo_set.open_db().unwrap();
+                db.open_tree(ADDRESS_INDEX_TREE).unwrap().clear().unwrap();
+            }
+            let start = std::time::Instant::now();
+            for _ in 0..20 {
+                assert_eq!(utxo_set.find_utxo(&pkh).unwrap().outputs.len(), 50);
+            }
+            println!(
+                "indexed={}: 20 balance queries in {:?}",
+                indexed,
+                start.elapsed()
+            );
+        }
+    }
+
+    #[test]
+    fn test_reindex_waits_for_queued_update() {
+        let params = ChainParams::default();
+        let dir = TestDir::new("reindex-lock");
+        let addr = Wallet::new().get_address(&params);
+        // Two handles on the same set, like a running node and the CLI
+        let node = UTXOSet::with_path(
+            Blockchain::open(dir.join("node")).unwrap(),
+            dir.join("utxos"),
+        );
+        let bc = Blockchain::create_at(dir.join("cli"), &addr).unwrap();
+        let mut cli = UTXOSet::with_path(bc, dir.join("utxos"));
+
+        // The node is in the middle of writing a block
+        let lock = node.lock().unwrap();
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        let reindex = std::thread::spawn(move || {
+            cli.reindex().unwrap();
+            done_tx.send(()).unwrap();
+            cli
+        });
+        assert!(
+            done_rx
+                .recv_timeout(std::time::Duration::from_millis(300))
+                .is_err()
+        );
+
+        drop(lock);
+        let cli = reindex.join().unwrap();
+        let outs = cli
+            .find_utxo(&get_pub_key_hash(&addr, &params).unwrap())
+            .unwrap();
+        assert_eq!(outs.outputs.len(), 1);
+        drop((cli, node));
+    }
 }
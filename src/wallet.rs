@@ -1,45 +1,112 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    thread,
+};
 
-use anyhow::Result;
-use base58::ToBase58;
+use anyhow::{Result, anyhow};
+use base58::{FromBase58, ToBase58};
 use bincode::{
     config::standard,
     serde::{decode_from_slice, encode_to_vec},
 };
+use chacha20poly1305::{
+    ChaCha20Poly1305, Key, KeyInit, Nonce,
+    aead::{Aead, AeadCore},
+};
 use log::info;
 use p256::{
-    ecdsa::{SigningKey, VerifyingKey},
-    elliptic_curve::rand_core::OsRng,
+    ecdsa::{
+        Signature, SigningKey, VerifyingKey,
+        signature::{Signer, Verifier},
+    },
+    elliptic_curve::rand_core::{OsRng, RngCore},
 };
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
-use crate::hash_pub_key;
+use crate::{
+    Blockchain, ChainParams, CoinSelectionStrategy, Network, Transaction, TxStatus, hash_pub_key,
+};
 
-const VERSION: u8 = 0x00;
-const ADDRESS_CHECKSUM_LEN: usize = 4;
+const BACKUP_SALT_LEN: usize = 16;
+const LABELS_TREE: &str = "labels";
+const SETTINGS_TREE: &str = "settings";
+const TRANSACTIONS_TREE: &str = "transactions";
+const SORT_BIP69_KEY: &str = "sort_bip69";
+const COIN_SELECTION_KEY: &str = "coin_selection";
+/// Put in front of a message before signing it, so a message signature
+/// can never pass for a transaction signature
+const SIGNED_MESSAGE_MAGIC: &[u8] = b"rs-blockchain Signed Message:\n";
+/// A signed message carries the compressed public key and the signature
+const COMPRESSED_PUB_KEY_LEN: usize = 33;
+const SIGNATURE_LEN: usize = 64;
+const BASE58_ALPHABET: &str = "123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+/// Bytes of a double SHA256, the longest checksum an address can carry
+const MAX_CHECKSUM_LEN: usize = 32;
 
+#[derive(Default)]
 pub struct Wallets {
     pub wallets: HashMap<String, Wallet>,
+    /// Local notes keyed by address or `txid:vout` outpoint. They stay in
+    /// the wallet store and are never put in a transaction or sent to peers.
+    pub labels: HashMap<String, String>,
+    /// Sort the inputs and outputs of new transactions as in BIP69, so
+    /// their order doesn't give away which wallet built them
+    pub sort_bip69: bool,
+    /// How new transactions pick the outputs they spend
+    pub coin_selection: CoinSelectionStrategy,
+    /// Transactions sent from this wallet by id, to follow them until they
+    /// confirm
+    pub transactions: HashMap<String, Transaction>,
+}
+
+/// What `Wallets::insert_wallet` did with a wallet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalletInsert {
+    Added,
+    /// A wallet with the same address was already stored and was kept
+    Existing,
+    /// A wallet with the same address was replaced
+    Overwritten,
 }
 
 impl Wallets {
-    pub fn new() -> Result<Wallets> {
-        let mut waleets = Self {
-            wallets: HashMap::default(),
-        };
-        waleets.load()?;
+    /// Loads the wallets stored at `path`, none if there is no store yet.
+    pub fn open(path: impl AsRef<Path>) -> Result<Wallets> {
+        let mut waleets = Self::default();
+        waleets.load_from(&sled::open(path)?)?;
         Ok(waleets)
     }
 
-    fn load(&mut self) -> Result<()> {
-        let db = sled::open("db/wallets")?;
+    fn load_from(&mut self, db: &sled::Db) -> Result<()> {
         for ele in db.into_iter() {
             let ele = ele?;
             let addr = String::from_utf8(ele.0.to_vec())?;
             let wallet: Wallet = decode_from_slice(&ele.1, standard()).map(|(w, _)| w)?;
             self.wallets.insert(addr, wallet);
         }
+        for ele in db.open_tree(LABELS_TREE)?.into_iter() {
+            let (target, label) = ele?;
+            self.labels.insert(
+                String::from_utf8(target.to_vec())?,
+                String::from_utf8(label.to_vec())?,
+            );
+        }
+        let settings = db.open_tree(SETTINGS_TREE)?;
+        self.sort_bip69 = settings.contains_key(SORT_BIP69_KEY)?;
+        if let Some(data) = settings.get(COIN_SELECTION_KEY)? {
+            self.coin_selection = decode_from_slice(&data, standard()).map(|(s, _)| s)?;
+        }
+        for ele in db.open_tree(TRANSACTIONS_TREE)?.into_iter() {
+            let (_, data) = ele?;
+            let (tx, _): (Transaction, usize) = decode_from_slice(&data, standard())?;
+            self.transactions.insert(tx.id.clone(), tx);
+        }
         Ok(())
     }
 
@@ -55,23 +122,203 @@ impl Wallets {
         self.wallets.get(addr)
     }
 
-    pub fn create_wallet(&mut self) -> String {
-        let wallet = Wallet::new();
-        let addr = wallet.get_address();
-        self.wallets.insert(addr.to_owned(), wallet);
-        info!("create wallet: {}", addr);
-        addr
+    /// The wallet whose key hashes to `pub_key_hash` under `params`.
+    pub fn find_by_pub_key_hash(
+        &self,
+        pub_key_hash: &[u8],
+        params: &ChainParams,
+    ) -> Option<&Wallet> {
+        self.wallets
+            .values()
+            .find(|w| hash_pub_key(&w.public_key, params) == pub_key_hash)
+    }
+
+    /// Creates a wallet and returns its address on a chain using `params`.
+    pub fn create_wallet(&mut self, params: &ChainParams) -> String {
+        loop {
+            let wallet = Wallet::new();
+            let addr = wallet.get_address(params);
+            if self.insert_wallet(wallet, false, params) == WalletInsert::Added {
+                info!("create wallet: {}", addr);
+                return addr;
+            }
+        }
+    }
+
+    /// Adds `wallet` under its address on a chain using `params`. A wallet
+    /// already stored there is only replaced when `overwrite` is set, so a
+    /// funded key can't be lost by accident.
+    pub fn insert_wallet(
+        &mut self,
+        wallet: Wallet,
+        overwrite: bool,
+        params: &ChainParams,
+    ) -> WalletInsert {
+        let addr = wallet.get_address(params);
+        match self.wallets.contains_key(&addr) {
+            false => {
+                self.wallets.insert(addr, wallet);
+                WalletInsert::Added
+            }
+            true if overwrite => {
+                self.wallets.insert(addr, wallet);
+                WalletInsert::Overwritten
+            }
+            true => WalletInsert::Existing,
+        }
     }
 
-    pub fn save(&self) -> Result<()> {
-        let db = sled::open("db/wallets")?;
+    /// Labels `target`, an address or a `txid:vout` outpoint, or removes
+    /// its label when `label` is `None`. Addresses must be valid under
+    /// `params`. Call `save_at` to persist it.
+    pub fn set_label(
+        &mut self,
+        target: &str,
+        label: Option<&str>,
+        params: &ChainParams,
+    ) -> Result<()> {
+        check_label_target(target, params)?;
+        match label {
+            Some(label) => {
+                self.labels.insert(target.to_owned(), label.to_owned());
+            }
+            None => {
+                self.labels.remove(target);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_label(&self, target: &str) -> Option<&str> {
+        self.labels.get(target).map(String::as_str)
+    }
+
+    /// Remembers `tx` for `transaction_status`. Call `save_at` to persist it.
+    pub fn record_transaction(&mut self, tx: &Transaction) {
+        self.transactions.insert(tx.id.clone(), tx.clone());
+    }
+
+    /// Status on `bc` of a transaction recorded with `record_transaction`,
+    /// see `Blockchain::transaction_status`.
+    pub fn transaction_status(&self, tx_id: &str, bc: &Blockchain) -> Result<TxStatus> {
+        let tx = self
+            .transactions
+            .get(tx_id)
+            .ok_or_else(|| anyhow!("Transaction {} wasn't sent from this wallet", tx_id))?;
+        bc.transaction_status(tx)
+    }
+
+    /// Writes the wallets to the store at `path`.
+    pub fn save_at(&self, path: impl AsRef<Path>) -> Result<()> {
+        self.save_to(&sled::open(path)?)
+    }
+
+    fn save_to(&self, db: &sled::Db) -> Result<()> {
         for (addr, wallet) in &self.wallets {
             let data = encode_to_vec(wallet, standard())?;
             db.insert(addr, data)?;
         }
+        // Rewritten whole so removed labels don't come back
+        let labels = db.open_tree(LABELS_TREE)?;
+        labels.clear()?;
+        for (target, label) in &self.labels {
+            labels.insert(target, label.as_bytes())?;
+        }
+        let transactions = db.open_tree(TRANSACTIONS_TREE)?;
+        for (id, tx) in &self.transactions {
+            transactions.insert(id, encode_to_vec(tx, standard())?)?;
+        }
+        let settings = db.open_tree(SETTINGS_TREE)?;
+        if self.sort_bip69 {
+            settings.insert(SORT_BIP69_KEY, &[])?;
+        } else {
+            settings.remove(SORT_BIP69_KEY)?;
+        }
+        settings.insert(
+            COIN_SELECTION_KEY,
+            encode_to_vec(self.coin_selection, standard())?,
+        )?;
         db.flush()?;
         Ok(())
     }
+
+    /// Writes every wallet to `path`, encrypted with a key derived from
+    /// `passphrase`.
+    pub fn backup(&self, path: impl AsRef<Path>, passphrase: &str) -> Result<()> {
+        let data = self.encrypt_backup(passphrase)?;
+        std::fs::write(path, data)?;
+        Ok(())
+    }
+
+    /// Imports the wallets of an encrypted backup, returning what happened
+    /// to each address on a chain using `params`. Wallets already in the
+    /// store are kept unless `overwrite` is set. Call `save_at` to persist
+    /// them.
+    pub fn restore(
+        &mut self,
+        path: impl AsRef<Path>,
+        passphrase: &str,
+        overwrite: bool,
+        params: &ChainParams,
+    ) -> Result<Vec<(String, WalletInsert)>> {
+        let data = std::fs::read(path)?;
+        let mut res = vec![];
+        for wallet in Self::decrypt_backup(&data, passphrase)? {
+            let addr = wallet.get_address(params);
+            res.push((addr, self.insert_wallet(wallet, overwrite, params)));
+        }
+        Ok(res)
+    }
+
+    pub fn encrypt_backup(&self, passphrase: &str) -> Result<Vec<u8>> {
+        let wallets: Vec<&Wallet> = self.wallets.values().collect();
+        let plaintext = encode_to_vec(&wallets, standard())?;
+
+        let mut salt = [0u8; BACKUP_SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let cipher = ChaCha20Poly1305::new(&derive_backup_key(passphrase, &salt)?);
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(&nonce, plaintext.as_slice())
+            .map_err(|_| anyhow!("Failed to encrypt wallet backup"))?;
+
+        let backup = WalletBackup {
+            salt,
+            nonce: nonce.into(),
+            ciphertext,
+        };
+        Ok(encode_to_vec(backup, standard())?)
+    }
+
+    pub fn decrypt_backup(data: &[u8], passphrase: &str) -> Result<Vec<Wallet>> {
+        let backup: WalletBackup = decode_from_slice(data, standard()).map(|(b, _)| b)?;
+
+        let cipher = ChaCha20Poly1305::new(&derive_backup_key(passphrase, &backup.salt)?);
+        let plaintext = cipher
+            .decrypt(
+                Nonce::from_slice(&backup.nonce),
+                backup.ciphertext.as_slice(),
+            )
+            .map_err(|_| anyhow!("Wrong passphrase or corrupted wallet backup"))?;
+
+        let wallets = decode_from_slice(&plaintext, standard()).map(|(w, _)| w)?;
+        Ok(wallets)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct WalletBackup {
+    salt: [u8; BACKUP_SALT_LEN],
+    nonce: [u8; 12],
+    ciphertext: Vec<u8>,
+}
+
+fn derive_backup_key(passphrase: &str, salt: &[u8]) -> Result<Key> {
+    let params = scrypt::Params::new(14, 8, 1, 32).map_err(|e| anyhow!("scrypt params: {}", e))?;
+    let mut key = Key::default();
+    scrypt::scrypt(passphrase.as_bytes(), salt, &params, &mut key)
+        .map_err(|e| anyhow!("scrypt: {}", e))?;
+    Ok(key)
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -95,18 +342,191 @@ impl Wallet {
         }
     }
 
-    pub fn get_address(&self) -> String {
-        let pub_key_hash = hash_pub_key(&self.public_key);
+    /// The address of this wallet on a chain using `params`.
+    pub fn get_address(&self, params: &ChainParams) -> String {
+        pub_key_hash_to_address(&params.address_hash.hash(&self.public_key), params)
+    }
+
+    /// Signs `message` for `verify_message`. The result, in base58, holds
+    /// the compressed public key followed by the signature, so it can be
+    /// checked against the address alone.
+    pub fn sign_message(&self, message: &str) -> Result<String> {
+        let signing_key = SigningKey::from_slice(&self.private_key)?;
+        let signature: Signature = signing_key.sign(&signed_message_payload(message));
+        let mut blob = VerifyingKey::from(&signing_key)
+            .to_encoded_point(true)
+            .as_bytes()
+            .to_vec();
+        blob.extend_from_slice(&signature.to_bytes());
+        Ok(blob.to_base58())
+    }
+}
+
+/// Whether `signature`, made by `Wallet::sign_message`, signs `message`
+/// with the key behind `address`, an address of a chain using `params`.
+/// Fails only when the address or the signature can't be decoded.
+pub fn verify_message(
+    address: &str,
+    message: &str,
+    signature: &str,
+    params: &ChainParams,
+) -> Result<bool> {
+    let info = decode_address(address, params)?;
+    if !info.checksum_valid() {
+        return Err(anyhow!("Invalid address {}", address));
+    }
+    let blob = signature
+        .from_base58()
+        .map_err(|e| anyhow!("Invalid base58 in signature: {:?}", e))?;
+    if blob.len() != COMPRESSED_PUB_KEY_LEN + SIGNATURE_LEN {
+        return Err(anyhow!(
+            "Signature decodes to {} bytes, expected {}",
+            blob.len(),
+            COMPRESSED_PUB_KEY_LEN + SIGNATURE_LEN
+        ));
+    }
+    let (pub_key, signature) = blob.split_at(COMPRESSED_PUB_KEY_LEN);
+    let pub_key = VerifyingKey::from_sec1_bytes(pub_key)
+        .map_err(|_| anyhow!("Invalid public key in signature"))?;
+    let signature =
+        Signature::from_slice(signature).map_err(|_| anyhow!("Invalid signature encoding"))?;
+
+    // Addresses hash the uncompressed key
+    let uncompressed = pub_key.to_encoded_point(false);
+    if hash_pub_key(uncompressed.as_bytes(), params) != info.pub_key_hash {
+        return Ok(false);
+    }
+    Ok(pub_key
+        .verify(&signed_message_payload(message), &signature)
+        .is_ok())
+}
+
+fn signed_message_payload(message: &str) -> Vec<u8> {
+    [SIGNED_MESSAGE_MAGIC, message.as_bytes()].concat()
+}
+
+/// Encodes a public key hash as a base58check address with the address
+/// version and checksum length of `params`.
+pub fn pub_key_hash_to_address(pub_key_hash: &[u8], params: &ChainParams) -> String {
+    let mut versioned_payload = vec![params.address_version];
+    versioned_payload.extend_from_slice(pub_key_hash);
+
+    let checksum = checksum(&versioned_payload, checksum_len(params));
+
+    let mut full_payload = versioned_payload;
+    full_payload.extend_from_slice(&checksum);
+
+    full_payload.to_base58()
+}
+
+/// Components of a base58check address, see `decode_address`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressInfo {
+    pub version: u8,
+    pub pub_key_hash: Vec<u8>,
+    /// Checksum carried by the address
+    pub checksum: Vec<u8>,
+    /// Checksum recomputed from the version and public key hash
+    pub expected_checksum: Vec<u8>,
+}
+
+impl AddressInfo {
+    pub fn checksum_valid(&self) -> bool {
+        self.checksum == self.expected_checksum
+    }
+}
 
-        let mut versioned_payload = vec![VERSION];
-        versioned_payload.extend_from_slice(&pub_key_hash);
+/// Splits `address` into its components, taking the checksum length
+/// from `params`.
+///
+/// Unlike `get_pub_key_hash` this doesn't require the address to be valid:
+/// a wrong checksum or version is reported rather than treated as an error.
+/// Only text that isn't base58, or is too short to hold a version and a
+/// checksum, fails.
+pub fn decode_address(address: &str, params: &ChainParams) -> Result<AddressInfo> {
+    let checksum_len = checksum_len(params);
+    let payload = address
+        .from_base58()
+        .map_err(|e| anyhow!("Invalid base58 in address: {:?}", e))?;
+    if payload.len() <= 1 + checksum_len {
+        return Err(anyhow!(
+            "Address decodes to {} bytes, too short to be an address",
+            payload.len()
+        ));
+    }
+    let (versioned_payload, stored) = payload.split_at(payload.len() - checksum_len);
+    Ok(AddressInfo {
+        version: versioned_payload[0],
+        pub_key_hash: versioned_payload[1..].to_vec(),
+        checksum: stored.to_vec(),
+        expected_checksum: checksum(versioned_payload, checksum_len),
+    })
+}
 
-        let checksum = checksum(&versioned_payload);
+/// Generates wallets on `threads` threads until one has an address under
+/// `params` starting with `prefix` right after the leading character, which
+/// the version byte fixes. Returns the wallet and the number of attempts, or `None` once
+/// `cancel` is set.
+pub fn find_vanity_wallet(
+    prefix: &str,
+    threads: usize,
+    cancel: &AtomicBool,
+    params: &ChainParams,
+) -> Result<Option<(Wallet, u64)>> {
+    if let Some(c) = prefix.chars().find(|c| !BASE58_ALPHABET.contains(*c)) {
+        return Err(anyhow!("'{}' never appears in an address", c));
+    }
+    let attempts = AtomicU64::new(0);
+    let found = Mutex::new(None);
+    thread::scope(|scope| {
+        for _ in 0..threads.max(1) {
+            scope.spawn(|| {
+                while !cancel.load(Ordering::Relaxed) {
+                    let wallet = Wallet::new();
+                    attempts.fetch_add(1, Ordering::Relaxed);
+                    if wallet.get_address(params)[1..].starts_with(prefix) {
+                        found.lock().unwrap().get_or_insert(wallet);
+                        cancel.store(true, Ordering::Relaxed);
+                    }
+                }
+            });
+        }
+    });
+    let attempts = attempts.into_inner();
+    Ok(found.into_inner().unwrap().map(|wallet| (wallet, attempts)))
+}
 
-        let mut full_payload = versioned_payload;
-        full_payload.extend_from_slice(&checksum);
+/// Accepts a valid address under `params` or a `txid:vout` outpoint.
+fn check_label_target(target: &str, params: &ChainParams) -> Result<()> {
+    if let Some((tx_id, v_out)) = target.split_once(':') {
+        let is_tx_id = tx_id.len() == 64 && tx_id.bytes().all(|b| b.is_ascii_hexdigit());
+        if !is_tx_id || v_out.parse::<u32>().is_err() {
+            return Err(anyhow!("Invalid outpoint {}, expected txid:vout", target));
+        }
+        return Ok(());
+    }
+    validate_address(target, params)
+}
 
-        full_payload.to_base58()
+/// Fails unless `address` is a base58check address with the address
+/// version and checksum length of `params`. An address of another network
+/// is reported as such, coins sent to it would be lost.
+pub fn validate_address(address: &str, params: &ChainParams) -> Result<()> {
+    let info = decode_address(address, params)?;
+    if !info.checksum_valid() {
+        return Err(anyhow!("Invalid address {}", address));
+    }
+    if info.version == params.address_version {
+        return Ok(());
+    }
+    match Network::from_address_version(info.version) {
+        Some(other) if other != params.network => Err(anyhow!(
+            "Address {} is a {} address, this node is on {}",
+            address,
+            other,
+            params.network
+        )),
+        _ => Err(anyhow!("Invalid address {}", address)),
     }
 }
 
@@ -118,7 +538,13 @@ fn new_key_pair() -> (Vec<u8>, Vec<u8>) {
     (private_key_bytes, pub_key_bytes)
 }
 
-fn checksum(payload: &[u8]) -> Vec<u8> {
+/// The checksum length of addresses under `params`, no longer than the
+/// double SHA256 it is cut from.
+fn checksum_len(params: &ChainParams) -> usize {
+    params.address_checksum_len.min(MAX_CHECKSUM_LEN)
+}
+
+fn checksum(payload: &[u8], len: usize) -> Vec<u8> {
     let mut first_sha = Sha256::new();
     first_sha.update(payload);
     let first_hash = first_sha.finalize();
@@ -127,5 +553,213 @@ fn checksum(payload: &[u8]) -> Vec<u8> {
     second_sha.update(first_hash);
     let second_hash = second_sha.finalize();
 
-    second_hash[..ADDRESS_CHECKSUM_LEN].to_vec()
+    second_hash[..len].to_vec()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{AddressHash, get_pub_key_hash, testutil::TestDir};
+
+    #[test]
+    fn test_backup_round_trip() {
+        let params = ChainParams::default();
+        let mut ws = Wallets::default();
+        let addrs = [(); 3].map(|_| ws.create_wallet(&params));
+
+        let data = ws.encrypt_backup("correct horse").unwrap();
+        assert!(Wallets::decrypt_backup(&data, "wrong horse").is_err());
+
+        let restored = Wallets::decrypt_backup(&data, "correct horse").unwrap();
+        assert_eq!(restored.len(), addrs.len());
+        for wallet in restored {
+            let original = ws.get_wallet(&wallet.get_address(&params)).unwrap();
+            assert_eq!(original.private_key, wallet.private_key);
+        }
+    }
+
+    #[test]
+    fn test_insert_wallet_keeps_existing_key() {
+        let mut ws = Wallets::default();
+        let wallet = Wallet::new();
+        let params = ChainParams::default();
+        let addr = wallet.get_address(&params);
+        let mut imported = wallet.clone();
+        imported.private_key = Wallet::new().private_key;
+
+        assert_eq!(
+            ws.insert_wallet(wallet.clone(), false, &params),
+            WalletInsert::Added
+        );
+        assert_eq!(
+            ws.insert_wallet(imported.clone(), false, &params),
+            WalletInsert::Existing
+        );
+        assert_eq!(
+            ws.get_wallet(&addr).unwrap().private_key,
+            wallet.private_key
+        );
+
+        assert_eq!(
+            ws.insert_wallet(imported.clone(), true, &params),
+            WalletInsert::Overwritten
+        );
+        assert_eq!(
+            ws.get_wallet(&addr).unwrap().private_key,
+            imported.private_key
+        );
+    }
+
+    #[test]
+    fn test_decode_address_reports_bad_checksum() {
+        let params = ChainParams::default();
+        let pub_key_hash = hash_pub_key(&Wallet::new().public_key, &params);
+        let address = pub_key_hash_to_address(&pub_key_hash, &params);
+        let info = decode_address(&address, &params).unwrap();
+        assert_eq!(info.version, Network::default().address_version());
+        assert_eq!(info.pub_key_hash, pub_key_hash);
+        assert!(info.checksum_valid());
+
+        let mut payload = address.from_base58().unwrap();
+        *payload.last_mut().unwrap() ^= 1;
+        let info = decode_address(&payload.to_base58(), &params).unwrap();
+        assert_eq!(info.pub_key_hash, pub_key_hash);
+        assert!(!info.checksum_valid());
+
+        assert!(decode_address("0OIl", &params).is_err());
+        assert!(decode_address(&[0u8; 5].to_base58(), &params).is_err());
+
+        // Unusable addresses are errors rather than panics
+        assert!(get_pub_key_hash(&payload.to_base58(), &params).is_err());
+        assert!(get_pub_key_hash("0OIl", &params).is_err());
+        assert!(get_pub_key_hash(&[0u8; 3].to_base58(), &params).is_err());
+        assert_eq!(get_pub_key_hash(&address, &params).unwrap(), pub_key_hash);
+
+        // A checksum longer than a double SHA256 is cut to 32 bytes
+        let long = ChainParams {
+            address_checksum_len: 40,
+            ..ChainParams::default()
+        };
+        let address = pub_key_hash_to_address(&pub_key_hash, &long);
+        validate_address(&address, &long).unwrap();
+        assert_eq!(get_pub_key_hash(&address, &long).unwrap(), pub_key_hash);
+    }
+
+    #[test]
+    fn test_sign_and_verify_message() {
+        let params = ChainParams::default();
+        let wallet = Wallet::new();
+        let address = wallet.get_address(&params);
+        let signature = wallet.sign_message("hello").unwrap();
+        assert!(verify_message(&address, "hello", &signature, &params).unwrap());
+        assert!(!verify_message(&address, "hello!", &signature, &params).unwrap());
+
+        let other = Wallet::new().get_address(&params);
+        assert!(!verify_message(&other, "hello", &signature, &params).unwrap());
+
+        let mut blob = signature.from_base58().unwrap();
+        *blob.last_mut().unwrap() ^= 1;
+        assert!(!verify_message(&address, "hello", &blob.to_base58(), &params).unwrap());
+        assert!(verify_message(&address, "hello", &blob[1..].to_base58(), &params).is_err());
+
+        // An address hashing the key with SHA256 only
+        let sha256 = ChainParams {
+            address_hash: AddressHash::Sha256,
+            ..ChainParams::default()
+        };
+        let address = wallet.get_address(&sha256);
+        assert!(verify_message(&address, "hello", &signature, &sha256).unwrap());
+        assert!(!verify_message(&address, "hello", &signature, &params).unwrap());
+    }
+
+    #[test]
+    fn test_address_params() {
+        let wallet = Wallet::new();
+        let fork = ChainParams {
+            address_version: 0x42,
+            address_checksum_len: 6,
+            ..ChainParams::default()
+        };
+        let params = [ChainParams::default(), fork];
+        let addresses = params.each_ref().map(|p| wallet.get_address(p));
+        assert_eq!(addresses[0], wallet.get_address(&ChainParams::default()));
+        assert_ne!(addresses[0], addresses[1]);
+
+        for (i, address) in addresses.iter().enumerate() {
+            assert!(validate_address(address, &params[i]).is_ok());
+            assert!(validate_address(address, &params[1 - i]).is_err());
+            assert_eq!(
+                get_pub_key_hash(address, &params[i]).unwrap(),
+                hash_pub_key(&wallet.public_key, &params[i])
+            );
+        }
+    }
+
+    #[test]
+    fn test_vanity_wallet() {
+        let params = ChainParams::default();
+        let cancel = AtomicBool::new(false);
+        let (wallet, attempts) = find_vanity_wallet("a", 2, &cancel, &params)
+            .unwrap()
+            .unwrap();
+        assert!(wallet.get_address(&params).starts_with("1a"));
+        assert!(attempts >= 1);
+
+        assert!(find_vanity_wallet("0", 1, &cancel, &params).is_err());
+        let cancelled = AtomicBool::new(true);
+        assert!(
+            find_vanity_wallet("zzzzzz", 1, &cancelled, &params)
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_wallets_use_network_addresses() {
+        let testnet = ChainParams::for_network(Network::Testnet);
+        let mut ws = Wallets::default();
+        let addr = ws.create_wallet(&testnet);
+        assert!(validate_address(&addr, &testnet).is_ok());
+        assert!(ws.get_wallet(&addr).is_some());
+        assert!(ws.set_label(&addr, Some("x"), &testnet).is_ok());
+        assert!(
+            ws.set_label(&addr, Some("x"), &ChainParams::default())
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_labels_survive_save_and_load() {
+        let dir = TestDir::new("labels");
+        let mut ws = Wallets::default();
+        let params = ChainParams::default();
+        let addr = ws.create_wallet(&params);
+        let outpoint = format!("{}:1", "ab".repeat(32));
+        ws.set_label(&addr, Some("savings"), &params).unwrap();
+        ws.set_label(&outpoint, Some("rent for may"), &params)
+            .unwrap();
+        assert!(ws.set_label("not an address", Some("x"), &params).is_err());
+        assert!(ws.set_label("abcd:1", Some("x"), &params).is_err());
+        ws.sort_bip69 = true;
+        // One handle throughout, sled may not release its lock right away
+        let db = sled::open(&dir).unwrap();
+        ws.save_to(&db).unwrap();
+
+        let mut loaded = Wallets::default();
+        loaded.load_from(&db).unwrap();
+        assert_eq!(loaded.wallets.len(), 1);
+        assert_eq!(loaded.get_label(&addr), Some("savings"));
+        assert_eq!(loaded.get_label(&outpoint), Some("rent for may"));
+        assert!(loaded.sort_bip69);
+
+        loaded.set_label(&outpoint, None, &params).unwrap();
+        loaded.sort_bip69 = false;
+        loaded.save_to(&db).unwrap();
+        let mut reloaded = Wallets::default();
+        reloaded.load_from(&db).unwrap();
+        assert_eq!(reloaded.get_label(&outpoint), None);
+        assert_eq!(reloaded.get_label(&addr), Some("savings"));
+        assert!(!reloaded.sort_bip69);
+        drop(db);
+    }
 }